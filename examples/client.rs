@@ -62,7 +62,7 @@ fn main() {
 
         // Send a message to all connected clients
         if let Ok(conn) = client.connection() {
-            conn.send(MessageKind::Instant, b"Hello from Client".to_vec());
+            conn.send(MessageKind::Instant, b"Hello from Client".to_vec()).ok();
         }
 
         // Send all outgoing messages.
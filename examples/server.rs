@@ -28,7 +28,7 @@ fn main() {
         while let Ok(event) = server.accept_receive() {
             // Handle events (e.g. Connection, Messages, etc.)
             match event {
-                ServerEvent::Connection(id) => {
+                ServerEvent::Connection(id, _) => {
                     let conn = server.connection(&id).unwrap();
                     println!(
                         "[Server] Client {} ({}, {}ms rtt) connected.",
@@ -65,7 +65,7 @@ fn main() {
 
         // Send a message to all connected clients
         for (_, conn) in server.connections() {
-            conn.send(MessageKind::Instant, b"Hello from Server".to_vec());
+            conn.send(MessageKind::Instant, b"Hello from Server".to_vec()).ok();
         }
 
         // Send all outgoing messages.
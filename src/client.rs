@@ -11,16 +11,19 @@
 use std::io::{Error, ErrorKind};
 use std::net::{SocketAddr, ToSocketAddrs};
 use std::sync::mpsc::TryRecvError;
+use std::time::Instant;
 use std::collections::VecDeque;
 
 
 // Internal Dependencies ------------------------------------------------------
+use shared::encryption::{seal_payload, KeyExchange, PAYLOAD_OVERHEAD};
+use shared::handshake::{challenge_packet_size, Cookie, HandshakePacket};
 use shared::stats::{Stats, StatsCollector};
 use shared::ticker::Ticker;
 use super::{
     Config,
-    Connection, ConnectionEvent,
-    RateLimiter, PacketModifier, Socket
+    Connection, ConnectionEvent, ConnectionState, MessageID,
+    ProtocolVersion, RateLimiter, RejectionReason, RequestID, PacketModifier, Socket
 };
 
 
@@ -34,6 +37,14 @@ pub enum ClientEvent {
     /// Emitted when a initial connection attempt to a server failed.
     ConnectionFailed,
 
+    /// Emitted when a server explicitly rejected the connection attempt.
+    ConnectionRejected(RejectionReason),
+
+    /// Emitted when a server rejected the connection attempt because it
+    /// speaks a different protocol version, providing the client's and the
+    /// server's version.
+    ConnectionVersionMismatch(ProtocolVersion, ProtocolVersion),
+
     /// Emitted when a existing connection to a server is lost.
     ConnectionLost,
 
@@ -47,11 +58,57 @@ pub enum ClientEvent {
     /// within the specified limits.
     PacketLost(Vec<u8>),
 
+    /// Emitted once the packet carrying a message has been confirmed by a
+    /// server.
+    ///
+    /// For messages which were split into fragments, this is only emitted
+    /// once all of their fragments have been confirmed.
+    MessageDelivered(MessageID),
+
+    /// Emitted for each unreliable or cancelled message whose packet was not
+    /// confirmed by a server within the specified limits.
+    MessageLost(MessageID),
+
+    /// Emitted for each message which expired before it could be send to a
+    /// server.
+    MessageExpired(MessageID),
+
+    /// Emitted once the send queue drained again after it was full.
+    SendQueueDrained,
+
+    /// Emitted for each request received from a server, which should be
+    /// answered via `Connection::respond`.
+    Request(RequestID, Vec<u8>),
+
+    /// Emitted once a server responded to a request, either with a result or
+    /// with an error.
+    Response(RequestID, Result<Vec<u8>, Vec<u8>>),
+
+    /// Emitted for each request which a server did not respond to in time or
+    /// before the connection was lost, closed or reset.
+    RequestTimedOut(RequestID),
+
     /// Emitted each time the connection's congestion state changes.
     ConnectionCongestionStateChanged(bool)
 
 }
 
+/// Enum indicating the client side state of the connection handshake.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Handshake {
+
+    /// The client is requesting a challenge from the server.
+    Requesting,
+
+    /// The client is returning the cookie of a received challenge to the
+    /// server and waits for the first packet of the established connection.
+    Responding(Cookie),
+
+    /// The server rejected the connection attempt.
+    Rejected
+
+}
+
 /// Implementation of a low latency socket client.
 ///
 /// # Basic Usage
@@ -76,7 +133,7 @@ pub enum ClientEvent {
 ///
 ///     // Schedule a message to the send to the server
 ///     if let Ok(connection) = client.connection() {
-///         connection.send(MessageKind::Instant, b"Ping".to_vec());
+///         connection.send(MessageKind::Instant, b"Ping".to_vec()).ok();
 ///     }
 ///
 ///     // Send all outgoing messages.
@@ -94,6 +151,11 @@ pub struct Client<S: Socket, R: RateLimiter, M: PacketModifier> {
     config: Config,
     socket: Option<S>,
     connection: Option<Connection<R, M>>,
+    handshake: Handshake,
+    handshake_time: Instant,
+    connect_payload: Vec<u8>,
+    response_payload: Vec<u8>,
+    key_exchange: KeyExchange,
     ticker: Ticker,
     peer_address: Option<SocketAddr>,
     local_address: Option<SocketAddr>,
@@ -111,6 +173,11 @@ impl<S: Socket, R: RateLimiter, M: PacketModifier> Client<S, R, M> {
             config: config,
             socket: None,
             connection: None,
+            handshake: Handshake::Requesting,
+            handshake_time: Instant::now(),
+            connect_payload: Vec::new(),
+            response_payload: Vec::new(),
+            key_exchange: KeyExchange::new(),
             ticker: Ticker::new(config),
             peer_address: None,
             local_address: None,
@@ -183,9 +250,55 @@ impl<S: Socket, R: RateLimiter, M: PacketModifier> Client<S, R, M> {
     }
 
     /// Establishes a connection with the server at the specified address.
+    ///
+    /// The connection is only considered established once the server has
+    /// challenged the connection attempt and acknowledged the client's
+    /// response with its first packet.
     pub fn connect<A: ToSocketAddrs>(&mut self, addr: A) -> Result<(), Error> {
+        self.connect_with_payload(addr, Vec::new())
+    }
 
-        if self.socket.is_none() {
+    /// Establishes a connection with the server at the specified address,
+    /// presenting a signed `ConnectToken` which was handed to the client by
+    /// a trusted backend.
+    ///
+    /// The token is treated as opaque data and passed on to the server as is.
+    /// It is only encrypted when `Config::encryption` is enabled, otherwise
+    /// anyone who observes the handshake can redeem the token first.
+    pub fn connect_with_token<A: ToSocketAddrs>(
+        &mut self,
+        addr: A,
+        token: Vec<u8>
+
+    ) -> Result<(), Error> {
+        self.connect_with_payload(addr, token)
+    }
+
+    /// Establishes a connection with the server at the specified address,
+    /// presenting the given `payload` to the server's `AdmissionPolicy`.
+    ///
+    /// The payload is send along with each challenge response and must fit
+    /// into a single packet of the configured `packet_max_size`. With
+    /// `Config::encryption` enabled it is sealed with the connection key,
+    /// taking up `16` additional bytes.
+    pub fn connect_with_payload<A: ToSocketAddrs>(
+        &mut self,
+        addr: A,
+        payload: Vec<u8>
+
+    ) -> Result<(), Error> {
+
+        let overhead = if self.config.encryption {
+            challenge_packet_size(&self.config) + PAYLOAD_OVERHEAD
+
+        } else {
+            challenge_packet_size(&self.config)
+        };
+
+        if payload.len() > self.config.packet_max_size.saturating_sub(overhead) {
+            Err(Error::new(ErrorKind::InvalidInput, "Connection payload exceeds packet_max_size."))
+
+        } else if self.socket.is_none() {
 
             let socket = try!(S::new(
                 "0.0.0.0:0",
@@ -207,6 +320,10 @@ impl<S: Socket, R: RateLimiter, M: PacketModifier> Client<S, R, M> {
                 M::new(self.config)
             ));
 
+            self.handshake = Handshake::Requesting;
+            self.handshake_time = Instant::now();
+            self.connect_payload = payload;
+            self.key_exchange = KeyExchange::new();
             self.should_receive = true;
 
             Ok(())
@@ -237,7 +354,7 @@ impl<S: Socket, R: RateLimiter, M: PacketModifier> Client<S, R, M> {
                 while let Ok((addr, packet)) = self.socket.as_mut().unwrap().try_recv() {
                     if addr == peer_address {
                         bytes_received += packet.len();
-                        self.connection.as_mut().unwrap().receive_packet(packet);
+                        self.receive_packet(packet);
                     }
                 }
 
@@ -252,7 +369,14 @@ impl<S: Socket, R: RateLimiter, M: PacketModifier> Client<S, R, M> {
                         ConnectionEvent::Closed(p) => ClientEvent::ConnectionClosed(p),
                         ConnectionEvent::Message(payload) => ClientEvent::Message(payload),
                         ConnectionEvent::CongestionStateChanged(c) => ClientEvent::ConnectionCongestionStateChanged(c),
-                        ConnectionEvent::PacketLost(payload) => ClientEvent::PacketLost(payload)
+                        ConnectionEvent::PacketLost(payload) => ClientEvent::PacketLost(payload),
+                        ConnectionEvent::MessageDelivered(id) => ClientEvent::MessageDelivered(id),
+                        ConnectionEvent::MessageLost(id) => ClientEvent::MessageLost(id),
+                        ConnectionEvent::MessageExpired(id) => ClientEvent::MessageExpired(id),
+                        ConnectionEvent::SendQueueDrained => ClientEvent::SendQueueDrained,
+                        ConnectionEvent::Request(request, payload) => ClientEvent::Request(request, payload),
+                        ConnectionEvent::Response(request, result) => ClientEvent::Response(request, result),
+                        ConnectionEvent::RequestTimedOut(request) => ClientEvent::RequestTimedOut(request)
                     });
                 }
 
@@ -281,10 +405,42 @@ impl<S: Socket, R: RateLimiter, M: PacketModifier> Client<S, R, M> {
         if self.socket.is_some() {
 
             let peer_address = self.peer_address.unwrap();
-            let bytes_sent = self.connection.as_mut().unwrap().send_packet(
-                self.socket.as_mut().unwrap(),
-                &peer_address
-            );
+            let connection = self.connection.as_mut().unwrap();
+            let socket = self.socket.as_mut().unwrap();
+
+            // Rejected connection attempts are not retried
+            let bytes_sent = if self.handshake == Handshake::Rejected {
+                0
+
+            // Perform the handshake until the connection is established or the
+            // server failed to respond in time, in which case the connection
+            // itself will detect the failure
+            } else if connection.state() == ConnectionState::Connecting
+                && self.handshake_time.elapsed() <= self.config.connection_init_threshold {
+
+                let packet = if let Handshake::Responding(cookie) = self.handshake {
+                    let key = if self.config.encryption {
+                        Some(self.key_exchange.public_key())
+
+                    } else {
+                        None
+                    };
+                    HandshakePacket::ChallengeResponse(cookie, key, &self.response_payload)
+
+                } else {
+                    HandshakePacket::ConnectRequest(ProtocolVersion::from_config(&self.config))
+
+                }.to_packet(&self.config, connection.id());
+
+                socket.send_to(&packet[..], peer_address).unwrap_or_else(|_| {
+                    panic!("Failed to send handshake packet to {:?}", peer_address)
+                });
+
+                packet.len() as u32
+
+            } else {
+                connection.send_packet(socket, &peer_address)
+            };
 
             self.stats_collector.set_bytes_sent(bytes_sent);
             self.stats_collector.tick();
@@ -312,6 +468,9 @@ impl<S: Socket, R: RateLimiter, M: PacketModifier> Client<S, R, M> {
     pub fn reset(&mut self) -> Result<(), Error> {
         if self.socket.is_some() {
             self.connection.as_mut().unwrap().reset();
+            self.handshake = Handshake::Requesting;
+            self.handshake_time = Instant::now();
+            self.key_exchange = KeyExchange::new();
             self.stats_collector.reset();
             self.stats.reset();
             self.events.clear();
@@ -331,6 +490,7 @@ impl<S: Socket, R: RateLimiter, M: PacketModifier> Client<S, R, M> {
             self.should_receive = false;
             self.peer_address = None;
             self.local_address = None;
+            self.connect_payload.clear();
             self.connection = None;
             self.socket = None;
             Ok(())
@@ -340,5 +500,86 @@ impl<S: Socket, R: RateLimiter, M: PacketModifier> Client<S, R, M> {
         }
     }
 
-}
+    // Internal ---------------------------------------------------------------
+    fn receive_packet(&mut self, packet: Vec<u8>) {
+
+        let connection = self.connection.as_mut().unwrap();
+
+        // Ignore packets which are meant for other connections
+        if Connection::<R, M>::id_from_packet(&self.config, &packet) != Some(connection.id()) {
+            return;
+        }
 
+        if let Some(handshake) = HandshakePacket::from_packet(&self.config, &packet) {
+
+            // Handshake packets are only relevant as long as the connection
+            // is still being established
+            if connection.state() == ConnectionState::Connecting
+                && self.handshake != Handshake::Rejected {
+
+                match handshake {
+                    HandshakePacket::Challenge(cookie, key) => {
+
+                        // With encryption enabled, only challenges which
+                        // provide a usable server key can be answered
+                        if self.config.encryption {
+
+                            // Servers without encryption never provide a key
+                            let key = match key {
+                                Some(key) => key,
+                                None => {
+                                    self.handshake = Handshake::Rejected;
+                                    self.events.push_back(ClientEvent::ConnectionRejected(
+                                        RejectionReason::EncryptionRequired
+                                    ));
+                                    return;
+                                }
+                            };
+
+                            let keys = self.key_exchange.connection_keys(
+                                &key, connection.id(), false
+                            );
+
+                            // The payload may carry a connect token and is
+                            // never send in plain text
+                            if let Some((client_key, server_key)) = keys {
+                                connection.set_encryption_keys(client_key, server_key);
+                                self.response_payload = seal_payload(
+                                    &client_key, &self.connect_payload, &cookie
+                                );
+
+                            } else {
+                                return;
+                            }
+
+                        } else {
+                            self.response_payload = self.connect_payload.clone();
+                        }
+
+                        self.handshake = Handshake::Responding(cookie);
+                        self.handshake_time = Instant::now();
+                    },
+                    HandshakePacket::Denial(RejectionReason::VersionMismatch(version)) => {
+                        self.handshake = Handshake::Rejected;
+                        self.events.push_back(ClientEvent::ConnectionVersionMismatch(
+                            ProtocolVersion::from_config(&self.config),
+                            version
+                        ));
+                    },
+                    HandshakePacket::Denial(reason) => {
+                        self.handshake = Handshake::Rejected;
+                        self.events.push_back(ClientEvent::ConnectionRejected(reason));
+                    },
+                    _ => {}
+                }
+
+            }
+
+        // Only accept connection packets once the challenge was answered
+        } else if let Handshake::Responding(_) = self.handshake {
+            connection.receive_packet(packet);
+        }
+
+    }
+
+}
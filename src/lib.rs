@@ -36,16 +36,33 @@ mod server;
 // Exports --------------------------------------------------------------------
 pub use shared::{
     BinaryRateLimiter,
+    BitReader,
+    BitWriter,
     Config,
+    ConnectToken,
     Connection,
     ConnectionID,
     ConnectionMap,
     ConnectionState,
     ConnectionEvent,
+    MessageID,
     MessageKind,
     NoopPacketModifier,
-    UdpSocket
+    ProtocolVersion,
+    RejectionReason,
+    RequestID,
+    UdpSocket,
+    MESSAGE_CHANNELS,
+    ORDERED_CHANNELS
 };
+#[cfg(any(feature = "lz4", feature = "deflate"))]
+pub use shared::NoDictionary;
+#[cfg(feature = "lz4")]
+pub use shared::Lz4PacketModifier;
+#[cfg(feature = "deflate")]
+pub use shared::DeflatePacketModifier;
+#[cfg(feature = "typed")]
+pub use shared::{encode_typed, MessageRegistry, TypedEvent, TypedMessage};
 pub use traits::*;
 pub use client::*;
 pub use server::*;
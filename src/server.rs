@@ -15,12 +15,18 @@ use std::collections::{HashMap, VecDeque};
 
 
 // Internal Dependencies ------------------------------------------------------
+use shared::encryption::{open_payload, ChallengeKeys};
+use shared::handshake::{
+    challenge_packet_size,
+    CookieGenerator, HandshakePacket, ProtocolVersion, RejectionReason, DENIAL_PACKET_SIZE
+};
 use shared::stats::{Stats, StatsCollector};
+use shared::TokenVerifier;
 use shared::ticker::Ticker;
 use super::{
-    Config,
-    ConnectionID, Connection, ConnectionEvent,
-    RateLimiter, PacketModifier, Socket
+    Config, ConnectToken,
+    ConnectionID, Connection, ConnectionEvent, MessageID, RequestID,
+    Admission, AdmissionPolicy, RateLimiter, PacketModifier, Socket
 };
 
 
@@ -29,7 +35,14 @@ use super::{
 pub enum ServerEvent {
 
     /// Event emitted once a new client connection has been established.
-    Connection(ConnectionID),
+    ///
+    /// Connections are only established after the client has completed the
+    /// challenge handshake and proven that it can receive packets at its
+    /// claimed address.
+    ///
+    /// For servers which require connect tokens, the verified token of the
+    /// client is included.
+    Connection(ConnectionID, Option<ConnectToken>),
 
     /// Event emitted when a existing client connection is lost.
     ConnectionLost(ConnectionID),
@@ -44,7 +57,38 @@ pub enum ServerEvent {
     ConnectionCongestionStateChanged(ConnectionID, bool),
 
     /// Event emitted each time a client connection packet is lost.
-    PacketLost(ConnectionID, Vec<u8>)
+    PacketLost(ConnectionID, Vec<u8>),
+
+    /// Event emitted once the packet carrying a message has been confirmed
+    /// by a client.
+    ///
+    /// For messages which were split into fragments, this is only emitted
+    /// once all of their fragments have been confirmed.
+    MessageDelivered(ConnectionID, MessageID),
+
+    /// Event emitted for each unreliable or cancelled message whose packet was
+    /// lost on its way to a client.
+    MessageLost(ConnectionID, MessageID),
+
+    /// Event emitted for each message which expired before it could be send
+    /// to a client.
+    MessageExpired(ConnectionID, MessageID),
+
+    /// Event emitted once a client connection's send queue drained again
+    /// after it was full.
+    SendQueueDrained(ConnectionID),
+
+    /// Event emitted for each request received from a client, which should
+    /// be answered via `Server::respond`.
+    Request(ConnectionID, RequestID, Vec<u8>),
+
+    /// Event emitted once a client responded to a request, either with a
+    /// result or with an error.
+    Response(ConnectionID, RequestID, Result<Vec<u8>, Vec<u8>>),
+
+    /// Event emitted for each request which a client did not respond to in
+    /// time or before its connection was lost or closed.
+    RequestTimedOut(ConnectionID, RequestID)
 
 }
 
@@ -73,7 +117,7 @@ pub enum ServerEvent {
 ///
 ///     // Send a message to all connected clients
 ///     for (_, conn) in server.connections() {
-///         conn.send(MessageKind::Instant, b"Ping".to_vec());
+///         conn.send(MessageKind::Instant, b"Ping".to_vec()).ok();
 ///     }
 ///
 ///     // Send all outgoing messages.
@@ -94,6 +138,11 @@ pub struct Server<S: Socket, R: RateLimiter, M: PacketModifier> {
     connections: HashMap<ConnectionID, Connection<R, M>>,
     addresses: HashMap<ConnectionID, SocketAddr>,
     dropped: Vec<ConnectionID>,
+    cookies: CookieGenerator,
+    challenge_keys: ChallengeKeys,
+    admission_policy: Option<Box<dyn AdmissionPolicy>>,
+    tokens: Option<TokenVerifier>,
+    verified_tokens: HashMap<ConnectionID, ConnectToken>,
     ticker: Ticker,
     local_address: Option<SocketAddr>,
     events: VecDeque<ServerEvent>,
@@ -112,6 +161,11 @@ impl<S: Socket, R: RateLimiter, M: PacketModifier> Server<S, R, M> {
             connections: HashMap::new(),
             addresses: HashMap::new(),
             dropped: Vec::new(),
+            cookies: CookieGenerator::new(),
+            challenge_keys: ChallengeKeys::new(),
+            admission_policy: None,
+            tokens: None,
+            verified_tokens: HashMap::new(),
             ticker: Ticker::new(config),
             local_address: None,
             events: VecDeque::new(),
@@ -154,6 +208,24 @@ impl<S: Socket, R: RateLimiter, M: PacketModifier> Server<S, R, M> {
         }
     }
 
+    /// Sends a request along with its `payload` to the specified client
+    /// connection, see `Connection::request`.
+    pub fn request(&mut self, id: &ConnectionID, payload: Vec<u8>) -> Result<RequestID, Error> {
+        self.connection(id)?.request(payload)
+    }
+
+    /// Responds to a request which was received from the specified client
+    /// connection via `ServerEvent::Request`, see `Connection::respond`.
+    pub fn respond(
+        &mut self,
+        id: &ConnectionID,
+        request: RequestID,
+        result: Result<Vec<u8>, Vec<u8>>
+
+    ) -> Result<MessageID, Error> {
+        self.connection(id)?.respond(request, result)
+    }
+
     /// Returns a mutable reference to the servers client connections.
     pub fn connections(&mut self) -> &mut HashMap<ConnectionID, Connection<R, M>> {
         &mut self.connections
@@ -187,6 +259,39 @@ impl<S: Socket, R: RateLimiter, M: PacketModifier> Server<S, R, M> {
 
     }
 
+    /// Sets the policy which decides whether incoming connection attempts
+    /// are accepted.
+    ///
+    /// The policy is consulted once a client has completed the challenge
+    /// handshake and receives the payload the client specified via
+    /// `Client::connect_with_payload`. Without a policy all connection
+    /// attempts are accepted as long as the server is not full.
+    pub fn set_admission_policy<P: AdmissionPolicy + 'static>(&mut self, policy: P) {
+        self.admission_policy = Some(Box::new(policy));
+    }
+
+    /// Removes the server's admission policy, accepting all further
+    /// connection attempts as long as the server is not full.
+    pub fn clear_admission_policy(&mut self) {
+        self.admission_policy = None;
+    }
+
+    /// Requires all further connection attempts to present a `ConnectToken`
+    /// which was signed with `key` and issued for the server's public
+    /// address `addr`.
+    ///
+    /// Clients presenting forged, expired or already used tokens are rejected
+    /// with `RejectionReason::InvalidToken` before any connection is created.
+    pub fn set_connect_token_key(&mut self, key: &[u8], addr: SocketAddr) {
+        self.tokens = Some(TokenVerifier::new(key, addr));
+    }
+
+    /// Removes the server's connect token key, no longer requiring any
+    /// tokens for further connection attempts.
+    pub fn clear_connect_token_key(&mut self) {
+        self.tokens = None;
+    }
+
     /// Binds the server to listen the specified address.
     pub fn listen<A: ToSocketAddrs>(&mut self, addr: A) -> Result<(), Error> {
 
@@ -229,7 +334,15 @@ impl<S: Socket, R: RateLimiter, M: PacketModifier> Server<S, R, M> {
 
                     // Try to extract the connection id from the packet
                     if let Some(id) = Connection::<R, M>::id_from_packet(&self.config, &packet) {
-                        bytes_received += self.receive_connection_packet(id, addr, packet);
+
+                        // Handshake packets never reach any connection
+                        if let Some(handshake) = HandshakePacket::from_packet(&self.config, &packet) {
+                            bytes_received += self.receive_handshake_packet(id, addr, handshake, packet.len());
+
+                        } else {
+                            bytes_received += self.receive_connection_packet(id, addr, packet);
+                        }
+
                     }
 
                 }
@@ -263,6 +376,7 @@ impl<S: Socket, R: RateLimiter, M: PacketModifier> Server<S, R, M> {
             for id in self.dropped.drain(0..) {
                 self.connections.remove(&id).unwrap().reset();
                 self.addresses.remove(&id);
+                self.verified_tokens.remove(&id);
             }
 
             // Create outgoing packets for all connections
@@ -282,7 +396,7 @@ impl<S: Socket, R: RateLimiter, M: PacketModifier> Server<S, R, M> {
                 // Collect all lost / closed connections
                 if !connection.open() {
                     // Map any remaining connection events
-                    map_connection_events(&mut self.events, connection);
+                    map_connection_events(&mut self.events, &mut self.verified_tokens, connection);
                     self.dropped.push(*id);
                 }
 
@@ -315,6 +429,7 @@ impl<S: Socket, R: RateLimiter, M: PacketModifier> Server<S, R, M> {
             self.events.clear();
             self.connections.clear();
             self.addresses.clear();
+            self.verified_tokens.clear();
             self.dropped.clear();
             self.ticker.reset();
             self.local_address = None;
@@ -337,10 +452,9 @@ impl<S: Socket, R: RateLimiter, M: PacketModifier> Server<S, R, M> {
 
         let packet_length = packet.len();
 
-        // Get existing connection
-        if self.connections.contains_key(&id) {
-
-            let connection = self.connections.get_mut(&id).unwrap();
+        // Packets for unknown connections are ignored, new connections can
+        // only be created via the handshake
+        if let Some(connection) = self.connections.get_mut(&id) {
 
             // Check if the packet was actually consumed by the connection.
             //
@@ -359,40 +473,165 @@ impl<S: Socket, R: RateLimiter, M: PacketModifier> Server<S, R, M> {
             }
 
             // Map any connection events
-            map_connection_events(&mut self.events, connection);
+            map_connection_events(&mut self.events, &mut self.verified_tokens, connection);
 
-            packet_length
+        }
 
-        // Or insert new one
-        } else {
+        packet_length
 
-            let mut conn = Connection::new(
-                self.config,
-                self.local_address.unwrap(),
-                addr,
-                R::new(self.config),
-                M::new(self.config)
-            );
+    }
 
-            conn.set_id(id);
+    fn receive_handshake_packet(
+        &mut self,
+        id: ConnectionID,
+        addr: SocketAddr,
+        packet: HandshakePacket,
+        packet_length: usize
 
-            self.connections.insert(id, conn);
-            self.addresses.insert(id, addr);
+    ) -> usize {
 
-            // Receive first packet
-            let connection = self.connections.get_mut(&id).unwrap();
+        // Ignore handshakes for already existing connections, these are
+        // re-transmissions from clients which have yet to receive their first
+        // packet from the server
+        if self.connections.contains_key(&id) {
+            return packet_length;
+        }
 
-            if connection.receive_packet(packet) {
+        match packet {
 
-                // Map any connection events
-                map_connection_events(&mut self.events, connection);
+            // Reply with a challenge cookie which is bound to the address the
+            // request originated from, no state is kept at this point
+            HandshakePacket::ConnectRequest(version) => {
+                let local_version = ProtocolVersion::from_config(&self.config);
+                let reply = if version != local_version {
+                    HandshakePacket::Denial(RejectionReason::VersionMismatch(local_version))
 
-            }
+                } else if packet_length < challenge_packet_size(&self.config) {
+                    HandshakePacket::Denial(RejectionReason::EncryptionRequired)
+
+                } else if self.connections.len() >= self.config.max_connections {
+                    HandshakePacket::Denial(RejectionReason::ServerFull)
+
+                } else if self.config.encryption {
+                    let cookie = self.cookies.generate(addr, id);
+                    let key = self.challenge_keys.key_exchange(&cookie).public_key();
+                    HandshakePacket::Challenge(cookie, Some(key))
+
+                } else {
+                    HandshakePacket::Challenge(self.cookies.generate(addr, id), None)
+                };
+
+                self.send_handshake_packet(id, addr, reply);
+            },
+
+            // Once the client returns a valid cookie from the address it
+            // was issued to, the connection is actually created, invalid or
+            // expired cookies are ignored
+            HandshakePacket::ChallengeResponse(cookie, key, payload) => {
+
+                // Clients with encryption enabled must provide a usable key
+                // for the key pair of their challenge and seal their payload
+                // with it
+                let accepted = if !self.cookies.verify(&cookie, addr, id, &self.config) {
+                    None
+
+                } else if let Some(key) = key {
+                    let keys = self.challenge_keys.key_exchange(&cookie).connection_keys(&key, id, true);
+                    keys.and_then(|keys| {
+                        open_payload(&keys.0, payload, &cookie).map(|payload| (payload, Some(keys)))
+                    })
+
+                } else {
+                    Some((payload.to_vec(), None))
+                };
+
+                if let Some((payload, keys)) = accepted {
+
+                    let payload = &payload[..];
+
+                    // Tokens are verified before the admission policy gets
+                    // to see any client
+                    let token = self.tokens.as_ref().map(|t| t.verify(payload));
+
+                    // Other clients might have completed their handshake in
+                    // the meantime
+                    let rejection = if self.connections.len() >= self.config.max_connections {
+                        Some(RejectionReason::ServerFull)
+
+                    } else if let Some(None) = token {
+                        Some(RejectionReason::InvalidToken)
+
+                    } else if let Some(policy) = self.admission_policy.as_mut() {
+                        match policy.admit(addr, id, payload) {
+                            Admission::Accept => None,
+                            Admission::Reject(mut reason) => {
+
+                                // Never reply with more data than the client
+                                // sent
+                                if let Some(reason) = reason.as_mut() {
+                                    reason.truncate(packet_length - DENIAL_PACKET_SIZE);
+                                }
+
+                                Some(RejectionReason::Denied(reason))
 
-            packet_length
+                            }
+                        }
+
+                    } else {
+                        None
+                    };
+
+                    if let Some(reason) = rejection {
+                        self.send_handshake_packet(id, addr, HandshakePacket::Denial(reason));
+
+                    } else {
+
+                        let mut conn = Connection::new(
+                            self.config,
+                            self.local_address.unwrap(),
+                            addr,
+                            R::new(self.config),
+                            M::new(self.config)
+                        );
+
+                        conn.set_id(id);
+
+                        if let Some((client_key, server_key)) = keys {
+                            conn.set_encryption_keys(server_key, client_key);
+                        }
+
+                        self.connections.insert(id, conn);
+                        self.addresses.insert(id, addr);
+
+                        // Tokens can only be used once
+                        if let Some(Some(token)) = token {
+                            self.tokens.as_mut().unwrap().consume(payload, token.expires);
+                            self.verified_tokens.insert(id, token);
+                        }
+
+                    }
+
+                }
+            },
+
+            // Servers never issue connection requests
+            HandshakePacket::Challenge(..) | HandshakePacket::Denial(_) => {}
 
         }
 
+        packet_length
+
+    }
+
+    fn send_handshake_packet(
+        &mut self,
+        id: ConnectionID,
+        addr: SocketAddr,
+        packet: HandshakePacket
+    ) {
+        // The address might be unverified, so failures are simply ignored
+        let packet = packet.to_packet(&self.config, id);
+        self.socket.as_mut().unwrap().send_to(&packet[..], addr).ok();
     }
 
 }
@@ -400,18 +639,28 @@ impl<S: Socket, R: RateLimiter, M: PacketModifier> Server<S, R, M> {
 // Helpers --------------------------------------------------------------------
 fn map_connection_events<R: RateLimiter, M: PacketModifier>(
     server_events: &mut VecDeque<ServerEvent>,
+    verified_tokens: &mut HashMap<ConnectionID, ConnectToken>,
     connection: &mut Connection<R, M>
 ) {
     let id = connection.id();
     for event in connection.events() {
         server_events.push_back(match event {
-            ConnectionEvent::Connected => ServerEvent::Connection(id),
+            ConnectionEvent::Connected => ServerEvent::Connection(id, verified_tokens.remove(&id)),
             ConnectionEvent::Lost => ServerEvent::ConnectionLost(id),
-            ConnectionEvent::FailedToConnect => unreachable!(),
+            // Clients which completed the handshake but never followed up
+            // are dropped silently since no connection was ever established
+            ConnectionEvent::FailedToConnect => continue,
             ConnectionEvent::Closed(p) => ServerEvent::ConnectionClosed(id, p),
             ConnectionEvent::Message(payload) => ServerEvent::Message(id, payload),
             ConnectionEvent::CongestionStateChanged(c) => ServerEvent::ConnectionCongestionStateChanged(id, c),
-            ConnectionEvent::PacketLost(payload) => ServerEvent::PacketLost(id, payload)
+            ConnectionEvent::PacketLost(payload) => ServerEvent::PacketLost(id, payload),
+            ConnectionEvent::MessageDelivered(message) => ServerEvent::MessageDelivered(id, message),
+            ConnectionEvent::MessageLost(message) => ServerEvent::MessageLost(id, message),
+            ConnectionEvent::MessageExpired(message) => ServerEvent::MessageExpired(id, message),
+            ConnectionEvent::SendQueueDrained => ServerEvent::SendQueueDrained(id),
+            ConnectionEvent::Request(request, payload) => ServerEvent::Request(id, request, payload),
+            ConnectionEvent::Response(request, result) => ServerEvent::Response(id, request, result),
+            ConnectionEvent::RequestTimedOut(request) => ServerEvent::RequestTimedOut(id, request)
         })
     }
 }
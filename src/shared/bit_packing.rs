@@ -0,0 +1,333 @@
+// Copyright (c) 2015-2017 Ivo Wetzel
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+
+// STD Dependencies -----------------------------------------------------------
+use std::cmp;
+use std::io::{Error, ErrorKind};
+
+
+/// Maximum number of 7 bit groups used by an encoded varint.
+const VARINT_MAX_GROUPS: u32 = 10;
+
+/// Writer which packs values into as few bits as their range requires.
+///
+/// Bits are written most significant bit first; the resulting bytes can be
+/// passed directly to `Connection::send` and unpacked by a `BitReader` on the
+/// receiving end.
+///
+/// # Examples
+///
+/// ```
+/// use cobalt::{BitReader, BitWriter};
+///
+/// let mut writer = BitWriter::new();
+/// writer.write_bool(true);
+/// writer.write_int(-3, -8, 7);
+/// writer.write_float(0.25, 0.0, 1.0, 0.01);
+/// writer.write_varint(300);
+///
+/// // 1 + 4 + 7 + 16 bits
+/// assert_eq!(writer.len(), 4);
+///
+/// let payload = writer.into_bytes();
+/// let mut reader = BitReader::new(&payload);
+/// assert!(reader.read_bool().unwrap());
+/// assert_eq!(reader.read_int(-8, 7).unwrap(), -3);
+/// assert_eq!(reader.read_float(0.0, 1.0, 0.01).unwrap(), 0.25);
+/// assert_eq!(reader.read_varint().unwrap(), 300);
+///
+/// // Reads past the end of the payload fail
+/// assert_eq!(reader.remaining_bits(), 4);
+/// assert!(reader.read_varint().is_err());
+/// ```
+#[derive(Debug, Default)]
+pub struct BitWriter {
+    data: Vec<u8>,
+    bits: usize
+}
+
+impl BitWriter {
+
+    /// Creates a new, empty writer.
+    pub fn new() -> BitWriter {
+        BitWriter::with_capacity(0)
+    }
+
+    /// Creates a new, empty writer with room for `bytes` bytes.
+    pub fn with_capacity(bytes: usize) -> BitWriter {
+        BitWriter {
+            data: Vec::with_capacity(bytes),
+            bits: 0
+        }
+    }
+
+    /// Returns the number of bytes written so far, including a partially
+    /// filled last byte.
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Returns whether nothing has been written yet.
+    pub fn is_empty(&self) -> bool {
+        self.bits == 0
+    }
+
+    /// Returns the number of bits written so far.
+    pub fn bit_len(&self) -> usize {
+        self.bits
+    }
+
+    /// Writes the lowest `bits` bits of `value`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bits` is larger than `32`.
+    pub fn write_bits(&mut self, value: u32, bits: u32) {
+
+        assert!(bits <= 32, "Cannot write more than 32 bits at once.");
+
+        let mut remaining = bits;
+        while remaining > 0 {
+
+            let offset = (self.bits % 8) as u32;
+            if offset == 0 {
+                self.data.push(0);
+            }
+
+            let count = cmp::min(8 - offset, remaining);
+            let chunk = (value as u64 >> (remaining - count)) & ((1 << count) - 1);
+            if let Some(byte) = self.data.last_mut() {
+                *byte |= (chunk << (8 - offset - count)) as u8;
+            }
+
+            remaining -= count;
+            self.bits += count as usize;
+
+        }
+
+    }
+
+    /// Writes a boolean as a single bit.
+    pub fn write_bool(&mut self, value: bool) {
+        self.write_bits(value as u32, 1);
+    }
+
+    /// Writes an integer within the inclusive range of `min` to `max`, using
+    /// only as many bits as the range requires.
+    ///
+    /// Values outside of the range are clamped.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `min` is larger than `max`.
+    pub fn write_int(&mut self, value: i32, min: i32, max: i32) {
+        let range = int_range(min, max);
+        let value = cmp::max(min, cmp::min(max, value));
+        self.write_bits((value as i64 - min as i64) as u32, bits_required(range));
+    }
+
+    /// Writes a float within the inclusive range of `min` to `max`,
+    /// quantized to steps of `precision`.
+    ///
+    /// Values outside of the range are clamped.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the range is empty or `precision` is not positive.
+    pub fn write_float(&mut self, value: f32, min: f32, max: f32, precision: f32) {
+        let steps = float_steps(min, max, precision);
+        let value = value.max(min).min(max);
+        let step = cmp::min(((value - min) as f64 / precision as f64).round() as u32, steps);
+        self.write_bits(step, bits_required(steps));
+    }
+
+    /// Writes an unsigned integer in groups of 7 bits, so small values take
+    /// up fewer bits.
+    pub fn write_varint(&mut self, mut value: u64) {
+        loop {
+            let group = (value & 0x7F) as u32;
+            value >>= 7;
+            if value == 0 {
+                self.write_bits(group, 8);
+                break;
+
+            } else {
+                self.write_bits(0x80 | group, 8);
+            }
+        }
+    }
+
+    /// Returns the written bytes with any unused bits of the last byte set
+    /// to zero.
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+
+}
+
+/// Reader which unpacks values written by a `BitWriter`.
+///
+/// All reads check the remaining length of the underlying data and validate
+/// the decoded values, so malformed payloads result in errors instead of
+/// panics.
+#[derive(Debug)]
+pub struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize
+}
+
+impl<'a> BitReader<'a> {
+
+    /// Creates a new reader over the bytes of a message payload.
+    pub fn new(data: &'a [u8]) -> BitReader<'a> {
+        BitReader {
+            data,
+            position: 0
+        }
+    }
+
+    /// Returns the number of bits which have not yet been read.
+    pub fn remaining_bits(&self) -> usize {
+        self.data.len() * 8 - self.position
+    }
+
+    /// Reads `bits` bits into the lowest bits of the returned value.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of the kind `ErrorKind::UnexpectedEof` in case there
+    /// are not enough bits left and `ErrorKind::InvalidInput` in case more
+    /// than `32` bits are requested.
+    pub fn read_bits(&mut self, bits: u32) -> Result<u32, Error> {
+
+        if bits > 32 {
+            return Err(Error::new(ErrorKind::InvalidInput, "Cannot read more than 32 bits at once."));
+
+        } else if bits as usize > self.remaining_bits() {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "Not enough bits left to read."));
+        }
+
+        let mut value = 0u64;
+        let mut remaining = bits;
+        while remaining > 0 {
+
+            let offset = (self.position % 8) as u32;
+            let count = cmp::min(8 - offset, remaining);
+            let byte = self.data[self.position / 8] as u64;
+            value = value << count | (byte >> (8 - offset - count)) & ((1 << count) - 1);
+
+            remaining -= count;
+            self.position += count as usize;
+
+        }
+
+        Ok(value as u32)
+
+    }
+
+    /// Reads a boolean written by `BitWriter::write_bool`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of the kind `ErrorKind::UnexpectedEof` in case there
+    /// are no bits left.
+    pub fn read_bool(&mut self) -> Result<bool, Error> {
+        self.read_bits(1).map(|bit| bit == 1)
+    }
+
+    /// Reads an integer written by `BitWriter::write_int` with the same
+    /// range.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of the kind `ErrorKind::UnexpectedEof` in case there
+    /// are not enough bits left and `ErrorKind::InvalidData` in case the
+    /// value lies outside of the range.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `min` is larger than `max`.
+    pub fn read_int(&mut self, min: i32, max: i32) -> Result<i32, Error> {
+        let range = int_range(min, max);
+        let value = self.read_bits(bits_required(range))?;
+        if value > range {
+            Err(Error::new(ErrorKind::InvalidData, "Integer is out of range."))
+
+        } else {
+            Ok((min as i64 + value as i64) as i32)
+        }
+    }
+
+    /// Reads a float written by `BitWriter::write_float` with the same range
+    /// and precision.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of the kind `ErrorKind::UnexpectedEof` in case there
+    /// are not enough bits left and `ErrorKind::InvalidData` in case the
+    /// value lies outside of the range.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the range is empty or `precision` is not positive.
+    pub fn read_float(&mut self, min: f32, max: f32, precision: f32) -> Result<f32, Error> {
+        let steps = float_steps(min, max, precision);
+        let step = self.read_bits(bits_required(steps))?;
+        if step > steps {
+            Err(Error::new(ErrorKind::InvalidData, "Float is out of range."))
+
+        } else if step == steps {
+            Ok(max)
+
+        } else {
+            Ok((min as f64 + step as f64 * precision as f64).min(max as f64) as f32)
+        }
+    }
+
+    /// Reads an unsigned integer written by `BitWriter::write_varint`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of the kind `ErrorKind::UnexpectedEof` in case there
+    /// are not enough bits left and `ErrorKind::InvalidData` in case the
+    /// value does not fit into 64 bits.
+    pub fn read_varint(&mut self) -> Result<u64, Error> {
+        let mut value = 0u64;
+        for i in 0..VARINT_MAX_GROUPS {
+            let group = self.read_bits(8)? as u64;
+            let bits = (group & 0x7F) << (i * 7);
+            if bits >> (i * 7) != group & 0x7F {
+                break;
+            }
+
+            value |= bits;
+            if group & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(Error::new(ErrorKind::InvalidData, "Varint is too large."))
+    }
+
+}
+
+
+// Static Helpers -------------------------------------------------------------
+fn bits_required(range: u32) -> u32 {
+    32 - range.leading_zeros()
+}
+
+fn int_range(min: i32, max: i32) -> u32 {
+    assert!(min <= max, "Minimum must not be larger than maximum.");
+    (max as i64 - min as i64) as u32
+}
+
+fn float_steps(min: f32, max: f32, precision: f32) -> u32 {
+    assert!(min < max && precision > 0.0, "Range must not be empty and precision must be positive.");
+    ((max as f64 - min as f64) / precision as f64).round().min(u32::MAX as f64) as u32
+}
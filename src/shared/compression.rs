@@ -0,0 +1,74 @@
+// Copyright (c) 2015-2017 Ivo Wetzel
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// STD Dependencies -----------------------------------------------------------
+use std::io::{Error, ErrorKind};
+
+
+// Internal Dependencies ------------------------------------------------------
+use ::CompressionDictionary;
+
+/// Flag marking payloads which are send as is.
+const PAYLOAD_UNCOMPRESSED: u8 = 0;
+
+/// Flag marking payloads which are send in compressed form.
+const PAYLOAD_COMPRESSED: u8 = 1;
+
+/// Number of bytes the compressing packet modifiers add to a payload.
+pub const COMPRESSION_OVERHEAD: usize = 1;
+
+/// Implementation of an empty compression dictionary.
+#[derive(Debug, Copy, Clone)]
+pub struct NoDictionary;
+
+impl CompressionDictionary for NoDictionary {
+    fn dictionary() -> &'static [u8] {
+        &[]
+    }
+}
+
+/// Flags and returns either the compressed or the original payload,
+/// depending on which of them is smaller.
+pub fn encode_payload(payload: &[u8], compressed: Option<Vec<u8>>) -> Option<Vec<u8>> {
+
+    // Empty payloads are never modified
+    if payload.is_empty() {
+        return None;
+    }
+
+    let mut data = Vec::with_capacity(payload.len() + COMPRESSION_OVERHEAD);
+    match compressed {
+        Some(ref compressed) if compressed.len() < payload.len() => {
+            data.push(PAYLOAD_COMPRESSED);
+            data.extend_from_slice(compressed);
+        },
+        _ => {
+            data.push(PAYLOAD_UNCOMPRESSED);
+            data.extend_from_slice(payload);
+        }
+    }
+
+    Some(data)
+
+}
+
+/// Decodes a payload flagged by `encode_payload`, invoking `decompress` for
+/// payloads which were send in compressed form.
+pub fn decode_payload<F>(payload: &[u8], decompress: F) -> Result<Option<Vec<u8>>, Error>
+    where F: FnOnce(&[u8]) -> Option<Vec<u8>> {
+
+    match payload.split_first() {
+        None => Ok(None),
+        Some((&PAYLOAD_UNCOMPRESSED, data)) => Ok(Some(data.to_vec())),
+        Some((&PAYLOAD_COMPRESSED, data)) => decompress(data).map(Some).ok_or_else(|| {
+            Error::new(ErrorKind::InvalidData, "Failed to decompress payload.")
+        }),
+        Some(_) => Err(Error::new(ErrorKind::InvalidData, "Unknown payload compression."))
+    }
+
+}
@@ -11,6 +11,10 @@
 use std::time::Duration;
 
 
+// Internal Dependencies ------------------------------------------------------
+use ::shared::message_queue::MESSAGE_CHANNELS;
+
+
 /// Structure defining connection and message configuration options.
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub struct Config {
@@ -26,12 +30,23 @@ pub struct Config {
     /// `[1, 2, 3, 4]`.
     pub protocol_header: [u8; 4],
 
+    /// Version of the application's own protocol. Default is `0`.
+    ///
+    /// Servers reject clients with a different version - or one speaking a
+    /// different version of cobalt's wire format - with
+    /// `RejectionReason::VersionMismatch`.
+    pub application_version: u32,
+
     /// Maximum roundtrip-time in milliseconds before a packet is considered
     /// lost. Default is `1000`.
     pub packet_drop_threshold: Duration,
 
     /// Maximum time in milliseconds until the first packet must be received
     /// before a connection attempt fails. Default is `100`.
+    ///
+    /// This also limits the time between the individual steps of the
+    /// connection handshake and the lifetime of the challenge cookies issued
+    /// by a server.
     pub connection_init_threshold: Duration,
 
     /// Maximum time in milliseconds between any two packets before the
@@ -42,17 +57,91 @@ pub struct Config {
     /// programmatically closing a connection. Default is `150`.
     pub connection_closing_threshold: Duration,
 
-    /// The percent of available packet bytes to use when serializing
-    /// `MessageKind::Instant` into a packet via a `MessageQueue`.
-    pub message_quota_instant: f32,
+    /// Maximum number of connections a server keeps at any one time.
+    ///
+    /// Once reached, any further connection attempts are rejected with
+    /// `RejectionReason::ServerFull`. Default is `usize::MAX`.
+    pub max_connections: usize,
 
-    /// The percent of available packet bytes to use when serializing
-    /// `MessageKind::Reliable` into a packet via a `MessageQueue`.
-    pub message_quota_reliable: f32,
+    /// Whether to encrypt and authenticate all connection traffic.
+    ///
+    /// Clients and servers agree on a pair of per-connection keys during the
+    /// handshake and all packets are sealed with ChaCha20-Poly1305 from there
+    /// on, adding `24` bytes of overhead to each packet. Servers reject
+    /// clients which do not use encryption with
+    /// `RejectionReason::EncryptionRequired` and clients report the same
+    /// rejection for servers which do not use it.
+    ///
+    /// The key agreement itself is not authenticated, so while this prevents
+    /// eavesdroppers from reading or forging packets, it does not protect
+    /// against an active man-in-the-middle. It does not provide forward
+    /// secrecy either, as the server derives its key pair for each
+    /// connection from a secret which lives as long as the server; anyone
+    /// who obtains that secret can decrypt recorded traffic of all its
+    /// connections. Default is `false`.
+    pub encryption: bool,
+
+    /// The percent of available packet bytes to use for each message channel
+    /// when serializing messages into a packet via a `MessageQueue`.
+    ///
+    /// The first two entries are used for `MessageKind::Instant` - which
+    /// includes `MessageKind::Sequenced` - and `MessageKind::Reliable`
+    /// respectively, followed by one entry for each of the `ORDERED_CHANNELS`
+    /// channels of `MessageKind::Ordered`. Any space
+    /// left over after applying the quotas is shared by all channels.
+    ///
+    /// Default is `60.0` for instant, `20.0` for reliable and `20.0` for the
+    /// first ordered channel, leaving the other ordered channels without a
+    /// quota.
+    pub message_quotas: [f32; MESSAGE_CHANNELS],
 
-    /// The percent of available packet bytes to use when serializing
-    /// `MessageKind::Ordered` into a packet via a `MessageQueue`.
-    pub message_quota_ordered: f32,
+    /// The default time to live of queued messages for each message kind,
+    /// indexed by `MessageKind`.
+    ///
+    /// Messages which could not be send before their time to live has passed
+    /// are dropped and reported via `ConnectionEvent::MessageExpired`,
+    /// reliable and ordered ones still take up a few bytes as placeholders.
+    /// The deadline of individual messages can be overridden via
+    /// `Connection::set_message_deadline`.
+    ///
+    /// Default is `None` for all kinds, which keeps messages queued until
+    /// they are send.
+    pub message_ttl: [Option<Duration>; 4],
+
+    /// Maximum number of data bytes which can be queued for sending on a
+    /// single connection.
+    ///
+    /// Sending further messages fails with `ErrorKind::WouldBlock` until the
+    /// queue has drained again. Default is `usize::MAX`.
+    pub send_queue_max_bytes: usize,
+
+    /// Maximum number of messages which can be queued for sending on a
+    /// single connection, fragments of messages which have already been split
+    /// up count individually.
+    ///
+    /// Sending further messages fails with `ErrorKind::WouldBlock` until the
+    /// queue has drained again. Default is `usize::MAX`.
+    pub send_queue_max_messages: usize,
+
+    /// The fraction of both `send_queue_max_bytes` and
+    /// `send_queue_max_messages` below which a full send queue is considered
+    /// drained again, emitting `ConnectionEvent::SendQueueDrained`.
+    ///
+    /// Values must be in the range of `0.0` to `1.0`. Default is `0.5`.
+    pub send_queue_low_watermark: f32,
+
+    /// Maximum time in milliseconds to wait for the response to a request
+    /// send via `Connection::request` before it is reported via
+    /// `ConnectionEvent::RequestTimedOut`. Default is `5000`.
+    pub request_timeout: Duration,
+
+    /// Maximum number of incoming requests which can await a response on a
+    /// single connection.
+    ///
+    /// Any further requests are answered with an empty error right away,
+    /// without being emitted as `ConnectionEvent::Request`. Default is
+    /// `1024`.
+    pub request_max_incoming: usize,
 
     /// Whether to keep track of ticks which exceed their maximum running time
     /// and speed up successive ticks in order to keep the desired target
@@ -98,14 +187,21 @@ impl Default for Config {
         Config {
             send_rate: 30,
             protocol_header: [1, 2, 3, 4],
+            application_version: 0,
             packet_max_size: 1400,
             packet_drop_threshold: Duration::from_millis(1000),
             connection_init_threshold: Duration::from_millis(100),
             connection_drop_threshold: Duration::from_millis(1000),
             connection_closing_threshold: Duration::from_millis(150),
-            message_quota_instant: 60.0,
-            message_quota_reliable: 20.0,
-            message_quota_ordered: 20.0,
+            max_connections: usize::MAX,
+            encryption: false,
+            message_quotas: [60.0, 20.0, 20.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+            message_ttl: [None; 4],
+            send_queue_max_bytes: usize::MAX,
+            send_queue_max_messages: usize::MAX,
+            send_queue_low_watermark: 0.5,
+            request_timeout: Duration::from_millis(5000),
+            request_max_incoming: 1024,
             tick_overflow_recovery: true,
             tick_overflow_recovery_rate: 1.0
         }
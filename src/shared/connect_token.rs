@@ -0,0 +1,274 @@
+// Copyright (c) 2015-2017 Ivo Wetzel
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+extern crate hmac;
+extern crate rand;
+extern crate sha2;
+
+
+// STD Dependencies -----------------------------------------------------------
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::collections::HashMap;
+
+
+// External Dependencies ------------------------------------------------------
+use self::hmac::{Hmac, Mac};
+use self::sha2::Sha256;
+
+
+/// Number of bytes used by the message authentication code of a token.
+const TOKEN_MAC_BYTES: usize = 32;
+
+/// Number of bytes used by the smallest possible token.
+const TOKEN_MIN_BYTES: usize = 8 + 8 + 8 + 1 + 4 + 2 + 2 + TOKEN_MAC_BYTES;
+
+/// Type alias for the message authentication code of a token.
+type TokenMac = [u8; TOKEN_MAC_BYTES];
+
+/// A connect token issued by a trusted backend.
+///
+/// Tokens are signed with a key which is shared between the backend and the
+/// servers, but never revealed to any client. Clients simply pass the signed
+/// token on to `Client::connect_with_token` and servers configured via
+/// `Server::set_connect_token_key` only accept connections which present a
+/// valid token.
+///
+/// Each signed token can only be used for a single connection. Unless both
+/// client and server enable `Config::encryption`, anyone who observes the
+/// handshake can redeem the token before the client does.
+///
+/// # Examples
+///
+/// ```
+/// use std::time::Duration;
+/// use cobalt::ConnectToken;
+///
+/// let token = ConnectToken::new(
+///     42,
+///     "127.0.0.1:1234".parse().unwrap(),
+///     Duration::from_secs(30),
+///     b"Player Name".to_vec()
+/// );
+///
+/// // Hand the signed token to the client
+/// let signed = token.sign(b"Shared secret key");
+///
+/// assert_eq!(ConnectToken::verify(&signed, b"Shared secret key"), Some(token));
+/// assert_eq!(ConnectToken::verify(&signed, b"Another key"), None);
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectToken {
+
+    /// The id of the user the token was issued to.
+    pub user_id: u64,
+
+    /// The public address of the server the token is valid for.
+    pub server_address: SocketAddr,
+
+    /// The point in time after which the token is no longer accepted.
+    ///
+    /// Tokens carry their expiry with a resolution of whole seconds.
+    pub expires: SystemTime,
+
+    /// Application specific data associated with the user.
+    pub user_data: Vec<u8>
+
+}
+
+impl ConnectToken {
+
+    /// Creates a new token for the specified user and server which remains
+    /// valid for the given duration.
+    pub fn new(
+        user_id: u64,
+        server_address: SocketAddr,
+        valid_for: Duration,
+        user_data: Vec<u8>
+
+    ) -> ConnectToken {
+        let expires = unix_secs(SystemTime::now() + valid_for);
+        ConnectToken {
+            user_id,
+            server_address,
+            expires: UNIX_EPOCH + Duration::from_secs(expires),
+            user_data
+        }
+    }
+
+    /// Serializes and signs the token with the given key.
+    ///
+    /// Each call embeds a new random nonce, so signing the same token twice
+    /// results in two tokens which can be used independently.
+    pub fn sign(&self, key: &[u8]) -> Vec<u8> {
+
+        let mut token = Vec::with_capacity(TOKEN_MIN_BYTES + 12 + self.user_data.len());
+        write_u64(&mut token, rand::random());
+        write_u64(&mut token, unix_secs(self.expires));
+        write_u64(&mut token, self.user_id);
+
+        match self.server_address.ip() {
+            IpAddr::V4(ip) => {
+                token.push(4);
+                token.extend_from_slice(&ip.octets());
+            },
+            IpAddr::V6(ip) => {
+                token.push(6);
+                token.extend_from_slice(&ip.octets());
+            }
+        }
+
+        let port = self.server_address.port();
+        token.push((port >> 8) as u8);
+        token.push(port as u8);
+
+        let len = self.user_data.len();
+        token.push((len >> 8) as u8);
+        token.push(len as u8);
+        token.extend_from_slice(&self.user_data);
+
+        let mac = token_mac(key, &token);
+        token.extend_from_slice(&mac);
+        token
+
+    }
+
+    /// Verifies the signature of a serialized token against the given key
+    /// and returns the contained token.
+    ///
+    /// Expiry, server address and re-use are checked by the server.
+    pub fn verify(token: &[u8], key: &[u8]) -> Option<ConnectToken> {
+
+        if token.len() < TOKEN_MIN_BYTES {
+            return None;
+        }
+
+        let (data, mac) = token.split_at(token.len() - TOKEN_MAC_BYTES);
+        if !mac_matches(key, data, mac) {
+            return None;
+        }
+
+        let expires = read_u64(&data[8..16]);
+        let user_id = read_u64(&data[16..24]);
+
+        let (ip, rest) = match data[24] {
+            4 => {
+                let mut octets = [0; 4];
+                octets.copy_from_slice(&data[25..29]);
+                (IpAddr::V4(Ipv4Addr::from(octets)), &data[29..])
+            },
+            6 if data.len() >= TOKEN_MIN_BYTES - TOKEN_MAC_BYTES + 12 => {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(&data[25..41]);
+                (IpAddr::V6(Ipv6Addr::from(octets)), &data[41..])
+            },
+            _ => return None
+        };
+
+        let port = (rest[0] as u16) << 8 | rest[1] as u16;
+        let len = (rest[2] as usize) << 8 | rest[3] as usize;
+        if rest.len() != 4 + len {
+            return None;
+        }
+
+        Some(ConnectToken {
+            user_id,
+            server_address: SocketAddr::new(ip, port),
+            expires: UNIX_EPOCH + Duration::from_secs(expires),
+            user_data: rest[4..].to_vec()
+        })
+
+    }
+
+}
+
+/// Server side verifier for signed connect tokens.
+///
+/// Keeps track of all tokens which have been used to create a connection
+/// until they expire, so that each token can only be used once.
+#[derive(Debug)]
+pub struct TokenVerifier {
+    key: Vec<u8>,
+    server_address: SocketAddr,
+    used: HashMap<TokenMac, SystemTime>
+}
+
+impl TokenVerifier {
+
+    /// Creates a new verifier which accepts tokens signed with `key` and
+    /// issued for `server_address`.
+    pub fn new(key: &[u8], server_address: SocketAddr) -> TokenVerifier {
+        TokenVerifier {
+            key: key.to_vec(),
+            server_address,
+            used: HashMap::new()
+        }
+    }
+
+    /// Returns the contained token in case the serialized token is authentic,
+    /// has not yet expired, was issued for this server and has not been used
+    /// before.
+    pub fn verify(&self, token: &[u8]) -> Option<ConnectToken> {
+        ConnectToken::verify(token, &self.key).and_then(|t| {
+            if t.expires > SystemTime::now()
+                && t.server_address == self.server_address
+                && !self.used.contains_key(&mac_from_token(token)) {
+                Some(t)
+
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Marks a previously verified token as used.
+    pub fn consume(&mut self, token: &[u8], expires: SystemTime) {
+        let now = SystemTime::now();
+        self.used.retain(|_, expires| *expires > now);
+        self.used.insert(mac_from_token(token), expires);
+    }
+
+}
+
+
+// Static Helpers -------------------------------------------------------------
+fn hmac(key: &[u8], data: &[u8]) -> Hmac<Sha256> {
+    let mut hmac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any size");
+    hmac.update(data);
+    hmac
+}
+
+fn token_mac(key: &[u8], data: &[u8]) -> TokenMac {
+    let mut mac = [0; TOKEN_MAC_BYTES];
+    mac.copy_from_slice(&hmac(key, data).finalize().into_bytes());
+    mac
+}
+
+fn mac_matches(key: &[u8], data: &[u8], mac: &[u8]) -> bool {
+    // Compared in constant time
+    hmac(key, data).verify_slice(mac).is_ok()
+}
+
+fn mac_from_token(token: &[u8]) -> TokenMac {
+    let mut mac = [0; TOKEN_MAC_BYTES];
+    mac.copy_from_slice(&token[token.len() - TOKEN_MAC_BYTES..]);
+    mac
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+fn write_u64(data: &mut Vec<u8>, value: u64) {
+    for i in 0..8 {
+        data.push((value >> (56 - i * 8)) as u8);
+    }
+}
+
+fn read_u64(data: &[u8]) -> u64 {
+    data.iter().fold(0, |v, b| v << 8 | *b as u64)
+}
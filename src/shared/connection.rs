@@ -10,6 +10,7 @@ extern crate rand;
 
 // STD Dependencies -----------------------------------------------------------
 use std::cmp;
+use std::io::{Error, ErrorKind};
 use std::vec::Drain;
 use std::net::SocketAddr;
 use std::time::{Duration, Instant};
@@ -17,23 +18,25 @@ use std::collections::{HashMap, VecDeque};
 
 
 // Internal Dependencies ------------------------------------------------------
-use super::message_queue::MessageQueue;
-use ::{Config, MessageKind, PacketModifier, RateLimiter, Socket};
+use super::encryption::{Key, PacketCipher, ENCRYPTION_OVERHEAD};
+use super::message_queue::{MessageID, MessageQueue};
+use super::packet_type::{PacketType, PACKET_TYPE_OFFSET};
+use super::rpc::{Frame, RequestID, Requests};
+use ::{
+    Config, MessageKind, PacketContext, PacketModifier, PacketProducer,
+    RateLimiter, Socket
+};
+#[cfg(feature = "typed")]
+use ::{encode_typed, TypedMessage};
 
 /// Maximum number of acknowledgement bits available in the packet header.
 const MAX_ACK_BITS: u32 = 32;
 
 /// Maximum packet sequence number before wrap around happens.
-const MAX_SEQ_NUMBER: u32 = 256;
+const MAX_SEQ_NUMBER: u32 = 65536;
 
 /// Number of bytes used by a packet header.
-const PACKET_HEADER_SIZE: usize = 14;
-
-/// Special packet data used to notify of programmtic connection closure.
-const CLOSURE_PACKET_DATA: [u8; 6] = [
-    0, 128, // Most distant sequence numbers
-    85, 85, 85, 85 // ack bitfield with every second bit set
-];
+const PACKET_HEADER_SIZE: usize = 17;
 
 /// Enum indicating the state of a `SentPacketAck`.
 #[derive(Debug, PartialEq)]
@@ -49,7 +52,8 @@ struct SentPacketAck {
     seq: u32,
     time: Instant,
     state: PacketState,
-    packet: Option<Vec<u8>>
+    packet: Option<Vec<u8>>,
+    messages: Vec<MessageID>
 }
 
 /// Enum indicating the state of a connection.
@@ -104,6 +108,44 @@ pub enum ConnectionEvent {
     /// of the connection within the specified limits.
     PacketLost(Vec<u8>),
 
+    /// Emitted once the packet carrying the message with the given id - or
+    /// all of its fragments - has been acknowledged by the remote end of the
+    /// connection.
+    MessageDelivered(MessageID),
+
+    /// Emitted for each message of the kinds `MessageKind::Instant` and
+    /// `MessageKind::Sequenced` whose packet was lost.
+    ///
+    /// Lost messages of all other kinds are re-send instead, unless they were
+    /// cancelled via `Connection::cancel` after they were send.
+    MessageLost(MessageID),
+
+    /// Emitted for each message which was dropped because its deadline
+    /// passed or it was replaced via `Connection::send_keyed` before it
+    /// could be send, as well as for instant and sequenced messages which
+    /// are too large to ever fit into a packet.
+    MessageExpired(MessageID),
+
+    /// Emitted once the send queue drained below its low watermark after a
+    /// message could not be send because the queue was full.
+    SendQueueDrained,
+
+    /// Emitted for each request received over the connection, which should
+    /// be answered via `Connection::respond`.
+    ///
+    /// Requests exceeding `Config::request_max_incoming` are answered with
+    /// an empty error instead.
+    Request(RequestID, Vec<u8>),
+
+    /// Emitted once the remote end of the connection responded to a request,
+    /// either with a result or with an error.
+    Response(RequestID, Result<Vec<u8>, Vec<u8>>),
+
+    /// Emitted for each request which was not responded to within the
+    /// `request_timeout` or before the connection was lost, closed or
+    /// reset.
+    RequestTimedOut(RequestID),
+
     /// Emitted each time the connection's congestion state changes.
     CongestionStateChanged(bool)
 }
@@ -186,6 +228,17 @@ pub struct Connection<R: RateLimiter, M: PacketModifier> {
     /// The packet modifier used for payload modification
     packet_modifier: M,
 
+    /// The cipher used for packet encryption and authentication
+    cipher: Option<PacketCipher>,
+
+    /// Outgoing requests awaiting a response and incoming requests awaiting
+    /// an answer
+    requests: Requests,
+
+    /// The optional producer which fills the remaining space of outgoing
+    /// packets
+    producer: Option<Box<dyn PacketProducer>>,
+
     /// List of accumulated connection events
     events: Vec<ConnectionEvent>
 
@@ -242,6 +295,9 @@ impl<R: RateLimiter, M: PacketModifier> Connection<R, M> {
             message_queue: MessageQueue::new(config),
             rate_limiter: rate_limiter,
             packet_modifier: packet_modifier,
+            cipher: None,
+            requests: Requests::new(),
+            producer: None,
             events: Vec::new()
         }
     }
@@ -266,7 +322,8 @@ impl<R: RateLimiter, M: PacketModifier> Connection<R, M> {
     ///     11, 22, 33, 44,
     ///      1,  2,  3,  4,
     ///      0,
-    ///      0,
+    ///      0,  0,
+    ///      0,  0,
     ///      0,  0, 0,  0
     /// ];
     ///
@@ -330,6 +387,44 @@ impl<R: RateLimiter, M: PacketModifier> Connection<R, M> {
         100.0 / cmp::max(self.sent_packets, 1) as f32 * self.lost_packets as f32
     }
 
+    /// Returns the number of messages of the specified `kind` which are
+    /// queued and waiting to be send over the connection.
+    pub fn queued_messages(&self, kind: MessageKind) -> usize {
+        self.message_queue.queued_messages(kind)
+    }
+
+    /// Returns the number of payload bytes which are queued and waiting to be
+    /// send over the connection.
+    pub fn queued_bytes(&self) -> usize {
+        self.message_queue.queued_bytes()
+    }
+
+    /// Returns the time the oldest queued message has been waiting to be send
+    /// over the connection.
+    pub fn oldest_queued_age(&self) -> Option<Duration> {
+        self.message_queue.oldest_queued_age()
+    }
+
+    /// Returns the number of reliable and ordered messages which have been
+    /// send but not yet acknowledged by the remote end of the connection.
+    pub fn unacked_reliable_messages(&self) -> usize {
+        self.message_queue.unacked_reliable_messages()
+    }
+
+    /// Returns the number of bytes buffered for messages which have only
+    /// been partially received over the connection, see
+    /// `MessageKind::Reliable` for details on fragmentation.
+    pub fn buffered_fragment_bytes(&self) -> usize {
+        self.message_queue.buffered_fragment_bytes()
+    }
+
+    /// Returns the number of bytes buffered for ordered messages which were
+    /// received out of order over the connection, see
+    /// `MessageKind::Ordered` for details on ordering.
+    pub fn buffered_ordered_bytes(&self) -> usize {
+        self.message_queue.buffered_ordered_bytes()
+    }
+
     /// Returns the socket address for the local end of this connection.
     pub fn local_addr(&self) -> SocketAddr {
         self.local_address
@@ -351,13 +446,204 @@ impl<R: RateLimiter, M: PacketModifier> Connection<R, M> {
         self.message_queue.set_config(config);
     }
 
+    /// Returns whether the connection encrypts and authenticates its packets.
+    pub fn encrypted(&self) -> bool {
+        self.cipher.is_some()
+    }
+
+    /// Enables authenticated encryption of all further packets, using
+    /// `send_key` for outgoing and `receive_key` for incoming packets.
+    ///
+    /// Once enabled, incoming packets which fail authentication or replay
+    /// earlier packets are dropped. Clients and servers set up the keys
+    /// during their handshake when `Config::encryption` is enabled.
+    pub fn set_encryption_keys(&mut self, send_key: Key, receive_key: Key) {
+        self.cipher = Some(PacketCipher::new(&send_key, &receive_key));
+    }
+
+    /// Overrides the packet counter of the connection's cipher.
+    #[cfg(test)]
+    pub fn set_encryption_counter(&mut self, counter: u64) {
+        if let Some(cipher) = self.cipher.as_mut() {
+            cipher.set_send_counter(counter);
+        }
+    }
+
     /// Sends a message of the specified `kind` along with its `payload` over
     /// the connection.
     ///
     /// How exactly the message is send and whether it is guaranteed to be
     /// delivered eventually is determined by its `MessageKind`.
-    pub fn send(&mut self, kind: MessageKind, payload: Vec<u8>) {
-        self.message_queue.send(kind, payload);
+    ///
+    /// Returns the id of the message which is later reported via either
+    /// `ConnectionEvent::MessageDelivered` or `ConnectionEvent::MessageLost`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of the kind `ErrorKind::InvalidInput` in case the
+    /// `payload` exceeds `65535` bytes and an error of the kind
+    /// `ErrorKind::WouldBlock` in case the connection's send queue is full,
+    /// see `Config::send_queue_max_bytes`.
+    pub fn send(&mut self, kind: MessageKind, payload: Vec<u8>) -> Result<MessageID, Error> {
+        self.message_queue.send(kind, payload)
+    }
+
+    /// Sends a message of the specified `kind` along with its `payload` over
+    /// the connection, replacing any older message of the same kind and
+    /// `key` which has not yet been send.
+    ///
+    /// This is useful for state updates where only the latest value matters,
+    /// e.g. when updates are produced faster than the `send_rate`. Replaced
+    /// messages are reported via `ConnectionEvent::MessageExpired`.
+    ///
+    /// Returns the id of the message.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of the kind `ErrorKind::InvalidInput` for messages of
+    /// the kind `MessageKind::Ordered` or in case the `payload` exceeds
+    /// `65535` bytes and an error of the kind `ErrorKind::WouldBlock` in case
+    /// the connection's send queue is full.
+    pub fn send_keyed(&mut self, kind: MessageKind, key: u32, payload: Vec<u8>) -> Result<MessageID, Error> {
+        self.message_queue.send_keyed(kind, key, payload)
+    }
+
+    /// Sends a message of the kind `MessageKind::Ordered` along with its
+    /// `payload` on the specified ordered `channel` of the connection.
+    ///
+    /// Messages are only delivered in order with respect to other messages on
+    /// the same channel, so a lost message does not hold back messages on any
+    /// of the other channels.
+    ///
+    /// Returns the id of the message.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of the kind `ErrorKind::InvalidInput` in case the
+    /// `payload` exceeds `65535` bytes and an error of the kind
+    /// `ErrorKind::WouldBlock` in case the connection's send queue is full.
+    ///
+    /// # Panics
+    ///
+    /// If `channel` is not smaller than `ORDERED_CHANNELS`.
+    pub fn send_ordered(&mut self, channel: u8, payload: Vec<u8>) -> Result<MessageID, Error> {
+        self.message_queue.send_ordered(channel, payload)
+    }
+
+    /// Sends a message of the kind `MessageKind::Sequenced` along with its
+    /// `payload` on the specified sequenced `stream` of the connection.
+    ///
+    /// The remote end drops any message which arrives after a more recent
+    /// message on the same stream.
+    ///
+    /// Returns the id of the message.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of the kind `ErrorKind::InvalidInput` in case the
+    /// `payload` exceeds `65535` bytes and an error of the kind
+    /// `ErrorKind::WouldBlock` in case the connection's send queue is full.
+    pub fn send_sequenced(&mut self, stream: u8, payload: Vec<u8>) -> Result<MessageID, Error> {
+        self.message_queue.send_sequenced(stream, payload)
+    }
+
+    /// Sends a typed message of the specified `kind` over the connection,
+    /// serializing the `value` via `encode_typed`.
+    ///
+    /// The remote end decodes the payload of the resulting
+    /// `ConnectionEvent::Message` via a `MessageRegistry`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error in case the value cannot be serialized, its payload
+    /// is too large or the connection's send queue is full.
+    #[cfg(feature = "typed")]
+    pub fn send_typed<T: TypedMessage>(&mut self, kind: MessageKind, value: &T) -> Result<MessageID, Error> {
+        self.send(kind, encode_typed(value)?)
+    }
+
+    /// Sends a request along with its `payload` over the connection.
+    ///
+    /// Requests are send as reliable messages and complete with either a
+    /// `ConnectionEvent::Response` or a `ConnectionEvent::RequestTimedOut`
+    /// which carry the returned id.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of the kind `ErrorKind::InvalidInput` in case the
+    /// `payload` is too large for a single message and an error of the kind
+    /// `ErrorKind::WouldBlock` in case the connection's send queue is full.
+    pub fn request(&mut self, payload: Vec<u8>) -> Result<RequestID, Error> {
+        let id = self.requests.next_id();
+        self.message_queue.send_rpc(Frame::Request(id, payload).into_bytes())?;
+        Ok(self.requests.send(Instant::now() + self.config.request_timeout))
+    }
+
+    /// Responds to the request with the given `id` which was received via
+    /// `ConnectionEvent::Request`, either with a result or with an error.
+    ///
+    /// Returns the id of the reliable message which carries the response.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of the kind `ErrorKind::NotFound` in case no such
+    /// request awaits a response, `ErrorKind::InvalidInput` in case the
+    /// result is too large for a single message and `ErrorKind::WouldBlock`
+    /// in case the connection's send queue is full.
+    pub fn respond(&mut self, id: RequestID, result: Result<Vec<u8>, Vec<u8>>) -> Result<MessageID, Error> {
+        if self.requests.awaits_response(id) {
+            let message = self.message_queue.send_rpc(Frame::Response(id, result).into_bytes())?;
+            self.requests.respond(id);
+            Ok(message)
+
+        } else {
+            Err(Error::new(ErrorKind::NotFound, "No such request awaits a response."))
+        }
+    }
+
+    /// Returns the number of requests which are awaiting a response from the
+    /// remote end of the connection.
+    pub fn pending_requests(&self) -> usize {
+        self.requests.pending()
+    }
+
+    /// Cancels the message with the given `id`, withdrawing it in case it has
+    /// not yet been send and no longer re-sending it otherwise.
+    ///
+    /// Cancelled messages of the kind `MessageKind::Ordered` are replaced
+    /// with empty placeholders, so later messages on their channel are not
+    /// held back.
+    ///
+    /// Returns `false` in case the message can no longer be cancelled.
+    pub fn cancel(&mut self, id: MessageID) -> bool {
+        self.message_queue.cancel(id)
+    }
+
+    /// Sets the `deadline` of the queued message with the given `id`,
+    /// overriding the default time to live configured for its kind.
+    ///
+    /// Messages which have not been send once their deadline has passed are
+    /// dropped and reported via `ConnectionEvent::MessageExpired`.
+    ///
+    /// Returns `false` in case the message is no longer queued.
+    pub fn set_message_deadline(&mut self, id: MessageID, deadline: Instant) -> bool {
+        self.message_queue.set_message_deadline(id, deadline)
+    }
+
+    /// Sets the producer which writes the freshest application data into the
+    /// space that remains in each outgoing packet once all queued messages
+    /// have been written.
+    ///
+    /// This avoids state updates going stale while they wait in the send
+    /// queue, since the producer is only invoked by
+    /// `Connection::send_packet` right before the packet goes out.
+    pub fn set_packet_producer<P: PacketProducer + 'static>(&mut self, producer: P) {
+        self.producer = Some(Box::new(producer));
+    }
+
+    /// Removes the connection's packet producer.
+    pub fn clear_packet_producer(&mut self) {
+        self.producer = None;
     }
 
     /// Returns a drain iterator over all queued events from this connection.
@@ -381,6 +667,39 @@ impl<R: RateLimiter, M: PacketModifier> Connection<R, M> {
             return false;
         }
 
+        // Drop any packets which fail authentication
+        let packet = if let Some(cipher) = self.cipher.as_mut() {
+            if let Some(packet) = cipher.open(&packet, PACKET_HEADER_SIZE) {
+                packet
+
+            } else {
+                return false;
+            }
+
+        } else {
+            packet
+        };
+
+        // Let the packet modifier verify and transform the payload
+        let payload = {
+
+            let context = PacketContext {
+                id: self.random_id,
+                peer_address: self.peer_address,
+                local_sequence: self.local_seq_number,
+                remote_sequence: seq_from_slice(&packet[9..11]),
+                header: &packet[..PACKET_HEADER_SIZE]
+            };
+
+            match self.packet_modifier.incoming_packet(
+                &packet[PACKET_HEADER_SIZE..], &context
+            ) {
+                Ok(payload) => payload,
+                Err(_) => return false
+            }
+
+        };
+
         // Update connection state
         if !self.update_receive_state(&packet) {
             return false;
@@ -390,21 +709,21 @@ impl<R: RateLimiter, M: PacketModifier> Connection<R, M> {
         self.last_receive_time = Instant::now();
 
         // Read remote sequence number
-        self.remote_seq_number = packet[8] as u32;
+        self.remote_seq_number = seq_from_slice(&packet[9..11]);
 
         // Get latest acknowledge sequence number
-        let ack_seq_number = packet[9] as u32;
+        let ack_seq_number = seq_from_slice(&packet[11..13]);
 
         // Get acknowledgement bitfield
-        let bitfield = (packet[10] as u32) << 24
-                     | (packet[11] as u32) << 16
-                     | (packet[12] as u32) << 8
-                     |  packet[13] as u32;
+        let bitfield = (packet[13] as u32) << 24
+                     | (packet[14] as u32) << 16
+                     | (packet[15] as u32) << 8
+                     |  packet[16] as u32;
 
         // Check recently send packets for their acknowledgment
         for i in 0..self.sent_ack_queue.len() {
 
-            if let Some(lost_packet) = {
+            if let Some((lost_packet, messages)) = {
 
                 let ack = &mut self.sent_ack_queue[i];
 
@@ -423,6 +742,11 @@ impl<R: RateLimiter, M: PacketModifier> Connection<R, M> {
 
                     ack.state = PacketState::Acked;
 
+                    // Notify about all messages which have been delivered
+                    for id in self.message_queue.acked_packet(&ack.messages) {
+                        self.events.push(ConnectionEvent::MessageDelivered(id));
+                    }
+
                     None
 
                 // Extract data from lost packets
@@ -431,7 +755,9 @@ impl<R: RateLimiter, M: PacketModifier> Connection<R, M> {
 
                     self.lost_packets = self.lost_packets.wrapping_add(1);
                     ack.state = PacketState::Lost;
-                    ack.packet.take()
+                    ack.packet.take().map(|packet| {
+                        (packet, ack.messages.split_off(0))
+                    })
 
                 // Keep all pending packets
                 } else {
@@ -441,27 +767,54 @@ impl<R: RateLimiter, M: PacketModifier> Connection<R, M> {
             } {
 
                 // Push messages from lost packets into the queue
-                self.message_queue.lost_packet(&lost_packet[PACKET_HEADER_SIZE..]);
+                let lost_messages = self.message_queue.lost_packet(
+                    &lost_packet[PACKET_HEADER_SIZE..], &messages
+                );
 
                 // Packet lost notification
                 self.events.push(ConnectionEvent::PacketLost(
                     lost_packet[PACKET_HEADER_SIZE..].to_vec()
                 ));
 
+                for id in lost_messages {
+                    self.events.push(ConnectionEvent::MessageLost(id));
+                }
+
             }
 
         }
 
         // Push packet data into message queue
-        if let Some(payload) = self.packet_modifier.incoming(
-            &packet[PACKET_HEADER_SIZE..]
-        ) {
+        if let Some(payload) = payload {
             self.message_queue.receive_packet(&payload[..]);
 
         } else {
             self.message_queue.receive_packet(&packet[PACKET_HEADER_SIZE..]);
         }
 
+        // Handle requests and responses, ignoring unexpected ones
+        for data in self.message_queue.received_rpc() {
+            if let Some(frame) = Frame::from_bytes(&data) {
+
+                // Fail requests right away once too many of them await a
+                // response
+                if let Frame::Request(id, _) = frame {
+                    if self.requests.incoming() >= self.config.request_max_incoming {
+                        let response = Frame::Response(id, Err(Vec::new()));
+                        self.message_queue.send_rpc(response.into_bytes()).ok();
+                        continue;
+                    }
+                }
+
+                if self.requests.receive(&frame) {
+                    self.events.push(match frame {
+                        Frame::Request(id, payload) => ConnectionEvent::Request(id, payload),
+                        Frame::Response(id, result) => ConnectionEvent::Response(id, result)
+                    });
+                }
+            }
+        }
+
         // Remove all acknowledged and lost packets from the sent ack queue
         self.sent_ack_queue.retain(|p| p.state == PacketState::Unknown);
 
@@ -488,11 +841,17 @@ impl<R: RateLimiter, M: PacketModifier> Connection<R, M> {
 
     ) -> u32 {
 
-        // Update connection state
+        // Update connection state, requests can no longer be responded to
+        // once the connection is gone
         if !self.update_send_state() {
+            self.fail_requests();
             return 0;
         }
 
+        for id in self.requests.timed_out(Instant::now()) {
+            self.events.push(ConnectionEvent::RequestTimedOut(id));
+        }
+
         let congested = self.rate_limiter.congested();
         let rtt = self.rtt();
         let packet_loss = self.packet_loss();
@@ -525,75 +884,133 @@ impl<R: RateLimiter, M: PacketModifier> Connection<R, M> {
         packet.push((self.random_id.0 >> 8) as u8);
         packet.push(self.random_id.0 as u8);
 
-        // Send closing packets if required
-        if self.state == ConnectionState::Closing {
-            packet.extend_from_slice(&CLOSURE_PACKET_DATA);
-
-        } else {
+        // Set packet type, this gets updated to the actual type once the
+        // messages have been written
+        packet.push(PacketType::Data as u8);
 
-            // Set local sequence number
-            packet.push(self.local_seq_number as u8);
+        // Set local sequence number
+        packet.push((self.local_seq_number >> 8) as u8);
+        packet.push(self.local_seq_number as u8);
 
-            // Set packet ack number
-            packet.push(self.remote_seq_number as u8);
+        // Set packet ack number
+        packet.push((self.remote_seq_number >> 8) as u8);
+        packet.push(self.remote_seq_number as u8);
 
-            // Construct ack bitfield from most recently received packets
-            let mut bitfield: u32 = 0;
-            for seq in &self.recv_ack_queue {
+        // Construct ack bitfield from most recently received packets
+        let mut bitfield: u32 = 0;
+        for seq in &self.recv_ack_queue {
 
-                // Ignore the remote sequence as it already gets set in the header
-                if *seq != self.remote_seq_number {
+            // Ignore the remote sequence as it already gets set in the header
+            if *seq != self.remote_seq_number {
 
-                    // Calculate bitfield index
-                    let bit = seq_bit_index(*seq, self.remote_seq_number);
-
-                    // Set ack bit
-                    if bit < MAX_ACK_BITS {
-                        bitfield |= (1 << bit) as u32;
-                    }
+                // Calculate bitfield index
+                let bit = seq_bit_index(*seq, self.remote_seq_number);
 
+                // Set ack bit
+                if bit < MAX_ACK_BITS {
+                    bitfield |= (1 << bit) as u32;
                 }
 
             }
 
-            // Set ack bitfield
-            packet.push((bitfield >> 24) as u8);
-            packet.push((bitfield >> 16) as u8);
-            packet.push((bitfield >> 8) as u8);
-            packet.push(bitfield as u8);
+        }
 
-            // Write messages from queue into the packet
-            self.message_queue.send_packet(
-                &mut packet, self.config.packet_max_size - PACKET_HEADER_SIZE
-            );
+        // Set ack bitfield
+        packet.push((bitfield >> 24) as u8);
+        packet.push((bitfield >> 16) as u8);
+        packet.push((bitfield >> 8) as u8);
+        packet.push(bitfield as u8);
 
-        }
+        // Send closing packets if required
+        let messages = if self.state == ConnectionState::Closing {
+            packet[PACKET_TYPE_OFFSET] = PacketType::Close as u8;
+            Vec::new()
 
-        // Send packet to socket
-        let bytes_sent = if let Some(mut payload) = self.packet_modifier.outgoing(
-            &packet[PACKET_HEADER_SIZE..]
-        ) {
+        } else {
 
-            // Combine existing header with modified packet payload
-            let mut packet = packet[..PACKET_HEADER_SIZE].to_vec();
-            packet.append(&mut payload);
+            // Write messages from queue into the packet, leaving room for
+            // payload modifications and encryption
+            let overhead = if self.cipher.is_some() {
+                PACKET_HEADER_SIZE + ENCRYPTION_OVERHEAD
+
+            } else {
+                PACKET_HEADER_SIZE
+            } + self.packet_modifier.max_overhead();
+
+            let available = self.config.packet_max_size - overhead;
+            let messages = self.message_queue.send_packet(&mut packet, available);
+
+            // Let the application fill the remaining space with fresh data,
+            // this has to come last as the produced message has no id
+            if let Some(producer) = self.producer.as_mut() {
+                let written = packet.len() - PACKET_HEADER_SIZE;
+                self.message_queue.produce_packet(
+                    &mut packet, available - written, producer.as_mut()
+                );
+            }
 
-            socket.send_to(
-                &packet[..], *addr
+            for id in self.message_queue.expired() {
+                self.events.push(ConnectionEvent::MessageExpired(id));
+            }
 
-            ).expect(&format!("Failed to send compressed packet to {:?}", addr));
+            if self.message_queue.drained() {
+                self.events.push(ConnectionEvent::SendQueueDrained);
+            }
 
-            // Number of all bytes sent
-            packet.len()
+            if packet.len() == PACKET_HEADER_SIZE {
+                packet[PACKET_TYPE_OFFSET] = PacketType::Ack as u8;
+            }
+
+            messages
+
+        };
+
+        // Combine existing header with modified packet payload
+        let modified = self.packet_modifier.outgoing_packet(
+            &packet[PACKET_HEADER_SIZE..],
+            &PacketContext {
+                id: self.random_id,
+                peer_address: *addr,
+                local_sequence: self.local_seq_number,
+                remote_sequence: self.remote_seq_number,
+                header: &packet[..PACKET_HEADER_SIZE]
+            }
+
+        ).map(|mut payload| {
+            let mut modified = packet[..PACKET_HEADER_SIZE].to_vec();
+            modified.append(&mut payload);
+            modified
+        });
+
+        // Encrypt the payload and authenticate the whole packet, packets
+        // which cannot be sealed - e.g. because all nonces have been used up -
+        // are never send in plain text but drop the connection instead
+        let sealed = if let Some(cipher) = self.cipher.as_mut() {
+            match cipher.seal(modified.as_ref().unwrap_or(&packet), PACKET_HEADER_SIZE) {
+                Some(sealed) => Some(sealed),
+                None => {
+                    self.state = ConnectionState::Lost;
+                    self.events.push(ConnectionEvent::Lost);
+                    self.fail_requests();
+                    return 0;
+                }
+            }
 
         } else {
-            socket.send_to(
-                &packet[..], *addr
+            None
+        };
+
+        // Send packet to socket
+        let bytes_sent = {
 
-            ).expect(&format!("Failed to send packet to {:?}", addr));
+            let data = sealed.as_ref().or(modified.as_ref()).unwrap_or(&packet);
+            socket.send_to(&data[..], *addr).unwrap_or_else(|_| {
+                panic!("Failed to send packet to {:?}", addr)
+            });
 
             // Number of all bytes sent
-            packet.len()
+            data.len()
+
         };
 
 
@@ -603,7 +1020,8 @@ impl<R: RateLimiter, M: PacketModifier> Connection<R, M> {
                 seq: self.local_seq_number,
                 time: Instant::now(),
                 state: PacketState::Unknown,
-                packet: Some(packet)
+                packet: Some(packet),
+                messages
             });
         }
 
@@ -626,6 +1044,9 @@ impl<R: RateLimiter, M: PacketModifier> Connection<R, M> {
     }
 
     /// Resets the connection for re-use with another address.
+    ///
+    /// Requests which are still awaiting a response are reported via
+    /// `ConnectionEvent::RequestTimedOut`.
     pub fn reset(&mut self) {
         self.state = ConnectionState::Connecting;
         self.local_seq_number = 0;
@@ -640,6 +1061,10 @@ impl<R: RateLimiter, M: PacketModifier> Connection<R, M> {
         self.lost_packets = 0;
         self.message_queue.reset();
         self.rate_limiter.reset();
+        self.cipher = None;
+        for id in self.requests.reset() {
+            self.events.push(ConnectionEvent::RequestTimedOut(id));
+        }
     }
 
     /// Closes the connection, no further packets will be received or send.
@@ -652,6 +1077,14 @@ impl<R: RateLimiter, M: PacketModifier> Connection<R, M> {
 
     fn update_receive_state(&mut self, packet: &[u8]) -> bool {
 
+        // Ignore any handshake packets and packets of unknown types
+        let packet_type = match PacketType::from_packet(packet) {
+            Some(PacketType::Data) => PacketType::Data,
+            Some(PacketType::Ack) => PacketType::Ack,
+            Some(PacketType::Close) => PacketType::Close,
+            _ => return false
+        };
+
         // Ignore any packets which do not match the desired protocol header
         &packet[0..4] == &self.config.protocol_header && match self.state {
 
@@ -663,6 +1096,11 @@ impl<R: RateLimiter, M: PacketModifier> Connection<R, M> {
 
             ConnectionState::Connecting => {
 
+                // Closure packets can only end established connections
+                if packet_type == PacketType::Close {
+                    return false;
+                }
+
                 // Once we receive the first valid packet we consider the
                 // connection as established
                 self.state = ConnectionState::Connected;
@@ -679,7 +1117,7 @@ impl<R: RateLimiter, M: PacketModifier> Connection<R, M> {
             ConnectionState::Connected => {
 
                 // Check for closure packet from remote
-                if &packet[8..14] == &CLOSURE_PACKET_DATA {
+                if packet_type == PacketType::Close {
                     self.state = ConnectionState::Closed;
                     self.events.push(ConnectionEvent::Closed(true));
                     false
@@ -688,7 +1126,7 @@ impl<R: RateLimiter, M: PacketModifier> Connection<R, M> {
                     // Check if the packet sequence number is more recent,
                     // otherwise drop it as a duplicate
                     seq_is_more_recent(
-                        packet[8] as u32, self.remote_seq_number
+                        seq_from_slice(&packet[9..11]), self.remote_seq_number
                     )
                 }
 
@@ -756,6 +1194,12 @@ impl<R: RateLimiter, M: PacketModifier> Connection<R, M> {
     }
 
     // Internal Helpers -------------------------------------------------------
+    fn fail_requests(&mut self) {
+        for id in self.requests.fail() {
+            self.events.push(ConnectionEvent::RequestTimedOut(id));
+        }
+    }
+
     fn send_ack_required(&self, seq: u32) -> bool {
         !self.sent_ack_queue.iter().any(|p| p.seq == seq)
     }
@@ -769,6 +1213,10 @@ fn moving_average(a: f32, b: Duration) -> f32 {
     (a - (a - b) * 0.10).max(0.0)
 }
 
+fn seq_from_slice(data: &[u8]) -> u32 {
+    (data[0] as u32) << 8 | data[1] as u32
+}
+
 fn seq_bit_index(seq: u32, ack: u32) -> u32 {
     if seq > ack {
         ack + (MAX_SEQ_NUMBER - 1 - seq)
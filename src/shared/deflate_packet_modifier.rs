@@ -0,0 +1,113 @@
+// Copyright (c) 2015-2017 Ivo Wetzel
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+extern crate flate2;
+
+
+// STD Dependencies -----------------------------------------------------------
+use std::io::Error;
+use std::marker::PhantomData;
+
+
+// External Dependencies ------------------------------------------------------
+use self::flate2::{
+    Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status
+};
+
+
+// Internal Dependencies ------------------------------------------------------
+use super::compression::{
+    decode_payload, encode_payload, NoDictionary, COMPRESSION_OVERHEAD
+};
+use ::{CompressionDictionary, Config, PacketContext, PacketModifier};
+
+
+/// Implementation of a packet modifier which compresses payloads with raw
+/// deflate.
+///
+/// Each payload is compressed independently, so lost packets have no effect on
+/// the decompression of later ones. Payloads which do not shrink are send
+/// uncompressed. An optional preset dictionary can be provided via a
+/// `CompressionDictionary` implementation.
+///
+/// Requires the `deflate` feature.
+#[derive(Debug)]
+pub struct DeflatePacketModifier<D: CompressionDictionary = NoDictionary> {
+    compress: Compress,
+    decompress: Decompress,
+    max_size: usize,
+    dictionary: PhantomData<D>
+}
+
+impl<D: CompressionDictionary> PacketModifier for DeflatePacketModifier<D> {
+
+    fn new(config: Config) -> DeflatePacketModifier<D> {
+        DeflatePacketModifier {
+            compress: Compress::new(Compression::fast(), false),
+            decompress: Decompress::new(false),
+            max_size: config.packet_max_size,
+            dictionary: PhantomData
+        }
+    }
+
+    fn max_overhead(&self) -> usize {
+        COMPRESSION_OVERHEAD
+    }
+
+    fn outgoing_packet(&mut self, payload: &[u8], _: &PacketContext) -> Option<Vec<u8>> {
+
+        self.compress.reset();
+
+        let dictionary = D::dictionary();
+        let compressed = if dictionary.is_empty() || self.compress.set_dictionary(dictionary).is_ok() {
+
+            // Compressed payloads which would exceed the original size are
+            // never used anyway
+            let mut data = Vec::with_capacity(payload.len());
+            match self.compress.compress_vec(payload, &mut data, FlushCompress::Finish) {
+                Ok(Status::StreamEnd) => Some(data),
+                _ => None
+            }
+
+        } else {
+            None
+        };
+
+        encode_payload(payload, compressed)
+
+    }
+
+    fn incoming_packet(
+        &mut self,
+        payload: &[u8],
+        _: &PacketContext
+
+    ) -> Result<Option<Vec<u8>>, Error> {
+
+        let decompress = &mut self.decompress;
+        let max_size = self.max_size;
+
+        decode_payload(payload, |data| {
+
+            decompress.reset(false);
+
+            let dictionary = D::dictionary();
+            if !dictionary.is_empty() && decompress.set_dictionary(dictionary).is_err() {
+                return None;
+            }
+
+            let mut payload = Vec::with_capacity(max_size);
+            match decompress.decompress_vec(data, &mut payload, FlushDecompress::Finish) {
+                Ok(Status::StreamEnd) => Some(payload),
+                _ => None
+            }
+
+        })
+
+    }
+
+}
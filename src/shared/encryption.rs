@@ -0,0 +1,318 @@
+// Copyright (c) 2015-2017 Ivo Wetzel
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+extern crate chacha20poly1305;
+extern crate hmac;
+extern crate rand;
+extern crate sha2;
+extern crate x25519_dalek;
+
+
+// STD Dependencies -----------------------------------------------------------
+use std::fmt;
+
+
+// External Dependencies ------------------------------------------------------
+use self::chacha20poly1305::{ChaCha20Poly1305, KeyInit, Nonce};
+use self::chacha20poly1305::aead::{Aead, Payload};
+use self::hmac::{Hmac, Mac};
+use self::sha2::Sha256;
+use self::x25519_dalek::{PublicKey as DalekPublicKey, StaticSecret};
+
+
+// Internal Dependencies ------------------------------------------------------
+use ::ConnectionID;
+
+/// Number of bytes used by a public key exchanged during the handshake.
+pub const PUBLIC_KEY_BYTES: usize = 32;
+
+/// Number of bytes used by a symmetric connection key.
+pub const KEY_BYTES: usize = 32;
+
+/// Number of bytes used by the packet counter which makes up the nonce.
+const COUNTER_BYTES: usize = 8;
+
+/// Number of bytes used by the authentication tag of a packet.
+const TAG_BYTES: usize = 16;
+
+/// Number of additional bytes added to each encrypted packet.
+pub const ENCRYPTION_OVERHEAD: usize = COUNTER_BYTES + TAG_BYTES;
+
+/// Number of additional bytes added to a sealed connection payload.
+pub const PAYLOAD_OVERHEAD: usize = TAG_BYTES;
+
+/// Type alias for the public keys exchanged during the handshake.
+pub type PublicKey = [u8; PUBLIC_KEY_BYTES];
+
+/// Type alias for symmetric connection keys.
+pub type Key = [u8; KEY_BYTES];
+
+/// X25519 key pair used to agree on a pair of connection keys.
+pub struct KeyExchange {
+    secret: StaticSecret,
+    public: PublicKey
+}
+
+impl KeyExchange {
+
+    /// Creates a new, random key pair.
+    pub fn new() -> KeyExchange {
+        KeyExchange::from_secret(rand::random::<[u8; KEY_BYTES]>())
+    }
+
+    fn from_secret(secret: [u8; KEY_BYTES]) -> KeyExchange {
+        let secret = StaticSecret::from(secret);
+        let public = DalekPublicKey::from(&secret).to_bytes();
+        KeyExchange {
+            secret,
+            public
+        }
+    }
+
+    /// Returns the public half of the key pair.
+    pub fn public_key(&self) -> PublicKey {
+        self.public
+    }
+
+    /// Derives the client-to-server and server-to-client keys for the
+    /// connection with the given `id`.
+    ///
+    /// Returns `None` in case the remote public key is not suited for key
+    /// agreement.
+    pub fn connection_keys(
+        &self,
+        remote: &PublicKey,
+        id: ConnectionID,
+        is_server: bool
+
+    ) -> Option<(Key, Key)> {
+
+        let shared = self.secret.diffie_hellman(&DalekPublicKey::from(*remote));
+        if !shared.was_contributory() {
+            return None;
+        }
+
+        // Bind the keys to both public keys and the connection
+        let (client, server) = if is_server {
+            (remote, &self.public)
+
+        } else {
+            (&self.public, remote)
+        };
+
+        let mut context = Vec::with_capacity(PUBLIC_KEY_BYTES * 2 + 4);
+        context.extend_from_slice(client);
+        context.extend_from_slice(server);
+        context.push((id.0 >> 24) as u8);
+        context.push((id.0 >> 16) as u8);
+        context.push((id.0 >> 8) as u8);
+        context.push(id.0 as u8);
+
+        Some((
+            derive_key(shared.as_bytes(), b"client to server", &context),
+            derive_key(shared.as_bytes(), b"server to client", &context)
+        ))
+
+    }
+
+}
+
+impl fmt::Debug for KeyExchange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "KeyExchange {{ public: {:?} }}", self.public)
+    }
+}
+
+/// Stateless source of the key pairs a server uses for its challenges.
+///
+/// Each challenge cookie gets its own key pair, which is derived from a
+/// random secret and the cookie itself, so the server does not need to keep
+/// any state until a challenge is answered. Anyone who obtains the secret
+/// can still derive the key pairs of all connections made while it was in
+/// use.
+pub struct ChallengeKeys {
+    secret: Key
+}
+
+impl ChallengeKeys {
+
+    /// Creates a new source with a random secret.
+    pub fn new() -> ChallengeKeys {
+        ChallengeKeys {
+            secret: rand::random::<[u8; KEY_BYTES]>()
+        }
+    }
+
+    /// Returns the key pair for the challenge with the given `cookie`.
+    pub fn key_exchange(&self, cookie: &[u8]) -> KeyExchange {
+        KeyExchange::from_secret(derive_key(&self.secret, b"challenge key", cookie))
+    }
+
+}
+
+impl fmt::Debug for ChallengeKeys {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ChallengeKeys")
+    }
+}
+
+/// Authenticated encryption of connection packets.
+///
+/// Packets are encrypted with ChaCha20-Poly1305, using the packet header as
+/// associated data and a packet counter as the nonce. The counter is send
+/// along with each packet and never wraps around; packets whose counter is
+/// not larger than that of the most recent authentic packet are rejected as
+/// replays.
+pub struct PacketCipher {
+    send: ChaCha20Poly1305,
+    receive: ChaCha20Poly1305,
+    send_counter: u64,
+    receive_counter: Option<u64>
+}
+
+impl PacketCipher {
+
+    /// Creates a new cipher from a pair of connection keys.
+    pub fn new(send_key: &Key, receive_key: &Key) -> PacketCipher {
+        PacketCipher {
+            send: ChaCha20Poly1305::new(send_key.into()),
+            receive: ChaCha20Poly1305::new(receive_key.into()),
+            send_counter: 0,
+            receive_counter: None
+        }
+    }
+
+    /// Returns whether all nonces have been used up.
+    pub fn exhausted(&self) -> bool {
+        self.send_counter == u64::MAX
+    }
+
+    /// Overrides the counter used for the next sealed packet.
+    #[cfg(test)]
+    pub fn set_send_counter(&mut self, counter: u64) {
+        self.send_counter = counter;
+    }
+
+    /// Encrypts the payload of a packet, leaving its header of `header_size`
+    /// bytes in plain text.
+    ///
+    /// Returns `None` once all nonces have been used up.
+    pub fn seal(&mut self, packet: &[u8], header_size: usize) -> Option<Vec<u8>> {
+
+        if self.exhausted() {
+            return None;
+        }
+
+        let counter = self.send_counter;
+        self.send_counter += 1;
+
+        let (header, payload) = packet.split_at(header_size);
+        self.send.encrypt(&nonce(counter), Payload {
+            msg: payload,
+            aad: header
+
+        }).ok().map(|mut ciphertext| {
+            let mut sealed = Vec::with_capacity(packet.len() + ENCRYPTION_OVERHEAD);
+            sealed.extend_from_slice(header);
+            for i in 0..COUNTER_BYTES {
+                sealed.push((counter >> (56 - i * 8)) as u8);
+            }
+            sealed.append(&mut ciphertext);
+            sealed
+        })
+
+    }
+
+    /// Authenticates and decrypts a packet sealed by the remote end of the
+    /// connection.
+    pub fn open(&mut self, packet: &[u8], header_size: usize) -> Option<Vec<u8>> {
+
+        if packet.len() < header_size + ENCRYPTION_OVERHEAD {
+            return None;
+        }
+
+        let (header, data) = packet.split_at(header_size);
+        let (counter, ciphertext) = data.split_at(COUNTER_BYTES);
+        let counter = counter.iter().fold(0, |c, b| c << 8 | *b as u64);
+
+        // Reject replayed packets before spending time on their decryption
+        if self.receive_counter.is_some_and(|c| counter <= c) {
+            return None;
+        }
+
+        self.receive.decrypt(&nonce(counter), Payload {
+            msg: ciphertext,
+            aad: header
+
+        }).ok().map(|mut payload| {
+            self.receive_counter = Some(counter);
+            let mut opened = header.to_vec();
+            opened.append(&mut payload);
+            opened
+        })
+
+    }
+
+}
+
+impl fmt::Debug for PacketCipher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f, "PacketCipher {{ send_counter: {}, receive_counter: {:?} }}",
+            self.send_counter, self.receive_counter
+        )
+    }
+}
+
+
+/// Encrypts the connection payload of a challenge response with the
+/// client-to-server key, authenticating the `cookie` along with it.
+///
+/// Each connection key seals at most one distinct payload, so a fixed nonce
+/// outside of the range used by `PacketCipher` is safe to use.
+pub fn seal_payload(key: &Key, payload: &[u8], cookie: &[u8]) -> Vec<u8> {
+    ChaCha20Poly1305::new(key.into()).encrypt(&payload_nonce(), Payload {
+        msg: payload,
+        aad: cookie
+
+    }).expect("Connection payloads never exceed the cipher's limits")
+}
+
+/// Authenticates and decrypts a connection payload sealed via
+/// `seal_payload()`.
+pub fn open_payload(key: &Key, sealed: &[u8], cookie: &[u8]) -> Option<Vec<u8>> {
+    ChaCha20Poly1305::new(key.into()).decrypt(&payload_nonce(), Payload {
+        msg: sealed,
+        aad: cookie
+
+    }).ok()
+}
+
+
+// Static Helpers -------------------------------------------------------------
+fn derive_key(shared: &[u8], label: &[u8], context: &[u8]) -> Key {
+    let mut hmac = <Hmac<Sha256> as Mac>::new_from_slice(shared).expect("HMAC accepts keys of any size");
+    hmac.update(label);
+    hmac.update(context);
+
+    let mut key = [0; KEY_BYTES];
+    key.copy_from_slice(&hmac.finalize().into_bytes());
+    key
+}
+
+fn payload_nonce() -> Nonce {
+    // Packet nonces always start with four zero bytes
+    [0xFF; 12].into()
+}
+
+fn nonce(counter: u64) -> Nonce {
+    let mut nonce = [0; 12];
+    for (i, byte) in nonce[4..].iter_mut().enumerate() {
+        *byte = (counter >> (56 - i * 8)) as u8;
+    }
+    nonce.into()
+}
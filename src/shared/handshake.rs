@@ -0,0 +1,391 @@
+// Copyright (c) 2015-2017 Ivo Wetzel
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+
+// STD Dependencies -----------------------------------------------------------
+use std::net::SocketAddr;
+use std::time::Instant;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::collections::hash_map::RandomState;
+
+
+// Internal Dependencies ------------------------------------------------------
+use super::encryption::{PublicKey, PUBLIC_KEY_BYTES};
+use super::packet_type::PacketType;
+use ::{Config, ConnectionID};
+
+/// Version of the wire format, incremented with each incompatible change.
+pub const WIRE_VERSION: u16 = 1;
+
+/// Number of bytes used by a challenge cookie.
+pub const COOKIE_BYTES: usize = 12;
+
+/// Number of bytes used by a serialized protocol version.
+const VERSION_BYTES: usize = 6;
+
+/// Number of bytes used by the header of any handshake packet.
+const HANDSHAKE_HEADER_SIZE: usize = 9;
+
+/// Number of bytes used by connection requests and challenges.
+///
+/// Connection requests are padded to the same size as the challenges which
+/// are send in reply to them, so a server can never be used to amplify the
+/// traffic directed at a spoofed address.
+pub const CHALLENGE_PACKET_SIZE: usize = HANDSHAKE_HEADER_SIZE + COOKIE_BYTES;
+
+/// Number of bytes used by connection requests and challenges when
+/// encryption is enabled.
+pub const ENCRYPTED_CHALLENGE_PACKET_SIZE: usize = CHALLENGE_PACKET_SIZE + PUBLIC_KEY_BYTES;
+
+/// Number of bytes used by denials without an application specific reason.
+pub const DENIAL_PACKET_SIZE: usize = HANDSHAKE_HEADER_SIZE + 1;
+
+/// Enum of reasons for which a server may reject a connection attempt.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RejectionReason {
+
+    /// The server has already reached its maximum number of connections.
+    ServerFull,
+
+    /// The server's `AdmissionPolicy` denied the connection, optionally
+    /// providing an application specific reason.
+    Denied(Option<Vec<u8>>),
+
+    /// The server requires a connect token and the one presented by the
+    /// client was either forged, expired, issued for another server or has
+    /// already been used.
+    InvalidToken,
+
+    /// The server requires encryption, but the client did not enable it.
+    ///
+    /// Clients with encryption enabled also report this rejection when the
+    /// server did not enable it.
+    EncryptionRequired,
+
+    /// The server speaks a different version of the protocol, which is
+    /// provided.
+    ///
+    /// Clients report this rejection via
+    /// `ClientEvent::ConnectionVersionMismatch`.
+    VersionMismatch(ProtocolVersion)
+
+}
+
+/// The protocol version spoken by a client or server.
+///
+/// Clients include their version in each connection request and servers
+/// reject any request with a version other than their own.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct ProtocolVersion {
+
+    /// The version of cobalt's wire format.
+    pub wire: u16,
+
+    /// The application specific version from `Config::application_version`.
+    pub application: u32
+
+}
+
+impl ProtocolVersion {
+
+    /// Returns the protocol version spoken with the given configuration.
+    pub fn from_config(config: &Config) -> ProtocolVersion {
+        ProtocolVersion {
+            wire: WIRE_VERSION,
+            application: config.application_version
+        }
+    }
+
+    fn from_slice(data: &[u8]) -> ProtocolVersion {
+        ProtocolVersion {
+            wire: (data[0] as u16) << 8 | data[1] as u16,
+            application: data[2..VERSION_BYTES].iter().fold(0, |v, b| v << 8 | *b as u32)
+        }
+    }
+
+    fn write(&self, packet: &mut Vec<u8>) {
+        packet.push((self.wire >> 8) as u8);
+        packet.push(self.wire as u8);
+        packet.push((self.application >> 24) as u8);
+        packet.push((self.application >> 16) as u8);
+        packet.push((self.application >> 8) as u8);
+        packet.push(self.application as u8);
+    }
+
+}
+
+/// Type alias for the opaque cookies issued by a server.
+pub type Cookie = [u8; COOKIE_BYTES];
+
+/// Enum of packets exchanged during the connection handshake.
+#[derive(Debug, Clone, PartialEq)]
+pub enum HandshakePacket<'a> {
+
+    /// Send by a client in order to request a challenge from a server,
+    /// including the client's protocol version.
+    ConnectRequest(ProtocolVersion),
+
+    /// Send by a server in reply to a connection request, including the
+    /// server's public key in case encryption is enabled.
+    Challenge(Cookie, Option<PublicKey>),
+
+    /// Send by a client in order to return the cookie of a challenge along
+    /// with its public key in case encryption is enabled and an optional
+    /// connection payload.
+    ChallengeResponse(Cookie, Option<PublicKey>, &'a [u8]),
+
+    /// Send by a server in order to reject a connection attempt.
+    Denial(RejectionReason)
+
+}
+
+impl<'a> HandshakePacket<'a> {
+
+    /// Parses a handshake packet from a packet with a valid protocol header.
+    pub fn from_packet(config: &Config, packet: &'a [u8]) -> Option<HandshakePacket<'a>> {
+
+        if packet.len() < HANDSHAKE_HEADER_SIZE {
+            return None;
+        }
+
+        let data = &packet[HANDSHAKE_HEADER_SIZE..];
+        match PacketType::from_packet(packet) {
+
+            // Requests from clients with encryption enabled carry additional
+            // padding
+            Some(PacketType::ConnectRequest) => {
+                if packet.len() == CHALLENGE_PACKET_SIZE
+                    || packet.len() == ENCRYPTED_CHALLENGE_PACKET_SIZE {
+                    Some(HandshakePacket::ConnectRequest(ProtocolVersion::from_slice(data)))
+
+                } else {
+                    None
+                }
+            },
+
+            Some(PacketType::Challenge) => {
+                if packet.len() == CHALLENGE_PACKET_SIZE {
+                    Some(HandshakePacket::Challenge(cookie_from_slice(data), None))
+
+                } else if packet.len() == ENCRYPTED_CHALLENGE_PACKET_SIZE {
+                    Some(HandshakePacket::Challenge(
+                        cookie_from_slice(data),
+                        Some(key_from_slice(&data[COOKIE_BYTES..]))
+                    ))
+
+                } else {
+                    None
+                }
+            },
+
+            Some(PacketType::ChallengeResponse) => {
+                if packet.len() >= challenge_packet_size(config) {
+                    let cookie = cookie_from_slice(data);
+                    let data = &data[COOKIE_BYTES..];
+                    Some(if config.encryption {
+                        HandshakePacket::ChallengeResponse(
+                            cookie,
+                            Some(key_from_slice(data)),
+                            &data[PUBLIC_KEY_BYTES..]
+                        )
+
+                    } else {
+                        HandshakePacket::ChallengeResponse(cookie, None, data)
+                    })
+
+                } else {
+                    None
+                }
+            },
+
+            Some(PacketType::Denial) if !data.is_empty() => {
+                match data[0] {
+                    1 => Some(RejectionReason::ServerFull),
+                    2 => Some(RejectionReason::Denied(None)),
+                    3 => Some(RejectionReason::Denied(Some(data[1..].to_vec()))),
+                    4 => Some(RejectionReason::InvalidToken),
+                    5 => Some(RejectionReason::EncryptionRequired),
+                    6 if data.len() > VERSION_BYTES => Some(
+                        RejectionReason::VersionMismatch(ProtocolVersion::from_slice(&data[1..]))
+                    ),
+                    _ => None
+
+                }.map(HandshakePacket::Denial)
+            },
+
+            _ => None
+
+        }
+
+    }
+
+    /// Serializes the handshake packet for the connection with the given `id`.
+    pub fn to_packet(&self, config: &Config, id: ConnectionID) -> Vec<u8> {
+
+        let mut packet = Vec::with_capacity(challenge_packet_size(config));
+
+        // Set packet protocol header
+        packet.extend_from_slice(&config.protocol_header);
+
+        // Set connection ID
+        packet.push((id.0 >> 24) as u8);
+        packet.push((id.0 >> 16) as u8);
+        packet.push((id.0 >> 8) as u8);
+        packet.push(id.0 as u8);
+
+        match *self {
+            HandshakePacket::ConnectRequest(version) => {
+                packet.push(PacketType::ConnectRequest as u8);
+                version.write(&mut packet);
+                packet.resize(challenge_packet_size(config), 0);
+            },
+            HandshakePacket::Challenge(cookie, key) => {
+                packet.push(PacketType::Challenge as u8);
+                packet.extend_from_slice(&cookie);
+                if let Some(key) = key {
+                    packet.extend_from_slice(&key);
+                }
+            },
+            HandshakePacket::ChallengeResponse(cookie, key, payload) => {
+                packet.push(PacketType::ChallengeResponse as u8);
+                packet.extend_from_slice(&cookie);
+                if let Some(key) = key {
+                    packet.extend_from_slice(&key);
+                }
+                packet.extend_from_slice(payload);
+            },
+            HandshakePacket::Denial(ref reason) => {
+                packet.push(PacketType::Denial as u8);
+                match *reason {
+                    RejectionReason::ServerFull => packet.push(1),
+                    RejectionReason::Denied(None) => packet.push(2),
+                    RejectionReason::Denied(Some(ref reason)) => {
+                        packet.push(3);
+                        packet.extend_from_slice(reason);
+                    },
+                    RejectionReason::InvalidToken => packet.push(4),
+                    RejectionReason::EncryptionRequired => packet.push(5),
+                    RejectionReason::VersionMismatch(version) => {
+                        packet.push(6);
+                        version.write(&mut packet);
+                    }
+                }
+            }
+        }
+
+        packet
+
+    }
+
+}
+
+/// Stateless generator for challenge cookies.
+///
+/// Each cookie contains the time at which it was issued as well as a keyed
+/// hash over this time, the socket address and the `ConnectionID` of the
+/// requesting client. Since the key never leaves the server, only clients
+/// which actually received a challenge at their claimed address are able to
+/// produce a matching challenge response.
+#[derive(Debug)]
+pub struct CookieGenerator {
+    secret: RandomState,
+    epoch: Instant
+}
+
+impl CookieGenerator {
+
+    /// Creates a new generator with a random secret key.
+    pub fn new() -> CookieGenerator {
+        CookieGenerator {
+            secret: RandomState::new(),
+            epoch: Instant::now()
+        }
+    }
+
+    /// Issues a new cookie for the client at the specified address.
+    pub fn generate(&self, addr: SocketAddr, id: ConnectionID) -> Cookie {
+
+        let time = self.time();
+        let hash = self.hash(time, addr, id);
+
+        let mut cookie = [0; COOKIE_BYTES];
+        for (i, byte) in cookie[..4].iter_mut().enumerate() {
+            *byte = (time >> (24 - i * 8)) as u8;
+        }
+
+        for (i, byte) in cookie[4..].iter_mut().enumerate() {
+            *byte = (hash >> (56 - i * 8)) as u8;
+        }
+
+        cookie
+
+    }
+
+    /// Verifies that a cookie was issued by this generator for the client at
+    /// the specified address and has not yet expired.
+    pub fn verify(
+        &self,
+        cookie: &Cookie,
+        addr: SocketAddr,
+        id: ConnectionID,
+        config: &Config
+
+    ) -> bool {
+
+        let time = cookie[..4].iter().fold(0, |t, b| t << 8 | *b as u32);
+        let hash = cookie[4..].iter().fold(0, |h, b| h << 8 | *b as u64);
+
+        let threshold = config.connection_init_threshold;
+        let max_age = threshold.as_secs() as u32 * 1000
+                    + threshold.subsec_millis();
+
+        self.time().wrapping_sub(time) <= max_age
+            && self.hash(time, addr, id) == hash
+
+    }
+
+    fn time(&self) -> u32 {
+        let elapsed = self.epoch.elapsed();
+        (elapsed.as_secs() as u32).wrapping_mul(1000)
+            .wrapping_add(elapsed.subsec_millis())
+    }
+
+    fn hash(&self, time: u32, addr: SocketAddr, id: ConnectionID) -> u64 {
+        let mut hasher = self.secret.build_hasher();
+        time.hash(&mut hasher);
+        addr.hash(&mut hasher);
+        id.hash(&mut hasher);
+        hasher.finish()
+    }
+
+}
+
+
+// Static Helpers -------------------------------------------------------------
+/// Returns the size of connection requests and challenges for the given
+/// configuration.
+pub fn challenge_packet_size(config: &Config) -> usize {
+    if config.encryption {
+        ENCRYPTED_CHALLENGE_PACKET_SIZE
+
+    } else {
+        CHALLENGE_PACKET_SIZE
+    }
+}
+
+fn key_from_slice(data: &[u8]) -> PublicKey {
+    let mut key = [0; PUBLIC_KEY_BYTES];
+    key.copy_from_slice(&data[..PUBLIC_KEY_BYTES]);
+    key
+}
+
+fn cookie_from_slice(data: &[u8]) -> Cookie {
+    let mut cookie = [0; COOKIE_BYTES];
+    cookie.copy_from_slice(&data[..COOKIE_BYTES]);
+    cookie
+}
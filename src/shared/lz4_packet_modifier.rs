@@ -0,0 +1,70 @@
+// Copyright (c) 2015-2017 Ivo Wetzel
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+extern crate lz4_flex;
+
+
+// STD Dependencies -----------------------------------------------------------
+use std::io::Error;
+use std::marker::PhantomData;
+
+
+// External Dependencies ------------------------------------------------------
+use self::lz4_flex::block;
+
+
+// Internal Dependencies ------------------------------------------------------
+use super::compression::{
+    decode_payload, encode_payload, NoDictionary, COMPRESSION_OVERHEAD
+};
+use ::{CompressionDictionary, Config, PacketContext, PacketModifier};
+
+
+/// Implementation of a packet modifier which compresses payloads with LZ4.
+///
+/// Payloads which do not shrink are send uncompressed. An optional preset
+/// dictionary can be provided via a `CompressionDictionary` implementation.
+///
+/// Requires the `lz4` feature.
+#[derive(Debug)]
+pub struct Lz4PacketModifier<D: CompressionDictionary = NoDictionary> {
+    buffer: Vec<u8>,
+    dictionary: PhantomData<D>
+}
+
+impl<D: CompressionDictionary> PacketModifier for Lz4PacketModifier<D> {
+
+    fn new(config: Config) -> Lz4PacketModifier<D> {
+        Lz4PacketModifier {
+            buffer: vec![0; config.packet_max_size],
+            dictionary: PhantomData
+        }
+    }
+
+    fn max_overhead(&self) -> usize {
+        COMPRESSION_OVERHEAD
+    }
+
+    fn outgoing_packet(&mut self, payload: &[u8], _: &PacketContext) -> Option<Vec<u8>> {
+        encode_payload(payload, Some(block::compress_with_dict(payload, D::dictionary())))
+    }
+
+    fn incoming_packet(
+        &mut self,
+        payload: &[u8],
+        _: &PacketContext
+
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let buffer = &mut self.buffer;
+        decode_payload(payload, |data| {
+            block::decompress_into_with_dict(data, buffer, D::dictionary()).ok().map(|len| {
+                buffer[..len].to_vec()
+            })
+        })
+    }
+
+}
@@ -8,18 +8,120 @@
 
 // STD Dependencies -----------------------------------------------------------
 use std::cmp;
-use std::collections::{BinaryHeap, HashSet, VecDeque};
+use std::mem;
+use std::io::{Error, ErrorKind};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
 
 
 // Internal Dependencies ------------------------------------------------------
-use ::Config;
+use ::{Config, PacketProducer};
 
 /// Maximum message ordering id before wrap around happens.
 const MAX_ORDER_ID: u16 = 4096;
 
+/// Maximum number of order ids by which a message of the kind
+/// `MessageKind::Ordered` can be ahead of the oldest message on its channel
+/// which has not yet been received.
+///
+/// Senders never put messages beyond this window in flight and receivers
+/// drop any message outside of it, which limits the number of messages
+/// buffered per channel and keeps order ids well clear of wrapping around.
+const ORDERED_WINDOW: u16 = MAX_ORDER_ID / 4;
+
+/// Maximum number of data bytes of the messages within the window of each
+/// channel of the kind `MessageKind::Ordered`.
+///
+/// Senders only put further messages in flight as long as the messages
+/// from the oldest unacknowledged one onwards fit into this limit, which is
+/// also the most a receiver buffers per channel for messages received out of
+/// order.
+const ORDERED_WINDOW_BYTES: usize = 4 * MESSAGE_MAX_BYTES;
+
 /// Number of bytes used in a single message header.
 const MESSAGE_HEADER_BYTES: usize = 4;
 
+/// Number of additional header bytes used by messages of the kinds
+/// `MessageKind::Ordered` and `MessageKind::Sequenced` to identify their
+/// channel or stream.
+const CHANNEL_HEADER_BYTES: usize = 1;
+
+/// Number of independent streams for messages of the kind
+/// `MessageKind::Sequenced`.
+const SEQUENCED_STREAMS: usize = 256;
+
+/// Number of independent channels for messages of the kind
+/// `MessageKind::Ordered`.
+pub const ORDERED_CHANNELS: usize = 8;
+
+/// Number of message channels of a queue, one for each of
+/// `MessageKind::Instant` and `MessageKind::Reliable` followed by the ordered
+/// channels.
+pub const MESSAGE_CHANNELS: usize = 2 + ORDERED_CHANNELS;
+
+/// Maximum number of data bytes which can be carried by a single message,
+/// regardless of whether it gets split into fragments.
+const MESSAGE_MAX_BYTES: usize = 65535;
+
+/// Bit set in the kind nibble of a message header for fragments of a larger
+/// message.
+const FRAGMENT_FLAG: u8 = 0x08;
+
+/// Bit set in the kind nibble of a message header for placeholders of expired
+/// or cancelled messages of the kind `MessageKind::Ordered`.
+///
+/// Placeholders of the kind `MessageKind::Reliable` are encoded as empty
+/// messages with the bit set as well, the RPC layer never sends empty
+/// messages.
+const EXPIRED_FLAG: u8 = 0x04;
+
+/// Bit set in the kind nibble of a message header for messages of the kind
+/// `MessageKind::Reliable` which carry requests and responses of the RPC
+/// layer.
+///
+/// Shares its bit with `EXPIRED_FLAG`, so the flags which are valid for each
+/// kind are defined in a single place by `valid_flags()`.
+const RPC_FLAG: u8 = 0x04;
+
+/// Number of bytes used by the fragment header that prefixes the data of each
+/// fragment.
+const FRAGMENT_HEADER_BYTES: usize = 6;
+
+/// Number of recently reassembled fragment groups which are remembered in
+/// order to ignore late duplicates of their fragments.
+const FRAGMENT_HISTORY: usize = 256;
+
+/// Maximum number of partially received messages which are buffered at any
+/// one time, the one which made no progress for the longest time gets
+/// dropped to make room for further messages.
+const FRAGMENT_BUFFERS_MAX: usize = 64;
+
+/// Maximum number of bytes buffered for partially received messages,
+/// counting the data of each fragment along with its headers.
+const FRAGMENT_BUFFERS_MAX_BYTES: usize = 16 * MESSAGE_MAX_BYTES;
+
+/// Multiple of `Config::packet_drop_threshold` after which partially
+/// received messages which have not received any further fragments are
+/// dropped.
+const FRAGMENT_TIMEOUT_FACTOR: u32 = 8;
+
+/// Maximum number of ids by which a message of the kind
+/// `MessageKind::Reliable` can be ahead of the oldest reliable message which
+/// has not yet been acknowledged.
+///
+/// Senders never put messages beyond this window in flight, so receivers
+/// can tell new messages from duplicates by remembering the ids within this
+/// window behind the most recent id they received. Both windows together
+/// must not exceed `MAX_ORDER_ID`.
+const RELIABLE_WINDOW: u16 = MAX_ORDER_ID / 4;
+
+/// Identifier of a message send via a `MessageQueue`.
+///
+/// Ids are only used locally in order to report the delivery or loss of
+/// individual messages and are never send over the network.
+#[derive(Debug, PartialEq, Eq, Hash, Copy, Clone, Ord, PartialOrd)]
+pub struct MessageID(pub u32);
+
 /// Enum for specification of a message handling algorithm.
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum MessageKind {
@@ -31,6 +133,19 @@ pub enum MessageKind {
     /// Message that is going to be re-send in case its containing packet is
     /// lost. No guarantees are made as for the order in which a message of
     /// this kind is going to be received by a remote queue.
+    ///
+    /// Messages which do not fit into a single packet are split into
+    /// fragments, each of them being re-send individually, and are reassembled
+    /// by the remote queue. Messages are limited to `65535` bytes and the
+    /// remote queue only buffers a limited number of partially received
+    /// messages.
+    ///
+    /// Each message carries an id which the remote queue uses to drop
+    /// duplicates, so a message which gets re-send because its packet was
+    /// merely delayed is still only received once. At most `1024` messages
+    /// are in flight at any time, further messages stay queued until the
+    /// oldest ones have been acknowledged. Expired or cancelled messages are
+    /// send as empty placeholders, so their ids never leave a gap.
     Reliable = 1,
 
     /// Message that is going to be re-send in case its containing packet is
@@ -39,31 +154,133 @@ pub enum MessageKind {
     /// , the remote queue will buffer the second message until the first one
     /// arrives and then make both of them available to the application at
     /// once.
-    Ordered = 2
+    ///
+    /// Ordering is only guaranteed within each of the `ORDERED_CHANNELS`
+    /// channels, so a lost message only holds back later messages on its own
+    /// channel. `MessageQueue::send` uses the first channel.
+    ///
+    /// At most `1024` messages and `262140` bytes per channel are in flight
+    /// at any time, further messages stay queued until the oldest ones have
+    /// been acknowledged.
+    ///
+    /// Just like `Reliable` messages, messages of this kind are fragmented in
+    /// case they do not fit into a single packet.
+    Ordered = 2,
+
+    /// Message that is going be send exactly once and ignored in case its
+    /// containing packet is lost, just like an `Instant` message.
+    ///
+    /// Messages of this kind carry a sequence number though, and the remote
+    /// queue drops any message which is older than the most recent one it
+    /// has already received on the same stream. This makes them suited for
+    /// continuous state updates where stale data is of no further use.
+    /// `MessageQueue::send` uses the first of the `256` streams.
+    Sequenced = 3
+}
+
+impl MessageKind {
+    fn deadline(self, config: &Config) -> Option<Instant> {
+        config.message_ttl[self as usize].map(|ttl| Instant::now() + ttl)
+    }
 }
 
-/// Structure for handling messages inside a `MessageQueue` with support for
-/// insertion into a binary min heap for order checking on received messages.
-#[derive(Debug, Eq, PartialEq)]
+/// Structure for handling messages inside a `MessageQueue`.
+#[derive(Debug)]
 struct Message {
+    id: MessageID,
     kind: MessageKind,
+    channel: u8,
     order: u16,
-    size: u16,
+    fragment: bool,
+    time: Instant,
+    expires: Option<Instant>,
+    expired: bool,
+    rpc: bool,
+    key: Option<u32>,
     data: Vec<u8>
 }
 
-impl Ord for Message {
-    // Explicitly implement the trait so the queue becomes a min-heap
-    // instead of a max-heap.
-    fn cmp(&self, other: &Self) -> cmp::Ordering {
-        other.order.cmp(&self.order)
-    }
+/// Structure for collecting the fragments of a single message until all of
+/// them have been received.
+#[derive(Debug)]
+struct FragmentBuffer {
+    kind: MessageKind,
+    channel: u8,
+    order: u16,
+    rpc: bool,
+    count: usize,
+    size: Option<usize>,
+    bytes: usize,
+    time: Instant,
+    fragments: HashMap<usize, Vec<u8>>
+}
+
+/// Send and receive state of a single channel for messages of the kind
+/// `MessageKind::Ordered`.
+#[derive(Debug)]
+struct OrderedChannel {
+
+    /// The local order id which gets attached to all messages send on the
+    /// channel
+    local_order_id: u16,
+
+    /// The remote order id that is expected for the next incoming message on
+    /// the channel
+    remote_order_id: u16,
+
+    /// Queue of outgoing messages
+    queue: VecDeque<Message>,
+
+    /// Ids, order ids and data bytes of all messages send on the channel
+    /// from the oldest one which has not yet been acknowledged onwards
+    unacked: VecDeque<(MessageID, u16, usize)>,
+
+    /// Incoming messages which were received out of order, by their order id
+    recv_buffer: HashMap<u16, Message>,
+
+    /// Number of data bytes buffered in `recv_buffer`
+    recv_bytes: usize
+
 }
 
-impl PartialOrd for Message {
-    fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
-        Some(self.cmp(other))
+impl OrderedChannel {
+
+    fn new() -> OrderedChannel {
+        OrderedChannel {
+            local_order_id: 0,
+            remote_order_id: 0,
+            queue: VecDeque::new(),
+            unacked: VecDeque::new(),
+            recv_buffer: HashMap::new(),
+            recv_bytes: 0
+        }
+    }
+
+    fn reset(&mut self) {
+        self.local_order_id = 0;
+        self.remote_order_id = 0;
+        self.queue.clear();
+        self.unacked.clear();
+        self.recv_buffer.clear();
+        self.recv_bytes = 0;
+    }
+
+    /// Returns the oldest order id which has not yet been acknowledged.
+    fn window(&self) -> Option<(u16, u16)> {
+
+        // Limit the window to the messages whose data fits into the remote
+        // buffer, the oldest message is always within the window though
+        let mut bytes = 0;
+        let size = self.unacked.iter().take(ORDERED_WINDOW as usize).position(|&(_, _, b)| {
+            bytes += b;
+            bytes > ORDERED_WINDOW_BYTES
+
+        }).map_or(ORDERED_WINDOW, |size| cmp::max(size, 1) as u16);
+
+        self.unacked.front().map(|&(_, order, _)| (order, size))
+
     }
+
 }
 
 /// Consuming iterator over the received messages of a `MessageQueue`.
@@ -94,13 +311,20 @@ pub struct MessageQueue {
     /// The queue's configuration
     config: Config,
 
-    /// The local order id which gets attached to all messages send as kind
-    /// `MessageKind::Ordered`
-    local_order_id: u16,
+    /// The id which gets assigned to the next message pushed into the queue
+    local_message_id: u32,
 
-    /// The remote order id that is expected for the next incoming message of
-    /// kind `MessageKind::Ordered`
-    remote_order_id: u16,
+    /// Number of fragments which have yet to be acknowledged for each
+    /// fragmented message
+    unacked_fragments: HashMap<MessageID, usize>,
+
+    /// The time at which each reliable or ordered message was queued and
+    /// whether it has been send at least once, until it gets acknowledged
+    unacked_reliable: HashMap<MessageID, (Instant, bool)>,
+
+    /// The local id which gets attached to all fragments of the next message
+    /// that needs to be split up
+    local_fragment_id: u16,
 
     /// Queue of outgoing messages of the kind `MessageKind::Instant`
     i_queue: VecDeque<Message>,
@@ -108,17 +332,72 @@ pub struct MessageQueue {
     /// Queue of outgoing messages of the kind `MessageKind::Reliable`
     r_queue: VecDeque<Message>,
 
-    /// Queue of outgoing messages of the kind `MessageKind::Ordered`
-    o_queue: VecDeque<Message>,
+    /// The local id which gets attached to the next message of the kind
+    /// `MessageKind::Reliable`
+    r_local_id: u16,
+
+    /// Ids and local ids of all messages of the kind `MessageKind::Reliable`
+    /// which have not yet been acknowledged, oldest first
+    r_unacked: VecDeque<(MessageID, u16)>,
+
+    /// The most recent remote id received for a message of the kind
+    /// `MessageKind::Reliable`
+    r_remote_id: u16,
+
+    /// Whether the message with each remote id has been received, only valid
+    /// for ids within `RELIABLE_WINDOW` behind `r_remote_id`
+    r_received: Vec<bool>,
+
+    /// Channels for messages of the kind `MessageKind::Ordered`
+    o_channels: Vec<OrderedChannel>,
+
+    /// The local sequence ids which get attached to the next message send on
+    /// each stream of the kind `MessageKind::Sequenced`
+    s_local_ids: Vec<u16>,
+
+    /// The most recent remote sequence ids received on each stream of the
+    /// kind `MessageKind::Sequenced`
+    s_remote_ids: Vec<Option<u16>>,
 
     /// Ordered queue of incoming messages
     recv_queue: VecDeque<Message>,
 
-    /// Binary Min-Heap to manage incomging, out of order messages
-    o_recv_heap: BinaryHeap<Message>,
+    /// Queue of incoming messages of the RPC layer
+    rpc_recv_queue: VecDeque<Vec<u8>>,
+
+    /// Incoming fragments of messages which have not been fully received yet
+    fragment_buffers: HashMap<u16, FragmentBuffer>,
+
+    /// Number of bytes buffered across all `fragment_buffers`
+    fragment_bytes: usize,
+
+    /// Ids of the most recently reassembled messages
+    fragment_history: VecDeque<u16>,
+
+    /// Ids of the messages which expired before they could be send
+    expired: Vec<MessageID>,
 
-    /// Set for avoiding duplication of out of order messages
-    o_recv_set: HashSet<u16>
+    /// Ids of expired messages of the kind `MessageKind::Ordered` which are
+    /// still send as placeholders
+    placeholders: HashSet<MessageID>,
+
+    /// Ids of cancelled messages which have been send but not yet
+    /// acknowledged, these are no longer re-send in case they get lost
+    cancelled: HashSet<MessageID>,
+
+    /// Number of messages - and fragments - waiting in the send queues
+    queued_messages: usize,
+
+    /// Number of data bytes waiting in the send queues
+    queued_bytes: usize,
+
+    /// Whether a message has been rejected since the send queues last
+    /// drained below their low watermark
+    rejected: bool,
+
+    /// Whether the send queues drained below their low watermark since the
+    /// last call to `MessageQueue::drained()`
+    drained: bool
 
 }
 
@@ -128,14 +407,31 @@ impl MessageQueue {
     pub fn new(config: Config) -> MessageQueue {
         MessageQueue {
             config: config,
-            local_order_id: 0,
-            remote_order_id: 0,
+            local_message_id: 0,
+            unacked_fragments: HashMap::new(),
+            unacked_reliable: HashMap::new(),
+            local_fragment_id: 0,
             i_queue: VecDeque::new(),
             r_queue: VecDeque::new(),
-            o_queue: VecDeque::new(),
+            r_local_id: 0,
+            r_unacked: VecDeque::new(),
+            r_remote_id: MAX_ORDER_ID - 1,
+            r_received: vec![true; MAX_ORDER_ID as usize],
+            o_channels: (0..ORDERED_CHANNELS).map(|_| OrderedChannel::new()).collect(),
+            s_local_ids: vec![0; SEQUENCED_STREAMS],
+            s_remote_ids: vec![None; SEQUENCED_STREAMS],
             recv_queue: VecDeque::new(),
-            o_recv_heap: BinaryHeap::new(),
-            o_recv_set: HashSet::new()
+            rpc_recv_queue: VecDeque::new(),
+            fragment_buffers: HashMap::new(),
+            fragment_bytes: 0,
+            fragment_history: VecDeque::new(),
+            expired: Vec::new(),
+            placeholders: HashSet::new(),
+            cancelled: HashSet::new(),
+            queued_messages: 0,
+            queued_bytes: 0,
+            rejected: false,
+            drained: false
         }
     }
 
@@ -149,50 +445,427 @@ impl MessageQueue {
         MessageIterator { messages: &mut self.recv_queue }
     }
 
+    /// Returns the data of all received messages of the RPC layer, see
+    /// `MessageQueue::send_rpc()`.
+    pub fn received_rpc(&mut self) -> Vec<Vec<u8>> {
+        self.rpc_recv_queue.drain(..).collect()
+    }
+
     /// Clears the queue of received messages, dismissing any messages which
     /// have not been fetched via `MessageQueue::received()`.
     pub fn dismiss(&mut self) {
         self.recv_queue.clear();
     }
 
+    /// Returns the ids of all messages which expired since the last call.
+    ///
+    /// Expired messages are removed from the queue by
+    /// `MessageQueue::send_packet()` and never reach the remote queue.
+    pub fn expired(&mut self) -> Vec<MessageID> {
+        self.expired.drain(..).collect()
+    }
+
+    /// Returns the number of queued messages of the specified `kind` which
+    /// are waiting to be send.
+    ///
+    /// Fragments of messages which have already been split up count
+    /// individually.
+    pub fn queued_messages(&self, kind: MessageKind) -> usize {
+        match kind {
+            MessageKind::Instant | MessageKind::Sequenced => {
+                self.i_queue.iter().filter(|m| m.kind == kind).count()
+            },
+            MessageKind::Reliable => self.r_queue.len(),
+            MessageKind::Ordered => self.o_channels.iter().map(|c| {
+                c.queue.iter().filter(|m| !m.expired).count()
+
+            }).sum()
+        }
+    }
+
+    /// Returns the number of data bytes of all queued messages which are
+    /// waiting to be send.
+    pub fn queued_bytes(&self) -> usize {
+        self.queued_bytes
+    }
+
+    /// Returns the number of bytes buffered for messages which have only
+    /// been partially received, counting the data of each fragment along
+    /// with its headers.
+    ///
+    /// This is limited to `1048560` bytes across at most `64` messages.
+    /// Messages which received no further fragments for eight times the
+    /// `packet_drop_threshold` are dropped.
+    pub fn buffered_fragment_bytes(&self) -> usize {
+        self.fragment_bytes
+    }
+
+    /// Returns the number of data bytes buffered for messages of the kind
+    /// `MessageKind::Ordered` which were received out of order.
+    ///
+    /// This is limited to `262140` bytes per channel.
+    pub fn buffered_ordered_bytes(&self) -> usize {
+        self.o_channels.iter().map(|c| c.recv_bytes).sum()
+    }
+
+    /// Returns the time the oldest queued message has been waiting for.
+    ///
+    /// Messages which are re-send count from the time they were originally
+    /// queued at.
+    pub fn oldest_queued_age(&self) -> Option<Duration> {
+        self.i_queue.iter().chain(self.r_queue.iter()).chain(
+            self.o_channels.iter().flat_map(|c| c.queue.iter())
+
+        ).filter(|m| !m.expired).map(|m| m.time).min().map(|t| t.elapsed())
+    }
+
+    /// Returns the number of messages of the kinds `MessageKind::Reliable`
+    /// and `MessageKind::Ordered` which have been send but not yet been
+    /// acknowledged by the remote queue.
+    pub fn unacked_reliable_messages(&self) -> usize {
+        self.unacked_reliable.values().filter(|&&(_, sent)| sent).count()
+    }
+
+    /// Returns whether the send queues drained below their low watermark
+    /// after a message was rejected because they were full.
+    ///
+    /// Returns `true` only once for each time the queues filled up.
+    pub fn drained(&mut self) -> bool {
+        mem::replace(&mut self.drained, false)
+    }
+
+    /// Sets the `deadline` of the queued message with the given `id`,
+    /// overriding the default time to live of its kind.
+    ///
+    /// Messages which are still queued once their deadline has passed are
+    /// dropped instead of being send. Messages which have already been split
+    /// into fragments can no longer expire.
+    ///
+    /// Returns `false` in case no such message is currently queued.
+    pub fn set_message_deadline(&mut self, id: MessageID, deadline: Instant) -> bool {
+
+        let message = self.i_queue.iter_mut().chain(self.r_queue.iter_mut()).chain(
+            self.o_channels.iter_mut().flat_map(|c| c.queue.iter_mut())
+
+        ).find(|m| m.id == id && !m.fragment && !m.expired);
+
+        if let Some(message) = message {
+            message.expires = Some(deadline);
+            true
+
+        } else {
+            false
+        }
+
+    }
+
+    /// Cancels the message with the given `id`.
+    ///
+    /// Queued messages are withdrawn before they are written into a packet.
+    /// Messages of the kinds `MessageKind::Reliable` and
+    /// `MessageKind::Ordered` are replaced with empty placeholders instead,
+    /// so the remote queue does not wait for their ids.
+    ///
+    /// Reliable and ordered messages which have already been send but not yet
+    /// acknowledged are no longer re-send; should their packet get lost, they
+    /// are reported as lost by `MessageQueue::lost_packet()` and replaced
+    /// with placeholders as well.
+    ///
+    /// Returns `false` in case the message can no longer be cancelled, e.g.
+    /// because it was already delivered, cancelled, or split into fragments.
+    pub fn cancel(&mut self, id: MessageID) -> bool {
+
+        if self.unacked_fragments.contains_key(&id) {
+            return false;
+        }
+
+        let cancelled = if let Some(index) = self.i_queue.iter().position(|m| m.id == id && !m.fragment) {
+            self.i_queue.remove(index);
+            true
+
+        } else if let Some(m) = self.r_queue.iter_mut().chain(
+            self.o_channels.iter_mut().flat_map(|c| c.queue.iter_mut())
+
+        ).find(|m| m.id == id && !m.fragment && !m.expired) {
+            // The order id has already been assigned
+            replace_with_placeholder(m, &mut self.placeholders);
+            true
+
+        } else if self.unacked_reliable.get(&id).is_some_and(|&(_, sent)| sent) {
+            return self.cancelled.insert(id);
+
+        } else {
+            false
+        };
+
+        self.update_queued();
+        cancelled
+
+    }
+
     /// Pushes a message of the specified `kind` along with its `data` into the
     /// queue. The message will eventually get serialized via
     /// `MessageQueue::send_packet()`.
-    pub fn send(&mut self, kind: MessageKind, data: Vec<u8>) {
+    ///
+    /// Messages of the kind `MessageKind::Ordered` are send on the first
+    /// ordered channel and messages of the kind `MessageKind::Sequenced` on
+    /// the first sequenced stream.
+    ///
+    /// Returns the id of the message.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of the kind `ErrorKind::InvalidInput` in case the
+    /// `data` exceeds `65535` bytes and an error of the kind
+    /// `ErrorKind::WouldBlock` in case the message would exceed the
+    /// `send_queue_max_bytes` or `send_queue_max_messages` limits of the
+    /// queue's configuration.
+    pub fn send(&mut self, kind: MessageKind, data: Vec<u8>) -> Result<MessageID, Error> {
+        match kind {
+            MessageKind::Ordered => self.send_ordered(0, data),
+            MessageKind::Sequenced => self.send_sequenced(0, data),
+            MessageKind::Instant => {
+                self.reserve(&data)?;
+                let message = Message {
+                    id: self.next_message_id(),
+                    kind: kind,
+                    channel: 0,
+                    order: 0,
+                    fragment: false,
+                    time: Instant::now(),
+                    expires: kind.deadline(&self.config),
+                    expired: false,
+                    rpc: false,
+                    key: None,
+                    data: data
+                };
+                let id = message.id;
+                self.i_queue.push_back(message);
+                Ok(id)
+            },
+            MessageKind::Reliable => self.send_reliable(data, false)
+        }
+    }
 
-        let message = Message {
-            kind: kind,
-            order: self.local_order_id,
-            size: data.len() as u16,
-            data: data
-        };
+    /// Pushes a message of the kind `MessageKind::Reliable` which carries
+    /// `data` of the RPC layer into the queue.
+    ///
+    /// The remote queue makes these messages available via
+    /// `MessageQueue::received_rpc()` instead of `MessageQueue::received()`.
+    /// They never expire.
+    ///
+    /// Returns the id of the message.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of the kind `ErrorKind::InvalidInput` in case the
+    /// `data` is empty - which is reserved for placeholders - or exceeds
+    /// `65535` bytes and an error of the kind `ErrorKind::WouldBlock` in case
+    /// the send queues are full.
+    pub fn send_rpc(&mut self, data: Vec<u8>) -> Result<MessageID, Error> {
+        if data.is_empty() {
+            Err(Error::new(ErrorKind::InvalidInput, "RPC messages cannot be empty."))
 
-        match kind {
-            MessageKind::Instant => self.i_queue.push_back(message),
-            MessageKind::Reliable => self.r_queue.push_back(message),
+        } else {
+            self.send_reliable(data, true)
+        }
+    }
+
+    /// Pushes a message of the specified `kind` along with its `data` into the
+    /// queue, replacing any older message of the same kind and `key` which
+    /// is still waiting to be send.
+    ///
+    /// The replacing message takes over the position of the replaced one in
+    /// the queue, the id of the replaced message can be retrieved via
+    /// `MessageQueue::expired()`. Messages which have already been split into
+    /// fragments or re-queued after their packet was lost are never replaced.
+    ///
+    /// Returns the id of the message.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of the kind `ErrorKind::InvalidInput` for messages of
+    /// the kind `MessageKind::Ordered` or in case the `data` exceeds `65535`
+    /// bytes and an error of the kind `ErrorKind::WouldBlock` in case the
+    /// send queues are full.
+    pub fn send_keyed(&mut self, kind: MessageKind, key: u32, data: Vec<u8>) -> Result<MessageID, Error> {
+
+        let index = match kind {
             MessageKind::Ordered => {
+                return Err(Error::new(ErrorKind::InvalidInput, "Ordered messages cannot be replaced."));
+            },
+            MessageKind::Reliable => self.r_queue.iter(),
+            MessageKind::Instant | MessageKind::Sequenced => self.i_queue.iter()
+
+        }.position(|m| m.kind == kind && m.key == Some(key));
+
+        if let Some(index) = index {
+
+            if data.len() > MESSAGE_MAX_BYTES {
+                return Err(Error::new(ErrorKind::InvalidInput, "Message exceeds the maximum message size."));
+            }
+
+            let replaced = if kind == MessageKind::Reliable {
+                &self.r_queue[index]
+
+            } else {
+                &self.i_queue[index]
+            };
+
+            let queued_bytes = self.queued_bytes - replaced.data.len() + data.len();
+            if queued_bytes > self.config.send_queue_max_bytes {
+                self.rejected = true;
+                return Err(Error::new(ErrorKind::WouldBlock, "Message send queue is full."));
+            }
+
+            let id = self.next_message_id();
+            let time = Instant::now();
+
+            // Keep the order or sequence id of the replaced message
+            let m = if kind == MessageKind::Reliable {
+                &mut self.r_queue[index]
+
+            } else {
+                &mut self.i_queue[index]
+            };
 
-                self.o_queue.push_back(message);
-                self.local_order_id += 1;
+            // The replaced message will never be send
+            self.expired.push(m.id);
 
-                if self.local_order_id == MAX_ORDER_ID {
-                    self.local_order_id = 0;
+            if self.unacked_reliable.remove(&m.id).is_some() {
+                self.unacked_reliable.insert(id, (time, false));
+                if let Some(entry) = self.r_unacked.iter_mut().find(|&&mut (other, _)| other == m.id) {
+                    entry.0 = id;
                 }
+            }
+
+            m.id = id;
+            m.time = time;
+            m.expires = kind.deadline(&self.config);
+            m.data = data;
+            self.queued_bytes = queued_bytes;
+            Ok(id)
 
+        } else {
+            let id = self.send(kind, data)?;
+            let queue = if kind == MessageKind::Reliable {
+                &mut self.r_queue
+
+            } else {
+                &mut self.i_queue
+            };
+
+            if let Some(m) = queue.back_mut() {
+                m.key = Some(key);
             }
+
+            Ok(id)
+        }
+
+    }
+
+    /// Pushes a message of the kind `MessageKind::Ordered` along with its
+    /// `data` into the specified ordered `channel` of the queue.
+    ///
+    /// Messages are only guaranteed to arrive in order with respect to other
+    /// messages on the same channel.
+    ///
+    /// Returns the id of the message.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of the kind `ErrorKind::InvalidInput` in case the
+    /// `data` exceeds `65535` bytes and an error of the kind
+    /// `ErrorKind::WouldBlock` in case the send queues are full.
+    ///
+    /// # Panics
+    ///
+    /// If `channel` is not smaller than `ORDERED_CHANNELS`.
+    pub fn send_ordered(&mut self, channel: u8, data: Vec<u8>) -> Result<MessageID, Error> {
+
+        assert!((channel as usize) < ORDERED_CHANNELS, "Invalid ordered channel.");
+        self.reserve(&data)?;
+
+        let id = self.next_message_id();
+        let time = Instant::now();
+        self.unacked_reliable.insert(id, (time, false));
+
+        let o_channel = &mut self.o_channels[channel as usize];
+        o_channel.unacked.push_back((id, o_channel.local_order_id, data.len()));
+        o_channel.queue.push_back(Message {
+            id,
+            kind: MessageKind::Ordered,
+            channel,
+            order: o_channel.local_order_id,
+            fragment: false,
+            time,
+            expires: MessageKind::Ordered.deadline(&self.config),
+            expired: false,
+            rpc: false,
+            key: None,
+            data
+        });
+
+        o_channel.local_order_id += 1;
+        if o_channel.local_order_id == MAX_ORDER_ID {
+            o_channel.local_order_id = 0;
+        }
+
+        Ok(id)
+
+    }
+
+    /// Pushes a message of the kind `MessageKind::Sequenced` along with its
+    /// `data` into the specified sequenced `stream` of the queue.
+    ///
+    /// The remote queue drops messages which are older than the most recent
+    /// message it has received on the same stream.
+    ///
+    /// Returns the id of the message.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of the kind `ErrorKind::InvalidInput` in case the
+    /// `data` exceeds `65535` bytes and an error of the kind
+    /// `ErrorKind::WouldBlock` in case the send queues are full.
+    pub fn send_sequenced(&mut self, stream: u8, data: Vec<u8>) -> Result<MessageID, Error> {
+
+        self.reserve(&data)?;
+
+        let id = self.next_message_id();
+        let sequence = &mut self.s_local_ids[stream as usize];
+        self.i_queue.push_back(Message {
+            id,
+            kind: MessageKind::Sequenced,
+            channel: stream,
+            order: *sequence,
+            fragment: false,
+            time: Instant::now(),
+            expires: MessageKind::Sequenced.deadline(&self.config),
+            expired: false,
+            rpc: false,
+            key: None,
+            data
+        });
+
+        *sequence += 1;
+        if *sequence == MAX_ORDER_ID {
+            *sequence = 0;
         }
 
+        Ok(id)
+
     }
 
     /// Serializes a number of internally queued messages into the
     /// `available` space within the `packet`.
     ///
     /// The used algorithm first tries to fill the available space with the
-    /// desired quotas for each `MessageKind` as defined in the queues
+    /// desired quotas for each message channel as defined in the queues
     /// configuration.
     ///
     /// Afterwards the remaining available space is filled by alternating
-    /// between the different message kinds until there is finally no more
+    /// between the different message channels until there is finally no more
     /// space left to insert any further messages into the packet.
     ///
     /// For example, if we have `512` bytes available inside the packer and we
@@ -203,141 +876,618 @@ impl MessageQueue {
     /// Then, after the other quotas have been taken into account, we'll try to
     /// fit more instant messages into the remaining available space within the
     /// packet.
-    pub fn send_packet(&mut self, packet: &mut Vec<u8>, available: usize) {
+    ///
+    /// Reliable and ordered messages which exceed the `available` space are
+    /// split into fragments beforehand, while instant and sequenced messages
+    /// which could never fit into a packet are dropped and can be retrieved
+    /// via `MessageQueue::expired()` as well.
+    ///
+    /// Messages whose deadline has passed are dropped before any of this
+    /// happens and can be retrieved via `MessageQueue::expired()`. Expired
+    /// messages of the kinds `MessageKind::Reliable` and
+    /// `MessageKind::Ordered` are still send as empty placeholders, so the
+    /// remote queue does not wait for them forever.
+    ///
+    /// Returns the ids of all messages which were written into the packet, in
+    /// the order they were written in.
+    pub fn send_packet(&mut self, packet: &mut Vec<u8>, available: usize) -> Vec<MessageID> {
+
+        // Drop stale messages before they take up any space
+        let now = Instant::now();
+        expire_messages(&mut self.i_queue, now, &mut self.expired, &mut self.placeholders);
+        expire_messages(&mut self.r_queue, now, &mut self.expired, &mut self.placeholders);
+        for channel in &mut self.o_channels {
+            expire_messages(&mut channel.queue, now, &mut self.expired, &mut self.placeholders);
+        }
+
+        // Placeholders still need to be acknowledged
+        for id in &self.expired {
+            if !self.placeholders.contains(id) {
+                self.unacked_reliable.remove(id);
+            }
+        }
+
+        let r_window = self.r_unacked.front().map(|&(_, order)| (order, RELIABLE_WINDOW));
+
+        // Prevent oversized messages from blocking their queues, instant and
+        // sequenced ones can never be send and are reported as expired
+        let expired = &mut self.expired;
+        self.i_queue.retain(|m| if is_oversized(m, available) {
+            expired.push(m.id);
+            false
+
+        } else {
+            true
+        });
+        fragment_messages(
+            &mut self.r_queue, available,
+            &mut self.local_fragment_id, &mut self.unacked_fragments
+        );
+
+        for channel in &mut self.o_channels {
+            fragment_messages(
+                &mut channel.queue, available,
+                &mut self.local_fragment_id, &mut self.unacked_fragments
+            );
+        }
 
         // First we are trying to fill the packet by using the set quotas
+        let quotas = self.config.message_quotas;
         let mut written = 0;
+        let mut ids = Vec::new();
         write_messages(
-            &mut self.i_queue, packet,
-            (available as f32 / 100.0 * self.config.message_quota_instant) as usize,
-            &mut written
+            &mut self.i_queue, None, packet,
+            (available as f32 / 100.0 * quotas[0]) as usize,
+            &mut written, &mut ids
         );
 
         write_messages(
-            &mut self.r_queue, packet,
-            (available as f32 / 100.0 * self.config.message_quota_reliable) as usize,
-            &mut written
+            &mut self.r_queue, r_window, packet,
+            (available as f32 / 100.0 * quotas[1]) as usize,
+            &mut written, &mut ids
         );
 
-        write_messages(
-            &mut self.o_queue, packet,
-            (available as f32 / 100.0 * self.config.message_quota_ordered) as usize,
-            &mut written
-        );
+        for (channel, quota) in self.o_channels.iter_mut().zip(&quotas[2..]) {
+            let window = channel.window();
+            write_messages(
+                &mut channel.queue, window, packet,
+                (available as f32 / 100.0 * quota) as usize,
+                &mut written, &mut ids
+            );
+        }
 
         // After that, we try to fill the remaining packet space by trying to
-        // add one message of each kind until no more messages can be fit in
+        // add one message of each channel until no more messages can be fit in
         let mut more = true;
         while more {
             more = false;
-            more |= write_message(&mut self.i_queue, packet, available, &mut written);
-            more |= write_message(&mut self.r_queue, packet, available, &mut written);
-            more |= write_message(&mut self.o_queue, packet, available, &mut written);
+            more |= write_message(&mut self.i_queue, None, packet, available, &mut written, &mut ids);
+            more |= write_message(&mut self.r_queue, r_window, packet, available, &mut written, &mut ids);
+            for channel in &mut self.o_channels {
+                let window = channel.window();
+                more |= write_message(&mut channel.queue, window, packet, available, &mut written, &mut ids);
+            }
         }
 
+        for id in &ids {
+            if let Some(&mut (_, ref mut sent)) = self.unacked_reliable.get_mut(id) {
+                *sent = true;
+            }
+        }
+
+        self.update_queued();
+        ids
+
+    }
+
+    /// Writes a message of the kind `MessageKind::Instant` with the data
+    /// obtained from `producer` into the `available` bytes which remain in a
+    /// packet after `MessageQueue::send_packet()`.
+    ///
+    /// The message is not assigned an id and must be the last one written
+    /// into the packet, so it is neither re-send nor reported once the
+    /// packet is acknowledged or lost.
+    ///
+    /// Returns whether any data was written into the packet.
+    pub fn produce_packet(
+        &mut self,
+        packet: &mut Vec<u8>,
+        available: usize,
+        producer: &mut dyn PacketProducer
+
+    ) -> bool {
+
+        if available <= MESSAGE_HEADER_BYTES {
+            return false;
+        }
+
+        let mut data = Vec::new();
+        producer.produce(available - MESSAGE_HEADER_BYTES, &mut data);
+        if data.is_empty() || data.len() > available - MESSAGE_HEADER_BYTES {
+            false
+
+        } else {
+            packet.push(MessageKind::Instant as u8);
+            packet.push(0);
+            packet.push((data.len() >> 8) as u8);
+            packet.push(data.len() as u8);
+            packet.append(&mut data);
+            true
+        }
+
+    }
+
+    /// Marks the messages with the given `ids` - as returned by
+    /// `MessageQueue::send_packet()` - as acknowledged by the remote queue.
+    ///
+    /// Returns the ids of all messages which have now been fully delivered,
+    /// fragmented messages are only delivered once all of their fragments have
+    /// been acknowledged.
+    pub fn acked_packet(&mut self, ids: &[MessageID]) -> Vec<MessageID> {
+
+        let mut delivered = Vec::new();
+        for id in ids {
+
+            let pending = if let Some(count) = self.unacked_fragments.get_mut(id) {
+                *count -= 1;
+                *count > 0
+
+            } else {
+                false
+            };
+
+            if !pending {
+                self.unacked_fragments.remove(id);
+                self.unacked_reliable.remove(id);
+                self.cancelled.remove(id);
+                if !self.placeholders.remove(id) {
+                    delivered.push(*id);
+                }
+            }
+
+        }
+
+        // Move the send windows of the ordered channels forward
+        let unacked = &self.unacked_reliable;
+        for channel in &mut self.o_channels {
+            while channel.unacked.front().is_some_and(|&(id, _, _)| !unacked.contains_key(&id)) {
+                channel.unacked.pop_front();
+            }
+        }
+
+        self.update_reliable_window();
+
+        delivered
+
     }
 
     /// Parses the contents of a packet into messages, appending all valid
     /// messages into the internal receive queue.
+    ///
+    /// Fragments are buffered until their message has been fully received.
     pub fn receive_packet(&mut self, packet: &[u8]) {
         for m in messages_from_packet(packet) {
+
+            let m = if m.fragment {
+                match self.receive_fragment(m) {
+                    Some(m) => m,
+                    None => continue
+                }
+
+            } else {
+                m
+            };
+
             match m.kind {
-                MessageKind::Instant | MessageKind::Reliable => {
-                    self.recv_queue.push_back(m);
-                },
-                MessageKind::Ordered => self.receive_ordered_message(m)
+                MessageKind::Instant => self.recv_queue.push_back(m),
+                MessageKind::Reliable => self.receive_reliable_message(m),
+                MessageKind::Ordered => self.receive_ordered_message(m),
+                MessageKind::Sequenced => self.receive_sequenced_message(m)
             }
+
         }
     }
 
     /// Parses the contents of a lost packet into messages, dropping all
-    /// messages of the type `MessageKind::Instant` and prepending all
-    /// remaining valid messages into the internal send queues for
-    /// re-transmission.
-    pub fn lost_packet(&mut self, packet: &[u8]) {
-        for m in messages_from_packet(packet) {
+    /// messages of the types `MessageKind::Instant` and
+    /// `MessageKind::Sequenced` and prepending all remaining valid messages
+    /// into the internal send queues for re-transmission.
+    ///
+    /// The `ids` of the messages are the ones returned by
+    /// `MessageQueue::send_packet()` when the packet was written.
+    ///
+    /// Cancelled messages are dropped as well and re-send as empty
+    /// placeholders instead.
+    ///
+    /// Returns the ids of all messages which were dropped.
+    pub fn lost_packet(&mut self, packet: &[u8], ids: &[MessageID]) -> Vec<MessageID> {
+
+        let mut lost = Vec::new();
+        for (mut m, id) in messages_from_packet(packet).into_iter().zip(ids) {
+
+            m.id = *id;
+
+            // Re-send messages keep the time they were originally queued at
+            if let Some(&(time, _)) = self.unacked_reliable.get(&m.id) {
+                m.time = time;
+            }
+
+            // Messages which were cancelled after they were send
+            if self.cancelled.remove(&m.id) {
+                lost.push(m.id);
+                replace_with_placeholder(&mut m, &mut self.placeholders);
+            }
+
             match m.kind {
-                MessageKind::Instant => {
-                    // ignore lost instant messages
-                },
+                MessageKind::Instant | MessageKind::Sequenced => lost.push(m.id),
                 MessageKind::Reliable => self.r_queue.push_front(m),
-                MessageKind::Ordered => self.o_queue.push_front(m)
+                MessageKind::Ordered => {
+                    self.o_channels[m.channel as usize].queue.push_front(m)
+                }
             }
+
         }
+
+        self.update_queued();
+        lost
+
     }
 
     /// Resets the queue, clearing all its internal structures and order ids.
     pub fn reset(&mut self) {
-        self.local_order_id = 0;
-        self.remote_order_id = 0;
+        self.local_message_id = 0;
+        self.unacked_fragments.clear();
+        self.unacked_reliable.clear();
         self.i_queue.clear();
         self.r_queue.clear();
-        self.o_queue.clear();
+        self.r_local_id = 0;
+        self.r_unacked.clear();
+        self.r_remote_id = MAX_ORDER_ID - 1;
+        for received in &mut self.r_received {
+            *received = true;
+        }
+        for channel in &mut self.o_channels {
+            channel.reset();
+        }
+        for sequence in &mut self.s_local_ids {
+            *sequence = 0;
+        }
+        for sequence in &mut self.s_remote_ids {
+            *sequence = None;
+        }
         self.recv_queue.clear();
-        self.o_recv_heap.clear();
-        self.o_recv_set.clear();
+        self.rpc_recv_queue.clear();
+        self.local_fragment_id = 0;
+        self.fragment_buffers.clear();
+        self.fragment_bytes = 0;
+        self.fragment_history.clear();
+        self.expired.clear();
+        self.placeholders.clear();
+        self.cancelled.clear();
+        self.queued_messages = 0;
+        self.queued_bytes = 0;
+        self.rejected = false;
+        self.drained = false;
     }
 
     // Internal Message Handling ----------------------------------------------
 
-    fn receive_ordered_message(&mut self, m: Message) {
+    fn next_message_id(&mut self) -> MessageID {
+        let id = MessageID(self.local_message_id);
+        self.local_message_id = self.local_message_id.wrapping_add(1);
+        id
+    }
 
-        // Check if the order ID matches the currently expected on
-        if m.order == self.remote_order_id {
+    fn update_reliable_window(&mut self) {
+        while self.r_unacked.front().is_some_and(|&(id, _)| !self.unacked_reliable.contains_key(&id)) {
+            self.r_unacked.pop_front();
+        }
+    }
 
-            // Received the message in order
-            self.recv_queue.push_back(m);
+    fn reserve(&mut self, data: &[u8]) -> Result<(), Error> {
+        if data.len() > MESSAGE_MAX_BYTES {
+            Err(Error::new(ErrorKind::InvalidInput, "Message exceeds the maximum message size."))
 
-            self.remote_order_id += 1;
-            if self.remote_order_id == MAX_ORDER_ID {
-                self.remote_order_id = 0;
-            }
+        } else if self.queued_messages >= self.config.send_queue_max_messages
+            || self.queued_bytes + data.len() > self.config.send_queue_max_bytes {
+            self.rejected = true;
+            Err(Error::new(ErrorKind::WouldBlock, "Message send queue is full."))
 
-            // Now check our heap for further messages we have received
-            // out of order and check if they are next in the expected
-            // order
-            let mut matches = true;
-            while matches {
+        } else {
+            self.queued_messages += 1;
+            self.queued_bytes += data.len();
+            Ok(())
+        }
+    }
 
-                // Check if the order id of the minimal item in the heap
-                // matches the expected next remote order id
-                matches = if let Some(msg) = self.o_recv_heap.peek() {
-                    msg.order == self.remote_order_id
+    fn update_queued(&mut self) {
 
-                } else {
-                    false
-                };
+        self.queued_messages = self.i_queue.len() + self.r_queue.len();
+        self.queued_bytes = self.i_queue.iter().chain(self.r_queue.iter()).map(|m| m.data.len()).sum();
+        for channel in &self.o_channels {
+            self.queued_messages += channel.queue.len();
+            self.queued_bytes += channel.queue.iter().map(|m| m.data.len()).sum::<usize>();
+        }
 
-                // We found another message, matching the next expected order id
-                if matches {
+        // Let producers know once there is room for more messages again
+        let watermark = self.config.send_queue_low_watermark;
+        if self.rejected
+            && self.queued_messages as f32 <= self.config.send_queue_max_messages as f32 * watermark
+            && self.queued_bytes as f32 <= self.config.send_queue_max_bytes as f32 * watermark {
+            self.rejected = false;
+            self.drained = true;
+        }
 
-                    // Unset duplication marker
-                    self.o_recv_set.remove(&self.remote_order_id);
+    }
 
-                    // Remove it from the heap and push it into the recv queue
-                    let msg = self.o_recv_heap.pop().unwrap();
-                    self.recv_queue.push_back(msg);
+    fn send_reliable(&mut self, data: Vec<u8>, rpc: bool) -> Result<MessageID, Error> {
+
+        self.reserve(&data)?;
+
+        let id = self.next_message_id();
+        let time = Instant::now();
+        self.unacked_reliable.insert(id, (time, false));
+        self.r_unacked.push_back((id, self.r_local_id));
+
+        self.r_queue.push_back(Message {
+            id,
+            kind: MessageKind::Reliable,
+            channel: 0,
+            order: self.r_local_id,
+            fragment: false,
+            time,
+            expires: if rpc {
+                None
+
+            } else {
+                MessageKind::Reliable.deadline(&self.config)
+            },
+            expired: false,
+            rpc,
+            key: None,
+            data
+        });
+
+        self.r_local_id += 1;
+        if self.r_local_id == MAX_ORDER_ID {
+            self.r_local_id = 0;
+        }
+
+        Ok(id)
+
+    }
+
+    fn receive_fragment(&mut self, m: Message) -> Option<Message> {
+
+        if m.data.len() < FRAGMENT_HEADER_BYTES {
+            return None;
+        }
+
+        let id = read_u16(&m.data[0..2]);
+        let index = read_u16(&m.data[2..4]) as usize;
+        let count = read_u16(&m.data[4..6]) as usize;
+        let size = m.data.len() - FRAGMENT_HEADER_BYTES;
+
+        // Ignore invalid fragments and late duplicates of messages which
+        // have already been reassembled
+        if index >= count || size == 0 || self.fragment_history.contains(&id) {
+            return None;
+        }
+
+        // All but the last fragment carry the same amount of data, ignore
+        // fragment counts which would exceed the maximum message size
+        if index + 1 < count && (count - 1) * size >= MESSAGE_MAX_BYTES {
+            return None;
+        }
+
+        // Drop partially received messages which made no progress for a
+        // while, their missing fragments are unlikely to ever arrive
+        let timeout = self.config.packet_drop_threshold * FRAGMENT_TIMEOUT_FACTOR;
+        let expired: Vec<u16> = self.fragment_buffers.iter().filter(|&(_, b)| {
+            m.time.duration_since(b.time) > timeout
+
+        }).map(|(other, _)| *other).collect();
+
+        for other in expired {
+            self.drop_fragment_buffer(other);
+        }
 
-                    self.remote_order_id += 1;
-                    if self.remote_order_id == MAX_ORDER_ID {
-                        self.remote_order_id = 0;
+        // Ignore fragments which do not match the rest of their message
+        if let Some(buffer) = self.fragment_buffers.get(&id) {
+            if buffer.kind != m.kind
+                || buffer.channel != m.channel
+                || buffer.order != m.order
+                || buffer.rpc != m.rpc
+                || buffer.count != count
+                || buffer.fragments.contains_key(&index)
+                || buffer.bytes + size > MESSAGE_MAX_BYTES
+                || buffer.size.is_some_and(|s| {
+                    if index + 1 < count {
+                        s != size
+
+                    } else {
+                        size > s
                     }
+                }) {
+                return None;
+            }
+        }
+
+        // Make room by dropping the messages which made no progress for the
+        // longest time
+        let cost = size + MESSAGE_HEADER_BYTES + FRAGMENT_HEADER_BYTES;
+        let new = !self.fragment_buffers.contains_key(&id);
+        while (new && self.fragment_buffers.len() >= FRAGMENT_BUFFERS_MAX)
+            || self.fragment_bytes + cost > FRAGMENT_BUFFERS_MAX_BYTES {
+
+            let oldest = self.fragment_buffers.iter().filter(|&(other, _)| {
+                *other != id
+
+            }).min_by_key(|&(_, b)| b.time).map(|(other, _)| *other);
+
+            match oldest {
+                Some(oldest) => {
+                    self.drop_fragment_buffer(oldest);
+                },
+                None => return None
+            }
+
+        }
+
+        let complete = {
+
+            let buffer = self.fragment_buffers.entry(id).or_insert_with(|| {
+                FragmentBuffer {
+                    kind: m.kind,
+                    channel: m.channel,
+                    order: m.order,
+                    rpc: m.rpc,
+                    count,
+                    size: None,
+                    bytes: 0,
+                    time: m.time,
+                    fragments: HashMap::new()
+                }
+            });
 
+            if index + 1 < count {
+                buffer.size = Some(size);
+            }
+
+            buffer.fragments.insert(index, m.data[FRAGMENT_HEADER_BYTES..].to_vec());
+            buffer.bytes += size;
+            buffer.time = m.time;
+            self.fragment_bytes += cost;
+
+            buffer.fragments.len() == count
+
+        };
+
+        if complete {
+
+            self.fragment_history.push_back(id);
+            if self.fragment_history.len() > FRAGMENT_HISTORY {
+                self.fragment_history.pop_front();
+            }
+
+            let mut buffer = self.drop_fragment_buffer(id).unwrap();
+            Some(Message {
+                id: MessageID(0),
+                kind: buffer.kind,
+                channel: buffer.channel,
+                order: buffer.order,
+                fragment: false,
+                time: m.time,
+                expires: None,
+                expired: false,
+                rpc: buffer.rpc,
+                key: None,
+                data: (0..count).flat_map(|i| buffer.fragments.remove(&i).unwrap()).collect()
+            })
+
+        } else {
+            None
+        }
+
+    }
+
+    fn drop_fragment_buffer(&mut self, id: u16) -> Option<FragmentBuffer> {
+        self.fragment_buffers.remove(&id).inspect(|buffer| {
+            self.fragment_bytes -= buffer.bytes
+                + buffer.fragments.len() * (MESSAGE_HEADER_BYTES + FRAGMENT_HEADER_BYTES);
+        })
+    }
+
+    fn receive_reliable_message(&mut self, m: Message) {
+
+        // Messages which are more recent than any other move the window
+        // forward, forgetting about the ids which wrapped around since
+        let ahead = order_distance(self.r_remote_id, m.order);
+        if ahead > 0 && ahead <= RELIABLE_WINDOW {
+            while self.r_remote_id != m.order {
+                self.r_remote_id = (self.r_remote_id + 1) % MAX_ORDER_ID;
+                self.r_received[self.r_remote_id as usize] = false;
+            }
+
+        // Senders never have older messages in flight, so these can only be
+        // duplicates
+        } else if order_distance(m.order, self.r_remote_id) >= RELIABLE_WINDOW {
+            return;
+        }
+
+        // Drop duplicates of received messages, these occur when a packet
+        // was only delayed or its acknowledgement got lost. Placeholders of
+        // expired messages only fill their id.
+        if !mem::replace(&mut self.r_received[m.order as usize], true) && !m.expired {
+            if m.rpc {
+                self.rpc_recv_queue.push_back(m.data);
+
+            } else {
+                self.recv_queue.push_back(m);
+            }
+        }
+
+    }
+
+    fn receive_sequenced_message(&mut self, m: Message) {
+
+        // Drop any message which is not more recent than the last one that was
+        // received on the same stream
+        let sequence = &mut self.s_remote_ids[m.channel as usize];
+        if sequence.is_none_or(|s| order_is_more_recent(m.order, s)) {
+            *sequence = Some(m.order);
+            self.recv_queue.push_back(m);
+        }
+
+    }
+
+    fn receive_ordered_message(&mut self, m: Message) {
+
+        let channel = &mut self.o_channels[m.channel as usize];
+
+        // Check if the order ID matches the currently expected on
+        if m.order == channel.remote_order_id {
+
+            // Received the message in order, placeholders of expired
+            // messages only advance the channel
+            if !m.expired {
+                self.recv_queue.push_back(m);
+            }
+
+            channel.remote_order_id += 1;
+            if channel.remote_order_id == MAX_ORDER_ID {
+                channel.remote_order_id = 0;
+            }
+
+            // Now check the buffer for further messages we have received
+            // out of order and which are next in the expected order
+            while let Some(msg) = channel.recv_buffer.remove(&channel.remote_order_id) {
+
+                channel.recv_bytes -= msg.data.len();
+                if !msg.expired {
+                    self.recv_queue.push_back(msg);
+                }
+
+                channel.remote_order_id += 1;
+                if channel.remote_order_id == MAX_ORDER_ID {
+                    channel.remote_order_id = 0;
                 }
 
             }
 
-        // Otherwise check if the message order is more recent and if not, we
-        // simply drop it. If it IS more recent, then we have received a future
-        // message out of order.
+        // Otherwise check if the message lies within the receive window and
+        // if not, we simply drop it. If it does, then we have received a
+        // future message out of order.
         //
-        // Also, before we insert the message into the min-heap, we check
-        // that there's no other message with the same order id in the heap
-        // already. Duplicates would require additional peek / pop later on
-        // when removing messages from the heap, so we resort to a Set here.
-        } else if order_is_more_recent(m.order, self.remote_order_id) && !self.o_recv_set.contains(&m.order) {
-            self.o_recv_set.insert(m.order);
-            self.o_recv_heap.push(m);
+        // Messages which are older than the expected one are outside of the
+        // window as well, since they wrap around to a large distance. The
+        // buffer itself takes care of any duplicates and never exceeds the
+        // bytes a sender puts in flight.
+        } else if order_distance(channel.remote_order_id, m.order) < ORDERED_WINDOW
+            && !channel.recv_buffer.contains_key(&m.order)
+            && channel.recv_bytes + m.data.len() <= ORDERED_WINDOW_BYTES {
+            channel.recv_bytes += m.data.len();
+            channel.recv_buffer.insert(m.order, m);
         }
 
     }
@@ -350,8 +1500,136 @@ fn order_is_more_recent(a: u16, b: u16) -> bool {
     || (b > a) && (b - a > MAX_ORDER_ID / 2)
 }
 
+fn order_distance(from: u16, to: u16) -> u16 {
+    (to + MAX_ORDER_ID - from) % MAX_ORDER_ID
+}
+
+fn read_u16(data: &[u8]) -> u16 {
+    (data[0] as u16) << 8 | data[1] as u16
+}
+
+fn valid_flags(kind: MessageKind) -> u8 {
+    match kind {
+        MessageKind::Reliable => FRAGMENT_FLAG | RPC_FLAG,
+        MessageKind::Ordered => FRAGMENT_FLAG | EXPIRED_FLAG,
+        MessageKind::Instant | MessageKind::Sequenced => 0
+    }
+}
+
+fn header_bytes(kind: MessageKind) -> usize {
+    match kind {
+        MessageKind::Ordered | MessageKind::Sequenced => {
+            MESSAGE_HEADER_BYTES + CHANNEL_HEADER_BYTES
+        },
+        _ => MESSAGE_HEADER_BYTES
+    }
+}
+
+fn is_oversized(message: &Message, available: usize) -> bool {
+    message.data.len() > MESSAGE_MAX_BYTES
+        || message.data.len() + header_bytes(message.kind) > available
+}
+
+fn expire_messages(
+    queue: &mut VecDeque<Message>,
+    now: Instant,
+    expired: &mut Vec<MessageID>,
+    placeholders: &mut HashSet<MessageID>
+) {
+    queue.retain_mut(|m| {
+        if m.expires.is_some_and(|e| e <= now) {
+            expired.push(m.id);
+
+            // Reliable and ordered messages are replaced with an empty
+            // placeholder since their order id has already been assigned
+            if m.kind == MessageKind::Reliable || m.kind == MessageKind::Ordered {
+                replace_with_placeholder(m, placeholders);
+                true
+
+            } else {
+                false
+            }
+
+        } else {
+            true
+        }
+    });
+}
+
+fn replace_with_placeholder(message: &mut Message, placeholders: &mut HashSet<MessageID>) {
+    placeholders.insert(message.id);
+    message.expires = None;
+    message.expired = true;
+    message.rpc = false;
+    message.key = None;
+    message.data.clear();
+}
+
+fn fragment_messages(
+    queue: &mut VecDeque<Message>,
+    available: usize,
+    fragment_id: &mut u16,
+    unacked: &mut HashMap<MessageID, usize>
+) {
+
+    if !queue.iter().any(|m| !m.fragment && is_oversized(m, available)) {
+        return;
+    }
+
+    let messages: Vec<Message> = queue.drain(..).collect();
+    for m in messages {
+
+        // Space left for the data of a single fragment
+        let size = cmp::min(
+            available.saturating_sub(header_bytes(m.kind) + FRAGMENT_HEADER_BYTES),
+            MESSAGE_MAX_BYTES - FRAGMENT_HEADER_BYTES
+        );
+
+        if m.fragment || size == 0 || !is_oversized(&m, available) {
+            queue.push_back(m);
+
+        } else {
+
+            // Messages never exceed MESSAGE_MAX_BYTES, so the fragment count
+            // always fits into its header
+            let count = m.data.len().div_ceil(size);
+            unacked.insert(m.id, count);
+
+            for (index, chunk) in m.data.chunks(size).enumerate() {
+                let mut data = Vec::with_capacity(FRAGMENT_HEADER_BYTES + chunk.len());
+                data.push((*fragment_id >> 8) as u8);
+                data.push(*fragment_id as u8);
+                data.push((index >> 8) as u8);
+                data.push(index as u8);
+                data.push((count >> 8) as u8);
+                data.push(count as u8);
+                data.extend_from_slice(chunk);
+                queue.push_back(Message {
+                    id: m.id,
+                    kind: m.kind,
+                    channel: m.channel,
+                    order: m.order,
+                    fragment: true,
+                    time: m.time,
+                    expires: None,
+                    expired: false,
+                    rpc: m.rpc,
+                    key: None,
+                    data
+                });
+            }
+
+            *fragment_id = fragment_id.wrapping_add(1);
+
+        }
+
+    }
+
+}
+
 fn messages_from_packet(packet: &[u8]) -> Vec<Message> {
 
+    let now = Instant::now();
     let available = packet.len();
     let mut index = 0;
     let mut messages = Vec::new();
@@ -367,31 +1645,68 @@ fn messages_from_packet(packet: &[u8]) -> Vec<Message> {
         let size_high = (packet[index + 2] as u16) << 8;
         let size = size_high | packet[index + 3] as u16;
 
-        // Lower 4 bits of byte 0 are the MessageKind
-        let kind = match packet[index] & 0x0F {
+        // Lower 2 bits of byte 0 are the MessageKind, followed by the
+        // expired - or rpc - and fragment flags
+        let kind = match packet[index] & 0x03 {
             0 => Some(MessageKind::Instant),
             1 => Some(MessageKind::Reliable),
             2 => Some(MessageKind::Ordered),
+            3 => Some(MessageKind::Sequenced),
             _ => None
         };
+        let flags = packet[index] & (FRAGMENT_FLAG | EXPIRED_FLAG | RPC_FLAG);
+        let fragment = flags & FRAGMENT_FLAG != 0;
+        let expired = flags & EXPIRED_FLAG != 0 && match kind {
+            Some(MessageKind::Ordered) => true,
+            Some(MessageKind::Reliable) => size == 0,
+            _ => false
+        };
+        let rpc = flags & RPC_FLAG != 0 && kind == Some(MessageKind::Reliable) && !expired;
+
+        // Ordered and sequenced messages are followed by their channel
+        let header = kind.map_or(MESSAGE_HEADER_BYTES, header_bytes);
+        if available - index < header {
+            break;
+        }
 
-        // Ignore any unknown message kind
-        if let Some(kind) = kind {
-            messages.push(Message {
-                kind: kind,
-                order: order_high | order_low,
-                size: size,
-                data: packet[
-                    index + MESSAGE_HEADER_BYTES..cmp::min(
-                        index + MESSAGE_HEADER_BYTES + size as usize,
-                        available
-                    )
-                ].to_vec()
+        let channel = if header > MESSAGE_HEADER_BYTES {
+            packet[index + MESSAGE_HEADER_BYTES]
 
-            });
+        } else {
+            0
+        };
+
+        // Ignore any unknown message kind or channel as well as any flags
+        // which are not valid for the kind, expired placeholders are never
+        // fragmented
+        match kind {
+            Some(MessageKind::Ordered) if channel as usize >= ORDERED_CHANNELS => {},
+            Some(kind) if flags & !valid_flags(kind) != 0 || (expired && fragment) => {},
+            Some(kind) => {
+                messages.push(Message {
+                    id: MessageID(0),
+                    kind: kind,
+                    channel,
+                    order: order_high | order_low,
+                    fragment,
+                    time: now,
+                    expires: None,
+                    expired,
+                    rpc,
+                    key: None,
+                    data: packet[
+                        index + header..cmp::min(
+                            index + header + size as usize,
+                            available
+                        )
+                    ].to_vec()
+
+                });
+            },
+            _ => {}
         }
 
-        index += size as usize + MESSAGE_HEADER_BYTES;
+        index += size as usize + header;
 
     }
 
@@ -401,20 +1716,24 @@ fn messages_from_packet(packet: &[u8]) -> Vec<Message> {
 
 fn write_messages(
     queue: &mut VecDeque<Message>,
+    window: Option<(u16, u16)>,
     packet: &mut Vec<u8>,
     available: usize,
-    written: &mut usize
+    written: &mut usize,
+    ids: &mut Vec<MessageID>
 ) {
     let mut used = 0;
-    while write_message(queue, packet, available, &mut used) {}
+    while write_message(queue, window, packet, available, &mut used, ids) {}
     *written += used;
 }
 
 fn write_message(
     queue: &mut VecDeque<Message>,
+    window: Option<(u16, u16)>,
     packet: &mut Vec<u8>,
     available: usize,
-    written: &mut usize
+    written: &mut usize,
+    ids: &mut Vec<MessageID>
 
 ) -> bool {
 
@@ -423,24 +1742,44 @@ fn write_message(
 
     } else {
 
-        let required = {
-            (queue.front().unwrap().size as usize) + MESSAGE_HEADER_BYTES
+        let (required, order) = {
+            let message = queue.front().unwrap();
+            (message.data.len() + header_bytes(message.kind), message.order)
         };
 
-        // If adding this message would exceed the available bytes, exit
-        if required > available - *written {
+        // If adding this message would exceed the available bytes, exit.
+        // Also keep messages outside of the send window queued until the
+        // remote queue has caught up.
+        if required > available - *written
+            || window.is_some_and(|(start, size)| order_distance(start, order) >= size) {
             false
 
         // Remove and serialize the message into the packet
         } else {
             let message = queue.pop_front().unwrap();
+            let mut flags = 0;
+            if message.fragment {
+                flags |= FRAGMENT_FLAG;
+            }
+            if message.expired {
+                flags |= EXPIRED_FLAG;
+            }
+            if message.rpc {
+                flags |= RPC_FLAG;
+            }
+            debug_assert_eq!(flags & !valid_flags(message.kind), 0, "Invalid message flags.");
+
             packet.push(
-                ((message.order & 0x0F00) >> 4) as u8 | (message.kind as u8)
+                ((message.order & 0x0F00) >> 4) as u8 | (message.kind as u8) | flags
             );
             packet.push(message.order as u8);
-            packet.push((message.size >> 8) as u8);
-            packet.push(message.size as u8);
+            packet.push((message.data.len() >> 8) as u8);
+            packet.push(message.data.len() as u8);
+            if header_bytes(message.kind) > MESSAGE_HEADER_BYTES {
+                packet.push(message.channel);
+            }
             packet.extend_from_slice(&message.data[..]);
+            ids.push(message.id);
             *written += required;
             true
         }
@@ -1,17 +1,35 @@
 // Modules --------------------------------------------------------------------
 mod binary_rate_limiter;
+mod bit_packing;
+#[cfg(any(feature = "lz4", feature = "deflate"))]
+mod compression;
 mod config;
+mod connect_token;
 mod connection;
+#[cfg(feature = "deflate")]
+mod deflate_packet_modifier;
+pub mod encryption;
+pub mod handshake;
+#[cfg(feature = "lz4")]
+mod lz4_packet_modifier;
 pub mod message_queue;
 mod noop_packet_modifier;
+pub mod packet_type;
+mod rpc;
 mod udp_socket;
 pub mod stats;
 pub mod ticker;
+#[cfg(feature = "typed")]
+mod typed_message;
 
 
 // Re-Exports -----------------------------------------------------------------
 pub use self::binary_rate_limiter::BinaryRateLimiter;
+pub use self::bit_packing::{BitReader, BitWriter};
 pub use self::config::Config;
+#[cfg(any(feature = "lz4", feature = "deflate"))]
+pub use self::compression::NoDictionary;
+pub use self::connect_token::{ConnectToken, TokenVerifier};
 pub use self::connection::{
     Connection,
     ConnectionID,
@@ -19,7 +37,15 @@ pub use self::connection::{
     ConnectionState,
     ConnectionEvent
 };
-pub use self::message_queue::MessageKind;
+#[cfg(feature = "deflate")]
+pub use self::deflate_packet_modifier::DeflatePacketModifier;
+pub use self::handshake::{ProtocolVersion, RejectionReason};
+#[cfg(feature = "lz4")]
+pub use self::lz4_packet_modifier::Lz4PacketModifier;
+pub use self::message_queue::{MessageID, MessageKind, MESSAGE_CHANNELS, ORDERED_CHANNELS};
 pub use self::noop_packet_modifier::NoopPacketModifier;
+pub use self::rpc::RequestID;
+#[cfg(feature = "typed")]
+pub use self::typed_message::{encode_typed, MessageRegistry, TypedEvent, TypedMessage};
 pub use self::udp_socket::UdpSocket;
 
@@ -0,0 +1,61 @@
+// Copyright (c) 2015-2017 Ivo Wetzel
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+/// Offset of the packet type within the header of every packet, directly
+/// following the protocol header and the connection id.
+pub const PACKET_TYPE_OFFSET: usize = 8;
+
+/// Enum of all packet types exchanged between clients and servers.
+///
+/// The type of each packet is send as a single byte following the connection
+/// id.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum PacketType {
+
+    /// Connection packet carrying messages.
+    Data = 0,
+
+    /// Connection packet without any messages, these only acknowledge
+    /// received packets and keep the connection alive.
+    Ack = 1,
+
+    /// Connection packet notifying of programmatic connection closure.
+    Close = 2,
+
+    /// Handshake packet send by a client in order to request a challenge.
+    ConnectRequest = 3,
+
+    /// Handshake packet send by a server in reply to a connection request.
+    Challenge = 4,
+
+    /// Handshake packet send by a client in order to answer a challenge.
+    ChallengeResponse = 5,
+
+    /// Handshake packet send by a server in order to reject a connection
+    /// attempt.
+    Denial = 6
+
+}
+
+impl PacketType {
+
+    /// Returns the type of a packet, if it has a known one.
+    pub fn from_packet(packet: &[u8]) -> Option<PacketType> {
+        match packet.get(PACKET_TYPE_OFFSET) {
+            Some(&0) => Some(PacketType::Data),
+            Some(&1) => Some(PacketType::Ack),
+            Some(&2) => Some(PacketType::Close),
+            Some(&3) => Some(PacketType::ConnectRequest),
+            Some(&4) => Some(PacketType::Challenge),
+            Some(&5) => Some(PacketType::ChallengeResponse),
+            Some(&6) => Some(PacketType::Denial),
+            _ => None
+        }
+    }
+
+}
@@ -0,0 +1,182 @@
+// Copyright (c) 2015-2017 Ivo Wetzel
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+
+// STD Dependencies -----------------------------------------------------------
+use std::collections::{HashMap, HashSet};
+use std::time::Instant;
+
+
+/// Number of bytes used by the header which prefixes each RPC frame.
+const FRAME_HEADER_BYTES: usize = 5;
+
+/// Identifier of a request send via `Connection::request`.
+///
+/// Ids are assigned by the requesting end of a connection, so the ids of
+/// incoming and outgoing requests are independent of each other.
+#[derive(Debug, PartialEq, Eq, Hash, Copy, Clone, Ord, PartialOrd)]
+pub struct RequestID(pub u32);
+
+/// Enum of frames exchanged by the RPC layer.
+#[derive(Debug, PartialEq)]
+pub enum Frame {
+
+    /// A request along with its payload.
+    Request(RequestID, Vec<u8>),
+
+    /// The response to a request, either a result or a remote error.
+    Response(RequestID, Result<Vec<u8>, Vec<u8>>)
+
+}
+
+impl Frame {
+
+    /// Parses a frame from the data of a received RPC message.
+    pub fn from_bytes(data: &[u8]) -> Option<Frame> {
+
+        if data.len() < FRAME_HEADER_BYTES {
+            return None;
+        }
+
+        let id = RequestID(data[1..FRAME_HEADER_BYTES].iter().fold(0, |id, b| id << 8 | *b as u32));
+        let payload = data[FRAME_HEADER_BYTES..].to_vec();
+        match data[0] {
+            0 => Some(Frame::Request(id, payload)),
+            1 => Some(Frame::Response(id, Ok(payload))),
+            2 => Some(Frame::Response(id, Err(payload))),
+            _ => None
+        }
+
+    }
+
+    /// Serializes the frame into the data of an RPC message.
+    pub fn into_bytes(self) -> Vec<u8> {
+
+        let (kind, id, mut payload) = match self {
+            Frame::Request(id, payload) => (0, id, payload),
+            Frame::Response(id, Ok(payload)) => (1, id, payload),
+            Frame::Response(id, Err(payload)) => (2, id, payload)
+        };
+
+        let mut data = Vec::with_capacity(FRAME_HEADER_BYTES + payload.len());
+        data.push(kind);
+        data.push((id.0 >> 24) as u8);
+        data.push((id.0 >> 16) as u8);
+        data.push((id.0 >> 8) as u8);
+        data.push(id.0 as u8);
+        data.append(&mut payload);
+        data
+
+    }
+
+}
+
+/// Bookkeeping of the outgoing and incoming requests of a connection.
+#[derive(Debug)]
+pub struct Requests {
+
+    /// The id which gets assigned to the next outgoing request
+    local_id: u32,
+
+    /// Deadlines of all outgoing requests which are awaiting a response
+    pending: HashMap<RequestID, Instant>,
+
+    /// Ids of all incoming requests which have not yet been responded to
+    incoming: HashSet<RequestID>
+
+}
+
+impl Requests {
+
+    /// Creates a new, empty set of requests.
+    pub fn new() -> Requests {
+        Requests {
+            local_id: 0,
+            pending: HashMap::new(),
+            incoming: HashSet::new()
+        }
+    }
+
+    /// Returns the id for the next outgoing request.
+    pub fn next_id(&self) -> RequestID {
+        RequestID(self.local_id)
+    }
+
+    /// Starts awaiting the response to the outgoing request with the id
+    /// returned by `Requests::next_id()`.
+    pub fn send(&mut self, deadline: Instant) -> RequestID {
+        let id = self.next_id();
+        self.local_id = self.local_id.wrapping_add(1);
+        self.pending.insert(id, deadline);
+        id
+    }
+
+    /// Returns the number of outgoing requests awaiting a response.
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Handles a received frame, returning `false` for requests which were
+    /// already received and responses which are not awaited (anymore).
+    pub fn receive(&mut self, frame: &Frame) -> bool {
+        match *frame {
+            Frame::Request(id, _) => self.incoming.insert(id),
+            Frame::Response(id, _) => self.pending.remove(&id).is_some()
+        }
+    }
+
+    /// Returns the number of incoming requests which have not yet been
+    /// responded to.
+    pub fn incoming(&self) -> usize {
+        self.incoming.len()
+    }
+
+    /// Returns whether the incoming request with the given `id` has not yet
+    /// been responded to.
+    pub fn awaits_response(&self, id: RequestID) -> bool {
+        self.incoming.contains(&id)
+    }
+
+    /// Marks the incoming request with the given `id` as responded to.
+    pub fn respond(&mut self, id: RequestID) {
+        self.incoming.remove(&id);
+    }
+
+    /// Removes and returns the ids of all outgoing requests whose deadline
+    /// has passed, oldest first.
+    pub fn timed_out(&mut self, now: Instant) -> Vec<RequestID> {
+        let mut ids: Vec<RequestID> = self.pending.iter().filter(|&(_, deadline)| {
+            *deadline <= now
+
+        }).map(|(id, _)| *id).collect();
+
+        for id in &ids {
+            self.pending.remove(id);
+        }
+
+        ids.sort();
+        ids
+    }
+
+    /// Removes and returns the ids of all outgoing requests, oldest first.
+    pub fn fail(&mut self) -> Vec<RequestID> {
+        let mut ids: Vec<RequestID> = self.pending.drain().map(|(id, _)| id).collect();
+        ids.sort();
+        ids
+    }
+
+    /// Clears all requests and ids, returning the ids of all outgoing
+    /// requests which were still awaiting a response, oldest first.
+    pub fn reset(&mut self) -> Vec<RequestID> {
+        let ids = self.fail();
+        self.local_id = 0;
+        self.incoming.clear();
+        ids
+    }
+
+}
@@ -0,0 +1,239 @@
+// Copyright (c) 2015-2017 Ivo Wetzel
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+extern crate bincode;
+extern crate serde;
+
+
+// STD Dependencies -----------------------------------------------------------
+use std::fmt;
+use std::any::Any;
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+
+
+// External Dependencies ------------------------------------------------------
+use self::bincode::Options;
+use self::serde::Serialize;
+use self::serde::de::DeserializeOwned;
+
+
+/// Number of bytes used by the type id which prefixes each typed message.
+const TYPE_ID_BYTES: usize = 2;
+
+/// Trait for application message types which are send via
+/// `Connection::send_typed`.
+///
+/// Each type is identified on the wire by its `ID`, which must be unique
+/// within the application's protocol.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate cobalt;
+/// # extern crate serde;
+/// use cobalt::{MessageRegistry, TypedMessage};
+/// use serde::{Serialize, Deserialize};
+///
+/// #[derive(Debug, PartialEq, Serialize, Deserialize)]
+/// struct Chat {
+///     text: String
+/// }
+///
+/// impl TypedMessage for Chat {
+///     const ID: u16 = 1;
+/// }
+///
+/// # fn main() {
+/// let mut registry = MessageRegistry::<()>::new();
+/// registry.register::<Chat>();
+///
+/// let payload = cobalt::encode_typed(&Chat { text: "Hello".to_string() }).unwrap();
+/// let event = registry.decode(&payload).unwrap();
+/// assert_eq!(event.downcast::<Chat>().ok(), Some(Chat { text: "Hello".to_string() }));
+/// # }
+/// ```
+pub trait TypedMessage: Serialize + DeserializeOwned + Any {
+
+    /// The id which identifies messages of this type.
+    const ID: u16;
+
+}
+
+/// A typed message decoded by a `MessageRegistry`.
+pub struct TypedEvent {
+    id: u16,
+    value: Box<dyn Any>
+}
+
+impl TypedEvent {
+
+    /// Returns the id of the message's type.
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+
+    /// Returns whether the message is of type `T`.
+    pub fn is<T: TypedMessage>(&self) -> bool {
+        self.value.is::<T>()
+    }
+
+    /// Returns the message as a value of type `T` or gives back the event in
+    /// case the message is of another type.
+    pub fn downcast<T: TypedMessage>(self) -> Result<T, TypedEvent> {
+        let id = self.id;
+        self.value.downcast::<T>().map(|value| *value).map_err(|value| {
+            TypedEvent {
+                id,
+                value
+            }
+        })
+    }
+
+}
+
+impl fmt::Debug for TypedEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "TypedEvent {{ id: {} }}", self.id)
+    }
+}
+
+/// Type alias for the functions which decode a registered message type.
+type Decoder = Box<dyn Fn(&[u8]) -> Result<Box<dyn Any>, Error>>;
+
+/// Type alias for the functions which handle a registered message type.
+type Handler<C> = Box<dyn FnMut(C, Box<dyn Any>)>;
+
+/// Registry of typed messages which decodes received message payloads and
+/// dispatches them to handlers.
+///
+/// Handlers receive a context `C` along with each message, for example the
+/// `ConnectionID` the message was received from on a server.
+pub struct MessageRegistry<C> {
+    decoders: HashMap<u16, Decoder>,
+    handlers: HashMap<u16, Handler<C>>
+}
+
+impl<C> MessageRegistry<C> {
+
+    /// Creates a new, empty registry.
+    pub fn new() -> MessageRegistry<C> {
+        MessageRegistry {
+            decoders: HashMap::new(),
+            handlers: HashMap::new()
+        }
+    }
+
+    /// Registers the message type `T` for decoding.
+    ///
+    /// Any type previously registered with the same id gets replaced.
+    pub fn register<T: TypedMessage>(&mut self) {
+        self.handlers.remove(&T::ID);
+        self.decoders.insert(T::ID, Box::new(|data| {
+            let value: T = options(data.len()).deserialize(data).map_err(|err| {
+                Error::new(ErrorKind::InvalidData, err)
+            })?;
+            Ok(Box::new(value))
+        }));
+    }
+
+    /// Registers the message type `T` and a `handler` which gets invoked for
+    /// each message of this type passed to `MessageRegistry::dispatch()`.
+    pub fn handle<T, F>(&mut self, mut handler: F) where T: TypedMessage, F: FnMut(C, T) + 'static {
+        self.register::<T>();
+        self.handlers.insert(T::ID, Box::new(move |context, value| {
+            if let Ok(value) = value.downcast::<T>() {
+                handler(context, *value);
+            }
+        }));
+    }
+
+    /// Decodes a message `payload` as received via
+    /// `ConnectionEvent::Message` into a typed event.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of the kind `ErrorKind::InvalidData` in case the
+    /// payload is not a valid message of any registered type.
+    pub fn decode(&self, payload: &[u8]) -> Result<TypedEvent, Error> {
+
+        if payload.len() < TYPE_ID_BYTES {
+            return Err(Error::new(ErrorKind::InvalidData, "Typed message is missing its type id."));
+        }
+
+        let id = (payload[0] as u16) << 8 | payload[1] as u16;
+        match self.decoders.get(&id) {
+            Some(decoder) => decoder(&payload[TYPE_ID_BYTES..]).map(|value| {
+                TypedEvent {
+                    id,
+                    value
+                }
+            }),
+            None => Err(Error::new(ErrorKind::InvalidData, "Unknown typed message id."))
+        }
+
+    }
+
+    /// Decodes a message `payload` and passes it along with the `context` to
+    /// the handler registered for its type.
+    ///
+    /// Returns the decoded event for message types which were registered
+    /// without a handler.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of the kind `ErrorKind::InvalidData` in case the
+    /// payload is not a valid message of any registered type.
+    pub fn dispatch(&mut self, context: C, payload: &[u8]) -> Result<Option<TypedEvent>, Error> {
+        let event = self.decode(payload)?;
+        match self.handlers.get_mut(&event.id) {
+            Some(handler) => {
+                handler(context, event.value);
+                Ok(None)
+            },
+            None => Ok(Some(event))
+        }
+    }
+
+}
+
+impl<C> Default for MessageRegistry<C> {
+    fn default() -> MessageRegistry<C> {
+        MessageRegistry::new()
+    }
+}
+
+impl<C> fmt::Debug for MessageRegistry<C> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut ids: Vec<&u16> = self.decoders.keys().collect();
+        ids.sort();
+        write!(f, "MessageRegistry {{ ids: {:?} }}", ids)
+    }
+}
+
+/// Serializes a `value` into a message payload, prefixed with the id of its
+/// type.
+///
+/// # Errors
+///
+/// Returns an error of the kind `ErrorKind::InvalidInput` in case the value
+/// cannot be serialized.
+pub fn encode_typed<T: TypedMessage>(value: &T) -> Result<Vec<u8>, Error> {
+    let mut payload = vec![(T::ID >> 8) as u8, T::ID as u8];
+    options(usize::MAX).serialize_into(&mut payload, value).map_err(|err| {
+        Error::new(ErrorKind::InvalidInput, err)
+    })?;
+    Ok(payload)
+}
+
+
+// Static Helpers -------------------------------------------------------------
+fn options(limit: usize) -> impl Options {
+    // Limit decoding to the size of the payload so malformed length prefixes
+    // cannot cause large allocations
+    bincode::DefaultOptions::new().with_limit(limit as u64)
+}
@@ -0,0 +1,193 @@
+// Copyright (c) 2015-2017 Ivo Wetzel
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+
+// STD Dependencies -----------------------------------------------------------
+use std::io::ErrorKind;
+
+
+// Internal Dependencies ------------------------------------------------------
+use ::{BitReader, BitWriter};
+
+
+// Tests ----------------------------------------------------------------------
+#[test]
+fn test_bits() {
+
+    let mut writer = BitWriter::new();
+    assert!(writer.is_empty());
+
+    writer.write_bits(0b101, 3);
+    writer.write_bits(0b1_1001_0110, 9);
+    writer.write_bits(0xFFFF_FFFF, 32);
+    writer.write_bits(0, 0);
+
+    assert_eq!(writer.bit_len(), 44);
+    assert_eq!(writer.len(), 6);
+    assert_eq!(writer.into_bytes(), vec![
+        0b1011_1001, 0b0110_1111, 255, 255, 255, 0b1111_0000
+    ]);
+
+    let data = [0b1011_1001, 0b0110_1111, 255, 255, 255, 0b1111_0000];
+    let mut reader = BitReader::new(&data);
+    assert_eq!(reader.read_bits(3).unwrap(), 0b101);
+    assert_eq!(reader.read_bits(9).unwrap(), 0b1_1001_0110);
+    assert_eq!(reader.read_bits(32).unwrap(), 0xFFFF_FFFF);
+    assert_eq!(reader.remaining_bits(), 4);
+    assert_eq!(reader.read_bits(4).unwrap(), 0);
+    assert_eq!(reader.remaining_bits(), 0);
+
+}
+
+#[test]
+fn test_bool() {
+
+    let mut writer = BitWriter::new();
+    for i in 0..10 {
+        writer.write_bool(i % 3 == 0);
+    }
+    assert_eq!(writer.len(), 2);
+
+    let data = writer.into_bytes();
+    let mut reader = BitReader::new(&data);
+    for i in 0..10 {
+        assert_eq!(reader.read_bool().unwrap(), i % 3 == 0);
+    }
+
+}
+
+#[test]
+fn test_int() {
+
+    let mut writer = BitWriter::new();
+    writer.write_int(-8, -8, 7);
+    writer.write_int(7, -8, 7);
+    writer.write_int(100, -8, 7);
+    writer.write_int(-100, -8, 7);
+    writer.write_int(5, 5, 5);
+    writer.write_int(i32::MIN, i32::MIN, i32::MAX);
+    writer.write_int(i32::MAX, i32::MIN, i32::MAX);
+
+    // 4 bits per value in the first range, none for a single value
+    assert_eq!(writer.bit_len(), 4 * 4 + 32 * 2);
+
+    let data = writer.into_bytes();
+    let mut reader = BitReader::new(&data);
+    assert_eq!(reader.read_int(-8, 7).unwrap(), -8);
+    assert_eq!(reader.read_int(-8, 7).unwrap(), 7);
+
+    // Values are clamped
+    assert_eq!(reader.read_int(-8, 7).unwrap(), 7);
+    assert_eq!(reader.read_int(-8, 7).unwrap(), -8);
+
+    assert_eq!(reader.read_int(5, 5).unwrap(), 5);
+    assert_eq!(reader.read_int(i32::MIN, i32::MAX).unwrap(), i32::MIN);
+    assert_eq!(reader.read_int(i32::MIN, i32::MAX).unwrap(), i32::MAX);
+
+}
+
+#[test]
+fn test_int_out_of_range() {
+
+    // 0..=4 requires 3 bits which can also encode 5..=7
+    let data = [0b1110_0000];
+    let mut reader = BitReader::new(&data);
+    assert_eq!(reader.read_int(0, 4).unwrap_err().kind(), ErrorKind::InvalidData);
+
+}
+
+#[test]
+fn test_float() {
+
+    let mut writer = BitWriter::new();
+    writer.write_float(0.5, -1.0, 1.0, 0.01);
+    writer.write_float(-0.123, -1.0, 1.0, 0.01);
+    writer.write_float(2.0, -1.0, 1.0, 0.01);
+    writer.write_float(-2.0, -1.0, 1.0, 0.01);
+    writer.write_float(123.4, 0.0, 360.0, 0.1);
+
+    // 200 and 3600 steps
+    assert_eq!(writer.bit_len(), 8 * 4 + 12);
+
+    let data = writer.into_bytes();
+    let mut reader = BitReader::new(&data);
+    assert!((reader.read_float(-1.0, 1.0, 0.01).unwrap() - 0.5).abs() < 0.005);
+    assert!((reader.read_float(-1.0, 1.0, 0.01).unwrap() + 0.12).abs() < 0.005);
+    assert_eq!(reader.read_float(-1.0, 1.0, 0.01).unwrap(), 1.0);
+    assert_eq!(reader.read_float(-1.0, 1.0, 0.01).unwrap(), -1.0);
+    assert!((reader.read_float(0.0, 360.0, 0.1).unwrap() - 123.4).abs() < 0.05);
+
+}
+
+#[test]
+fn test_float_out_of_range() {
+    let data = [255];
+    let mut reader = BitReader::new(&data);
+    assert_eq!(reader.read_float(-1.0, 1.0, 0.01).unwrap_err().kind(), ErrorKind::InvalidData);
+}
+
+#[test]
+fn test_varint() {
+
+    let values = [0, 1, 127, 128, 300, 16_383, 16_384, u32::MAX as u64, u64::MAX];
+
+    let mut writer = BitWriter::new();
+    writer.write_bool(true);
+    for value in &values {
+        writer.write_varint(*value);
+    }
+
+    // Varints need not be byte aligned
+    assert_eq!(writer.bit_len(), 1 + 8 * (1 + 1 + 1 + 2 + 2 + 2 + 3 + 5 + 10));
+
+    let data = writer.into_bytes();
+    let mut reader = BitReader::new(&data);
+    assert!(reader.read_bool().unwrap());
+    for value in &values {
+        assert_eq!(reader.read_varint().unwrap(), *value);
+    }
+
+}
+
+#[test]
+fn test_varint_too_large() {
+
+    // More than 64 bits of data
+    let mut data = vec![0xFF; 9];
+    data.push(0x02);
+    let mut reader = BitReader::new(&data);
+    assert_eq!(reader.read_varint().unwrap_err().kind(), ErrorKind::InvalidData);
+
+    // Continuation past the maximum number of groups
+    let data = vec![0x80; 11];
+    let mut reader = BitReader::new(&data);
+    assert_eq!(reader.read_varint().unwrap_err().kind(), ErrorKind::InvalidData);
+
+}
+
+#[test]
+fn test_read_past_end() {
+
+    let data = [0b1000_0000];
+    let mut reader = BitReader::new(&data);
+    assert_eq!(reader.read_bits(9).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+
+    // Failed reads do not consume any bits
+    assert_eq!(reader.read_bits(8).unwrap(), 0b1000_0000);
+    assert_eq!(reader.read_bool().unwrap_err().kind(), ErrorKind::UnexpectedEof);
+    assert_eq!(reader.read_int(0, 3).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+    assert_eq!(reader.read_float(0.0, 1.0, 0.1).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+    assert_eq!(reader.read_varint().unwrap_err().kind(), ErrorKind::UnexpectedEof);
+    assert_eq!(reader.read_bits(33).unwrap_err().kind(), ErrorKind::InvalidInput);
+
+    // Truncated varint
+    let data = [0x80];
+    let mut reader = BitReader::new(&data);
+    assert_eq!(reader.read_varint().unwrap_err().kind(), ErrorKind::UnexpectedEof);
+
+}
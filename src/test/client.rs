@@ -17,7 +17,7 @@ use std::sync::mpsc::TryRecvError;
 use super::MockSocket;
 use ::{
     BinaryRateLimiter, Client, ClientEvent, Config, MessageKind,
-    NoopPacketModifier
+    NoopPacketModifier, RejectionReason
 };
 
 
@@ -184,21 +184,252 @@ fn test_client_connection_success() {
             (id >> 16) as u8,
             (id >> 8) as u8,
              id as u8,
-            0,
-            0,
+            1,
+            0, 0,
+            0, 0,
             0, 0, 0, 0
         ])
     ]);
 
     assert_eq!(client_events(&mut client), vec![ClientEvent::Connection]);
-    assert_eq!(client.bytes_sent(), 28);
-    assert_eq!(client.bytes_received(), 0);
+    assert_eq!(client.bytes_sent(), 63);
+    assert_eq!(client.bytes_received(), 21);
 
     // Send again to update states
     client.send(true).ok();
 
-    assert_eq!(client.bytes_sent(), 42);
-    assert_eq!(client.bytes_received(), 14);
+    assert_eq!(client.bytes_sent(), 80);
+    assert_eq!(client.bytes_received(), 38);
+
+}
+
+#[test]
+fn test_client_handshake() {
+
+    let mut client = Client::<MockSocket, BinaryRateLimiter, NoopPacketModifier>::new(Config::default());
+    client.connect("255.1.1.1:5678").ok();
+
+    let id = client.connection().unwrap().id().0;
+    let request = vec![
+        1, 2, 3, 4,
+        (id >> 24) as u8,
+        (id >> 16) as u8,
+        (id >> 8) as u8,
+         id as u8,
+        3, // connect request
+        0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0
+    ];
+
+    // Connection packets are ignored before a challenge has been received
+    client.socket().unwrap().mock_receive(vec![
+        ("255.1.1.1:5678", vec![
+            1, 2, 3, 4,
+            (id >> 24) as u8,
+            (id >> 16) as u8,
+            (id >> 8) as u8,
+             id as u8,
+            1,
+            0, 0,
+            0, 0,
+            0, 0, 0, 0
+        ])
+    ]);
+
+    assert_eq!(client_events(&mut client), vec![]);
+
+    // Challenges for other connections are ignored
+    client.socket().unwrap().mock_receive(vec![
+        ("255.1.1.1:5678", challenge_packet(id.wrapping_add(1)))
+    ]);
+
+    assert_eq!(client_events(&mut client), vec![]);
+
+    // Connection requests are repeated until a challenge is received
+    client.socket().unwrap().assert_sent(vec![
+        ("255.1.1.1:5678", request.clone()),
+        ("255.1.1.1:5678", request.clone())
+    ]);
+
+    client.socket().unwrap().mock_receive(vec![
+        ("255.1.1.1:5678", challenge_packet(id))
+    ]);
+
+    assert_eq!(client_events(&mut client), vec![]);
+
+    // Challenge responses are repeated until the first connection packet is
+    // received
+    let response = vec![
+        1, 2, 3, 4,
+        (id >> 24) as u8,
+        (id >> 16) as u8,
+        (id >> 8) as u8,
+         id as u8,
+        5, // challenge response
+        0, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9
+    ];
+
+    client.send(false).ok();
+    client.send(false).ok();
+    client.socket().unwrap().assert_sent(vec![
+        ("255.1.1.1:5678", request),
+        ("255.1.1.1:5678", response.clone()),
+        ("255.1.1.1:5678", response)
+    ]);
+
+    client.socket().unwrap().mock_receive(vec![
+        ("255.1.1.1:5678", vec![
+            1, 2, 3, 4,
+            (id >> 24) as u8,
+            (id >> 16) as u8,
+            (id >> 8) as u8,
+             id as u8,
+            1,
+            0, 0,
+            0, 0,
+            0, 0, 0, 0
+        ])
+    ]);
+
+    assert_eq!(client.receive(), Ok(ClientEvent::Connection));
+
+}
+
+#[test]
+fn test_client_connection_rejected() {
+
+    let mut client = client_init(Config {
+        connection_init_threshold: Duration::from_millis(100),
+        .. Config::default()
+    });
+
+    // Mock the receival of a denial from the server
+    let id = client.connection().unwrap().id().0;
+    client.socket().unwrap().mock_receive(vec![
+        ("255.1.1.1:5678", vec![
+            1, 2, 3, 4,
+            (id >> 24) as u8,
+            (id >> 16) as u8,
+            (id >> 8) as u8,
+             id as u8,
+            6, // denial
+            1 // server full
+        ])
+    ]);
+
+    assert_eq!(client_events(&mut client), vec![
+        ClientEvent::ConnectionRejected(RejectionReason::ServerFull)
+    ]);
+
+    // One last challenge response
+    assert_eq!(client.socket().unwrap().sent_count(), 1);
+
+    // We expect no additional packets to be send once the connection was
+    // rejected and no failure to be reported
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(client_events(&mut client), vec![]);
+    client.socket().unwrap().assert_sent_none();
+
+    // Resetting the client should re-try the connection
+    client.reset().ok();
+    client.send(false).ok();
+    assert_eq!(client.socket().unwrap().sent_count(), 1);
+
+}
+
+#[test]
+fn test_client_connection_rejected_without_encryption() {
+
+    let mut client = Client::<MockSocket, BinaryRateLimiter, NoopPacketModifier>::new(Config {
+        encryption: true,
+        connection_init_threshold: Duration::from_millis(100),
+        .. Config::default()
+    });
+    client.connect("255.1.1.1:5678").ok();
+    client.send(false).ok();
+    assert_eq!(client.socket().unwrap().sent_count(), 1);
+
+    // Mock the receival of a challenge from a server without encryption
+    let id = client.connection().unwrap().id().0;
+    client.socket().unwrap().mock_receive(vec![
+        ("255.1.1.1:5678", challenge_packet(id))
+    ]);
+
+    assert_eq!(client_events(&mut client), vec![
+        ClientEvent::ConnectionRejected(RejectionReason::EncryptionRequired)
+    ]);
+
+    // One last connection request
+    assert_eq!(client.socket().unwrap().sent_count(), 1);
+
+    // No challenge response is send and no failure is reported
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(client_events(&mut client), vec![]);
+    client.socket().unwrap().assert_sent_none();
+
+}
+
+#[test]
+fn test_client_connect_with_payload() {
+
+    let mut client = Client::<MockSocket, BinaryRateLimiter, NoopPacketModifier>::new(Config {
+        packet_max_size: 27,
+        .. Config::default()
+    });
+
+    // Payloads must fit into a single challenge response
+    assert_eq!(
+        client.connect_with_payload("255.1.1.1:5678", vec![0; 7]).unwrap_err().kind(),
+        ErrorKind::InvalidInput
+    );
+
+    assert!(client.connect_with_payload("255.1.1.1:5678", b"secret".to_vec()).is_ok());
+
+    // Mock the receival of the server's challenge
+    let id = client.connection().unwrap().id().0;
+    client.send(false).ok();
+    client.socket().unwrap().sent();
+    client.socket().unwrap().mock_receive(vec![
+        ("255.1.1.1:5678", challenge_packet(id))
+    ]);
+
+    assert_eq!(client.receive(), Err(TryRecvError::Empty));
+
+    // The payload is send along with the cookie
+    client.send(false).ok();
+    client.socket().unwrap().assert_sent(vec![
+        ("255.1.1.1:5678", [
+            1, 2, 3, 4,
+            (id >> 24) as u8,
+            (id >> 16) as u8,
+            (id >> 8) as u8,
+             id as u8,
+            5, // challenge response
+            0, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, // cookie
+            115, 101, 99, 114, 101, 116 // payload
+
+        ].to_vec())
+    ]);
+
+    // Mock the receival of a denial with a reason
+    client.socket().unwrap().mock_receive(vec![
+        ("255.1.1.1:5678", vec![
+            1, 2, 3, 4,
+            (id >> 24) as u8,
+            (id >> 16) as u8,
+            (id >> 8) as u8,
+             id as u8,
+            6, // denial
+            3, // denied with reason
+            1, 2, 3
+        ])
+    ]);
+
+    assert_eq!(client_events(&mut client), vec![
+        ClientEvent::ConnectionRejected(RejectionReason::Denied(Some(vec![1, 2, 3])))
+    ]);
+
+    // One last challenge response
+    assert_eq!(client.socket().unwrap().sent_count(), 1);
 
 }
 
@@ -217,8 +448,9 @@ fn test_client_reset_events() {
             (id >> 16) as u8,
             (id >> 8) as u8,
              id as u8,
-            0,
-            0,
+            1,
+            0, 0,
+            0, 0,
             0, 0, 0, 0
         ])
     ]);
@@ -238,8 +470,9 @@ fn test_client_reset_events() {
             (id >> 16) as u8,
             (id >> 8) as u8,
              id as u8,
-            0,
-            0,
+            1,
+            0, 0,
+            0, 0,
             0, 0, 0, 0
         ])
     ]);
@@ -276,8 +509,9 @@ fn test_client_connection_ignore_non_peer() {
             (id >> 16) as u8,
             (id >> 8) as u8,
              id as u8,
-            0,
-            0,
+            1,
+            0, 0,
+            0, 0,
             0, 0, 0, 0
         ])
     ]);
@@ -303,15 +537,16 @@ fn test_client_connection_loss_and_reconnect() {
             (id >> 16) as u8,
             (id >> 8) as u8,
              id as u8,
-            0,
-            0,
+            1,
+            0, 0,
+            0, 0,
             0, 0, 0, 0
         ])
     ]);
 
     assert_eq!(client_events(&mut client), vec![ClientEvent::Connection]);
-    assert_eq!(client.bytes_sent(), 28);
-    assert_eq!(client.bytes_received(), 0);
+    assert_eq!(client.bytes_sent(), 63);
+    assert_eq!(client.bytes_received(), 21);
 
     // Let the connection time out
     thread::sleep(Duration::from_millis(200));
@@ -334,17 +569,20 @@ fn test_client_connection_loss_and_reconnect() {
     assert_eq!(client.bytes_sent(), 0);
     assert_eq!(client.bytes_received(), 0);
 
-    // Mock the receival of the first server packet which acknowledges the client
+    // Mock the receival of a new challenge and the first server packet which
+    // acknowledges the client
     let id = client.connection().unwrap().id().0;
     client.socket().unwrap().mock_receive(vec![
+        ("255.1.1.1:5678", challenge_packet(id)),
         ("255.1.1.1:5678", vec![
             1, 2, 3, 4,
             (id >> 24) as u8,
             (id >> 16) as u8,
             (id >> 8) as u8,
              id as u8,
-            0,
-            0,
+            1,
+            0, 0,
+            0, 0,
             0, 0, 0, 0
         ])
     ]);
@@ -353,7 +591,7 @@ fn test_client_connection_loss_and_reconnect() {
 
     // Expect one last packet
     assert_eq!(client.socket().unwrap().sent_count(), 1);
-    assert_eq!(client.bytes_sent(), 14);
+    assert_eq!(client.bytes_sent(), 21);
     assert_eq!(client.bytes_received(), 0);
 
 }
@@ -366,7 +604,7 @@ fn test_client_send() {
         .. Config::default()
     });
 
-    assert_eq!(client.bytes_sent(), 14);
+    assert_eq!(client.bytes_sent(), 42);
 
     // Mock the receival of the first server packet which acknowledges the client
     let id = client.connection().unwrap().id().0;
@@ -377,8 +615,9 @@ fn test_client_send() {
             (id >> 16) as u8,
             (id >> 8) as u8,
              id as u8,
-            0,
-            0,
+            1,
+            0, 0,
+            0, 0,
             0, 0, 0, 0
         ])
     ]);
@@ -386,36 +625,36 @@ fn test_client_send() {
     assert_eq!(client_events(&mut client), vec![ClientEvent::Connection]);
 
     // States should not be updated before the next send() call
-    assert_eq!(client.bytes_sent(), 28);
-    assert_eq!(client.bytes_received(), 0);
+    assert_eq!(client.bytes_sent(), 63);
+    assert_eq!(client.bytes_received(), 21);
     client.send(false).ok();
 
-    assert_eq!(client.bytes_sent(), 42);
-    assert_eq!(client.bytes_received(), 14);
+    assert_eq!(client.bytes_sent(), 80);
+    assert_eq!(client.bytes_received(), 38);
 
-    // Verify initial connection packets
+    // Verify the last challenge response and the initial connection packet
     client.socket().unwrap().assert_sent(vec![
         ("255.1.1.1:5678", [
             1, 2, 3, 4,
             9, 8, 7, 6,
-            1,
-            0,
-            0, 0, 0, 0
+            5, // challenge response
+            0, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9
 
         ].to_vec()),
         ("255.1.1.1:5678", [
             1, 2, 3, 4,
             9, 8, 7, 6,
-            2,
-            0,
+            1,
+            0, 0,
+            0, 0,
             0, 0, 0, 0
 
         ].to_vec())
     ]);
 
     // Send messages to server
-    client.connection().unwrap().send(MessageKind::Instant, b"Foo".to_vec());
-    client.connection().unwrap().send(MessageKind::Reliable, b"Bar".to_vec());
+    client.connection().unwrap().send(MessageKind::Instant, b"Foo".to_vec()).ok();
+    client.connection().unwrap().send(MessageKind::Reliable, b"Bar".to_vec()).ok();
 
     // Packets should not be send before the next send() call
     client.socket().unwrap().assert_sent_none();
@@ -425,8 +664,9 @@ fn test_client_send() {
         ("255.1.1.1:5678", [
             1, 2, 3, 4,
             9, 8, 7, 6,
-            3,
             0,
+            0, 1,
+            0, 0,
             0, 0, 0, 0,
             0, 0, 0, 3, 70, 111, 111,
             1, 0, 0, 3, 66, 97, 114
@@ -434,7 +674,7 @@ fn test_client_send() {
         ].to_vec())
     ]);
 
-    assert_eq!(client.bytes_sent(), 70);
+    assert_eq!(client.bytes_sent(), 111);
 
 }
 
@@ -455,7 +695,8 @@ fn test_client_receive() {
             (id >> 8) as u8,
              id as u8,
             0,
-            0,
+            0, 0,
+            0, 0,
             0, 0, 0, 0,
             1, 0, 0, 3, 70, 111, 111,
             0, 0, 0, 3, 66, 97, 114
@@ -469,10 +710,10 @@ fn test_client_receive() {
     ]);
 
     // Stats should not be updated before next send() call
-    assert_eq!(client.bytes_received(), 0);
+    assert_eq!(client.bytes_received(), 21);
 
     client.send(false).ok();
-    assert_eq!(client.bytes_received(), 28);
+    assert_eq!(client.bytes_received(), 52);
 
     // Ignore duplicates
     client.socket().unwrap().mock_receive(vec![
@@ -483,7 +724,8 @@ fn test_client_receive() {
             (id >> 8) as u8,
              id as u8,
             0,
-            0,
+            0, 0,
+            0, 0,
             0, 0, 0, 0,
             1, 0, 0, 3, 70, 111, 111,
             0, 0, 0, 3, 66, 97, 114
@@ -497,8 +739,9 @@ fn test_client_receive() {
             (id >> 16) as u8,
             (id >> 8) as u8,
              id as u8,
-            1,
             0,
+            0, 1,
+            0, 0,
             0, 0, 0, 0,
             0, 0, 0, 3, 66, 97, 122
         ])
@@ -522,8 +765,9 @@ fn test_client_close_by_remote() {
             (id >> 16) as u8,
             (id >> 8) as u8,
              id as u8,
-            0,
-            0,
+            1,
+            0, 0,
+            0, 0,
             0, 0, 0, 0
         ])
     ]);
@@ -540,8 +784,9 @@ fn test_client_close_by_remote() {
             (id >> 16) as u8,
             (id >> 8) as u8,
              id as u8,
-            0, 128, // Most distant sequence numbers
-            85, 85, 85, 85 // ack bitfield with every second bit set
+            2, // closure packet type
+            0, 0, 0, 0, // sequence numbers
+            0, 0, 0, 0 // ack bitfield
         ])
     ]);
 
@@ -564,8 +809,9 @@ fn test_client_close_by_local() {
             (id >> 16) as u8,
             (id >> 8) as u8,
              id as u8,
-            0,
-            0,
+            1,
+            0, 0,
+            0, 0,
             0, 0, 0, 0
         ])
     ]);
@@ -581,9 +827,8 @@ fn test_client_close_by_local() {
             (id >> 16) as u8,
             (id >> 8) as u8,
              id as u8,
-            1,
-            0,
-            0, 0, 0, 0
+            5, // challenge response
+            0, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9
 
         ].to_vec())
     ]);
@@ -600,9 +845,10 @@ fn test_client_close_by_local() {
             (id >> 16) as u8,
             (id >> 8) as u8,
              id as u8,
-            0,
-            128,
-            85, 85, 85, 85
+            2,
+            0, 0,
+            0, 0,
+            0, 0, 0, 0
 
         ].to_vec())
     ]);
@@ -679,7 +925,7 @@ fn client_init(config: Config) -> Client<MockSocket, BinaryRateLimiter, NoopPack
     let mut client = Client::<MockSocket, BinaryRateLimiter, NoopPacketModifier>::new(config);
     client.connect("255.1.1.1:5678").ok();
 
-    // Verify initial connection packet
+    // Verify initial connection request
     let id = client.connection().unwrap().id().0;
     client.send(false).ok();
     client.socket().unwrap().assert_sent(vec![
@@ -689,9 +935,31 @@ fn client_init(config: Config) -> Client<MockSocket, BinaryRateLimiter, NoopPack
             (id >> 16) as u8,
             (id >> 8) as u8,
              id as u8,
-            0,
-            0,
-            0, 0, 0, 0
+            3, // connect request
+            0, 1, 0, 0, 0, 0, // protocol version
+            0, 0, 0, 0, 0, 0 // padding
+
+        ].to_vec())
+    ]);
+
+    // Mock the receival of the server's challenge
+    client.socket().unwrap().mock_receive(vec![
+        ("255.1.1.1:5678", challenge_packet(id))
+    ]);
+
+    assert_eq!(client.receive(), Err(TryRecvError::Empty));
+
+    // Verify that the challenge cookie is returned to the server
+    client.send(false).ok();
+    client.socket().unwrap().assert_sent(vec![
+        ("255.1.1.1:5678", [
+            1, 2, 3, 4,
+            (id >> 24) as u8,
+            (id >> 16) as u8,
+            (id >> 8) as u8,
+             id as u8,
+            5, // challenge response
+            0, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9 // cookie
 
         ].to_vec())
     ]);
@@ -700,6 +968,18 @@ fn client_init(config: Config) -> Client<MockSocket, BinaryRateLimiter, NoopPack
 
 }
 
+fn challenge_packet(id: u32) -> Vec<u8> {
+    vec![
+        1, 2, 3, 4,
+        (id >> 24) as u8,
+        (id >> 16) as u8,
+        (id >> 8) as u8,
+         id as u8,
+        4, // challenge
+        0, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9 // cookie
+    ]
+}
+
 fn client_events(client: &mut Client<MockSocket, BinaryRateLimiter, NoopPacketModifier>) -> Vec<ClientEvent> {
     client.send(false).ok();
     let mut events = Vec::new();
@@ -0,0 +1,108 @@
+// Copyright (c) 2015-2017 Ivo Wetzel
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Internal Dependencies ------------------------------------------------------
+use ::{
+    CompressionDictionary, Config, ConnectionID, PacketContext, PacketModifier
+};
+#[cfg(feature = "lz4")]
+use ::Lz4PacketModifier;
+#[cfg(feature = "deflate")]
+use ::DeflatePacketModifier;
+
+
+// Tests ----------------------------------------------------------------------
+#[test]
+#[cfg(feature = "lz4")]
+fn test_lz4_packet_modifier() {
+    assert_compression::<Lz4PacketModifier>();
+}
+
+#[test]
+#[cfg(feature = "lz4")]
+fn test_lz4_packet_modifier_dictionary() {
+    assert_dictionary::<Lz4PacketModifier, Lz4PacketModifier<GreetingDictionary>>();
+}
+
+#[test]
+#[cfg(feature = "deflate")]
+fn test_deflate_packet_modifier() {
+    assert_compression::<DeflatePacketModifier>();
+}
+
+#[test]
+#[cfg(feature = "deflate")]
+fn test_deflate_packet_modifier_dictionary() {
+    assert_dictionary::<DeflatePacketModifier, DeflatePacketModifier<GreetingDictionary>>();
+}
+
+
+// Helpers --------------------------------------------------------------------
+struct GreetingDictionary;
+
+impl CompressionDictionary for GreetingDictionary {
+    fn dictionary() -> &'static [u8] {
+        b"Hello World, how are you doing today?"
+    }
+}
+
+fn assert_compression<M: PacketModifier>() {
+
+    let header = [0; 14];
+    let context = context(&header);
+    let mut modifier = M::new(Config::default());
+
+    assert_eq!(modifier.max_overhead(), 1);
+
+    // Empty payloads stay unmodified
+    assert_eq!(modifier.outgoing_packet(&[], &context), None);
+    assert_eq!(modifier.incoming_packet(&[], &context).unwrap(), None);
+
+    // Compressible payloads shrink
+    let payload = vec![42; 512];
+    let compressed = modifier.outgoing_packet(&payload, &context).unwrap();
+    assert_eq!(compressed[0], 1);
+    assert!(compressed.len() < payload.len());
+    assert_eq!(modifier.incoming_packet(&compressed, &context).unwrap(), Some(payload));
+
+    // Incompressible payloads are send as is
+    let payload = b"Foo".to_vec();
+    let uncompressed = modifier.outgoing_packet(&payload, &context).unwrap();
+    assert_eq!(uncompressed, vec![0, 70, 111, 111]);
+    assert_eq!(modifier.incoming_packet(&uncompressed, &context).unwrap(), Some(payload));
+
+    // Unknown flags and corrupted data are rejected
+    assert!(modifier.incoming_packet(&[2, 70, 111, 111], &context).is_err());
+    assert!(modifier.incoming_packet(&[1, 255, 255, 255, 255], &context).is_err());
+
+}
+
+fn assert_dictionary<M: PacketModifier, D: PacketModifier>() {
+
+    let header = [0; 14];
+    let context = context(&header);
+    let mut plain = M::new(Config::default());
+    let mut with_dictionary = D::new(Config::default());
+
+    let payload = b"Hello World, how are you doing?".to_vec();
+    let compressed = with_dictionary.outgoing_packet(&payload, &context).unwrap();
+    assert_eq!(compressed[0], 1);
+    assert!(compressed.len() < plain.outgoing_packet(&payload, &context).unwrap().len());
+    assert_eq!(with_dictionary.incoming_packet(&compressed, &context).unwrap(), Some(payload));
+
+}
+
+fn context(header: &[u8]) -> PacketContext<'_> {
+    PacketContext {
+        id: ConnectionID(0),
+        peer_address: "127.0.0.1:1234".parse().unwrap(),
+        local_sequence: 0,
+        remote_sequence: 0,
+        header
+    }
+}
@@ -0,0 +1,87 @@
+// Copyright (c) 2015-2017 Ivo Wetzel
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// STD Dependencies -----------------------------------------------------------
+use std::time::{Duration, SystemTime};
+
+
+// Internal Dependencies ------------------------------------------------------
+use ::ConnectToken;
+use shared::TokenVerifier;
+
+
+#[test]
+fn test_token_sign_verify() {
+
+    let token = ConnectToken::new(
+        1234567890123,
+        "[2001:db8::1]:7000".parse().unwrap(),
+        Duration::from_secs(60),
+        vec![1, 2, 3, 4, 5]
+    );
+
+    let signed = token.sign(b"key");
+    assert_eq!(signed.len(), 8 + 8 + 8 + 1 + 16 + 2 + 2 + 5 + 32);
+    assert_eq!(ConnectToken::verify(&signed, b"key"), Some(token.clone()));
+
+    // Each signature uses a new nonce
+    assert!(token.sign(b"key") != signed);
+
+    // Wrong keys are detected
+    assert_eq!(ConnectToken::verify(&signed, b"other key"), None);
+
+    // Any modification is detected
+    for i in 0..signed.len() {
+        let mut modified = signed.clone();
+        modified[i] ^= 1;
+        assert_eq!(ConnectToken::verify(&modified, b"key"), None);
+    }
+
+    // Truncated tokens are detected
+    for i in 0..signed.len() {
+        assert_eq!(ConnectToken::verify(&signed[..i], b"key"), None);
+    }
+
+}
+
+#[test]
+fn test_token_verifier() {
+
+    let token = ConnectToken::new(
+        1,
+        "127.0.0.1:1234".parse().unwrap(),
+        Duration::from_secs(60),
+        Vec::new()
+    );
+
+    let mut verifier = TokenVerifier::new(b"key", "127.0.0.1:1234".parse().unwrap());
+
+    let signed = token.sign(b"key");
+    assert_eq!(verifier.verify(&signed), Some(token.clone()));
+
+    // Verification alone does not use up a token
+    assert_eq!(verifier.verify(&signed), Some(token.clone()));
+
+    verifier.consume(&signed, token.expires);
+    assert_eq!(verifier.verify(&signed), None);
+
+    // Other tokens for the same user are still accepted
+    let again = token.sign(b"key");
+    assert_eq!(verifier.verify(&again), Some(token.clone()));
+
+    // Expired tokens are rejected
+    let mut expired = token.clone();
+    expired.expires = SystemTime::now() - Duration::from_secs(1);
+    assert_eq!(verifier.verify(&expired.sign(b"key")), None);
+
+    // Tokens for other servers are rejected
+    let mut other = token.clone();
+    other.server_address = "127.0.0.1:1235".parse().unwrap();
+    assert_eq!(verifier.verify(&other.sign(b"key")), None);
+
+}
@@ -8,17 +8,20 @@
 
 // STD Dependencies -----------------------------------------------------------
 use std::f32;
+use std::rc::Rc;
+use std::cell::Cell;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::net::SocketAddr;
+use std::io::{Error, ErrorKind};
 
 
 // Internal Dependencies ------------------------------------------------------
 use super::MockSocket;
 use ::{
     Connection, ConnectionID, ConnectionState, ConnectionEvent, Socket,
-    Config, MessageKind, PacketModifier, BinaryRateLimiter, NoopPacketModifier,
-    RateLimiter
+    Config, MessageID, MessageKind, PacketContext, PacketModifier, BinaryRateLimiter, NoopPacketModifier,
+    RateLimiter, RequestID
 };
 
 macro_rules! assert_f32_eq {
@@ -122,8 +125,9 @@ fn test_close_local() {
     conn.receive_packet([
         1, 2, 3, 4,
         0, 0, 0, 0, // ConnectionID is ignored by receive_packet)
-        0, // local sequence number
-        0, // remote sequence number we confirm
+        1, // packet type
+        0, 0, // local sequence number
+        0, 0, // remote sequence number we confirm
         0, 0, 0, 0 // bitfield
 
     ].to_vec());
@@ -142,7 +146,10 @@ fn test_close_local() {
         (conn.id().0 >> 8) as u8,
          conn.id().0 as u8,
 
-        0, 128, 85, 85, 85, 85  // closure packet data
+        2, // closure packet type
+        0, 0, // local sequence number
+        0, 0, // remote sequence number
+        0, 0, 0, 0 // ack bitfield
 
     ].to_vec())]);
 
@@ -157,7 +164,10 @@ fn test_close_local() {
         (conn.id().0 >> 8) as u8,
          conn.id().0 as u8,
 
-        0, 128, 85, 85, 85, 85
+        2,
+        0, 1,
+        0, 0,
+        0, 0, 0, 0
 
     ].to_vec())]);
 
@@ -184,8 +194,9 @@ fn test_close_remote() {
     conn.receive_packet([
         1, 2, 3, 4,
         0, 0, 0, 0, // ConnectionID is ignored by receive_packet)
-        0, // local sequence number
-        0, // remote sequence number we confirm
+        1, // packet type
+        0, 0, // local sequence number
+        0, 0, // remote sequence number we confirm
         0, 0, 0, 0 // bitfield
 
     ].to_vec());
@@ -199,7 +210,10 @@ fn test_close_remote() {
     conn.receive_packet([
         1, 2, 3, 4,
         0, 0, 0, 0, // ConnectionID is ignored by receive_packet)
-        0, 128, 85, 85, 85, 85 // closure packet data
+        2, // closure packet type
+        0, 1, // local sequence number
+        0, 0, // remote sequence number
+        0, 0, 0, 0 // ack bitfield
 
     ].to_vec());
 
@@ -228,7 +242,10 @@ fn test_connecting_failed() {
     conn.receive_packet([
         1, 2, 3, 4,
         0, 0, 0, 0,
-        0, 128, 85, 85, 85, 85 // closure packet data
+        2, // closure packet type
+        0, 1, // local sequence number
+        0, 0, // remote sequence number
+        0, 0, 0, 0 // ack bitfield
 
     ].to_vec());
 
@@ -255,25 +272,25 @@ fn test_send_sequence_wrap_around() {
     let mut socket = MockSocket::new(conn.local_addr(), 0).unwrap();
     let address = conn.peer_addr();
 
-    for i in 0..256 {
+    for i in 0..65536 {
 
         conn.send_packet(&mut socket, &address);
 
-        socket.assert_sent(vec![("255.1.1.2:5678", [
-            // protocol id
-            1, 2, 3, 4,
-
-            // connection id
-            (conn.id().0 >> 24) as u8,
-            (conn.id().0 >> 16) as u8,
-            (conn.id().0 >> 8) as u8,
-             conn.id().0 as u8,
+        let packet = socket.sent().pop().unwrap().1;
+        assert_eq!(packet[9..11].to_vec(), vec![
+            (i >> 8) as u8, i as u8 // local sequence number
+        ]);
 
-            i as u8, // local sequence number
-            0, // remote sequence number
-            0, 0, 0, 0  // ack bitfield
+        // Acknowledge each packet right away so they do not pile up
+        conn.receive_packet([
+            1, 2, 3, 4,
+            0, 0, 0, 0,
+            1, // packet type
+            (i >> 8) as u8, i as u8, // remote sequence number
+            (i >> 8) as u8, i as u8, // local sequence number we confirm
+            0, 0, 0, 0
 
-        ].to_vec())]);
+        ].to_vec());
 
     }
 
@@ -289,12 +306,15 @@ fn test_send_sequence_wrap_around() {
         (conn.id().0 >> 8) as u8,
          conn.id().0 as u8,
 
-        0, // local sequence number
-        0, // remote sequence number
-        0, 0, 0, 0  // ack bitfield
+        1, // packet type
+        0, 0, // local sequence number
+        255, 255, // remote sequence number
+        127, 255, 255, 255  // ack bitfield
 
     ].to_vec())]);
 
+    assert_f32_eq!(conn.packet_loss(), 0.0);
+
 }
 
 #[test]
@@ -316,8 +336,9 @@ fn test_send_and_receive_packet() {
         (conn.id().0 >> 8) as u8,
          conn.id().0 as u8,
 
-        0, // local sequence number
-        0, // remote sequence number
+        1, // packet type
+        0, 0, // local sequence number
+        0, 0, // remote sequence number
         0, 0, 0, 0  // ack bitfield
 
     ].to_vec())]);
@@ -330,8 +351,9 @@ fn test_send_and_receive_packet() {
         (conn.id().0 >> 16) as u8,
         (conn.id().0 >> 8) as u8,
          conn.id().0 as u8,
-        1, // local sequence number
-        0,
+        1, // packet type
+        0, 1, // local sequence number
+        0, 0,
         0, 0, 0, 0
 
     ].to_vec())]);
@@ -348,8 +370,9 @@ fn test_send_and_receive_packet() {
         (conn.id().0 >> 8) as u8,
          conn.id().0 as u8,
 
-        2, // local sequence number
-        0,
+        1, // packet type
+        0, 2, // local sequence number
+        0, 0,
         0, 0, 0, 0
 
     ].to_vec())]);
@@ -358,8 +381,9 @@ fn test_send_and_receive_packet() {
     conn.receive_packet([
         1, 2, 3, 4,
         0, 0, 0, 0, // ConnectionID is ignored by receive_packet)
-        17, // local sequence number
-        2, // remote sequence number we confirm
+        1, // packet type
+        0, 17, // local sequence number
+        0, 2, // remote sequence number we confirm
         0, 0, 0, 3, // confirm the first two packets
 
     ].to_vec());
@@ -371,8 +395,9 @@ fn test_send_and_receive_packet() {
     conn.receive_packet([
         1, 2, 3, 4,
         0, 0, 0, 0, // ConnectionID is ignored by receive_packet)
-        18, // local sequence number
-        3, // remote sequence number we confirm
+        1, // packet type
+        0, 18, // local sequence number
+        0, 3, // remote sequence number we confirm
         0, 0, 0, 0
 
     ].to_vec());
@@ -380,8 +405,9 @@ fn test_send_and_receive_packet() {
     conn.receive_packet([
         1, 2, 3, 4,
         0, 0, 0, 0, // ConnectionID is ignored by receive_packet)
-        19, // local sequence number
-        4, // remote sequence number we confirm
+        1, // packet type
+        0, 19, // local sequence number
+        0, 4, // remote sequence number we confirm
         0, 0, 0, 0
 
     ].to_vec());
@@ -389,8 +415,9 @@ fn test_send_and_receive_packet() {
     conn.receive_packet([
         1, 2, 3, 4,
         0, 0, 0, 0, // ConnectionID is ignored by receive_packet)
-        27, // local sequence number
-        4, // remote sequence number we confirm
+        1, // packet type
+        0, 27, // local sequence number
+        0, 4, // remote sequence number we confirm
         0, 0, 0, 0
 
     ].to_vec());
@@ -404,8 +431,9 @@ fn test_send_and_receive_packet() {
         (conn.id().0 >> 8) as u8,
          conn.id().0 as u8,
 
-        3, // local sequence number
-        27, // remove sequence number set by receive_packet)
+        1, // packet type
+        0, 3, // local sequence number
+        0, 27, // remove sequence number set by receive_packet)
 
         // Ack bitfield
         0, 0, 3, 128 // 0000_0000 0000_0000 0000_0011 1000_0000
@@ -425,8 +453,9 @@ fn test_receive_packet() {
     assert_eq!(conn.receive_packet([
         1, 2, 3, 4,
         0, 0, 0, 0, // ConnectionID is ignored by receive_packet)
-        0, // local sequence number
-        0, // remote sequence number we confirm
+        1, // packet type
+        0, 0, // local sequence number
+        0, 0, // remote sequence number we confirm
         0, 0, 0, 0
 
     ].to_vec()), true);
@@ -435,8 +464,9 @@ fn test_receive_packet() {
     assert_eq!(conn.receive_packet([
         1, 2, 3, 4,
         0, 0, 0, 0, // ConnectionID is ignored by receive_packet)
-        0, // local sequence number
-        0, // remote sequence number we confirm
+        1, // packet type
+        0, 0, // local sequence number
+        0, 0, // remote sequence number we confirm
         0, 0, 0, 0
 
     ].to_vec()), false);
@@ -444,8 +474,9 @@ fn test_receive_packet() {
     assert_eq!(conn.receive_packet([
         1, 2, 3, 4,
         0, 0, 0, 0, // ConnectionID is ignored by receive_packet)
-        1, // local sequence number
-        0, // remote sequence number we confirm
+        1, // packet type
+        0, 1, // local sequence number
+        0, 0, // remote sequence number we confirm
         0, 0, 0, 0
 
     ].to_vec()), true);
@@ -453,8 +484,9 @@ fn test_receive_packet() {
     assert_eq!(conn.receive_packet([
         1, 2, 3, 4,
         0, 0, 0, 0, // ConnectionID is ignored by receive_packet)
-        2, // local sequence number
-        0, // remote sequence number we confirm
+        1, // packet type
+        0, 2, // local sequence number
+        0, 0, // remote sequence number we confirm
         0, 0, 0, 0
 
     ].to_vec()), true);
@@ -463,8 +495,9 @@ fn test_receive_packet() {
     assert_eq!(conn.receive_packet([
         1, 2, 3, 4,
         0, 0, 0, 0, // ConnectionID is ignored by receive_packet)
-        1, // local sequence number
-        0, // remote sequence number we confirm
+        1, // packet type
+        0, 1, // local sequence number
+        0, 0, // remote sequence number we confirm
         0, 0, 0, 0
 
     ].to_vec()), false);
@@ -481,8 +514,9 @@ fn test_receive_packet_ack_overflow() {
         conn.receive_packet([
             1, 2, 3, 4,
             0, 0, 0, 0,
-            i,
-            0,
+            1,
+            0, i,
+            0, 0,
             0, 0, 0, 0
 
         ].to_vec());
@@ -500,8 +534,9 @@ fn test_receive_packet_ack_overflow() {
         (conn.id().0 >> 8) as u8,
          conn.id().0 as u8,
 
-        0, // local sequence number
-        32, // remote sequence to ack
+        1, // packet type
+        0, 0, // local sequence number
+        0, 32, // remote sequence to ack
 
         // The remote sequence values is already confirmed via the value above
         // so the highest bit is not set in this case
@@ -519,11 +554,11 @@ fn test_send_and_receive_messages() {
     let address = conn.peer_addr();
 
     // Test Message Sending
-    conn.send(MessageKind::Instant, b"Foo".to_vec());
-    conn.send(MessageKind::Instant, b"Bar".to_vec());
-    conn.send(MessageKind::Reliable, b"Test".to_vec());
-    conn.send(MessageKind::Ordered, b"Hello".to_vec());
-    conn.send(MessageKind::Ordered, b"World".to_vec());
+    conn.send(MessageKind::Instant, b"Foo".to_vec()).ok();
+    conn.send(MessageKind::Instant, b"Bar".to_vec()).ok();
+    conn.send(MessageKind::Reliable, b"Test".to_vec()).ok();
+    conn.send(MessageKind::Ordered, b"Hello".to_vec()).ok();
+    conn.send(MessageKind::Ordered, b"World".to_vec()).ok();
 
     conn.send_packet(&mut socket, &address);
     socket.assert_sent(vec![
@@ -534,7 +569,8 @@ fn test_send_and_receive_messages() {
             (conn.id().0 >> 8) as u8,
              conn.id().0 as u8,
             0,
-            0,
+            0, 0,
+            0, 0,
             0, 0, 0, 0,
 
             // Foo
@@ -547,10 +583,10 @@ fn test_send_and_receive_messages() {
             1, 0, 0, 4, 84, 101, 115, 116,
 
             // Hello
-            2, 0, 0, 5, 72, 101, 108, 108, 111,
+            2, 0, 0, 5, 0, 72, 101, 108, 108, 111,
 
             // World
-            2, 1, 0, 5, 87, 111, 114, 108, 100
+            2, 1, 0, 5, 0, 87, 111, 114, 108, 100
 
         ].to_vec())
     ]);
@@ -560,7 +596,8 @@ fn test_send_and_receive_messages() {
         1, 2, 3, 4,
         0, 0, 0, 0,
         0,
-        0,
+        0, 0,
+        0, 0,
         0, 0, 0, 0,
 
         // Foo
@@ -575,10 +612,10 @@ fn test_send_and_receive_messages() {
         // We actually test inverse receiving order here!
 
         // World
-        2, 1, 0, 5, 87, 111, 114, 108, 100,
+        2, 1, 0, 5, 0, 87, 111, 114, 108, 100,
 
         // Hello
-        2, 0, 0, 5, 72, 101, 108, 108, 111
+        2, 0, 0, 5, 0, 72, 101, 108, 108, 111
 
     ].to_vec());
 
@@ -587,6 +624,11 @@ fn test_send_and_receive_messages() {
 
     assert_eq!(messages, vec![
         ConnectionEvent::Connected,
+        ConnectionEvent::MessageDelivered(MessageID(0)),
+        ConnectionEvent::MessageDelivered(MessageID(1)),
+        ConnectionEvent::MessageDelivered(MessageID(2)),
+        ConnectionEvent::MessageDelivered(MessageID(3)),
+        ConnectionEvent::MessageDelivered(MessageID(4)),
         ConnectionEvent::Message(b"Foo".to_vec()),
         ConnectionEvent::Message(b"Bar".to_vec()),
         ConnectionEvent::Message(b"Test".to_vec()),
@@ -598,8 +640,9 @@ fn test_send_and_receive_messages() {
     conn.receive_packet([
         1, 2, 3, 4,
         0, 0, 0, 0,
-        1,
-        1,
+        0,
+        0, 1,
+        0, 1,
         0, 0, 0, 0,
 
         // Foo
@@ -617,7 +660,8 @@ fn test_send_and_receive_messages() {
             (conn.id().0 >> 8) as u8,
              conn.id().0 as u8,
             1,
-            1,
+            0, 1,
+            0, 1,
             0, 0, 0, 1
 
         ].to_vec())
@@ -629,32 +673,54 @@ fn test_send_and_receive_messages() {
 }
 
 #[test]
-fn test_receive_invalid_packets() {
+fn test_send_and_receive_fragmented_messages() {
 
     let mut conn = create_connection(None);
+    let mut remote = create_connection(None);
+    let mut socket = MockSocket::new(conn.local_addr(), 0).unwrap();
+    let address = conn.peer_addr();
 
-    // Empty packet
-    conn.receive_packet([].to_vec());
+    // Messages larger than a single packet are split up
+    let data: Vec<u8> = (0..4000u32).map(|i| i as u8).collect();
+    conn.send(MessageKind::Ordered, data.clone()).ok();
 
-    // Garbage packet
-    conn.receive_packet([
-        1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14
+    for _ in 0..3 {
+        conn.send_packet(&mut socket, &address);
+    }
 
-    ].to_vec());
+    let packets: Vec<Vec<u8>> = socket.sent().into_iter().map(|p| p.1).collect();
+    assert_eq!(packets.len(), 3);
+
+    // And reassembled by the remote connection
+    for packet in packets {
+        assert!(packet.len() <= 1400);
+        remote.receive_packet(packet);
+    }
+
+    let events: Vec<ConnectionEvent> = remote.events().collect();
+    assert_eq!(events, vec![
+        ConnectionEvent::Connected,
+        ConnectionEvent::Message(data)
+    ]);
 
 }
 
 #[test]
-fn test_rtt() {
+fn test_send_expired_messages() {
 
-    let mut conn = create_connection(None);
+    let mut config = Config::default();
+    config.message_ttl[MessageKind::Instant as usize] = Some(Duration::from_millis(0));
+
+    let mut conn = create_connection(Some(config));
     let mut socket = MockSocket::new(conn.local_addr(), 0).unwrap();
     let address = conn.peer_addr();
 
-    assert_eq!(conn.rtt(), 0);
-
-    // First packet
+    let foo = conn.send(MessageKind::Instant, b"Foo".to_vec()).unwrap();
+    let bar = conn.send(MessageKind::Reliable, b"Bar".to_vec()).unwrap();
+    assert!(conn.set_message_deadline(bar, Instant::now()));
+    conn.send(MessageKind::Reliable, b"Baz".to_vec()).ok();
     conn.send_packet(&mut socket, &address);
+
     socket.assert_sent(vec![
         ("255.1.1.2:5678", [
             1, 2, 3, 4,
@@ -663,162 +729,95 @@ fn test_rtt() {
             (conn.id().0 >> 8) as u8,
              conn.id().0 as u8,
             0,
-            0,
-            0, 0, 0, 0
+            0, 0,
+            0, 0,
+            0, 0, 0, 0,
+
+            // Placeholder of Bar
+            5, 0, 0, 0,
+
+            // Baz
+            1, 1, 0, 3, 66, 97, 122
+
         ].to_vec())
     ]);
 
-    thread::sleep(Duration::from_millis(500));
-    conn.receive_packet([
-        1, 2, 3, 4,
-        0, 0, 0, 0,
-        0,
-        0, // confirm the packet above
-        0, 0,
-        0, 0
+    let events: Vec<ConnectionEvent> = conn.events().collect();
+    assert_eq!(events, vec![
+        ConnectionEvent::MessageExpired(foo),
+        ConnectionEvent::MessageExpired(bar)
+    ]);
 
-    ].to_vec());
+}
 
-    // Expect RTT value to have moved by 10% of the overall roundtrip time
-    assert!(conn.rtt() >= 40);
+#[test]
+fn test_send_queue_drained() {
 
-    // Second packet
-    conn.send_packet(&mut socket, &address);
-    socket.assert_sent(vec![
-        ("255.1.1.2:5678", [
-            1, 2, 3, 4,
-            (conn.id().0 >> 24) as u8,
-            (conn.id().0 >> 16) as u8,
-            (conn.id().0 >> 8) as u8,
-             conn.id().0 as u8,
-            1,
-            0,
-            0, 0, 0, 0
-        ].to_vec())
-    ]);
-    conn.receive_packet([
-        1, 2, 3, 4,
-        0, 0, 0, 0,
-        1,
-        1, // confirm the packet above
-        0, 0,
-        0, 0
+    let mut conn = create_connection(Some(Config {
+        send_queue_max_messages: 1,
+        .. Config::default()
+    }));
+    let mut socket = MockSocket::new(conn.local_addr(), 0).unwrap();
+    let address = conn.peer_addr();
 
-    ].to_vec());
+    conn.send(MessageKind::Instant, b"Foo".to_vec()).unwrap();
+    assert_eq!(conn.send(MessageKind::Instant, b"Bar".to_vec()).unwrap_err().kind(), ErrorKind::WouldBlock);
 
-    // Third packet
     conn.send_packet(&mut socket, &address);
-    socket.assert_sent(vec![
-        ("255.1.1.2:5678", [
-            1, 2, 3, 4,
-            (conn.id().0 >> 24) as u8,
-            (conn.id().0 >> 16) as u8,
-            (conn.id().0 >> 8) as u8,
-             conn.id().0 as u8,
-            2,
-            1,
-            0, 0, 0, 1
-        ].to_vec())
-    ]);
-    conn.receive_packet([
-        1, 2, 3, 4,
-        0, 0, 0, 0,
-        2,
-        2, // confirm the packet above
-        0, 0,
-        0, 0
 
-    ].to_vec());
+    let events: Vec<ConnectionEvent> = conn.events().collect();
+    assert_eq!(events, vec![ConnectionEvent::SendQueueDrained]);
 
-    // Fourth packet
-    conn.send_packet(&mut socket, &address);
-    socket.assert_sent(vec![
-        ("255.1.1.2:5678", [
-            1, 2, 3, 4,
-            (conn.id().0 >> 24) as u8,
-            (conn.id().0 >> 16) as u8,
-            (conn.id().0 >> 8) as u8,
-             conn.id().0 as u8,
-            3,
-            2,
-            0, 0, 0, 3
-        ].to_vec())
-    ]);
-    conn.receive_packet([
-        1, 2, 3, 4,
-        0, 0, 0, 0,
-        3,
-        3, // confirm the packet above
-        0, 0,
-        0, 0
+    conn.send(MessageKind::Instant, b"Bar".to_vec()).unwrap();
 
-    ].to_vec());
+}
 
-    // Fifth packet
-    conn.send_packet(&mut socket, &address);
-    socket.assert_sent(vec![
-        ("255.1.1.2:5678", [
-            1, 2, 3, 4,
-            (conn.id().0 >> 24) as u8,
-            (conn.id().0 >> 16) as u8,
-            (conn.id().0 >> 8) as u8,
-             conn.id().0 as u8,
-            4,
-            3,
-            0, 0, 0, 7
-        ].to_vec())
-    ]);
-    conn.receive_packet([
-        1, 2, 3, 4,
-        0, 0, 0, 0,
-        4,
-        4, // confirm the packet above
-        0, 0,
-        0, 0
+#[test]
+fn test_send_queue_introspection() {
 
-    ].to_vec());
+    let mut conn = create_connection(None);
+    let mut socket = MockSocket::new(conn.local_addr(), 0).unwrap();
+    let address = conn.peer_addr();
+
+    conn.send(MessageKind::Instant, b"Foo".to_vec()).unwrap();
+    conn.send(MessageKind::Reliable, b"Bar".to_vec()).unwrap();
+    assert_eq!(conn.queued_messages(MessageKind::Instant), 1);
+    assert_eq!(conn.queued_messages(MessageKind::Reliable), 1);
+    assert_eq!(conn.queued_bytes(), 6);
+    assert!(conn.oldest_queued_age().is_some());
+    assert_eq!(conn.unacked_reliable_messages(), 0);
 
-    // Sixth packet
     conn.send_packet(&mut socket, &address);
-    socket.assert_sent(vec![
-        ("255.1.1.2:5678", [
-            1, 2, 3, 4,
-            (conn.id().0 >> 24) as u8,
-            (conn.id().0 >> 16) as u8,
-            (conn.id().0 >> 8) as u8,
-             conn.id().0 as u8,
-            5,
-            4,
-            0, 0, 0, 15
-        ].to_vec())
-    ]);
+    assert_eq!(conn.queued_bytes(), 0);
+    assert_eq!(conn.oldest_queued_age(), None);
+    assert_eq!(conn.unacked_reliable_messages(), 1);
 
+    // Acknowledge the packet
     conn.receive_packet([
         1, 2, 3, 4,
         0, 0, 0, 0,
-        5,
-        5, // confirm the packet above
+        0,
+        0, 0,
         0, 0,
-        0, 0
+        0, 0, 0, 0
 
     ].to_vec());
 
-    // Expect RTT to have reduced by 10%
-    assert!(conn.rtt() <= 40);
+    assert_eq!(conn.unacked_reliable_messages(), 0);
 
 }
 
 #[test]
-fn test_rtt_tick_correction() {
+fn test_send_keyed_messages() {
 
     let mut conn = create_connection(None);
     let mut socket = MockSocket::new(conn.local_addr(), 0).unwrap();
     let address = conn.peer_addr();
 
-    assert_eq!(conn.rtt(), 0);
-
-    // First packet
+    let foo = conn.send_keyed(MessageKind::Instant, 17, b"Foo".to_vec()).unwrap();
+    conn.send_keyed(MessageKind::Instant, 17, b"Bar".to_vec()).ok();
     conn.send_packet(&mut socket, &address);
+
     socket.assert_sent(vec![
         ("255.1.1.2:5678", [
             1, 2, 3, 4,
@@ -827,45 +826,36 @@ fn test_rtt_tick_correction() {
             (conn.id().0 >> 8) as u8,
              conn.id().0 as u8,
             0,
-            0,
-            0, 0, 0, 0
-        ].to_vec())
-    ]);
+            0, 0,
+            0, 0,
+            0, 0, 0, 0,
 
-    thread::sleep(Duration::from_millis(500));
-    conn.receive_packet([
-        1, 2, 3, 4,
-        0, 0, 0, 0,
-        0,
-        0, // confirm the packet above
-        0, 0,
-        0, 0
+            // Bar
+            0, 0, 0, 3, 66, 97, 114
 
-    ].to_vec());
+        ].to_vec())
+    ]);
 
-    // Expect RTT value to have been reduced after normal tick
-    assert!(conn.rtt() <= 55);
+    let events: Vec<ConnectionEvent> = conn.events().collect();
+    assert_eq!(events, vec![ConnectionEvent::MessageExpired(foo)]);
 
 }
 
 #[test]
-fn test_packet_loss() {
+fn test_cancel_messages() {
 
-    let config = Config {
-        // set a low threshold for packet loss
+    let mut conn = create_connection(Some(Config {
         packet_drop_threshold: Duration::from_millis(10),
         .. Config::default()
-    };
-
-    let mut conn = create_connection(Some(config));
+    }));
     let mut socket = MockSocket::new(conn.local_addr(), 0).unwrap();
     let address = conn.peer_addr();
 
-    conn.send(MessageKind::Instant, b"Packet Instant".to_vec());
-    conn.send(MessageKind::Reliable, b"Packet Reliable".to_vec());
-    conn.send(MessageKind::Ordered, b"Packet Ordered".to_vec());
-
+    let foo = conn.send(MessageKind::Reliable, b"Foo".to_vec()).unwrap();
+    let bar = conn.send(MessageKind::Reliable, b"Bar".to_vec()).unwrap();
+    assert!(conn.cancel(foo));
     conn.send_packet(&mut socket, &address);
+
     socket.assert_sent(vec![
         ("255.1.1.2:5678", [
             1, 2, 3, 4,
@@ -874,83 +864,815 @@ fn test_packet_loss() {
             (conn.id().0 >> 8) as u8,
              conn.id().0 as u8,
             0,
-            0,
+            0, 0,
+            0, 0,
             0, 0, 0, 0,
 
-            // Packet 1
-            0, 0, 0, 14, 80, 97, 99, 107, 101, 116, 32, 73, 110, 115, 116, 97, 110, 116,
-
-            // Packet 2
-            1, 0, 0, 15, 80, 97, 99, 107, 101, 116, 32, 82, 101, 108, 105, 97, 98, 108, 101,
+            // Placeholder of Foo
+            5, 0, 0, 0,
 
-            // Packet 3
-            2, 0, 0, 14, 80, 97, 99, 107, 101, 116, 32, 79, 114, 100, 101, 114, 101, 100
+            // Bar
+            1, 1, 0, 3, 66, 97, 114
 
         ].to_vec())
     ]);
 
-    let events: Vec<ConnectionEvent> = conn.events().collect();
-    assert_eq!(events.len(), 0);
-
-    assert_f32_eq!(conn.packet_loss(), 0.0);
-
-    // Wait a bit so the packets will definitely get dropped
+    // Cancel the message after it was send and let its packet get lost
+    assert!(conn.cancel(bar));
     thread::sleep(Duration::from_millis(20));
-
-    // Now receive a packet and check for the lost packets
     conn.receive_packet([
         1, 2, 3, 4,
         0, 0, 0, 0,
-        0, 2, 0, 0, // Set ack seq to non-0 so we trigger the packet loss
-        0, 0
+        1,
+        0, 0, 0, 2,
+        0, 0, 0, 0
 
     ].to_vec());
 
-    // RTT should be left untouched the lost packet
-    assert_eq!(conn.rtt(), 0);
-
-    // But packet loss should spike up
-    assert_f32_eq!(conn.packet_loss(), 100.0);
-
     let events: Vec<ConnectionEvent> = conn.events().collect();
     assert_eq!(events, vec![
         ConnectionEvent::Connected,
-        ConnectionEvent::PacketLost(vec![
-            0, 0, 0, 14, 80, 97, 99, 107, 101, 116, 32, 73, 110, 115, 116, 97, 110, 116,
-            1, 0, 0, 15, 80, 97, 99, 107, 101, 116, 32, 82, 101, 108, 105, 97, 98, 108, 101,
-            2, 0, 0, 14, 80, 97, 99, 107, 101, 116, 32, 79, 114, 100, 101, 114, 101, 100
-        ])
+        ConnectionEvent::PacketLost(vec![5, 0, 0, 0, 1, 1, 0, 3, 66, 97, 114]),
+        ConnectionEvent::MessageLost(bar)
     ]);
 
-    // The messages from the lost packet should have been re-inserted into
-    // the message_queue and should be send again with the next packet.
+    // The message is not re-send, only placeholders for both ids
     conn.send_packet(&mut socket, &address);
-    socket.assert_sent(vec![
-        ("255.1.1.2:5678", [
-            1, 2, 3, 4,
-            (conn.id().0 >> 24) as u8,
-            (conn.id().0 >> 16) as u8,
-            (conn.id().0 >> 8) as u8,
-             conn.id().0 as u8,
-            1,
-            0,
-            0, 0, 0, 0,
+    assert_eq!(socket.sent()[0].1[17..], [5, 1, 0, 0, 5, 0, 0, 0]);
 
-            // Packet 2
-            1, 0, 0, 15, 80, 97, 99, 107, 101, 116, 32, 82, 101, 108, 105, 97, 98, 108, 101,
+}
 
-            // Packet 3
-            2, 0, 0, 14, 80, 97, 99, 107, 101, 116, 32, 79, 114, 100, 101, 114, 101, 100
+#[test]
+fn test_request_response() {
 
-        ].to_vec())
-    ]);
+    let mut conn = create_connection(None);
+    let mut remote = create_connection(None);
+    let mut socket = MockSocket::new(conn.local_addr(), 0).unwrap();
+    let mut remote_socket = MockSocket::new(remote.local_addr(), 0).unwrap();
+    let address = conn.peer_addr();
 
-    // Fully receive the next packet
-    conn.receive_packet([
-        1, 2, 3, 4,
+    assert_eq!(conn.request(b"Ping".to_vec()).unwrap(), RequestID(0));
+    assert_eq!(conn.request(b"Fail".to_vec()).unwrap(), RequestID(1));
+    assert_eq!(conn.pending_requests(), 2);
+
+    conn.send_packet(&mut socket, &address);
+    for packet in socket.sent() {
+        remote.receive_packet(packet.1);
+    }
+
+    let events: Vec<ConnectionEvent> = remote.events().collect();
+    assert_eq!(events, vec![
+        ConnectionEvent::Connected,
+        ConnectionEvent::Request(RequestID(0), b"Ping".to_vec()),
+        ConnectionEvent::Request(RequestID(1), b"Fail".to_vec())
+    ]);
+
+    // Each request can only be responded to once
+    remote.respond(RequestID(0), Ok(b"Pong".to_vec())).unwrap();
+    remote.respond(RequestID(1), Err(b"Error".to_vec())).unwrap();
+    assert_eq!(remote.respond(RequestID(1), Ok(Vec::new())).unwrap_err().kind(), ErrorKind::NotFound);
+    assert_eq!(remote.respond(RequestID(2), Ok(Vec::new())).unwrap_err().kind(), ErrorKind::NotFound);
+
+    remote.send_packet(&mut remote_socket, &address);
+    for packet in remote_socket.sent() {
+        conn.receive_packet(packet.1);
+    }
+
+    let events: Vec<ConnectionEvent> = conn.events().collect();
+    assert_eq!(events, vec![
+        ConnectionEvent::Connected,
+        ConnectionEvent::MessageDelivered(MessageID(0)),
+        ConnectionEvent::MessageDelivered(MessageID(1)),
+        ConnectionEvent::Response(RequestID(0), Ok(b"Pong".to_vec())),
+        ConnectionEvent::Response(RequestID(1), Err(b"Error".to_vec()))
+    ]);
+
+    assert_eq!(conn.pending_requests(), 0);
+
+}
+
+#[test]
+fn test_request_timeout() {
+
+    let mut conn = create_connection(Some(Config {
+        request_timeout: Duration::from_millis(0),
+        .. Config::default()
+    }));
+    let mut socket = MockSocket::new(conn.local_addr(), 0).unwrap();
+    let address = conn.peer_addr();
+
+    conn.request(b"Ping".to_vec()).ok();
+    conn.send_packet(&mut socket, &address);
+
+    let events: Vec<ConnectionEvent> = conn.events().collect();
+    assert_eq!(events, vec![ConnectionEvent::RequestTimedOut(RequestID(0))]);
+    assert_eq!(conn.pending_requests(), 0);
+
+    // Late responses are ignored
+    conn.receive_packet([
+        1, 2, 3, 4,
+        0, 0, 0, 0,
+        0,
+        0, 0,
+        0, 0,
+        0, 0, 0, 0,
+        5, 0, 0, 9, 1, 0, 0, 0, 0, 80, 111, 110, 103
+
+    ].to_vec());
+
+    let events: Vec<ConnectionEvent> = conn.events().collect();
+    assert_eq!(events, vec![
+        ConnectionEvent::Connected,
+        ConnectionEvent::MessageDelivered(MessageID(0))
+    ]);
+
+}
+
+#[test]
+fn test_request_connection_failure() {
+
+    let mut conn = create_connection(Some(Config {
+        connection_init_threshold: Duration::from_millis(10),
+        .. Config::default()
+    }));
+    let mut socket = MockSocket::new(conn.local_addr(), 0).unwrap();
+    let address = conn.peer_addr();
+
+    conn.request(b"Ping".to_vec()).ok();
+    conn.request(b"Pong".to_vec()).ok();
+    conn.send_packet(&mut socket, &address);
+
+    // Pending requests fail along with the connection
+    thread::sleep(Duration::from_millis(20));
+    conn.send_packet(&mut socket, &address);
+
+    let events: Vec<ConnectionEvent> = conn.events().collect();
+    assert_eq!(events, vec![
+        ConnectionEvent::FailedToConnect,
+        ConnectionEvent::RequestTimedOut(RequestID(0)),
+        ConnectionEvent::RequestTimedOut(RequestID(1))
+    ]);
+
+}
+
+#[test]
+fn test_request_incoming_limit() {
+
+    let mut conn = create_connection(None);
+    let mut remote = create_connection(Some(Config {
+        request_max_incoming: 2,
+        .. Config::default()
+    }));
+    let mut socket = MockSocket::new(conn.local_addr(), 0).unwrap();
+    let mut remote_socket = MockSocket::new(remote.local_addr(), 0).unwrap();
+    let address = conn.peer_addr();
+
+    conn.request(b"Foo".to_vec()).ok();
+    conn.request(b"Bar".to_vec()).ok();
+    conn.request(b"Baz".to_vec()).ok();
+    conn.send_packet(&mut socket, &address);
+    for packet in socket.sent() {
+        remote.receive_packet(packet.1);
+    }
+
+    // Requests exceeding the limit are not emitted
+    let events: Vec<ConnectionEvent> = remote.events().collect();
+    assert_eq!(events, vec![
+        ConnectionEvent::Connected,
+        ConnectionEvent::Request(RequestID(0), b"Foo".to_vec()),
+        ConnectionEvent::Request(RequestID(1), b"Bar".to_vec())
+    ]);
+
+    // But are failed right away
+    remote.send_packet(&mut remote_socket, &address);
+    for packet in remote_socket.sent() {
+        conn.receive_packet(packet.1);
+    }
+
+    let events: Vec<ConnectionEvent> = conn.events().collect();
+    assert_eq!(events, vec![
+        ConnectionEvent::Connected,
+        ConnectionEvent::MessageDelivered(MessageID(0)),
+        ConnectionEvent::MessageDelivered(MessageID(1)),
+        ConnectionEvent::MessageDelivered(MessageID(2)),
+        ConnectionEvent::Response(RequestID(2), Err(Vec::new()))
+    ]);
+
+    assert_eq!(conn.pending_requests(), 2);
+
+    // Responding makes room for further requests
+    remote.respond(RequestID(0), Ok(Vec::new())).ok();
+    conn.request(b"Qux".to_vec()).ok();
+    conn.send_packet(&mut socket, &address);
+    for packet in socket.sent() {
+        remote.receive_packet(packet.1);
+    }
+
+    let events: Vec<ConnectionEvent> = remote.events().collect();
+    assert_eq!(events, vec![
+        ConnectionEvent::MessageDelivered(MessageID(0)),
+        ConnectionEvent::Request(RequestID(3), b"Qux".to_vec())
+    ]);
+
+}
+
+#[test]
+fn test_request_reset() {
+
+    let mut conn = create_connection(None);
+    conn.request(b"Foo".to_vec()).ok();
+    conn.request(b"Bar".to_vec()).ok();
+
+    // Requests in flight are reported once the connection is reset
+    conn.reset();
+    assert_eq!(conn.pending_requests(), 0);
+
+    let events: Vec<ConnectionEvent> = conn.events().collect();
+    assert_eq!(events, vec![
+        ConnectionEvent::RequestTimedOut(RequestID(0)),
+        ConnectionEvent::RequestTimedOut(RequestID(1))
+    ]);
+
+    // Ids start over
+    assert_eq!(conn.request(b"Baz".to_vec()).unwrap(), RequestID(0));
+
+}
+
+#[test]
+fn test_packet_producer() {
+
+    let mut conn = create_connection(None);
+    let mut socket = MockSocket::new(conn.local_addr(), 0).unwrap();
+    let address = conn.peer_addr();
+
+    let budget = Rc::new(Cell::new(0));
+    let available = budget.clone();
+    conn.set_packet_producer(move |bytes: usize, payload: &mut Vec<u8>| {
+        available.set(bytes);
+        payload.extend_from_slice(b"Bar");
+    });
+
+    conn.send(MessageKind::Reliable, b"Foo".to_vec()).ok();
+    conn.send_packet(&mut socket, &address);
+
+    // The producer fills the space left after the queued messages
+    assert_eq!(budget.get(), 1400 - 17 - 7 - 4);
+    socket.assert_sent(vec![
+        ("255.1.1.2:5678", [
+            1, 2, 3, 4,
+            (conn.id().0 >> 24) as u8,
+            (conn.id().0 >> 16) as u8,
+            (conn.id().0 >> 8) as u8,
+             conn.id().0 as u8,
+            0,
+            0, 0,
+            0, 0,
+            0, 0, 0, 0,
+
+            // Foo
+            1, 0, 0, 3, 70, 111, 111,
+
+            // Bar
+            0, 0, 0, 3, 66, 97, 114
+
+        ].to_vec())
+    ]);
+
+    conn.clear_packet_producer();
+    conn.send_packet(&mut socket, &address);
+
+    socket.assert_sent(vec![
+        ("255.1.1.2:5678", [
+            1, 2, 3, 4,
+            (conn.id().0 >> 24) as u8,
+            (conn.id().0 >> 16) as u8,
+            (conn.id().0 >> 8) as u8,
+             conn.id().0 as u8,
+            1,
+            0, 1,
+            0, 0,
+            0, 0, 0, 0
+
+        ].to_vec())
+    ]);
+
+}
+
+#[test]
+fn test_receive_invalid_packets() {
+
+    let mut conn = create_connection(None);
+
+    // Empty packet
+    conn.receive_packet([].to_vec());
+
+    // Garbage packet
+    conn.receive_packet([
+        1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17
+
+    ].to_vec());
+
+}
+
+#[test]
+fn test_receive_packet_types() {
+
+    let mut conn = create_connection(None);
+
+    // Closure packets cannot end a connection which was never established
+    assert!(!conn.receive_packet([
+        1, 2, 3, 4,
+        0, 0, 0, 0,
+        2, // closure packet type
+        0, 0,
+        0, 0,
+        0, 0, 0, 0
+
+    ].to_vec()));
+
+    // Handshake packets and unknown packet types are ignored
+    for packet_type in &[3, 4, 5, 6, 7, 255] {
+        assert!(!conn.receive_packet([
+            1, 2, 3, 4,
+            0, 0, 0, 0,
+            *packet_type,
+            0, 0,
+            0, 0,
+            0, 0, 0, 0
+
+        ].to_vec()));
+    }
+
+    assert!(conn.state() == ConnectionState::Connecting);
+    assert_eq!(conn.events().count(), 0);
+
+    // Data packets establish the connection
+    assert!(conn.receive_packet([
+        1, 2, 3, 4,
+        0, 0, 0, 0,
+        0, // data packet type
+        0, 0,
+        0, 0,
+        0, 0, 0, 0,
+        0, 0, 0, 3, 70, 111, 111
+
+    ].to_vec()));
+
+    let events: Vec<ConnectionEvent> = conn.events().collect();
+    assert_eq!(events, vec![
+        ConnectionEvent::Connected,
+        ConnectionEvent::Message(b"Foo".to_vec())
+    ]);
+
+}
+
+#[test]
+fn test_encryption() {
+
+    let mut conn = create_connection(None);
+    let mut remote = create_connection(None);
+    let mut socket = MockSocket::new(conn.local_addr(), 0).unwrap();
+    let address = conn.peer_addr();
+
+    assert!(!conn.encrypted());
+    conn.set_encryption_keys([1; 32], [2; 32]);
+    remote.set_encryption_keys([2; 32], [1; 32]);
+    assert!(conn.encrypted());
+
+    conn.send(MessageKind::Instant, b"Hello World".to_vec()).ok();
+    conn.send_packet(&mut socket, &address);
+
+    let packet = socket.sent().pop().unwrap().1;
+
+    // Header, packet counter, encrypted message and authentication tag
+    assert_eq!(packet.len(), 17 + 8 + 4 + 11 + 16);
+
+    // The header itself stays readable
+    assert_eq!(packet[8..25].to_vec(), vec![
+        0, // packet type
+        0, 0, 0, 0, 0, 0, 0, 0, // sequence numbers and bitfield
+        0, 0, 0, 0, 0, 0, 0, 0 // packet counter
+    ]);
+
+    assert!(!packet.windows(11).any(|w| w == b"Hello World"));
+
+    // Unencrypted packets are dropped
+    assert!(!remote.receive_packet([
+        1, 2, 3, 4,
+        0, 0, 0, 0,
+        1,
+        0, 0,
+        0, 0,
+        0, 0, 0, 0
+
+    ].to_vec()));
+
+    // Modifications of the header or the payload are detected
+    for i in 8..packet.len() {
+        let mut modified = packet.clone();
+        modified[i] ^= 1;
+        assert!(!remote.receive_packet(modified));
+    }
+
+    assert!(remote.state() == ConnectionState::Connecting);
+
+    // Authentic packets are accepted
+    assert!(remote.receive_packet(packet.clone()));
+    assert!(remote.state() == ConnectionState::Connected);
+
+    let events: Vec<ConnectionEvent> = remote.events().collect();
+    assert_eq!(events, vec![
+        ConnectionEvent::Connected,
+        ConnectionEvent::Message(b"Hello World".to_vec())
+    ]);
+
+    // Replayed packets are dropped
+    assert!(!remote.receive_packet(packet));
+
+    // Forged closure packets are dropped
+    remote.receive_packet([
+        1, 2, 3, 4,
+        0, 0, 0, 0,
+        2,
+        0, 2,
+        0, 0,
+        0, 0, 0, 0
+
+    ].to_vec());
+
+    assert!(remote.state() == ConnectionState::Connected);
+
+    // Authentic closure packets are accepted
+    conn.close();
+    conn.send_packet(&mut socket, &address);
+
+    let packet = socket.sent().pop().unwrap().1;
+    assert_eq!(packet.len(), 17 + 8 + 16);
+    remote.receive_packet(packet);
+
+    assert!(remote.state() == ConnectionState::Closed);
+
+    // Resetting the connection disables encryption
+    conn.reset();
+    assert!(!conn.encrypted());
+
+}
+
+#[test]
+fn test_encryption_exhausted() {
+
+    let mut conn = create_connection(None);
+    let mut socket = MockSocket::new(conn.local_addr(), 0).unwrap();
+    let address = conn.peer_addr();
+
+    conn.set_encryption_keys([1; 32], [2; 32]);
+    conn.set_encryption_counter(u64::MAX - 1);
+    conn.request(b"Foo".to_vec()).ok();
+
+    // The last available nonce is still used
+    conn.send(MessageKind::Instant, b"Hello World".to_vec()).ok();
+    assert!(conn.send_packet(&mut socket, &address) > 0);
+    assert_eq!(socket.sent().pop().unwrap().1[17..25].to_vec(), vec![
+        255, 255, 255, 255, 255, 255, 255, 254
+    ]);
+
+    // Afterwards packets are dropped instead of being send in plain text
+    conn.send(MessageKind::Instant, b"Hello World".to_vec()).ok();
+    assert_eq!(conn.send_packet(&mut socket, &address), 0);
+    assert!(socket.sent().is_empty());
+
+    assert!(conn.state() == ConnectionState::Lost);
+    let events: Vec<ConnectionEvent> = conn.events().collect();
+    assert_eq!(events, vec![
+        ConnectionEvent::Lost,
+        ConnectionEvent::RequestTimedOut(RequestID(0))
+    ]);
+
+    // Lost connections no longer send anything
+    assert_eq!(conn.send_packet(&mut socket, &address), 0);
+    assert!(socket.sent().is_empty());
+
+}
+
+#[test]
+fn test_rtt() {
+
+    let mut conn = create_connection(None);
+    let mut socket = MockSocket::new(conn.local_addr(), 0).unwrap();
+    let address = conn.peer_addr();
+
+    assert_eq!(conn.rtt(), 0);
+
+    // First packet
+    conn.send_packet(&mut socket, &address);
+    socket.assert_sent(vec![
+        ("255.1.1.2:5678", [
+            1, 2, 3, 4,
+            (conn.id().0 >> 24) as u8,
+            (conn.id().0 >> 16) as u8,
+            (conn.id().0 >> 8) as u8,
+             conn.id().0 as u8,
+            1,
+            0, 0,
+            0, 0,
+            0, 0, 0, 0
+        ].to_vec())
+    ]);
+
+    thread::sleep(Duration::from_millis(500));
+    conn.receive_packet([
+        1, 2, 3, 4,
+        0, 0, 0, 0,
+        1,
+        0, 0,
+        0, 0, // confirm the packet above
+        0, 0, 0, 0
+
+    ].to_vec());
+
+    // Expect RTT value to have moved by 10% of the overall roundtrip time
+    assert!(conn.rtt() >= 40);
+
+    // Second packet
+    conn.send_packet(&mut socket, &address);
+    socket.assert_sent(vec![
+        ("255.1.1.2:5678", [
+            1, 2, 3, 4,
+            (conn.id().0 >> 24) as u8,
+            (conn.id().0 >> 16) as u8,
+            (conn.id().0 >> 8) as u8,
+             conn.id().0 as u8,
+            1,
+            0, 1,
+            0, 0,
+            0, 0, 0, 0
+        ].to_vec())
+    ]);
+    conn.receive_packet([
+        1, 2, 3, 4,
+        0, 0, 0, 0,
+        1,
+        0, 1,
+        0, 1, // confirm the packet above
+        0, 0, 0, 0
+
+    ].to_vec());
+
+    // Third packet
+    conn.send_packet(&mut socket, &address);
+    socket.assert_sent(vec![
+        ("255.1.1.2:5678", [
+            1, 2, 3, 4,
+            (conn.id().0 >> 24) as u8,
+            (conn.id().0 >> 16) as u8,
+            (conn.id().0 >> 8) as u8,
+             conn.id().0 as u8,
+            1,
+            0, 2,
+            0, 1,
+            0, 0, 0, 1
+        ].to_vec())
+    ]);
+    conn.receive_packet([
+        1, 2, 3, 4,
+        0, 0, 0, 0,
+        1,
+        0, 2,
+        0, 2, // confirm the packet above
+        0, 0, 0, 0
+
+    ].to_vec());
+
+    // Fourth packet
+    conn.send_packet(&mut socket, &address);
+    socket.assert_sent(vec![
+        ("255.1.1.2:5678", [
+            1, 2, 3, 4,
+            (conn.id().0 >> 24) as u8,
+            (conn.id().0 >> 16) as u8,
+            (conn.id().0 >> 8) as u8,
+             conn.id().0 as u8,
+            1,
+            0, 3,
+            0, 2,
+            0, 0, 0, 3
+        ].to_vec())
+    ]);
+    conn.receive_packet([
+        1, 2, 3, 4,
+        0, 0, 0, 0,
+        1,
+        0, 3,
+        0, 3, // confirm the packet above
+        0, 0, 0, 0
+
+    ].to_vec());
+
+    // Fifth packet
+    conn.send_packet(&mut socket, &address);
+    socket.assert_sent(vec![
+        ("255.1.1.2:5678", [
+            1, 2, 3, 4,
+            (conn.id().0 >> 24) as u8,
+            (conn.id().0 >> 16) as u8,
+            (conn.id().0 >> 8) as u8,
+             conn.id().0 as u8,
+            1,
+            0, 4,
+            0, 3,
+            0, 0, 0, 7
+        ].to_vec())
+    ]);
+    conn.receive_packet([
+        1, 2, 3, 4,
+        0, 0, 0, 0,
+        1,
+        0, 4,
+        0, 4, // confirm the packet above
+        0, 0, 0, 0
+
+    ].to_vec());
+
+    // Sixth packet
+    conn.send_packet(&mut socket, &address);
+    socket.assert_sent(vec![
+        ("255.1.1.2:5678", [
+            1, 2, 3, 4,
+            (conn.id().0 >> 24) as u8,
+            (conn.id().0 >> 16) as u8,
+            (conn.id().0 >> 8) as u8,
+             conn.id().0 as u8,
+            1,
+            0, 5,
+            0, 4,
+            0, 0, 0, 15
+        ].to_vec())
+    ]);
+
+    conn.receive_packet([
+        1, 2, 3, 4,
+        0, 0, 0, 0,
+        1,
+        0, 5,
+        0, 5, // confirm the packet above
+        0, 0, 0, 0
+
+    ].to_vec());
+
+    // Expect RTT to have reduced by 10%
+    assert!(conn.rtt() <= 40);
+
+}
+
+#[test]
+fn test_rtt_tick_correction() {
+
+    let mut conn = create_connection(None);
+    let mut socket = MockSocket::new(conn.local_addr(), 0).unwrap();
+    let address = conn.peer_addr();
+
+    assert_eq!(conn.rtt(), 0);
+
+    // First packet
+    conn.send_packet(&mut socket, &address);
+    socket.assert_sent(vec![
+        ("255.1.1.2:5678", [
+            1, 2, 3, 4,
+            (conn.id().0 >> 24) as u8,
+            (conn.id().0 >> 16) as u8,
+            (conn.id().0 >> 8) as u8,
+             conn.id().0 as u8,
+            1,
+            0, 0,
+            0, 0,
+            0, 0, 0, 0
+        ].to_vec())
+    ]);
+
+    thread::sleep(Duration::from_millis(500));
+    conn.receive_packet([
+        1, 2, 3, 4,
+        0, 0, 0, 0,
+        1,
+        0, 0,
+        0, 0, // confirm the packet above
+        0, 0, 0, 0
+
+    ].to_vec());
+
+    // Expect RTT value to have been reduced after normal tick
+    assert!(conn.rtt() <= 55);
+
+}
+
+#[test]
+fn test_packet_loss() {
+
+    let config = Config {
+        // set a low threshold for packet loss
+        packet_drop_threshold: Duration::from_millis(10),
+        .. Config::default()
+    };
+
+    let mut conn = create_connection(Some(config));
+    let mut socket = MockSocket::new(conn.local_addr(), 0).unwrap();
+    let address = conn.peer_addr();
+
+    conn.send(MessageKind::Instant, b"Packet Instant".to_vec()).ok();
+    conn.send(MessageKind::Reliable, b"Packet Reliable".to_vec()).ok();
+    conn.send(MessageKind::Ordered, b"Packet Ordered".to_vec()).ok();
+
+    conn.send_packet(&mut socket, &address);
+    socket.assert_sent(vec![
+        ("255.1.1.2:5678", [
+            1, 2, 3, 4,
+            (conn.id().0 >> 24) as u8,
+            (conn.id().0 >> 16) as u8,
+            (conn.id().0 >> 8) as u8,
+             conn.id().0 as u8,
+            0,
+            0, 0,
+            0, 0,
+            0, 0, 0, 0,
+
+            // Packet 1
+            0, 0, 0, 14, 80, 97, 99, 107, 101, 116, 32, 73, 110, 115, 116, 97, 110, 116,
+
+            // Packet 2
+            1, 0, 0, 15, 80, 97, 99, 107, 101, 116, 32, 82, 101, 108, 105, 97, 98, 108, 101,
+
+            // Packet 3
+            2, 0, 0, 14, 0, 80, 97, 99, 107, 101, 116, 32, 79, 114, 100, 101, 114, 101, 100
+
+        ].to_vec())
+    ]);
+
+    let events: Vec<ConnectionEvent> = conn.events().collect();
+    assert_eq!(events.len(), 0);
+
+    assert_f32_eq!(conn.packet_loss(), 0.0);
+
+    // Wait a bit so the packets will definitely get dropped
+    thread::sleep(Duration::from_millis(20));
+
+    // Now receive a packet and check for the lost packets
+    conn.receive_packet([
+        1, 2, 3, 4,
+        0, 0, 0, 0,
+        1, // packet type
+        0, 0, 0, 2, // Set ack seq to non-0 so we trigger the packet loss
+        0, 0, 0, 0
+
+    ].to_vec());
+
+    // RTT should be left untouched the lost packet
+    assert_eq!(conn.rtt(), 0);
+
+    // But packet loss should spike up
+    assert_f32_eq!(conn.packet_loss(), 100.0);
+
+    let events: Vec<ConnectionEvent> = conn.events().collect();
+    assert_eq!(events, vec![
+        ConnectionEvent::Connected,
+        ConnectionEvent::PacketLost(vec![
+            0, 0, 0, 14, 80, 97, 99, 107, 101, 116, 32, 73, 110, 115, 116, 97, 110, 116,
+            1, 0, 0, 15, 80, 97, 99, 107, 101, 116, 32, 82, 101, 108, 105, 97, 98, 108, 101,
+            2, 0, 0, 14, 0, 80, 97, 99, 107, 101, 116, 32, 79, 114, 100, 101, 114, 101, 100
+        ]),
+        ConnectionEvent::MessageLost(MessageID(0))
+    ]);
+
+    // The messages from the lost packet should have been re-inserted into
+    // the message_queue and should be send again with the next packet.
+    conn.send_packet(&mut socket, &address);
+    socket.assert_sent(vec![
+        ("255.1.1.2:5678", [
+            1, 2, 3, 4,
+            (conn.id().0 >> 24) as u8,
+            (conn.id().0 >> 16) as u8,
+            (conn.id().0 >> 8) as u8,
+             conn.id().0 as u8,
+            0,
+            0, 1,
+            0, 0,
+            0, 0, 0, 0,
+
+            // Packet 2
+            1, 0, 0, 15, 80, 97, 99, 107, 101, 116, 32, 82, 101, 108, 105, 97, 98, 108, 101,
+
+            // Packet 3
+            2, 0, 0, 14, 0, 80, 97, 99, 107, 101, 116, 32, 79, 114, 100, 101, 114, 101, 100
+
+        ].to_vec())
+    ]);
+
+    // Fully receive the next packet
+    conn.receive_packet([
+        1, 2, 3, 4,
         0, 0, 0, 0,
-        0, 1, 0, 0,
-        0, 0
+        1,
+        0, 0, 0, 1,
+        0, 0, 0, 0
 
     ].to_vec());
 
@@ -1012,8 +1734,8 @@ fn test_packet_modification() {
     let address = conn.peer_addr();
 
     // First we send a packet to test compression
-    conn.send(MessageKind::Instant, b"Foo".to_vec());
-    conn.send(MessageKind::Instant, b"Bar".to_vec());
+    conn.send(MessageKind::Instant, b"Foo".to_vec()).ok();
+    conn.send(MessageKind::Instant, b"Bar".to_vec()).ok();
     conn.send_packet(&mut socket, &address);
     socket.assert_sent(vec![
         ("255.1.1.2:5678", [
@@ -1023,7 +1745,8 @@ fn test_packet_modification() {
             (conn.id().0 >> 8) as u8,
              conn.id().0 as u8,
             0,
-            0,
+            0, 0,
+            0, 0,
             0, 0, 0, 0
 
         ].to_vec())
@@ -1033,8 +1756,9 @@ fn test_packet_modification() {
     conn.receive_packet([
         1, 2, 3, 4,
         0, 0, 0, 0,
+        0,
+        0, 0, 0, 0,
         0, 0, 0, 0,
-        0, 0,
         1, 2, 3, 4, 128, 96, 7
 
     ].to_vec());
@@ -1042,12 +1766,126 @@ fn test_packet_modification() {
     let events: Vec<ConnectionEvent> = conn.events().collect();
     assert_eq!(events, vec![
         ConnectionEvent::Connected,
+        ConnectionEvent::MessageDelivered(MessageID(0)),
+        ConnectionEvent::MessageDelivered(MessageID(1)),
         ConnectionEvent::Message(b"Foo".to_vec()),
         ConnectionEvent::Message(b"Bar".to_vec())
     ]);
 
 }
 
+#[test]
+fn test_packet_modification_context() {
+
+    #[derive(Debug, Copy, Clone)]
+    struct ChecksumPacketModifier;
+
+    impl PacketModifier for ChecksumPacketModifier {
+
+        fn new(_: Config) -> ChecksumPacketModifier {
+            ChecksumPacketModifier
+        }
+
+        fn outgoing_packet(&mut self, data: &[u8], context: &PacketContext) -> Option<Vec<u8>> {
+
+            assert_eq!(context.id, ConnectionID(16909060));
+            assert_eq!(context.peer_address, "255.1.1.2:5678".parse().unwrap());
+            assert_eq!(context.local_sequence, context.header[10] as u32);
+            assert_eq!(context.remote_sequence, context.header[12] as u32);
+
+            // Append a checksum over the header and the payload
+            let mut payload = data.to_vec();
+            payload.push(checksum(context.header, data));
+            Some(payload)
+
+        }
+
+        fn incoming_packet(
+            &mut self,
+            data: &[u8],
+            context: &PacketContext
+
+        ) -> Result<Option<Vec<u8>>, Error> {
+
+            assert_eq!(context.id, ConnectionID(16909060));
+            assert_eq!(context.remote_sequence, context.header[10] as u32);
+
+            match data.split_last() {
+                Some((sum, payload)) if *sum == checksum(context.header, payload) => {
+                    Ok(Some(payload.to_vec()))
+                },
+                _ => Err(Error::new(ErrorKind::InvalidData, "Checksum mismatch"))
+            }
+
+        }
+
+    }
+
+    fn checksum(header: &[u8], payload: &[u8]) -> u8 {
+        header.iter().chain(payload).fold(0, |sum, b| sum ^ *b)
+    }
+
+    let mut conn = create_connection_with_modifier::<ChecksumPacketModifier>(None);
+    let mut remote = create_connection_with_modifier::<ChecksumPacketModifier>(None);
+    let mut socket = MockSocket::new(conn.local_addr(), 0).unwrap();
+    let address = conn.peer_addr();
+
+    conn.set_id(ConnectionID(16909060));
+    remote.set_id(ConnectionID(16909060));
+
+    conn.send(MessageKind::Instant, b"Foo".to_vec()).ok();
+    conn.send_packet(&mut socket, &address);
+    socket.assert_sent(vec![
+        ("255.1.1.2:5678", [
+            1, 2, 3, 4,
+            1, 2, 3, 4,
+            0,
+            0, 0,
+            0, 0,
+            0, 0, 0, 0,
+            0, 0, 0, 3, 70, 111, 111, // Foo
+            69 // checksum
+
+        ].to_vec())
+    ]);
+
+    // Packets rejected by the modifier are dropped without affecting the
+    // connection
+    assert!(!remote.receive_packet([
+        1, 2, 3, 4,
+        1, 2, 3, 4,
+        0,
+        0, 0,
+        0, 0,
+        0, 0, 0, 0,
+        0, 0, 0, 3, 70, 111, 111,
+        70
+
+    ].to_vec()));
+
+    assert!(remote.state() == ConnectionState::Connecting);
+    assert_eq!(remote.events().count(), 0);
+
+    assert!(remote.receive_packet([
+        1, 2, 3, 4,
+        1, 2, 3, 4,
+        0,
+        0, 0,
+        0, 0,
+        0, 0, 0, 0,
+        0, 0, 0, 3, 70, 111, 111,
+        69
+
+    ].to_vec()));
+
+    let events: Vec<ConnectionEvent> = remote.events().collect();
+    assert_eq!(events, vec![
+        ConnectionEvent::Connected,
+        ConnectionEvent::Message(b"Foo".to_vec())
+    ]);
+
+}
+
 
 // Helpers --------------------------------------------------------------------
 fn create_connection_with_modifier<T: PacketModifier>(config: Option<Config>) -> Connection<BinaryRateLimiter, T> {
@@ -6,9 +6,16 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+// STD Dependencies -----------------------------------------------------------
+use std::cmp;
+use std::thread;
+use std::io::ErrorKind;
+use std::time::{Duration, Instant};
+
+
 // Internal Dependencies ------------------------------------------------------
 use ::Config;
-use ::shared::message_queue::{MessageKind, MessageQueue};
+use ::shared::message_queue::{MessageID, MessageKind, MessageQueue};
 
 
 // Tests ----------------------------------------------------------------------
@@ -16,7 +23,7 @@ use ::shared::message_queue::{MessageKind, MessageQueue};
 fn test_debug_fmt() {
 
     let mut q = MessageQueue::new(Config::default());
-    q.send(MessageKind::Instant, b"Hello World".to_vec());
+    q.send(MessageKind::Instant, b"Hello World".to_vec()).ok();
 
     // Check debug fmt support
     assert_ne!(format!("{:?}", q), "");
@@ -29,34 +36,34 @@ fn test_send_write() {
     let mut q = MessageQueue::new(Config::default());
 
     // Filled from quota
-    q.send(MessageKind::Instant, b"Hello World".to_vec());
-    q.send(MessageKind::Instant, b"Hello World".to_vec());
+    q.send(MessageKind::Instant, b"Hello World".to_vec()).ok();
+    q.send(MessageKind::Instant, b"Hello World".to_vec()).ok();
 
     // Added by filling buffer
-    q.send(MessageKind::Instant, b"Hello World".to_vec());
+    q.send(MessageKind::Instant, b"Hello World".to_vec()).ok();
 
     // Put into packet 2
-    q.send(MessageKind::Instant, b"Hello World2".to_vec());
-    q.send(MessageKind::Instant, b"Hello World2".to_vec());
+    q.send(MessageKind::Instant, b"Hello World2".to_vec()).ok();
+    q.send(MessageKind::Instant, b"Hello World2".to_vec()).ok();
 
     // Filled from quota
-    q.send(MessageKind::Reliable, b"Foo".to_vec());
+    q.send(MessageKind::Reliable, b"Foo".to_vec()).ok();
 
     // Put into packet 2 by quota
-    q.send(MessageKind::Reliable, b"Foo2".to_vec());
+    q.send(MessageKind::Reliable, b"Foo2".to_vec()).ok();
 
     // Put into packet 2 by filling buffer
-    q.send(MessageKind::Reliable, b"Foo More".to_vec());
+    q.send(MessageKind::Reliable, b"Foo More".to_vec()).ok();
 
     // Filled from quota
-    q.send(MessageKind::Ordered, b"Bar".to_vec());
+    q.send(MessageKind::Ordered, b"Bar".to_vec()).ok();
 
     // Put into packet 2 by quota
-    q.send(MessageKind::Ordered, b"Bar2".to_vec());
+    q.send(MessageKind::Ordered, b"Bar2".to_vec()).ok();
 
     // Put into packet 3
-    q.send(MessageKind::Ordered, b"Bar More".to_vec());
-    q.send(MessageKind::Ordered, b"Bar Even More".to_vec());
+    q.send(MessageKind::Ordered, b"Bar More".to_vec()).ok();
+    q.send(MessageKind::Ordered, b"Bar Even More".to_vec()).ok();
 
     // Check Packet 1
     let mut buffer = Vec::new();
@@ -70,7 +77,7 @@ fn test_send_write() {
         // Foo
         1, 0, 0, 3, 70, 111, 111,
         // Bar
-        2, 0, 0, 3, 66, 97, 114,
+        2, 0, 0, 3, 0, 66, 97, 114,
         // Hello World
         0, 0, 0, 11, 72, 101, 108, 108, 111, 32, 87, 111, 114, 108, 100
 
@@ -86,11 +93,11 @@ fn test_send_write() {
         // Hello World2
         0, 0, 0, 12, 72, 101, 108, 108, 111, 32, 87, 111, 114, 108, 100, 50,
         // Foo2
-        1, 0, 0, 4, 70, 111, 111, 50,
+        1, 1, 0, 4, 70, 111, 111, 50,
         // Bar2
-        2, 1, 0, 4, 66, 97, 114, 50,
+        2, 1, 0, 4, 0, 66, 97, 114, 50,
         // Foo More
-        1, 0, 0, 8, 70, 111, 111, 32, 77, 111, 114, 101
+        1, 2, 0, 8, 70, 111, 111, 32, 77, 111, 114, 101
 
     ].to_vec());
 
@@ -100,10 +107,10 @@ fn test_send_write() {
 
     assert_eq!(buffer, [
         // Bar More
-        2, 2, 0, 8, 66, 97, 114, 32, 77, 111, 114, 101,
+        2, 2, 0, 8, 0, 66, 97, 114, 32, 77, 111, 114, 101,
 
         // Bar Even More
-        2, 3, 0, 13, 66, 97, 114, 32, 69, 118, 101, 110, 32, 77, 111, 114, 101
+        2, 3, 0, 13, 0, 66, 97, 114, 32, 69, 118, 101, 110, 32, 77, 111, 114, 101
     ].to_vec());
 
 }
@@ -121,7 +128,7 @@ fn test_send_write_long() {
         laborum";
 
     let mut q = MessageQueue::new(Config::default());
-    q.send(MessageKind::Instant, msg.to_vec());
+    q.send(MessageKind::Instant, msg.to_vec()).ok();
 
     let mut buffer = Vec::new();
     q.send_packet(&mut buffer, 1400);
@@ -176,15 +183,17 @@ fn test_packet_lost_write() {
         // Foo2
         1, 0, 0, 4, 70, 111, 111, 50,
         // Bar2
-        2, 1, 0, 4, 66, 97, 114, 50,
+        2, 1, 0, 4, 0, 66, 97, 114, 50,
         // Foo More
         1, 0, 0, 8, 70, 111, 111, 32, 77, 111, 114, 101
+    ], &[
+        MessageID(0), MessageID(1), MessageID(2), MessageID(3), MessageID(4)
     ]);
 
     // Send some more messages
-    q.send(MessageKind::Instant, b"Hello World".to_vec());
-    q.send(MessageKind::Reliable, b"Foo5".to_vec());
-    q.send(MessageKind::Ordered, b"Bar3".to_vec());
+    q.send(MessageKind::Instant, b"Hello World".to_vec()).ok();
+    q.send(MessageKind::Reliable, b"Foo5".to_vec()).ok();
+    q.send(MessageKind::Ordered, b"Bar3".to_vec()).ok();
 
     let mut buffer = Vec::new();
     q.send_packet(&mut buffer, 64);
@@ -197,13 +206,13 @@ fn test_packet_lost_write() {
         1, 0, 0, 8, 70, 111, 111, 32, 77, 111, 114, 101,
 
         // Bar2
-        2, 1, 0, 4, 66, 97, 114, 50,
+        2, 1, 0, 4, 0, 66, 97, 114, 50,
 
         // Foo2
         1, 0, 0, 4, 70, 111, 111, 50,
 
         // Bar3
-        2, 0, 0, 4, 66, 97, 114, 51,
+        2, 0, 0, 4, 0, 66, 97, 114, 51,
 
         // Foo5
         1, 0, 0, 4, 70, 111, 111, 53
@@ -224,7 +233,7 @@ fn test_receive_read() {
         // Foo
         1, 0, 0, 3, 70, 111, 111,
         // Bar
-        2, 0, 0, 3, 66, 97, 114,
+        2, 0, 0, 3, 0, 66, 97, 114,
         // Hello World
         0, 0, 0, 11, 72, 101, 108, 108, 111, 32, 87, 111, 114, 108, 100
     ].to_vec();
@@ -304,7 +313,7 @@ fn test_receive_read_out_of_order() {
 
     // Receive one out of order(#1) "World" message
     q.receive_packet(&[
-        2, 1, 0, 5, 87, 111, 114, 108, 100
+        2, 1, 0, 5, 0, 87, 111, 114, 108, 100
     ]);
 
     // We expect no message yet
@@ -312,7 +321,7 @@ fn test_receive_read_out_of_order() {
 
     // Receive one out of order(#3) "order!" message
     q.receive_packet(&[
-        2, 3, 0, 6, 111, 114, 100, 101, 114, 33
+        2, 3, 0, 6, 0, 111, 114, 100, 101, 114, 33
     ]);
 
     // We still expect no message yet
@@ -320,7 +329,7 @@ fn test_receive_read_out_of_order() {
 
     // Receive the actual first "Hello" message
     q.receive_packet(&[
-        2, 0, 0, 5, 72, 101, 108, 108, 111
+        2, 0, 0, 5, 0, 72, 101, 108, 108, 111
     ]);
 
     // We now expect both "Hello" and "World"
@@ -328,7 +337,7 @@ fn test_receive_read_out_of_order() {
 
     // Receive the order(#2) "out of" message
     q.receive_packet(&[
-        2, 2, 0, 6, 111, 117, 116, 32, 111, 102
+        2, 2, 0, 6, 0, 111, 117, 116, 32, 111, 102
     ]);
 
     // We now expect both "out of" and "order!"
@@ -387,7 +396,7 @@ fn test_receive_ordered_decoding_wrap_around() {
     for i in 0..4096 {
 
         q.receive_packet(&[
-            2 | ((i & 0x0F00) >> 4) as u8, (i as u8), 0, 2, (i >> 8) as u8, i as u8
+            2 | ((i & 0x0F00) >> 4) as u8, (i as u8), 0, 2, 0, (i >> 8) as u8, i as u8
         ]);
 
         assert_eq!(messages(&mut q), [[(i >> 8) as u8, i as u8]]);
@@ -396,7 +405,7 @@ fn test_receive_ordered_decoding_wrap_around() {
 
     // Should now expect order=0 again
     q.receive_packet(&[
-        2, 0, 0, 2, 0, 0
+        2, 0, 0, 2, 0, 0, 0
     ]);
     assert_eq!(messages(&mut q), [[0, 0]]);
 
@@ -408,22 +417,23 @@ fn test_receive_ordered_encoding_wrap_around() {
     let mut q = MessageQueue::new(Config::default());
     for i in 0..4096 {
 
-        q.send(MessageKind::Ordered, [(i >> 8) as u8, i as u8].to_vec());
+        q.send(MessageKind::Ordered, [(i >> 8) as u8, i as u8].to_vec()).ok();
 
         let mut buffer = Vec::new();
-        q.send_packet(&mut buffer, 64);
+        let ids = q.send_packet(&mut buffer, 64);
         assert_eq!(buffer, [
-            2 | ((i & 0x0F00) >> 4) as u8, (i as u8), 0, 2, (i >> 8) as u8, i as u8].to_vec()
+            2 | ((i & 0x0F00) >> 4) as u8, (i as u8), 0, 2, 0, (i >> 8) as u8, i as u8].to_vec()
         );
+        q.acked_packet(&ids);
 
     }
 
     // Should now write order=0 again
-    q.send(MessageKind::Ordered, [0, 0].to_vec());
+    q.send(MessageKind::Ordered, [0, 0].to_vec()).ok();
 
     let mut buffer = Vec::new();
     q.send_packet(&mut buffer, 64);
-    assert_eq!(buffer, [2, 0, 0, 2, 0, 0].to_vec());
+    assert_eq!(buffer, [2, 0, 0, 2, 0, 0, 0].to_vec());
 
 }
 
@@ -431,11 +441,11 @@ fn test_receive_ordered_encoding_wrap_around() {
 fn test_reset() {
 
     let mut q = MessageQueue::new(Config::default());
-    q.send(MessageKind::Instant, b"Hello World".to_vec());
-    q.send(MessageKind::Instant, b"Hello World".to_vec());
-    q.send(MessageKind::Reliable, b"Hello World".to_vec());
-    q.send(MessageKind::Ordered, b"Hello World".to_vec());
-    q.send(MessageKind::Ordered, b"Hello World".to_vec());
+    q.send(MessageKind::Instant, b"Hello World".to_vec()).ok();
+    q.send(MessageKind::Instant, b"Hello World".to_vec()).ok();
+    q.send(MessageKind::Reliable, b"Hello World".to_vec()).ok();
+    q.send(MessageKind::Ordered, b"Hello World".to_vec()).ok();
+    q.send(MessageKind::Ordered, b"Hello World".to_vec()).ok();
 
     // Reset all queues and order ids
     q.reset();
@@ -446,9 +456,9 @@ fn test_reset() {
     assert_eq!(buffer, [].to_vec());
 
     // Check that local_order_id has been reset
-    q.send(MessageKind::Ordered, b"".to_vec());
+    q.send(MessageKind::Ordered, b"".to_vec()).ok();
     q.send_packet(&mut buffer, 64);
-    assert_eq!(buffer, [2, 0, 0, 0].to_vec());
+    assert_eq!(buffer, [2, 0, 0, 0, 0].to_vec());
 }
 
 #[test]
@@ -457,13 +467,13 @@ fn test_in_order_duplicates() {
     let mut q = MessageQueue::new(Config::default());
 
     q.receive_packet(&[
-        2, 0, 0, 1, 53, // Expected #1
-        2, 1, 0, 1, 54, // Expected #2
-        2, 1, 0, 1, 55,
-        2, 1, 0, 1, 56,
-        2, 2, 0, 1, 57, // Expected #3
-        2, 2, 0, 1, 58,
-        2, 3, 0, 1, 59  // Expected #4
+        2, 0, 0, 1, 0, 53, // Expected #1
+        2, 1, 0, 1, 0, 54, // Expected #2
+        2, 1, 0, 1, 0, 55,
+        2, 1, 0, 1, 0, 56,
+        2, 2, 0, 1, 0, 57, // Expected #3
+        2, 2, 0, 1, 0, 58,
+        2, 3, 0, 1, 0, 59  // Expected #4
     ]);
 
     assert_eq!(messages(&mut q), [[53], [54], [57], [59]]);
@@ -476,19 +486,1440 @@ fn test_out_of_order_duplicates() {
     let mut q = MessageQueue::new(Config::default());
 
     q.receive_packet(&[
-        2, 0, 0, 1, 53, // Expected #1
-        2, 2, 0, 1, 54, // Expected #3
-        2, 2, 0, 1, 55,
-        2, 2, 0, 1, 56,
-        2, 1, 0, 1, 57, // Expected #2
-        2, 4, 0, 1, 58, // Expected #5
-        2, 3, 0, 1, 59  // Expected #4
+        2, 0, 0, 1, 0, 53, // Expected #1
+        2, 2, 0, 1, 0, 54, // Expected #3
+        2, 2, 0, 1, 0, 55,
+        2, 2, 0, 1, 0, 56,
+        2, 1, 0, 1, 0, 57, // Expected #2
+        2, 4, 0, 1, 0, 58, // Expected #5
+        2, 3, 0, 1, 0, 59  // Expected #4
     ]);
 
     assert_eq!(messages(&mut q), [[53], [57], [54], [59], [58]]);
 
 }
 
+#[test]
+fn test_send_write_ordered_channels() {
+
+    let mut q = MessageQueue::new(Config {
+        message_quotas: [0.0, 0.0, 0.0, 50.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+        .. Config::default()
+    });
+
+    // Each channel has its own order ids
+    q.send(MessageKind::Ordered, b"Foo".to_vec()).ok();
+    q.send_ordered(0, b"Bar".to_vec()).ok();
+    q.send_ordered(1, b"Hello".to_vec()).ok();
+    q.send_ordered(1, b"World".to_vec()).ok();
+
+    let mut buffer = Vec::new();
+    q.send_packet(&mut buffer, 40);
+    assert_eq!(buffer, [
+        // Hello (filled from quota of channel 1)
+        2, 0, 0, 5, 1, 72, 101, 108, 108, 111,
+        // World (filled from quota of channel 1)
+        2, 1, 0, 5, 1, 87, 111, 114, 108, 100,
+        // Foo
+        2, 0, 0, 3, 0, 70, 111, 111,
+        // Bar
+        2, 1, 0, 3, 0, 66, 97, 114
+    ].to_vec());
+
+}
+
+#[test]
+fn test_receive_read_ordered_channels() {
+
+    let mut q = MessageQueue::new(Config::default());
+    q.receive_packet(&[
+        // Channel 1, order #1
+        2, 1, 0, 1, 1, 49,
+        // Channel 0, order #0
+        2, 0, 0, 1, 0, 53,
+        // Channel 0, order #1
+        2, 1, 0, 1, 0, 54,
+        // Unknown channel
+        2, 0, 0, 1, 8, 55
+    ]);
+
+    // Channel 1 waits for its own missing message only
+    assert_eq!(messages(&mut q), [[53], [54]]);
+
+    q.receive_packet(&[
+        // Channel 1, order #0
+        2, 0, 0, 1, 1, 48
+    ]);
+
+    assert_eq!(messages(&mut q), [[48], [49]]);
+
+}
+
+#[test]
+fn test_send_write_sequenced() {
+
+    let mut q = MessageQueue::new(Config::default());
+    q.send(MessageKind::Sequenced, b"Foo".to_vec()).ok();
+    q.send_sequenced(0, b"Bar".to_vec()).ok();
+    q.send_sequenced(5, b"Baz".to_vec()).ok();
+
+    let mut buffer = Vec::new();
+    let ids = q.send_packet(&mut buffer, 64);
+    assert_eq!(buffer, [
+        // Foo
+        3, 0, 0, 3, 0, 70, 111, 111,
+        // Bar
+        3, 1, 0, 3, 0, 66, 97, 114,
+        // Baz
+        3, 0, 0, 3, 5, 66, 97, 122
+    ].to_vec());
+
+    // Lost sequenced messages are not re-send
+    assert_eq!(q.lost_packet(&buffer[..], &ids), ids);
+
+    let mut buffer = Vec::new();
+    q.send_packet(&mut buffer, 64);
+    assert_eq!(buffer, [].to_vec());
+
+}
+
+#[test]
+fn test_receive_read_sequenced() {
+
+    let mut q = MessageQueue::new(Config::default());
+    q.receive_packet(&[
+        3, 1, 0, 1, 0, 49, // Stream 0, #1
+        3, 0, 0, 1, 0, 48, // Stream 0, #0 (stale)
+        3, 0, 0, 1, 1, 53, // Stream 1, #0
+        3, 3, 0, 1, 0, 51, // Stream 0, #3
+        3, 1, 0, 1, 0, 49, // Stream 0, #1 (duplicate)
+        3, 2, 0, 1, 0, 50  // Stream 0, #2 (stale)
+    ]);
+
+    assert_eq!(messages(&mut q), [[49], [53], [51]]);
+
+}
+
+#[test]
+fn test_receive_sequenced_wrap_around() {
+
+    let mut q = MessageQueue::new(Config::default());
+    for i in 0..4096 {
+
+        q.receive_packet(&[
+            3 | ((i & 0x0F00) >> 4) as u8, (i as u8), 0, 2, 0, (i >> 8) as u8, i as u8
+        ]);
+
+        assert_eq!(messages(&mut q), [[(i >> 8) as u8, i as u8]]);
+
+    }
+
+    // Sequence 0 is now more recent than 4095 again
+    q.receive_packet(&[
+        3, 0, 0, 2, 0, 0, 0
+    ]);
+    assert_eq!(messages(&mut q), [[0, 0]]);
+
+}
+
+#[test]
+fn test_send_write_fragmented() {
+
+    let mut q = MessageQueue::new(Config::default());
+    q.send(MessageKind::Reliable, b"Hello World, Hello".to_vec()).ok();
+    q.send(MessageKind::Ordered, b"Foo Bar Baz Qux!!".to_vec()).ok();
+
+    // Instant messages which can never fit are dropped
+    let dropped = q.send(MessageKind::Instant, b"Hello World, Hello".to_vec()).unwrap();
+
+    let mut buffer = Vec::new();
+    q.send_packet(&mut buffer, 20);
+    assert_eq!(q.expired(), [dropped]);
+    assert_eq!(buffer, [
+        // Hello Worl (fragment 0 of 2)
+        9, 0, 0, 16, 0, 0, 0, 0, 0, 2,
+        72, 101, 108, 108, 111, 32, 87, 111, 114, 108
+    ].to_vec());
+
+    let mut buffer = Vec::new();
+    q.send_packet(&mut buffer, 20);
+    assert_eq!(buffer, [
+        // d, Hello (fragment 1 of 2)
+        9, 0, 0, 14, 0, 0, 0, 1, 0, 2,
+        100, 44, 32, 72, 101, 108, 108, 111
+    ].to_vec());
+
+    let mut buffer = Vec::new();
+    q.send_packet(&mut buffer, 20);
+    assert_eq!(buffer, [
+        // Foo Bar B (fragment 0 of 2)
+        10, 0, 0, 15, 0, 0, 1, 0, 0, 0, 2,
+        70, 111, 111, 32, 66, 97, 114, 32, 66
+    ].to_vec());
+
+    let mut buffer = Vec::new();
+    q.send_packet(&mut buffer, 20);
+    assert_eq!(buffer, [
+        // az Qux!! (fragment 1 of 2)
+        10, 0, 0, 14, 0, 0, 1, 0, 1, 0, 2,
+        97, 122, 32, 81, 117, 120, 33, 33
+    ].to_vec());
+
+    let mut buffer = Vec::new();
+    q.send_packet(&mut buffer, 20);
+    assert_eq!(buffer, [].to_vec());
+
+}
+
+#[test]
+fn test_send_receive_fragmented_large() {
+
+    let mut q = MessageQueue::new(Config::default());
+    let mut r = MessageQueue::new(Config::default());
+
+    // Messages of the maximum size are split into fragments which fit into
+    // the available space
+    let data: Vec<u8> = (0..65535u32).map(|i| i as u8).collect();
+    q.send(MessageKind::Reliable, data.clone()).ok();
+
+    let mut packets = 0;
+    loop {
+        let mut buffer = Vec::new();
+        q.send_packet(&mut buffer, 1400);
+        if buffer.is_empty() {
+            break;
+        }
+        r.receive_packet(&buffer[..]);
+        packets += 1;
+    }
+
+    assert_eq!(packets, 48);
+    assert_eq!(messages(&mut r), [data]);
+
+}
+
+#[test]
+fn test_send_oversized() {
+
+    let mut q = MessageQueue::new(Config::default());
+
+    // Messages exceeding the maximum size are rejected instead of being
+    // dropped once they fail to fragment
+    for kind in [MessageKind::Instant, MessageKind::Reliable, MessageKind::Ordered, MessageKind::Sequenced] {
+        let err = q.send(kind, vec![0; 65536]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+
+    let err = q.send_ordered(1, vec![0; 65536]).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+
+    q.send_keyed(MessageKind::Reliable, 1, b"Foo".to_vec()).ok();
+    let err = q.send_keyed(MessageKind::Reliable, 1, vec![0; 65536]).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+
+    assert_eq!(q.queued_messages(MessageKind::Reliable), 1);
+    assert_eq!(q.queued_messages(MessageKind::Ordered), 0);
+    assert_eq!(q.queued_bytes(), 3);
+    assert_eq!(q.unacked_reliable_messages(), 0);
+
+}
+
+#[test]
+fn test_fragment_lost_write() {
+
+    let mut q = MessageQueue::new(Config::default());
+    let mut r = MessageQueue::new(Config::default());
+    q.send(MessageKind::Reliable, b"Hello World, Hello".to_vec()).ok();
+
+    let mut lost = Vec::new();
+    let ids = q.send_packet(&mut lost, 20);
+    assert!(q.lost_packet(&lost[..], &ids).is_empty());
+
+    // Only the lost fragment gets re-send
+    let mut buffer = Vec::new();
+    q.send_packet(&mut buffer, 20);
+    assert_eq!(buffer, [
+        9, 0, 0, 16, 0, 0, 0, 0, 0, 2,
+        72, 101, 108, 108, 111, 32, 87, 111, 114, 108
+    ].to_vec());
+    r.receive_packet(&buffer[..]);
+    assert!(messages(&mut r).is_empty());
+
+    let mut buffer = Vec::new();
+    q.send_packet(&mut buffer, 20);
+    assert_eq!(buffer, [
+        9, 0, 0, 14, 0, 0, 0, 1, 0, 2,
+        100, 44, 32, 72, 101, 108, 108, 111
+    ].to_vec());
+    r.receive_packet(&buffer[..]);
+    assert_eq!(messages(&mut r), [b"Hello World, Hello".to_vec()]);
+
+}
+
+#[test]
+fn test_receive_read_fragmented() {
+
+    let mut q = MessageQueue::new(Config::default());
+    q.receive_packet(&[
+        // Order #1, fragment 1 of 2
+        10, 1, 0, 8, 0, 0, 2, 0, 1, 0, 2, 51, 52,
+        // Reliable, fragment 1 of 2
+        9, 0, 0, 8, 0, 0, 0, 1, 0, 2, 49, 50,
+        // Order #1, fragment 0 of 2
+        10, 1, 0, 8, 0, 0, 2, 0, 0, 0, 2, 53, 54
+    ]);
+
+    // Order #1 is held back until order #0 has been received
+    assert!(messages(&mut q).is_empty());
+
+    q.receive_packet(&[
+        // Reliable, fragment 0 of 2
+        9, 0, 0, 8, 0, 0, 0, 0, 0, 2, 55, 56,
+        // Duplicate
+        9, 0, 0, 8, 0, 0, 0, 0, 0, 2, 55, 56,
+        // Order #0, fragment 0 of 1
+        10, 0, 0, 7, 0, 0, 1, 0, 0, 0, 1, 57
+    ]);
+
+    assert_eq!(messages(&mut q), [
+        [55, 56, 49, 50].to_vec(),
+        [57].to_vec(),
+        [53, 54, 51, 52].to_vec()
+    ]);
+
+    // Late duplicates of reassembled messages are ignored
+    q.receive_packet(&[
+        9, 0, 0, 8, 0, 0, 0, 1, 0, 2, 49, 50,
+        9, 0, 0, 8, 0, 0, 0, 0, 0, 2, 55, 56
+    ]);
+
+    assert!(messages(&mut q).is_empty());
+
+}
+
+#[test]
+fn test_receive_invalid_fragments() {
+
+    let mut q = MessageQueue::new(Config::default());
+    q.receive_packet(&[
+        // Missing fragment header
+        9, 0, 0, 2, 0, 0,
+        // Index out of bounds
+        9, 0, 0, 7, 0, 1, 0, 2, 0, 2, 49,
+        // Mismatching fragment count
+        9, 0, 0, 7, 0, 2, 0, 0, 0, 2, 49,
+        9, 0, 0, 7, 0, 2, 0, 1, 0, 3, 50
+    ]);
+
+    assert!(messages(&mut q).is_empty());
+
+}
+
+#[test]
+fn test_receive_fragments_of_unfragmented_kinds() {
+
+    let mut q = MessageQueue::new(Config::default());
+    q.receive_packet(&[
+        // Instant, fragment 0 of 1
+        8, 0, 0, 7, 0, 0, 0, 0, 0, 1, 49,
+        // Sequenced, fragment 0 of 1
+        11, 0, 0, 7, 0, 0, 1, 0, 0, 0, 1, 50
+    ]);
+
+    assert!(messages(&mut q).is_empty());
+    assert_eq!(q.buffered_fragment_bytes(), 0);
+
+}
+
+#[test]
+fn test_receive_fragments_exceeding_message_size() {
+
+    let mut q = MessageQueue::new(Config::default());
+
+    // 65535 fragments of 100 bytes each
+    let mut packet = vec![9, 0, 0, 106, 0, 0, 0, 0, 255, 255];
+    packet.extend_from_slice(&[0; 100]);
+    q.receive_packet(&packet[..]);
+    assert_eq!(q.buffered_fragment_bytes(), 0);
+
+    // Fragments whose size does not match the rest of their message
+    q.receive_packet(&[
+        9, 0, 0, 8, 0, 1, 0, 0, 0, 3, 49, 50,
+        9, 0, 0, 7, 0, 1, 0, 1, 0, 3, 51,
+        9, 0, 0, 9, 0, 1, 0, 2, 0, 3, 52, 53, 54
+    ]);
+
+    assert_eq!(q.buffered_fragment_bytes(), 12);
+    assert!(messages(&mut q).is_empty());
+
+}
+
+#[test]
+fn test_receive_fragment_flood() {
+
+    let mut q = MessageQueue::new(Config::default());
+
+    // Packets full of single byte fragments of distinct, huge messages
+    for p in 0..20u16 {
+        let mut packet = Vec::new();
+        for i in 0..130u16 {
+            let id = p * 130 + i;
+            packet.extend_from_slice(&[
+                9, 0, 0, 7, (id >> 8) as u8, id as u8, 0, 0, 255, 255, 0
+            ]);
+        }
+        q.receive_packet(&packet[..]);
+    }
+
+    // Only the most recent messages are kept
+    assert_eq!(q.buffered_fragment_bytes(), 64 * 11);
+
+    // Legit messages still get through
+    q.receive_packet(&[
+        9, 0, 0, 8, 255, 255, 0, 0, 0, 2, 49, 50,
+        9, 0, 0, 8, 255, 255, 0, 1, 0, 2, 51, 52
+    ]);
+
+    assert_eq!(messages(&mut q), [[49, 50, 51, 52].to_vec()]);
+    assert_eq!(q.buffered_fragment_bytes(), 63 * 11);
+
+}
+
+#[test]
+fn test_receive_fragment_bytes_limit() {
+
+    let mut q = MessageQueue::new(Config::default());
+
+    // Messages of the maximum size which never complete
+    for id in 0..32u16 {
+        for index in 0..48u16 {
+            let mut packet = vec![
+                9, 5, 91, 9, (id >> 8) as u8, id as u8, (index >> 8) as u8, index as u8, 0, 48
+            ];
+            packet.extend_from_slice(&[0; 1365]);
+            q.receive_packet(&packet[..]);
+        }
+    }
+
+    assert!(q.buffered_fragment_bytes() <= 16 * 65535);
+
+}
+
+#[test]
+fn test_receive_fragment_timeout() {
+
+    let mut q = MessageQueue::new(Config {
+        packet_drop_threshold: Duration::from_millis(10),
+        .. Config::default()
+    });
+
+    q.receive_packet(&[9, 0, 0, 7, 0, 0, 0, 0, 0, 2, 49]);
+    assert_eq!(q.buffered_fragment_bytes(), 11);
+
+    // Messages which made no progress for a while are dropped
+    thread::sleep(Duration::from_millis(100));
+    q.receive_packet(&[9, 0, 0, 7, 0, 1, 0, 0, 0, 2, 50]);
+    assert_eq!(q.buffered_fragment_bytes(), 11);
+
+    q.receive_packet(&[9, 0, 0, 7, 0, 0, 0, 1, 0, 2, 51]);
+    assert!(messages(&mut q).is_empty());
+
+}
+
+#[test]
+fn test_message_ids() {
+
+    let mut q = MessageQueue::new(Config::default());
+    assert_eq!(q.send(MessageKind::Instant, b"Foo".to_vec()).unwrap(), MessageID(0));
+    assert_eq!(q.send(MessageKind::Reliable, b"Bar".to_vec()).unwrap(), MessageID(1));
+    assert_eq!(q.send_ordered(1, b"Baz".to_vec()).unwrap(), MessageID(2));
+    assert_eq!(q.send_sequenced(0, b"Qux".to_vec()).unwrap(), MessageID(3));
+
+    let mut buffer = Vec::new();
+    assert_eq!(q.send_packet(&mut buffer, 64), [
+        MessageID(0), MessageID(3), MessageID(1), MessageID(2)
+    ]);
+
+    // Ids start over after a reset
+    q.reset();
+    assert_eq!(q.send(MessageKind::Instant, b"Foo".to_vec()).unwrap(), MessageID(0));
+
+}
+
+#[test]
+fn test_acked_packet_delivered() {
+
+    let mut q = MessageQueue::new(Config::default());
+    q.send(MessageKind::Instant, b"Foo".to_vec()).ok();
+    q.send(MessageKind::Reliable, b"Bar".to_vec()).ok();
+
+    let mut buffer = Vec::new();
+    let ids = q.send_packet(&mut buffer, 64);
+    assert_eq!(q.acked_packet(&ids), [MessageID(0), MessageID(1)]);
+
+}
+
+#[test]
+fn test_acked_packet_fragmented() {
+
+    let mut q = MessageQueue::new(Config::default());
+    let id = q.send(MessageKind::Reliable, b"Hello World, Hello".to_vec()).unwrap();
+
+    let mut first = Vec::new();
+    let first_ids = q.send_packet(&mut first, 20);
+    assert_eq!(first_ids, [id]);
+
+    let mut second = Vec::new();
+    let second_ids = q.send_packet(&mut second, 20);
+    assert_eq!(second_ids, [id]);
+
+    // Messages are only delivered once all of their fragments are
+    assert!(q.acked_packet(&second_ids).is_empty());
+    assert_eq!(q.acked_packet(&first_ids), [id]);
+
+}
+
+#[test]
+fn test_lost_packet_ids() {
+
+    let mut q = MessageQueue::new(Config::default());
+    let instant = q.send(MessageKind::Instant, b"Foo".to_vec()).unwrap();
+    q.send(MessageKind::Reliable, b"Bar".to_vec()).ok();
+    let sequenced = q.send(MessageKind::Sequenced, b"Baz".to_vec()).unwrap();
+
+    let mut buffer = Vec::new();
+    let ids = q.send_packet(&mut buffer, 64);
+    assert_eq!(q.lost_packet(&buffer[..], &ids), [instant, sequenced]);
+
+    // The reliable message keeps its id when re-send
+    let mut buffer = Vec::new();
+    assert_eq!(q.send_packet(&mut buffer, 64), [MessageID(1)]);
+
+}
+
+#[test]
+fn test_receive_reliable_duplicates() {
+
+    let mut q = MessageQueue::new(Config::default());
+    q.receive_packet(&[
+        // Foo
+        1, 0, 0, 3, 70, 111, 111,
+        // Bar
+        1, 1, 0, 3, 66, 97, 114,
+        // Foo again
+        1, 0, 0, 3, 70, 111, 111
+    ]);
+
+    // Later duplicates are dropped as well
+    q.receive_packet(&[
+        // Bar again
+        1, 1, 0, 3, 66, 97, 114
+    ]);
+
+    assert_eq!(messages(&mut q), [b"Foo".to_vec(), b"Bar".to_vec()]);
+
+}
+
+#[test]
+fn test_reliable_delayed_packet() {
+
+    let mut q = MessageQueue::new(Config::default());
+    let mut r = MessageQueue::new(Config::default());
+    q.send(MessageKind::Reliable, b"Foo".to_vec()).ok();
+
+    // The packet is declared lost but only arrives late
+    let mut delayed = Vec::new();
+    let ids = q.send_packet(&mut delayed, 64);
+    q.lost_packet(&delayed[..], &ids);
+
+    let mut buffer = Vec::new();
+    q.send_packet(&mut buffer, 64);
+    assert_eq!(buffer, delayed);
+
+    r.receive_packet(&buffer[..]);
+    r.receive_packet(&delayed[..]);
+    assert_eq!(messages(&mut r), [b"Foo".to_vec()]);
+
+}
+
+#[test]
+fn test_receive_reliable_history() {
+
+    let mut q = MessageQueue::new(Config::default());
+    let mut r = MessageQueue::new(Config::default());
+    for i in 0..4096u32 {
+        q.send(MessageKind::Reliable, vec![i as u8]).ok();
+    }
+
+    loop {
+        let mut buffer = Vec::new();
+        let ids = q.send_packet(&mut buffer, 1400);
+        if buffer.is_empty() {
+            break;
+        }
+        r.receive_packet(&buffer[..]);
+        q.acked_packet(&ids);
+    }
+    assert_eq!(messages(&mut r).len(), 4096);
+
+    // Ids wrap around and only the most recent ones are remembered
+    q.send(MessageKind::Reliable, b"Foo".to_vec()).ok();
+
+    let mut buffer = Vec::new();
+    q.send_packet(&mut buffer, 1400);
+    r.receive_packet(&buffer[..]);
+    r.receive_packet(&buffer[..]);
+    assert_eq!(messages(&mut r), [b"Foo".to_vec()]);
+
+}
+
+#[test]
+fn test_receive_reliable_wrapped() {
+
+    let mut q = MessageQueue::new(Config::default());
+    q.receive_packet(&[1, 0, 0, 3, 70, 111, 111]);
+
+    // Step through the id space up until it wraps around
+    for order in (512..4096u16).step_by(512) {
+        q.receive_packet(&[((order & 0x0F00) >> 4) as u8 | 1, order as u8, 0, 1, 0]);
+    }
+
+    assert_eq!(messages(&mut q).len(), 8);
+
+    // New messages re-using the id of an old one are received
+    q.receive_packet(&[1, 0, 0, 3, 66, 97, 114]);
+    assert_eq!(messages(&mut q), [b"Bar".to_vec()]);
+
+    // Messages far ahead of the window are dropped
+    q.receive_packet(&[1 | 0x40, 2, 0, 3, 66, 97, 122]);
+    assert!(messages(&mut q).is_empty());
+
+}
+
+#[test]
+fn test_receive_reliable_late_duplicate() {
+
+    let mut q = MessageQueue::new(Config::default());
+    q.receive_packet(&[1, 0, 0, 3, 70, 111, 111]);
+
+    for order in 1..2100u16 {
+        q.receive_packet(&[((order & 0x0F00) >> 4) as u8 | 1, order as u8, 0, 1, 0]);
+    }
+
+    assert_eq!(messages(&mut q).len(), 2100);
+
+    // Duplicates arriving long after their message are still dropped
+    q.receive_packet(&[1, 0, 0, 3, 70, 111, 111]);
+    assert!(messages(&mut q).is_empty());
+
+}
+
+#[test]
+fn test_send_reliable_window() {
+
+    let mut q = MessageQueue::new(Config::default());
+    for i in 0..1100u32 {
+        q.send(MessageKind::Reliable, vec![i as u8]).ok();
+    }
+
+    // At most 1024 messages are in flight
+    let mut first = Vec::new();
+    let mut sent = 0;
+    loop {
+        let mut buffer = Vec::new();
+        let ids = q.send_packet(&mut buffer, 1400);
+        if buffer.is_empty() {
+            break;
+        }
+        if first.is_empty() {
+            first = ids.clone();
+        }
+        sent += ids.len();
+    }
+
+    assert_eq!(sent, 1024);
+    assert_eq!(q.queued_messages(MessageKind::Reliable), 76);
+
+    // Acknowledging the oldest messages moves the window forward
+    q.acked_packet(&first);
+
+    let mut buffer = Vec::new();
+    assert_eq!(q.send_packet(&mut buffer, 1400).len(), 76);
+
+}
+
+#[test]
+fn test_receive_reliable_placeholder() {
+
+    let mut q = MessageQueue::new(Config::default());
+    q.receive_packet(&[
+        // Placeholders only fill their id
+        5, 0, 0, 0,
+        1, 1, 0, 3, 70, 111, 111,
+        5, 0, 0, 0
+    ]);
+
+    assert_eq!(messages(&mut q), [b"Foo".to_vec()]);
+    assert!(q.received_rpc().is_empty());
+
+    // Empty RPC messages are reserved for placeholders
+    let err = q.send_rpc(Vec::new()).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+
+}
+
+#[test]
+fn test_send_expired() {
+
+    let mut message_ttl = [Some(Duration::from_millis(0)); 4];
+    message_ttl[MessageKind::Sequenced as usize] = None;
+
+    let mut q = MessageQueue::new(Config {
+        message_ttl,
+        .. Config::default()
+    });
+    let instant = q.send(MessageKind::Instant, b"Foo".to_vec()).unwrap();
+    let reliable = q.send(MessageKind::Reliable, b"Bar".to_vec()).unwrap();
+    let ordered = q.send(MessageKind::Ordered, b"Baz".to_vec()).unwrap();
+    let sequenced = q.send(MessageKind::Sequenced, b"Qux".to_vec()).unwrap();
+
+    let mut buffer = Vec::new();
+    let ids = q.send_packet(&mut buffer, 64);
+    assert_eq!(ids, [sequenced, reliable, ordered]);
+    assert_eq!(q.expired(), [instant, reliable, ordered]);
+    assert!(q.expired().is_empty());
+
+    // Expired reliable and ordered messages leave an empty placeholder
+    assert_eq!(buffer, [
+        // Qux
+        3, 0, 0, 3, 0, 81, 117, 120,
+        // Bar
+        5, 0, 0, 0,
+        // Baz
+        6, 0, 0, 0, 0
+    ].to_vec());
+
+    // Placeholders are re-send until they are acknowledged, without being
+    // reported as delivered
+    assert_eq!(q.lost_packet(&buffer[..], &ids), [sequenced]);
+
+    let mut buffer = Vec::new();
+    let ids = q.send_packet(&mut buffer, 64);
+    assert_eq!(ids, [reliable, ordered]);
+    assert_eq!(buffer, [5, 0, 0, 0, 6, 0, 0, 0, 0].to_vec());
+    assert!(q.acked_packet(&ids).is_empty());
+
+}
+
+#[test]
+fn test_send_expired_reliable_window() {
+
+    let mut q = MessageQueue::new(Config::default());
+    let mut expired = Vec::new();
+    for i in 0..1100u32 {
+        let id = q.send(MessageKind::Reliable, vec![i as u8]).unwrap();
+        q.set_message_deadline(id, Instant::now());
+        expired.push(id);
+    }
+
+    let hello = q.send(MessageKind::Reliable, b"Hello".to_vec()).unwrap();
+
+    // The placeholders of expired messages keep the remote queue in step
+    let mut r = MessageQueue::new(Config::default());
+    let mut delivered = Vec::new();
+    loop {
+        let mut buffer = Vec::new();
+        let ids = q.send_packet(&mut buffer, 1400);
+        if buffer.is_empty() {
+            break;
+        }
+        r.receive_packet(&buffer[..]);
+        delivered.append(&mut q.acked_packet(&ids));
+    }
+
+    assert_eq!(q.expired(), expired);
+    assert_eq!(delivered, [hello]);
+    assert_eq!(messages(&mut r), [b"Hello".to_vec()]);
+
+}
+
+#[test]
+fn test_set_message_deadline() {
+
+    let mut q = MessageQueue::new(Config::default());
+    let foo = q.send(MessageKind::Instant, b"Foo".to_vec()).unwrap();
+    let bar = q.send(MessageKind::Reliable, b"Bar".to_vec()).unwrap();
+    assert!(q.set_message_deadline(foo, Instant::now()));
+    assert!(q.set_message_deadline(bar, Instant::now() + Duration::from_secs(60)));
+
+    let mut buffer = Vec::new();
+    assert_eq!(q.send_packet(&mut buffer, 64), [bar]);
+    assert_eq!(q.expired(), [foo]);
+
+    // Messages which are no longer queued are not affected
+    assert!(!q.set_message_deadline(foo, Instant::now()));
+    assert!(!q.set_message_deadline(bar, Instant::now()));
+
+}
+
+#[test]
+fn test_receive_expired_placeholder() {
+
+    let mut q = MessageQueue::new(Config::default());
+    q.receive_packet(&[
+        // Bar
+        2, 1, 0, 3, 0, 66, 97, 114,
+        // Placeholder for the first message
+        6, 0, 0, 0, 0,
+        // Placeholders of other kinds are invalid
+        5, 0, 0, 3, 70, 111, 111
+    ]);
+
+    assert_eq!(messages(&mut q), [b"Bar".to_vec()]);
+
+}
+
+#[test]
+fn test_send_queue_max_bytes() {
+
+    let mut q = MessageQueue::new(Config {
+        send_queue_max_bytes: 8,
+        .. Config::default()
+    });
+
+    q.send(MessageKind::Instant, b"Foo".to_vec()).unwrap();
+    q.send(MessageKind::Reliable, b"Bar".to_vec()).unwrap();
+    assert_eq!(q.send(MessageKind::Ordered, b"Baz".to_vec()).unwrap_err().kind(), ErrorKind::WouldBlock);
+    assert_eq!(q.send_sequenced(0, b"Qux".to_vec()).unwrap_err().kind(), ErrorKind::WouldBlock);
+    q.send(MessageKind::Instant, b"AB".to_vec()).unwrap();
+
+    // Rejected messages do not use up any ids
+    let mut buffer = Vec::new();
+    assert_eq!(q.send_packet(&mut buffer, 64), [MessageID(0), MessageID(2), MessageID(1)]);
+    assert!(q.drained());
+    assert!(!q.drained());
+
+    q.send(MessageKind::Ordered, b"Baz".to_vec()).unwrap();
+
+}
+
+#[test]
+fn test_send_queue_max_messages() {
+
+    let mut q = MessageQueue::new(Config {
+        send_queue_max_messages: 2,
+        .. Config::default()
+    });
+
+    q.send(MessageKind::Reliable, b"Foo".to_vec()).unwrap();
+    q.send(MessageKind::Reliable, b"Bar".to_vec()).unwrap();
+    assert!(q.send(MessageKind::Reliable, b"Baz".to_vec()).is_err());
+
+    // Lost messages count against the limit again
+    let mut buffer = Vec::new();
+    let ids = q.send_packet(&mut buffer, 64);
+    q.lost_packet(&buffer[..], &ids);
+    assert!(q.send(MessageKind::Reliable, b"Baz".to_vec()).is_err());
+
+    q.send_packet(&mut Vec::new(), 64);
+    q.send(MessageKind::Reliable, b"Baz".to_vec()).unwrap();
+
+}
+
+#[test]
+fn test_send_queue_low_watermark() {
+
+    let mut q = MessageQueue::new(Config {
+        send_queue_max_messages: 4,
+        .. Config::default()
+    });
+
+    for _ in 0..4 {
+        q.send(MessageKind::Reliable, b"Foo".to_vec()).unwrap();
+    }
+
+    // Nothing to report unless a message was rejected
+    q.send_packet(&mut Vec::new(), 7);
+    assert!(!q.drained());
+
+    q.send(MessageKind::Reliable, b"Foo".to_vec()).unwrap();
+    assert!(q.send(MessageKind::Reliable, b"Foo".to_vec()).is_err());
+
+    // Still above half of the limit
+    q.send_packet(&mut Vec::new(), 7);
+    assert!(!q.drained());
+
+    q.send_packet(&mut Vec::new(), 7);
+    assert!(q.drained());
+
+}
+
+#[test]
+fn test_queue_introspection() {
+
+    let mut q = MessageQueue::new(Config::default());
+    assert_eq!(q.queued_bytes(), 0);
+    assert_eq!(q.oldest_queued_age(), None);
+
+    q.send(MessageKind::Instant, b"Foo".to_vec()).unwrap();
+    q.send(MessageKind::Sequenced, b"Bar".to_vec()).unwrap();
+    q.send(MessageKind::Reliable, b"Hello".to_vec()).unwrap();
+    q.send_ordered(0, b"World".to_vec()).unwrap();
+    q.send_ordered(3, b"!".to_vec()).unwrap();
+
+    assert_eq!(q.queued_messages(MessageKind::Instant), 1);
+    assert_eq!(q.queued_messages(MessageKind::Sequenced), 1);
+    assert_eq!(q.queued_messages(MessageKind::Reliable), 1);
+    assert_eq!(q.queued_messages(MessageKind::Ordered), 2);
+    assert_eq!(q.queued_bytes(), 17);
+    assert_eq!(q.unacked_reliable_messages(), 0);
+
+    thread::sleep(Duration::from_millis(10));
+    assert!(q.oldest_queued_age().unwrap() >= Duration::from_millis(10));
+
+    // Only room for the instant and sequenced messages
+    let mut buffer = Vec::new();
+    q.send_packet(&mut buffer, 20);
+    assert_eq!(q.queued_messages(MessageKind::Instant), 0);
+    assert_eq!(q.queued_messages(MessageKind::Sequenced), 0);
+    assert_eq!(q.queued_bytes(), 11);
+
+    let mut buffer = Vec::new();
+    let ids = q.send_packet(&mut buffer, 64);
+    assert_eq!(q.queued_bytes(), 0);
+    assert_eq!(q.oldest_queued_age(), None);
+    assert_eq!(q.unacked_reliable_messages(), 3);
+
+    // Lost messages are queued again but remain unacknowledged
+    q.lost_packet(&buffer[..], &ids);
+    assert_eq!(q.queued_messages(MessageKind::Ordered), 2);
+    assert_eq!(q.unacked_reliable_messages(), 3);
+    assert!(q.oldest_queued_age().unwrap() >= Duration::from_millis(10));
+
+    let mut buffer = Vec::new();
+    let ids = q.send_packet(&mut buffer, 64);
+    q.acked_packet(&ids);
+    assert_eq!(q.unacked_reliable_messages(), 0);
+
+}
+
+#[test]
+fn test_send_ordered_window() {
+
+    let mut q = MessageQueue::new(Config::default());
+    for i in 0..1100u32 {
+        q.send_ordered(1, vec![i as u8]).unwrap();
+    }
+
+    // Only a window's worth of messages is put in flight
+    let mut packets = Vec::new();
+    loop {
+        let mut buffer = Vec::new();
+        let ids = q.send_packet(&mut buffer, 1400);
+        if ids.is_empty() {
+            break;
+        }
+        packets.push(ids);
+    }
+
+    assert_eq!(packets.iter().map(|ids| ids.len()).sum::<usize>(), 1024);
+    assert_eq!(q.queued_messages(MessageKind::Ordered), 76);
+
+    // Acknowledging later messages does not move the window
+    let last = packets.pop().unwrap();
+    q.acked_packet(&last);
+    assert!(q.send_packet(&mut Vec::new(), 1400).is_empty());
+
+    // But acknowledging the oldest ones does
+    let first = packets.remove(0);
+    q.acked_packet(&first);
+
+    let mut buffer = Vec::new();
+    assert_eq!(q.send_packet(&mut buffer, 1400).len(), cmp::min(first.len(), 76));
+
+}
+
+#[test]
+fn test_receive_ordered_window() {
+
+    let mut q = MessageQueue::new(Config::default());
+    q.receive_packet(&[
+        // Outside of the window
+        2 | 0x40, 0, 0, 1, 0, 1,
+        // Just inside of the window
+        2 | 0x30, 255, 0, 1, 0, 2,
+        // Duplicate
+        2 | 0x30, 255, 0, 1, 0, 3
+    ]);
+    assert!(messages(&mut q).is_empty());
+
+    for i in 0..1023u32 {
+        q.receive_packet(&[
+            2 | ((i & 0x0F00) >> 4) as u8, i as u8, 0, 1, 0, 0
+        ]);
+    }
+
+    assert_eq!(messages(&mut q).last(), Some(&vec![2]));
+
+    // Old duplicates are outside of the window as well
+    q.receive_packet(&[2, 0, 0, 1, 0, 4]);
+    assert!(messages(&mut q).is_empty());
+
+}
+
+#[test]
+fn test_send_ordered_window_bytes() {
+
+    let mut q = MessageQueue::new(Config::default());
+    for _ in 0..10 {
+        q.send(MessageKind::Ordered, vec![0; 60000]).unwrap();
+    }
+
+    // Only as many messages are put in flight as the remote queue buffers
+    let mut packets = Vec::new();
+    loop {
+        let mut buffer = Vec::new();
+        let ids = q.send_packet(&mut buffer, 1400);
+        if ids.is_empty() {
+            break;
+        }
+        packets.push(ids);
+    }
+
+    let mut sent: Vec<MessageID> = packets.iter().flat_map(|ids| ids.iter().cloned()).collect();
+    sent.dedup();
+    assert_eq!(sent, [MessageID(0), MessageID(1), MessageID(2), MessageID(3)]);
+
+    // Delivering the oldest message moves the window forward
+    let mut delivered = Vec::new();
+    for ids in packets.iter().filter(|ids| ids.contains(&MessageID(0))) {
+        delivered.append(&mut q.acked_packet(ids));
+    }
+    assert_eq!(delivered, [MessageID(0)]);
+
+    let mut buffer = Vec::new();
+    assert_eq!(q.send_packet(&mut buffer, 1400)[0], MessageID(4));
+
+}
+
+#[test]
+fn test_receive_ordered_window_bytes() {
+
+    let mut q = MessageQueue::new(Config::default());
+
+    // Withhold the first message while sending large ones after it
+    for order in 1..10u16 {
+        let mut packet = Vec::new();
+        for index in 0..60u16 {
+            packet.push(((order & 0x0F00) >> 4) as u8 | 0x08 | 2);
+            packet.push(order as u8);
+            packet.extend_from_slice(&[3, 238, 0]);
+            packet.extend_from_slice(&[(order >> 8) as u8, order as u8, 0, index as u8, 0, 60]);
+            packet.extend_from_slice(&[0; 1000]);
+        }
+        q.receive_packet(&packet[..]);
+    }
+
+    // Only as many messages are buffered as fit into the window
+    assert_eq!(q.buffered_fragment_bytes(), 0);
+    assert_eq!(q.buffered_ordered_bytes(), 4 * 60000);
+
+    q.receive_packet(&[2, 0, 0, 3, 0, 70, 111, 111]);
+    assert_eq!(messages(&mut q).len(), 5);
+    assert_eq!(q.buffered_ordered_bytes(), 0);
+
+}
+
+#[test]
+fn test_receive_ordered_out_of_order_wrap_around() {
+
+    let mut q = MessageQueue::new(Config::default());
+    for i in 0..4094u32 {
+        q.receive_packet(&[
+            2 | ((i & 0x0F00) >> 4) as u8, i as u8, 0, 0, 0
+        ]);
+    }
+    messages(&mut q);
+
+    q.receive_packet(&[
+        // 1
+        2, 1, 0, 1, 0, 3,
+        // 0
+        2, 0, 0, 1, 0, 2,
+        // 4095
+        2 | 0xF0, 255, 0, 1, 0, 1,
+        // 4094
+        2 | 0xF0, 254, 0, 1, 0, 0
+    ]);
+
+    assert_eq!(messages(&mut q), [[0], [1], [2], [3]]);
+
+}
+
+#[test]
+fn test_send_rpc() {
+
+    let mut q = MessageQueue::new(Config::default());
+    q.send_rpc(b"Foo".to_vec()).ok();
+    q.send(MessageKind::Reliable, b"Bar".to_vec()).ok();
+
+    // RPC messages are flagged reliable messages
+    let mut buffer = Vec::new();
+    q.send_packet(&mut buffer, 64);
+    assert_eq!(buffer, [
+        5, 0, 0, 3, 70, 111, 111,
+        1, 1, 0, 3, 66, 97, 114
+    ]);
+
+    // And are received separately
+    let mut r = MessageQueue::new(Config::default());
+    r.receive_packet(&buffer[..]);
+    assert_eq!(messages(&mut r), [b"Bar".to_vec()]);
+    assert_eq!(r.received_rpc(), [b"Foo".to_vec()]);
+    assert!(r.received_rpc().is_empty());
+
+}
+
+#[test]
+fn test_send_rpc_fragmented() {
+
+    let mut q = MessageQueue::new(Config::default());
+    let mut r = MessageQueue::new(Config::default());
+    let data: Vec<u8> = (0..100u32).map(|i| i as u8).collect();
+    q.send_rpc(data.clone()).ok();
+
+    for _ in 0..2 {
+        let mut buffer = Vec::new();
+        q.send_packet(&mut buffer, 64);
+        assert_eq!(buffer[0], 0x0D);
+        r.receive_packet(&buffer[..]);
+    }
+
+    assert!(messages(&mut r).is_empty());
+    assert_eq!(r.received_rpc(), [data]);
+
+}
+
+#[test]
+fn test_receive_rpc_invalid_kind() {
+
+    let mut q = MessageQueue::new(Config::default());
+    q.receive_packet(&[
+        // Only reliable messages can belong to the RPC layer
+        4, 0, 0, 3, 70, 111, 111,
+        7, 0, 0, 3, 0, 66, 97, 114,
+        12, 0, 0, 3, 70, 111, 111,
+        // Valid
+        5, 0, 0, 4, 84, 101, 115, 116
+    ]);
+
+    assert!(messages(&mut q).is_empty());
+    assert_eq!(q.received_rpc(), [b"Test".to_vec()]);
+
+    // For ordered messages the same bit marks expired placeholders, which
+    // never carry data for the RPC layer
+    q.receive_packet(&[
+        6, 0, 0, 3, 0, 70, 111, 111,
+        2, 1, 0, 3, 0, 66, 97, 114
+    ]);
+
+    assert_eq!(messages(&mut q), [b"Bar".to_vec()]);
+    assert!(q.received_rpc().is_empty());
+
+}
+
+#[test]
+fn test_cancel_queued() {
+
+    let mut q = MessageQueue::new(Config::default());
+    let foo = q.send(MessageKind::Instant, b"Foo".to_vec()).unwrap();
+    let bar = q.send(MessageKind::Reliable, b"Bar".to_vec()).unwrap();
+    q.send(MessageKind::Sequenced, b"Baz".to_vec()).ok();
+    assert_eq!(q.queued_bytes(), 9);
+
+    assert!(q.cancel(foo));
+    assert!(q.cancel(bar));
+    assert_eq!(q.queued_bytes(), 3);
+
+    // Messages can only be cancelled once
+    assert!(!q.cancel(foo));
+    assert!(!q.cancel(MessageID(10)));
+
+    // Cancelled reliable messages are replaced with placeholders
+    let mut buffer = Vec::new();
+    let ids = q.send_packet(&mut buffer, 64);
+    assert_eq!(buffer, [
+        3, 0, 0, 3, 0, 66, 97, 122,
+        5, 0, 0, 0
+    ]);
+    assert_eq!(q.unacked_reliable_messages(), 1);
+
+    q.acked_packet(&ids);
+    assert_eq!(q.unacked_reliable_messages(), 0);
+
+}
+
+#[test]
+fn test_cancel_queued_ordered() {
+
+    let mut q = MessageQueue::new(Config::default());
+    let foo = q.send(MessageKind::Ordered, b"Foo".to_vec()).unwrap();
+    let bar = q.send(MessageKind::Ordered, b"Bar".to_vec()).unwrap();
+    assert!(q.cancel(foo));
+    assert!(!q.cancel(foo));
+
+    // Cancelled ordered messages are replaced with placeholders
+    let mut buffer = Vec::new();
+    let ids = q.send_packet(&mut buffer, 64);
+    assert_eq!(buffer, [
+        6, 0, 0, 0, 0,
+        2, 1, 0, 3, 0, 66, 97, 114
+    ]);
+
+    assert_eq!(q.acked_packet(&ids), [bar]);
+
+    let mut r = MessageQueue::new(Config::default());
+    r.receive_packet(&buffer[..]);
+    assert_eq!(messages(&mut r), [b"Bar".to_vec()]);
+
+}
+
+#[test]
+fn test_cancel_sent() {
+
+    let mut q = MessageQueue::new(Config::default());
+    let foo = q.send(MessageKind::Instant, b"Foo".to_vec()).unwrap();
+    let bar = q.send(MessageKind::Reliable, b"Bar".to_vec()).unwrap();
+    let baz = q.send(MessageKind::Ordered, b"Baz".to_vec()).unwrap();
+
+    let mut lost = Vec::new();
+    let ids = q.send_packet(&mut lost, 64);
+
+    // Sent instant messages are never re-send anyway
+    assert!(!q.cancel(foo));
+    assert!(q.cancel(bar));
+    assert!(q.cancel(baz));
+    assert!(!q.cancel(bar));
+
+    // Cancelled messages are reported as lost instead of being re-send
+    assert_eq!(q.lost_packet(&lost[..], &ids), [foo, bar, baz]);
+    assert_eq!(q.unacked_reliable_messages(), 2);
+
+    // With placeholders being send in their place
+    let mut buffer = Vec::new();
+    let ids = q.send_packet(&mut buffer, 64);
+    assert_eq!(buffer, [5, 0, 0, 0, 6, 0, 0, 0, 0]);
+    assert!(q.acked_packet(&ids).is_empty());
+    assert_eq!(q.unacked_reliable_messages(), 0);
+
+}
+
+#[test]
+fn test_cancel_sent_acked() {
+
+    let mut q = MessageQueue::new(Config::default());
+    let foo = q.send(MessageKind::Reliable, b"Foo".to_vec()).unwrap();
+
+    let mut buffer = Vec::new();
+    let ids = q.send_packet(&mut buffer, 64);
+    assert!(q.cancel(foo));
+
+    // Cancelled messages which arrive anyway are still delivered
+    assert_eq!(q.acked_packet(&ids), [foo]);
+    assert!(!q.cancel(foo));
+
+}
+
+#[test]
+fn test_cancel_fragmented() {
+
+    let mut q = MessageQueue::new(Config::default());
+    let foo = q.send(MessageKind::Reliable, vec![0; 100]).unwrap();
+
+    let mut buffer = Vec::new();
+    q.send_packet(&mut buffer, 64);
+
+    // Messages which have been split up can no longer be cancelled
+    assert!(!q.cancel(foo));
+
+}
+
+#[test]
+fn test_cancel_reliable_window() {
+
+    let mut q = MessageQueue::new(Config::default());
+    for i in 0..1100u32 {
+        let id = q.send(MessageKind::Reliable, vec![i as u8]).unwrap();
+        assert!(q.cancel(id));
+    }
+
+    let hello = q.send(MessageKind::Reliable, b"Hello".to_vec()).unwrap();
+
+    // The placeholders of cancelled messages keep the remote queue in step
+    let mut r = MessageQueue::new(Config::default());
+    let mut delivered = Vec::new();
+    loop {
+        let mut buffer = Vec::new();
+        let ids = q.send_packet(&mut buffer, 1400);
+        if buffer.is_empty() {
+            break;
+        }
+        r.receive_packet(&buffer[..]);
+        delivered.append(&mut q.acked_packet(&ids));
+    }
+
+    assert_eq!(delivered, [hello]);
+    assert_eq!(messages(&mut r), [b"Hello".to_vec()]);
+
+}
+
+#[test]
+fn test_send_keyed() {
+
+    let mut q = MessageQueue::new(Config::default());
+    q.send_keyed(MessageKind::Instant, 1, b"Foo".to_vec()).ok();
+    q.send_keyed(MessageKind::Reliable, 1, b"Foo".to_vec()).ok();
+    q.send(MessageKind::Instant, b"Bar".to_vec()).ok();
+    q.send_keyed(MessageKind::Instant, 2, b"Test".to_vec()).ok();
+
+    // Newer messages replace older ones with the same kind and key
+    let id = q.send_keyed(MessageKind::Instant, 1, b"Hello".to_vec()).unwrap();
+    assert_eq!(id, MessageID(4));
+    q.send_keyed(MessageKind::Reliable, 1, b"World".to_vec()).ok();
+    assert_eq!(q.queued_messages(MessageKind::Instant), 3);
+    assert_eq!(q.queued_messages(MessageKind::Reliable), 1);
+    assert_eq!(q.queued_bytes(), 17);
+
+    let mut buffer = Vec::new();
+    let ids = q.send_packet(&mut buffer, 64);
+    assert_eq!(ids, [MessageID(4), MessageID(2), MessageID(3), MessageID(5)]);
+    assert_eq!(buffer, [
+        // Hello
+        0, 0, 0, 5, 72, 101, 108, 108, 111,
+        // Bar
+        0, 0, 0, 3, 66, 97, 114,
+        // Test
+        0, 0, 0, 4, 84, 101, 115, 116,
+        // World
+        1, 0, 0, 5, 87, 111, 114, 108, 100
+    ]);
+
+    // Replaced messages are reported as expired
+    assert_eq!(q.expired(), [MessageID(0), MessageID(1)]);
+
+    // Only the latest message is awaiting acknowledgement
+    assert_eq!(q.unacked_reliable_messages(), 1);
+    assert_eq!(q.acked_packet(&ids), ids);
+
+    // Messages which have been send are no longer replaced
+    q.send_keyed(MessageKind::Instant, 1, b"Foo".to_vec()).ok();
+    assert_eq!(q.queued_messages(MessageKind::Instant), 1);
+
+}
+
+#[test]
+fn test_send_keyed_sequenced() {
+
+    let mut q = MessageQueue::new(Config::default());
+    q.send_keyed(MessageKind::Sequenced, 1, b"Foo".to_vec()).ok();
+    q.send_keyed(MessageKind::Sequenced, 2, b"Bar".to_vec()).ok();
+    q.send_keyed(MessageKind::Sequenced, 1, b"Baz".to_vec()).ok();
+
+    // Replacing messages keep the sequence id of the replaced ones
+    let mut buffer = Vec::new();
+    q.send_packet(&mut buffer, 64);
+    assert_eq!(buffer, [
+        3, 0, 0, 3, 0, 66, 97, 122,
+        3, 1, 0, 3, 0, 66, 97, 114
+    ]);
+
+    let mut r = MessageQueue::new(Config::default());
+    r.receive_packet(&buffer[..]);
+    assert_eq!(messages(&mut r), [b"Baz".to_vec(), b"Bar".to_vec()]);
+
+}
+
+#[test]
+fn test_send_keyed_errors() {
+
+    let mut q = MessageQueue::new(Config {
+        send_queue_max_bytes: 4,
+        .. Config::default()
+    });
+
+    let err = q.send_keyed(MessageKind::Ordered, 1, b"Foo".to_vec()).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+
+    // Replacing messages are subject to the queue limits
+    q.send_keyed(MessageKind::Reliable, 1, b"Foo".to_vec()).ok();
+    let err = q.send_keyed(MessageKind::Reliable, 1, b"Hello".to_vec()).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::WouldBlock);
+    assert!(q.send_keyed(MessageKind::Reliable, 1, b"Test".to_vec()).is_ok());
+    assert_eq!(q.queued_bytes(), 4);
+
+}
+
+#[test]
+fn test_produce_packet() {
+
+    let mut q = MessageQueue::new(Config::default());
+    q.send(MessageKind::Reliable, b"Foo".to_vec()).ok();
+
+    let mut buffer = Vec::new();
+    let ids = q.send_packet(&mut buffer, 20);
+    assert_eq!(ids, vec![MessageID(0)]);
+
+    let mut budget = 0;
+    assert!(q.produce_packet(&mut buffer, 13, &mut |available: usize, payload: &mut Vec<u8>| {
+        budget = available;
+        payload.extend_from_slice(b"Bar");
+    }));
+
+    assert_eq!(budget, 9);
+    assert_eq!(buffer, [
+        1, 0, 0, 3, 70, 111, 111,
+        0, 0, 0, 3, 66, 97, 114
+    ].to_vec());
+
+    // Produced messages are received like any other instant message
+    let mut r = MessageQueue::new(Config::default());
+    r.receive_packet(&buffer[..]);
+    assert_eq!(messages(&mut r), vec![b"Foo".to_vec(), b"Bar".to_vec()]);
+
+    // Produced messages are neither re-send nor reported once lost
+    assert!(q.lost_packet(&buffer[..], &ids[..]).is_empty());
+
+    let mut buffer = Vec::new();
+    assert_eq!(q.send_packet(&mut buffer, 64), vec![MessageID(0)]);
+    assert_eq!(buffer, [1, 0, 0, 3, 70, 111, 111].to_vec());
+
+}
+
+#[test]
+fn test_produce_packet_limits() {
+
+    let mut q = MessageQueue::new(Config::default());
+    let mut buffer = Vec::new();
+
+    // Empty payloads are not written
+    assert!(!q.produce_packet(&mut buffer, 64, &mut |_: usize, _: &mut Vec<u8>| {}));
+
+    // Payloads exceeding the available bytes are dropped
+    assert!(!q.produce_packet(&mut buffer, 6, &mut |_: usize, payload: &mut Vec<u8>| {
+        payload.extend_from_slice(b"Foo");
+    }));
+
+    // Producers are not invoked without any room for data
+    let mut invoked = false;
+    assert!(!q.produce_packet(&mut buffer, 4, &mut |_: usize, _: &mut Vec<u8>| {
+        invoked = true;
+    }));
+
+    assert!(!invoked);
+    assert!(buffer.is_empty());
+
+}
+
 // Helpers --------------------------------------------------------------------
 fn messages(q: &mut MessageQueue) -> Vec<Vec<u8>> {
     let mut messages = Vec::new();
@@ -20,7 +20,7 @@ use super::super::Socket;
 
 // Mock Packet Data Abstraction -----------------------------------------------
 #[derive(Debug, Clone, Eq, PartialEq)]
-pub struct MockPacket(SocketAddr, Vec<u8>);
+pub struct MockPacket(pub SocketAddr, pub Vec<u8>);
 
 impl Ord for MockPacket {
 
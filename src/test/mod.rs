@@ -1,9 +1,15 @@
 // Modules --------------------------------------------------------------------
+mod bit_packing;
 mod client;
+#[cfg(any(feature = "lz4", feature = "deflate"))]
+mod compression;
+mod connect_token;
 mod connection;
 mod message_queue;
 mod mock_socket;
 mod server;
+#[cfg(feature = "typed")]
+mod typed_message;
 
 
 // Re-Exports -----------------------------------------------------------------
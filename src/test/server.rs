@@ -8,16 +8,18 @@
 
 // STD Dependencies -----------------------------------------------------------
 use std::thread;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
 use std::io::ErrorKind;
 use std::sync::mpsc::TryRecvError;
+use std::net::{IpAddr, SocketAddr};
 
 
 // Internal Dependencies ------------------------------------------------------
 use super::MockSocket;
 use ::{
-    BinaryRateLimiter, ConnectionID, Config, MessageKind, NoopPacketModifier,
-    Server, ServerEvent
+    Admission, BinaryRateLimiter, Client, ClientEvent, ConnectToken,
+    ConnectionID, Config, MessageID, MessageKind, NoopPacketModifier, ProtocolVersion,
+    RejectionReason, RequestID, Server, ServerEvent
 };
 
 
@@ -150,66 +152,787 @@ fn test_server_connection() {
     server.listen("127.0.0.1:1234").ok();
 
     // Accept a incoming connection
+    server_handshake(&mut server, "255.1.1.1:1000", [9, 8, 7, 6]);
     server.socket().unwrap().mock_receive(vec![
         ("255.1.1.1:1000", vec![
             1, 2, 3, 4,
             9, 8, 7, 6,
-            0,
-            0,
+            1,
+            0, 0,
+            0, 0,
+            0, 0, 0, 0
+        ])
+    ]);
+
+    assert_eq!(server_events(&mut server), vec![
+        ServerEvent::Connection(ConnectionID(151521030), None)
+    ]);
+
+    assert_eq!(server.connections().keys().collect::<Vec<&ConnectionID>>(), vec![&ConnectionID(151521030)]);
+
+    // Accept another incoming connection
+    server_handshake(&mut server, "255.1.1.1:2000", [4, 0, 0, 1]);
+    server.socket().unwrap().mock_receive(vec![
+        ("255.1.1.1:2000", vec![
+            1, 2, 3, 4,
+            4, 0, 0, 1,
+            1,
+            0, 0,
+            0, 0,
+            0, 0, 0, 0
+        ])
+    ]);
+
+    assert_eq!(server_events(&mut server), vec![
+        ServerEvent::Connection(ConnectionID(67108865), None)
+    ]);
+
+    {
+        let mut keys = server.connections().keys().collect::<Vec<&ConnectionID>>();
+        keys.sort();
+        assert_eq!(keys, vec![
+            &ConnectionID(67108865),
+            &ConnectionID(151521030)
+        ]);
+    }
+
+    // Switch the first connection to another peer address
+    server.socket().unwrap().mock_receive(vec![
+        ("255.1.1.2:1003", vec![
+            1, 2, 3, 4,
+            9, 8, 7, 6,
+            1,
+            0, 0,
+            0, 0,
             0, 0, 0, 0
         ])
     ]);
 
-    assert_eq!(server_events(&mut server), vec![
-        ServerEvent::Connection(ConnectionID(151521030))
+    // Shutdown and drop all connections
+    server.shutdown().ok();
+
+    assert_eq!(server_events(&mut server), vec![]);
+
+    // All connections should have been removed
+    assert!(server.connections().keys().collect::<Vec<&ConnectionID>>().is_empty());
+    assert!(server.connection(&ConnectionID(151521030)).is_err());
+    assert!(server.connection(&ConnectionID(67108865)).is_err());
+
+}
+
+#[test]
+fn test_server_handshake() {
+
+    let mut server = Server::<MockSocket, BinaryRateLimiter, NoopPacketModifier>::new(Config::default());
+    server.listen("127.0.0.1:1234").ok();
+
+    // Packets from unknown connections should neither create a connection
+    // nor trigger any reply
+    server.socket().unwrap().mock_receive(vec![
+        ("255.1.1.1:1000", vec![
+            1, 2, 3, 4,
+            9, 8, 7, 6,
+            1,
+            0, 0,
+            0, 0,
+            0, 0, 0, 0
+        ])
+    ]);
+
+    assert_eq!(server_events(&mut server), vec![]);
+    assert_eq!(server.connections().len(), 0);
+    server.socket().unwrap().assert_sent_none();
+
+    // Request a challenge
+    server.socket().unwrap().mock_receive(vec![
+        ("255.1.1.1:1000", vec![
+            1, 2, 3, 4,
+            9, 8, 7, 6,
+            3, // connect request
+            0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0
+        ])
+    ]);
+
+    assert_eq!(server_events(&mut server), vec![]);
+    assert_eq!(server.connections().len(), 0);
+
+    // The challenge should never be bigger than the request
+    let challenge = server.socket().unwrap().sent().pop().unwrap();
+    assert_eq!(challenge.1.len(), 21);
+
+    let mut response = vec![
+        1, 2, 3, 4,
+        9, 8, 7, 6,
+        5 // challenge response
+    ];
+    response.extend_from_slice(&challenge.1[9..]);
+
+    // Cookies are bound to the address they were issued to
+    server.socket().unwrap().mock_receive(vec![
+        ("255.1.1.2:1000", response.clone())
+    ]);
+
+    assert_eq!(server_events(&mut server), vec![]);
+    assert_eq!(server.connections().len(), 0);
+
+    // Cookies are bound to the connection id they were issued for
+    let mut other_response = response.clone();
+    other_response[7] = 7;
+    server.socket().unwrap().mock_receive(vec![
+        ("255.1.1.1:1000", other_response)
+    ]);
+
+    assert_eq!(server_events(&mut server), vec![]);
+    assert_eq!(server.connections().len(), 0);
+
+    // Cookies cannot be forged
+    let mut forged_response = response.clone();
+    forged_response[18] = forged_response[18].wrapping_add(1);
+    server.socket().unwrap().mock_receive(vec![
+        ("255.1.1.1:1000", forged_response)
+    ]);
+
+    assert_eq!(server_events(&mut server), vec![]);
+    assert_eq!(server.connections().len(), 0);
+    server.socket().unwrap().assert_sent_none();
+
+    // A valid response creates the connection, which then starts to send
+    // packets to the client
+    server.socket().unwrap().mock_receive(vec![
+        ("255.1.1.1:1000", response.clone())
+    ]);
+
+    assert_eq!(server_events(&mut server), vec![]);
+    assert_eq!(server.connections().len(), 1);
+
+    server.send(false).ok();
+    server.socket().unwrap().assert_sent(vec![
+        ("255.1.1.1:1000", [
+            1, 2, 3, 4,
+            9, 8, 7, 6,
+            1,
+            0, 0,
+            0, 0,
+            0, 0, 0, 0
+
+        ].to_vec())
+    ]);
+
+    // Re-transmitted responses are ignored
+    server.socket().unwrap().mock_receive(vec![
+        ("255.1.1.1:1000", response)
+    ]);
+
+    server.accept_receive().ok();
+    assert_eq!(server.connections().len(), 1);
+
+    // The connection is established with the first packet from the client
+    server.socket().unwrap().mock_receive(vec![
+        ("255.1.1.1:1000", vec![
+            1, 2, 3, 4,
+            9, 8, 7, 6,
+            1,
+            0, 0,
+            0, 0,
+            0, 0, 0, 0
+        ])
+    ]);
+
+    assert_eq!(server_events(&mut server), vec![
+        ServerEvent::Connection(ConnectionID(151521030), None)
+    ]);
+
+}
+
+#[test]
+fn test_server_handshake_expired() {
+
+    let mut server = Server::<MockSocket, BinaryRateLimiter, NoopPacketModifier>::new(Config {
+        connection_init_threshold: Duration::from_millis(50),
+        .. Config::default()
+    });
+    server.listen("127.0.0.1:1234").ok();
+
+    server.socket().unwrap().mock_receive(vec![
+        ("255.1.1.1:1000", vec![
+            1, 2, 3, 4,
+            9, 8, 7, 6,
+            3, // connect request
+            0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0
+        ])
+    ]);
+
+    assert_eq!(server_events(&mut server), vec![]);
+
+    let challenge = server.socket().unwrap().sent().pop().unwrap();
+    let mut response = vec![
+        1, 2, 3, 4,
+        9, 8, 7, 6,
+        5 // challenge response
+    ];
+    response.extend_from_slice(&challenge.1[9..]);
+
+    // Cookies expire after the connection init threshold
+    thread::sleep(Duration::from_millis(100));
+
+    server.socket().unwrap().mock_receive(vec![
+        ("255.1.1.1:1000", response)
+    ]);
+
+    assert_eq!(server_events(&mut server), vec![]);
+    assert_eq!(server.connections().len(), 0);
+
+}
+
+#[test]
+fn test_server_handshake_connection_failure() {
+
+    let mut server = Server::<MockSocket, BinaryRateLimiter, NoopPacketModifier>::new(Config {
+        connection_init_threshold: Duration::from_millis(50),
+        .. Config::default()
+    });
+    server.listen("127.0.0.1:1234").ok();
+
+    server_handshake(&mut server, "255.1.1.1:1000", [9, 8, 7, 6]);
+    assert_eq!(server.connections().len(), 1);
+
+    // Clients which never send their first packet are dropped silently
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(server_events(&mut server), vec![]);
+
+    server.send(false).ok();
+    assert_eq!(server.connections().len(), 0);
+
+}
+
+#[test]
+fn test_server_max_connections() {
+
+    let mut server = Server::<MockSocket, BinaryRateLimiter, NoopPacketModifier>::new(Config {
+        max_connections: 1,
+        .. Config::default()
+    });
+    server.listen("127.0.0.1:1234").ok();
+
+    // Two clients request a challenge while the server still has capacity
+    server.socket().unwrap().mock_receive(vec![
+        ("255.1.1.1:1000", vec![
+            1, 2, 3, 4,
+            9, 8, 7, 6,
+            3, // connect request
+            0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0
+        ]),
+        ("255.1.1.2:2000", vec![
+            1, 2, 3, 4,
+            5, 5, 1, 1,
+            3, // connect request
+            0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0
+        ])
+    ]);
+
+    assert_eq!(server_events(&mut server), vec![]);
+
+    let challenges = server.socket().unwrap().sent();
+    assert_eq!(challenges.len(), 2);
+
+    // The first client completes its handshake
+    let mut response = vec![
+        1, 2, 3, 4,
+        9, 8, 7, 6,
+        5 // challenge response
+    ];
+    response.extend_from_slice(&challenges[0].1[9..]);
+    server.socket().unwrap().mock_receive(vec![("255.1.1.1:1000", response)]);
+
+    assert_eq!(server_events(&mut server), vec![]);
+    assert_eq!(server.connections().len(), 1);
+
+    // The second client is denied once it responds to its challenge
+    let mut response = vec![
+        1, 2, 3, 4,
+        5, 5, 1, 1,
+        5 // challenge response
+    ];
+    response.extend_from_slice(&challenges[1].1[9..]);
+    server.socket().unwrap().mock_receive(vec![("255.1.1.2:2000", response)]);
+
+    assert_eq!(server_events(&mut server), vec![]);
+    assert_eq!(server.connections().len(), 1);
+
+    let denial = [
+        1, 2, 3, 4,
+        5, 5, 1, 1,
+        6, // denial
+        1 // server full
+    ];
+
+    server.socket().unwrap().assert_sent(vec![
+        // Initial packet of the first connection
+        ("255.1.1.1:1000", [
+            1, 2, 3, 4,
+            9, 8, 7, 6,
+            1,
+            0, 0,
+            0, 0,
+            0, 0, 0, 0
+
+        ].to_vec()),
+        ("255.1.1.2:2000", denial.to_vec())
+    ]);
+
+    // Further requests are denied right away
+    server.socket().unwrap().mock_receive(vec![
+        ("255.1.1.2:2000", vec![
+            1, 2, 3, 4,
+            5, 5, 1, 1,
+            3, // connect request
+            0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0
+        ])
+    ]);
+
+    assert_eq!(server_events(&mut server), vec![]);
+    server.socket().unwrap().assert_sent(vec![
+        ("255.1.1.1:1000", [
+            1, 2, 3, 4,
+            9, 8, 7, 6,
+            1,
+            0, 1,
+            0, 0,
+            0, 0, 0, 0
+
+        ].to_vec()),
+        ("255.1.1.2:2000", denial.to_vec())
+    ]);
+
+}
+
+#[test]
+fn test_server_version_mismatch() {
+
+    let mut server = Server::<MockSocket, BinaryRateLimiter, NoopPacketModifier>::new(Config {
+        application_version: 3,
+        .. Config::default()
+    });
+    server.listen("127.0.0.1:1234").ok();
+
+    // Requests with a different application or wire version are denied
+    server.socket().unwrap().mock_receive(vec![
+        ("255.1.1.1:1000", vec![
+            1, 2, 3, 4,
+            9, 8, 7, 6,
+            3, // connect request
+            0, 1, 0, 0, 0, 2, // protocol version
+            0, 0, 0, 0, 0, 0 // padding
+        ]),
+        ("255.1.1.2:2000", vec![
+            1, 2, 3, 4,
+            5, 5, 1, 1,
+            3, // connect request
+            0, 2, 0, 0, 0, 3, // protocol version
+            0, 0, 0, 0, 0, 0 // padding
+        ])
+    ]);
+
+    assert_eq!(server_events(&mut server), vec![]);
+
+    let denial = [
+        1, 2, 3, 4,
+        0, 0, 0, 0,
+        6, // denial
+        6, // version mismatch
+        0, 1, 0, 0, 0, 3 // server version
+    ];
+
+    server.socket().unwrap().assert_sent(vec![
+        ("255.1.1.1:1000", denial.to_vec()),
+        ("255.1.1.2:2000", denial.to_vec())
+    ]);
+
+    // Clients with the same version are challenged
+    server.socket().unwrap().mock_receive(vec![
+        ("255.1.1.1:1000", vec![
+            1, 2, 3, 4,
+            9, 8, 7, 6,
+            3, // connect request
+            0, 1, 0, 0, 0, 3, // protocol version
+            0, 0, 0, 0, 0, 0 // padding
+        ])
+    ]);
+
+    assert_eq!(server_events(&mut server), vec![]);
+
+    let challenge = server.socket().unwrap().sent().pop().unwrap();
+    assert_eq!(challenge.1[8], 4);
+
+    // Mismatching clients report both versions
+    let mut client = Client::<MockSocket, BinaryRateLimiter, NoopPacketModifier>::new(Config {
+        application_version: 2,
+        .. Config::default()
+    });
+    client.connect("127.0.0.1:1234").ok();
+    client.send(false).ok();
+
+    let request = client.socket().unwrap().sent().pop().unwrap().1;
+    server.socket().unwrap().mock_receive(vec![("255.1.1.1:1000", request)]);
+    server_events(&mut server);
+
+    let denial = server.socket().unwrap().sent().pop().unwrap().1;
+    client.socket().unwrap().mock_receive(vec![("127.0.0.1:1234", denial)]);
+
+    assert_eq!(client.receive(), Ok(ClientEvent::ConnectionVersionMismatch(
+        ProtocolVersion {
+            wire: 1,
+            application: 2
+        },
+        ProtocolVersion {
+            wire: 1,
+            application: 3
+        }
+    )));
+
+}
+
+#[test]
+fn test_server_admission_policy() {
+
+    let mut server = Server::<MockSocket, BinaryRateLimiter, NoopPacketModifier>::new(Config::default());
+    server.listen("127.0.0.1:1234").ok();
+
+    server.set_admission_policy(|addr: SocketAddr, id: ConnectionID, payload: &[u8]| {
+        assert_eq!(addr.ip(), "255.1.1.1".parse::<IpAddr>().unwrap());
+        assert_ne!(id, ConnectionID(0));
+        if payload == b"secret" {
+            Admission::Accept
+
+        } else if payload.is_empty() {
+            Admission::Reject(None)
+
+        } else {
+            Admission::Reject(Some(b"Invalid password".to_vec()))
+        }
+    });
+
+    // Clients without a payload are denied without a reason
+    server_handshake_with_payload(&mut server, "255.1.1.1:1000", [9, 8, 7, 6], b"");
+    server.socket().unwrap().assert_sent(vec![
+        ("255.1.1.1:1000", [
+            1, 2, 3, 4,
+            9, 8, 7, 6,
+            6, // denial
+            2 // denied
+
+        ].to_vec())
+    ]);
+
+    // Clients with the wrong payload are denied with the policy's reason
+    server_handshake_with_payload(&mut server, "255.1.1.1:2000", [5, 5, 1, 1], b"guessed");
+    server.socket().unwrap().assert_sent(vec![
+        ("255.1.1.1:2000", [
+            1, 2, 3, 4,
+            5, 5, 1, 1,
+            6, // denial
+            3, // denied with reason
+            73, 110, 118, 97, 108, 105, 100, 32, 112, 97, 115, 115, 119, 111, 114, 100
+
+        ].to_vec())
+    ]);
+
+    // Reasons never make a denial exceed the size of the challenge response
+    server_handshake_with_payload(&mut server, "255.1.1.1:3000", [4, 4, 4, 4], b"x");
+    server.socket().unwrap().assert_sent(vec![
+        ("255.1.1.1:3000", [
+            1, 2, 3, 4,
+            4, 4, 4, 4,
+            6, // denial
+            3, // denied with reason
+            73, 110, 118, 97, 108, 105, 100, 32, 112, 97, 115, 115
+
+        ].to_vec())
     ]);
 
-    assert_eq!(server.connections().keys().collect::<Vec<&ConnectionID>>(), vec![&ConnectionID(151521030)]);
+    assert_eq!(server.connections().len(), 0);
 
-    // Accept another incoming connection
+    // Clients with the correct payload are accepted
+    server_handshake_with_payload(&mut server, "255.1.1.1:4000", [1, 1, 1, 1], b"secret");
+    assert_eq!(server.connections().len(), 1);
+    assert!(server.connection(&ConnectionID(16843009)).is_ok());
+
+    // Without a policy all clients are accepted
+    server.clear_admission_policy();
+    server_handshake(&mut server, "255.1.1.2:1000", [9, 8, 7, 6]);
+    assert_eq!(server.connections().len(), 2);
+
+}
+
+#[test]
+fn test_server_connect_tokens() {
+
+    let mut server = Server::<MockSocket, BinaryRateLimiter, NoopPacketModifier>::new(Config::default());
+    server.listen("127.0.0.1:1234").ok();
+    server.set_connect_token_key(b"secret", "1.2.3.4:1234".parse().unwrap());
+
+    let token = ConnectToken::new(
+        42,
+        "1.2.3.4:1234".parse().unwrap(),
+        Duration::from_secs(30),
+        b"Player".to_vec()
+    );
+
+    let denial = |id: [u8; 4]| vec![
+        1, 2, 3, 4,
+        id[0], id[1], id[2], id[3],
+        6, // denial
+        4 // invalid token
+    ];
+
+    // Clients without a token are rejected
+    server_handshake(&mut server, "255.1.1.1:1000", [1, 1, 1, 1]);
+    server.socket().unwrap().assert_sent(vec![("255.1.1.1:1000", denial([1, 1, 1, 1]))]);
+
+    // Forged tokens are rejected
+    server_handshake_with_payload(&mut server, "255.1.1.1:1000", [1, 1, 1, 1], &token.sign(b"guessed"));
+    server.socket().unwrap().assert_sent(vec![("255.1.1.1:1000", denial([1, 1, 1, 1]))]);
+
+    // Expired tokens are rejected
+    let mut expired = token.clone();
+    expired.expires = SystemTime::now() - Duration::from_secs(1);
+    server_handshake_with_payload(&mut server, "255.1.1.1:1000", [1, 1, 1, 1], &expired.sign(b"secret"));
+    server.socket().unwrap().assert_sent(vec![("255.1.1.1:1000", denial([1, 1, 1, 1]))]);
+
+    // Tokens for other servers are rejected
+    let mut other = token.clone();
+    other.server_address = "1.2.3.4:1235".parse().unwrap();
+    server_handshake_with_payload(&mut server, "255.1.1.1:1000", [1, 1, 1, 1], &other.sign(b"secret"));
+    server.socket().unwrap().assert_sent(vec![("255.1.1.1:1000", denial([1, 1, 1, 1]))]);
+
+    assert_eq!(server.connections().len(), 0);
+
+    // Valid tokens are accepted and passed on with the connection event
+    let signed = token.sign(b"secret");
+    server_handshake_with_payload(&mut server, "255.1.1.1:1000", [9, 8, 7, 6], &signed);
     server.socket().unwrap().mock_receive(vec![
-        ("255.1.1.1:2000", vec![
+        ("255.1.1.1:1000", vec![
             1, 2, 3, 4,
-            4, 0, 0, 1,
-            0,
-            0,
+            9, 8, 7, 6,
+            1,
+            0, 0,
+            0, 0,
             0, 0, 0, 0
         ])
     ]);
 
     assert_eq!(server_events(&mut server), vec![
-        ServerEvent::Connection(ConnectionID(67108865))
+        ServerEvent::Connection(ConnectionID(151521030), Some(token.clone()))
     ]);
 
-    {
-        let mut keys = server.connections().keys().collect::<Vec<&ConnectionID>>();
-        keys.sort();
-        assert_eq!(keys, vec![
-            &ConnectionID(67108865),
-            &ConnectionID(151521030)
-        ]);
-    }
+    // Replayed tokens are rejected
+    server_handshake_with_payload(&mut server, "255.1.1.2:1000", [5, 5, 1, 1], &signed);
+    let packet = server.socket().unwrap().sent().pop().unwrap();
+    assert_eq!(packet.0, "255.1.1.2:1000".parse().unwrap());
+    assert_eq!(packet.1, denial([5, 5, 1, 1]));
 
-    // Switch the first connection to another peer address
+    assert_eq!(server.connections().len(), 1);
+
+    // Tokens are no longer required once the key is cleared
+    server.clear_connect_token_key();
+    server_handshake(&mut server, "255.1.1.2:1000", [5, 5, 1, 1]);
+    assert_eq!(server.connections().len(), 2);
+
+}
+
+#[test]
+fn test_server_encryption() {
+
+    let config = Config {
+        encryption: true,
+        .. Config::default()
+    };
+
+    let mut server = Server::<MockSocket, BinaryRateLimiter, NoopPacketModifier>::new(config);
+    server.listen("127.0.0.1:1234").ok();
+
+    // Clients without encryption are rejected
     server.socket().unwrap().mock_receive(vec![
-        ("255.1.1.2:1003", vec![
+        ("255.1.1.1:1000", vec![
             1, 2, 3, 4,
             9, 8, 7, 6,
-            0,
-            0,
-            0, 0, 0, 0
+            3, // connect request
+            0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0
         ])
     ]);
 
-    // Shutdown and drop all connections
-    server.shutdown().ok();
-
     assert_eq!(server_events(&mut server), vec![]);
+    server.socket().unwrap().assert_sent(vec![
+        ("255.1.1.1:1000", [
+            1, 2, 3, 4,
+            9, 8, 7, 6,
+            6, // denial
+            5 // encryption required
 
-    // All connections should have been removed
-    assert!(server.connections().keys().collect::<Vec<&ConnectionID>>().is_empty());
-    assert!(server.connection(&ConnectionID(151521030)).is_err());
-    assert!(server.connection(&ConnectionID(67108865)).is_err());
+        ].to_vec())
+    ]);
+
+    // Clients with encryption complete the handshake
+    let mut client = Client::<MockSocket, BinaryRateLimiter, NoopPacketModifier>::new(config);
+    client.connect("127.0.0.1:1234").ok();
+
+    server.send(false).ok();
+
+    let mut events = Vec::new();
+    let mut client_events = Vec::new();
+    let mut server_received = Vec::new();
+    for i in 0..4 {
+
+        if i == 3 {
+            client.connection().unwrap().send(MessageKind::Instant, b"Ping".to_vec()).ok();
+            server.connection(&client.connection().unwrap().id()).unwrap().send(
+                MessageKind::Instant, b"Pong".to_vec()
+            ).ok();
+        }
+
+        client.send(false).ok();
+        for packet in client.socket().unwrap().sent() {
+            server_received.push(packet.1.len());
+            server.socket().unwrap().mock_receive(vec![("255.1.1.1:2000", packet.1)]);
+        }
+
+        while let Ok(event) = server.accept_receive() {
+            events.push(event);
+        }
+
+        server.send(false).ok();
+        for packet in server.socket().unwrap().sent() {
+            client.socket().unwrap().mock_receive(vec![("127.0.0.1:1234", packet.1)]);
+        }
+
+        while let Ok(event) = client.receive() {
+            client_events.push(event);
+        }
+
+    }
+
+    // Request and response carry the public keys, the response payload and
+    // data packets are sealed
+    assert_eq!(server_received, vec![53, 69, 41, 49]);
+
+    assert_eq!(client_events, vec![
+        ClientEvent::Connection,
+        ClientEvent::MessageDelivered(MessageID(0)),
+        ClientEvent::Message(b"Pong".to_vec())
+    ]);
+
+    let id = client.connection().unwrap().id();
+    assert_eq!(events, vec![
+        ServerEvent::Connection(id, None),
+        ServerEvent::Message(id, b"Ping".to_vec())
+    ]);
+
+    assert!(server.connection(&id).unwrap().encrypted());
+    assert!(client.connection().unwrap().encrypted());
+
+    // Clients without encryption are informed about the requirement
+    let mut client = Client::<MockSocket, BinaryRateLimiter, NoopPacketModifier>::new(Config::default());
+    client.connect("127.0.0.1:1234").ok();
+    client.send(false).ok();
+
+    let request = client.socket().unwrap().sent().pop().unwrap().1;
+    server.socket().unwrap().mock_receive(vec![("255.1.1.2:2000", request)]);
+    server_events(&mut server);
+
+    let denial = server.socket().unwrap().sent().pop().unwrap().1;
+    client.socket().unwrap().mock_receive(vec![("127.0.0.1:1234", denial)]);
+    assert_eq!(client.receive(), Ok(ClientEvent::ConnectionRejected(
+        RejectionReason::EncryptionRequired
+    )));
+
+}
+
+#[test]
+fn test_server_encryption_challenge_keys() {
+
+    let config = Config {
+        encryption: true,
+        .. Config::default()
+    };
+
+    let mut server = Server::<MockSocket, BinaryRateLimiter, NoopPacketModifier>::new(config);
+    server.listen("127.0.0.1:1234").ok();
+
+    let mut keys = Vec::new();
+    for _ in 0..2 {
+
+        let mut client = Client::<MockSocket, BinaryRateLimiter, NoopPacketModifier>::new(config);
+        client.connect("127.0.0.1:1234").ok();
+        client.send(false).ok();
+
+        let request = client.socket().unwrap().sent().pop().unwrap().1;
+        server.socket().unwrap().mock_receive(vec![("255.1.1.1:2000", request)]);
+        server_events(&mut server);
+
+        let challenge = server.socket().unwrap().sent().pop().unwrap().1;
+        assert_eq!(challenge.len(), 53);
+        keys.push(challenge[21..].to_vec());
+
+    }
+
+    // Each challenge comes with its own key pair
+    assert_ne!(keys[0], keys[1]);
+
+}
+
+#[test]
+fn test_server_encryption_connect_tokens() {
+
+    let config = Config {
+        encryption: true,
+        .. Config::default()
+    };
+
+    let mut server = Server::<MockSocket, BinaryRateLimiter, NoopPacketModifier>::new(config);
+    server.listen("127.0.0.1:1234").ok();
+    server.set_connect_token_key(b"secret", "127.0.0.1:1234".parse().unwrap());
+
+    let token = ConnectToken::new(
+        42,
+        "127.0.0.1:1234".parse().unwrap(),
+        Duration::from_secs(30),
+        b"Player".to_vec()
+    );
+
+    let signed = token.sign(b"secret");
+    let mut client = Client::<MockSocket, BinaryRateLimiter, NoopPacketModifier>::new(config);
+    client.connect_with_token("127.0.0.1:1234", signed.clone()).ok();
+
+    let mut responses = Vec::new();
+    let mut events = Vec::new();
+    for _ in 0..3 {
+
+        client.send(false).ok();
+        for packet in client.socket().unwrap().sent() {
+            responses.push(packet.1.clone());
+            server.socket().unwrap().mock_receive(vec![("255.1.1.1:2000", packet.1)]);
+        }
+
+        while let Ok(event) = server.accept_receive() {
+            events.push(event);
+        }
+
+        server.send(false).ok();
+        for packet in server.socket().unwrap().sent() {
+            client.socket().unwrap().mock_receive(vec![("127.0.0.1:1234", packet.1)]);
+        }
+
+        while client.receive().is_ok() {}
+
+    }
+
+    // The token is never send in plain text
+    assert!(responses.iter().all(|packet| {
+        !packet.windows(signed.len()).any(|w| w == &signed[..])
+    }));
+
+    let id = client.connection().unwrap().id();
+    assert_eq!(events, vec![ServerEvent::Connection(id, Some(token))]);
 
 }
 
@@ -220,28 +943,41 @@ fn test_server_connection_address_remap() {
     server.listen("127.0.0.1:1234").ok();
 
     // Accept a incoming connection
+    server_handshake(&mut server, "255.1.1.1:1000", [9, 8, 7, 6]);
     server.socket().unwrap().mock_receive(vec![
         ("255.1.1.1:1000", vec![
             1, 2, 3, 4,
             9, 8, 7, 6,
-            0,
-            0,
+            1,
+            0, 0,
+            0, 0,
             0, 0, 0, 0
         ])
     ]);
 
     assert_eq!(server_events(&mut server), vec![
-        ServerEvent::Connection(ConnectionID(151521030))
+        ServerEvent::Connection(ConnectionID(151521030), None)
     ]);
 
     // Test send to initial address
     server.send(false).ok();
     server.socket().unwrap().assert_sent(vec![
+        // First packet send after the handshake completed
         ("255.1.1.1:1000", [
             1, 2, 3, 4,
             9, 8, 7, 6,
-            0,
-            0,
+            1,
+            0, 0,
+            0, 0,
+            0, 0, 0, 0
+
+        ].to_vec()),
+        ("255.1.1.1:1000", [
+            1, 2, 3, 4,
+            9, 8, 7, 6,
+            1,
+            0, 1,
+            0, 0,
             0, 0, 0, 0
 
         ].to_vec())
@@ -253,7 +989,8 @@ fn test_server_connection_address_remap() {
             1, 2, 3, 4,
             9, 8, 7, 6,
             1,
-            0,
+            0, 1,
+            0, 0,
             0, 0, 0, 0
         ])
     ]);
@@ -268,7 +1005,8 @@ fn test_server_connection_address_remap() {
             1, 2, 3, 4,
             9, 8, 7, 6,
             1,
-            1,
+            0, 2,
+            0, 1,
             0, 0, 0, 1
 
         ].to_vec())
@@ -279,8 +1017,9 @@ fn test_server_connection_address_remap() {
         ("255.1.1.8:4000", vec![
             1, 2, 3, 4,
             9, 8, 7, 6,
-            0,
-            0,
+            1,
+            0, 0,
+            0, 0,
             0, 0, 0, 0
         ])
     ]);
@@ -293,8 +1032,9 @@ fn test_server_connection_address_remap() {
         ("255.1.1.4:2000", [
             1, 2, 3, 4,
             9, 8, 7, 6,
-            2,
             1,
+            0, 3,
+            0, 1,
             0, 0, 0, 1
 
         ].to_vec())
@@ -314,12 +1054,14 @@ fn test_server_reset_events() {
     server.listen("127.0.0.1:1234").ok();
 
     // Accept a incoming connection
+    server_handshake(&mut server, "255.1.1.1:1000", [9, 8, 7, 6]);
     server.socket().unwrap().mock_receive(vec![
         ("255.1.1.1:1000", vec![
             1, 2, 3, 4,
             9, 8, 7, 6,
-            0,
-            0,
+            1,
+            0, 0,
+            0, 0,
             0, 0, 0, 0
         ])
     ]);
@@ -344,35 +1086,46 @@ fn test_server_send() {
     server.listen("127.0.0.1:1234").ok();
 
     // Accept a incoming connection
+    server_handshake(&mut server, "255.1.1.1:1000", [9, 8, 7, 6]);
     server.socket().unwrap().mock_receive(vec![
         ("255.1.1.1:1000", vec![
             1, 2, 3, 4,
             9, 8, 7, 6,
-            0,
-            0,
+            1,
+            0, 0,
+            0, 0,
             0, 0, 0, 0
         ])
     ]);
 
     assert_eq!(server_events(&mut server), vec![
-        ServerEvent::Connection(ConnectionID(151521030))
+        ServerEvent::Connection(ConnectionID(151521030), None)
     ]);
 
     assert!(server.connection(&ConnectionID(1)).is_err());
 
     // Send via connection handle
-    server.connection(&ConnectionID(151521030)).unwrap().send(MessageKind::Instant, b"Foo".to_vec());
-    server.connection(&ConnectionID(151521030)).unwrap().send(MessageKind::Instant, b"Bar".to_vec());
+    server.connection(&ConnectionID(151521030)).unwrap().send(MessageKind::Instant, b"Foo".to_vec()).ok();
+    server.connection(&ConnectionID(151521030)).unwrap().send(MessageKind::Instant, b"Bar".to_vec()).ok();
 
     // Check connections map entries
     assert_eq!(server.connections().keys().collect::<Vec<&ConnectionID>>(), vec![&ConnectionID(151521030)]);
 
     // Stats should not be updated before send is called
-    assert_eq!(server.bytes_sent(), 0);
-    assert_eq!(server.bytes_received(), 0);
+    assert_eq!(server.bytes_sent(), 17);
+    assert_eq!(server.bytes_received(), 42);
 
-    // No messages should be send before send is called
-    server.socket().unwrap().assert_sent_none();
+    // No messages should be send before send is called, only the initial
+    // packet of the connection
+    server.socket().unwrap().assert_sent(vec![("255.1.1.1:1000", [
+        1, 2, 3, 4,
+        9, 8, 7, 6,
+        1,
+        0, 0,
+        0, 0,
+        0, 0, 0, 0
+
+    ].to_vec())]);
 
     // Both messages should be send after the send call
     server.send(false).ok();
@@ -380,7 +1133,8 @@ fn test_server_send() {
         1, 2, 3, 4,
         9, 8, 7, 6,
         0,
-        0,
+        0, 1,
+        0, 0,
         0, 0, 0, 0,
         0, 0, 0, 3, 70, 111, 111,
         0, 0, 0, 3, 66, 97, 114
@@ -388,8 +1142,8 @@ fn test_server_send() {
     ].to_vec())]);
 
     // Stats should be updated after send call
-    assert_eq!(server.bytes_sent(), 28);
-    assert_eq!(server.bytes_received(), 14);
+    assert_eq!(server.bytes_sent(), 48);
+    assert_eq!(server.bytes_received(), 59);
 
     // Switch connection to new address
     server.socket().unwrap().mock_receive(vec![
@@ -397,7 +1151,8 @@ fn test_server_send() {
             1, 2, 3, 4,
             9, 8, 7, 6,
             1,
-            0,
+            0, 1,
+            0, 0,
             0, 0, 0, 0
         ])
     ]);
@@ -405,7 +1160,7 @@ fn test_server_send() {
     server.accept_receive().ok();
 
     // Send to new address
-    server.connection(&ConnectionID(151521030)).unwrap().send(MessageKind::Instant, b"Baz".to_vec());
+    server.connection(&ConnectionID(151521030)).unwrap().send(MessageKind::Instant, b"Baz".to_vec()).ok();
 
     // Check connections map entries
     assert_eq!(server.connections().keys().collect::<Vec<&ConnectionID>>(), vec![&ConnectionID(151521030)]);
@@ -416,16 +1171,17 @@ fn test_server_send() {
         ("255.1.1.2:1001", [
             1, 2, 3, 4,
             9, 8, 7, 6,
-            1,
-            1,
+            0,
+            0, 2,
+            0, 1,
             0, 0, 0, 1,
             0, 0, 0, 3, 66, 97, 122
 
         ].to_vec())
     ]);
 
-    assert_eq!(server.bytes_sent(), 49);
-    assert_eq!(server.bytes_received(), 28);
+    assert_eq!(server.bytes_sent(), 72);
+    assert_eq!(server.bytes_received(), 76);
 
     // Shutdown and reset stats
     server.shutdown().ok();
@@ -441,12 +1197,15 @@ fn test_server_receive() {
     server.listen("127.0.0.1:1234").ok();
 
     // Accept incoming connections
+    server_handshake(&mut server, "255.1.1.1:1000", [9, 8, 7, 6]);
+    server_handshake(&mut server, "255.1.1.2:2000", [5, 5, 1, 1]);
     server.socket().unwrap().mock_receive(vec![
         ("255.1.1.1:1000", vec![
             1, 2, 3, 4,
             9, 8, 7, 6,
             0,
-            0,
+            0, 0,
+            0, 0,
             0, 0, 0, 0,
             0, 0, 0, 3, 66, 97, 122
         ]),
@@ -454,7 +1213,8 @@ fn test_server_receive() {
             1, 2, 3, 4,
             5, 5, 1, 1,
             0,
-            0,
+            0, 0,
+            0, 0,
             0, 0, 0, 0,
             1, 0, 0, 3, 70, 111, 111
         ])
@@ -465,7 +1225,8 @@ fn test_server_receive() {
             1, 2, 3, 4,
             9, 8, 7, 6,
             0,
-            0,
+            0, 0,
+            0, 0,
             0, 0, 0, 0,
             0, 0, 0, 3, 66, 97, 122
         ]),
@@ -473,16 +1234,17 @@ fn test_server_receive() {
             1, 2, 3, 4,
             5, 5, 1, 1,
             0,
-            0,
+            0, 0,
+            0, 0,
             0, 0, 0, 0,
             1, 0, 0, 3, 70, 111, 111
         ])
     ]);
 
     assert_eq!(server_events(&mut server), vec![
-        ServerEvent::Connection(ConnectionID(151521030)),
+        ServerEvent::Connection(ConnectionID(151521030), None),
         ServerEvent::Message(ConnectionID(151521030), b"Baz".to_vec()),
-        ServerEvent::Connection(ConnectionID(84214017)),
+        ServerEvent::Connection(ConnectionID(84214017), None),
         ServerEvent::Message(ConnectionID(84214017), b"Foo".to_vec())
     ]);
 
@@ -492,7 +1254,8 @@ fn test_server_receive() {
             1, 2, 3, 4,
             9, 8, 7, 6,
             0,
-            0,
+            0, 0,
+            0, 0,
             0, 0, 0, 0,
             0, 0, 0, 3, 66, 97, 122
         ]),
@@ -500,7 +1263,8 @@ fn test_server_receive() {
             1, 2, 3, 4,
             5, 5, 1, 1,
             0,
-            0,
+            0, 0,
+            0, 0,
             0, 0, 0, 0,
             1, 0, 0, 3, 70, 111, 111
         ])
@@ -513,16 +1277,18 @@ fn test_server_receive() {
         ("255.1.1.1:1000", vec![
             1, 2, 3, 4,
             9, 8, 7, 6,
-            1,
             0,
+            0, 1,
+            0, 0,
             0, 0, 0, 0,
             1, 0, 0, 3, 70, 111, 111
         ]),
         ("255.1.1.2:2000", vec![
             1, 2, 3, 4,
             5, 5, 1, 1,
-            1,
             0,
+            0, 1,
+            0, 0,
             0, 0, 0, 0,
             0, 0, 0, 3, 66, 97, 122
         ])
@@ -542,19 +1308,23 @@ fn test_server_connection_close() {
     server.listen("127.0.0.1:1234").ok();
 
     // Accept incoming connections
+    server_handshake(&mut server, "255.1.1.1:1000", [9, 8, 7, 6]);
+    server_handshake(&mut server, "255.1.1.2:2000", [5, 5, 1, 1]);
     server.socket().unwrap().mock_receive(vec![
         ("255.1.1.1:1000", vec![
             1, 2, 3, 4,
             9, 8, 7, 6,
-            0,
-            0,
+            1,
+            0, 0,
+            0, 0,
             0, 0, 0, 0
         ]),
         ("255.1.1.2:2000", vec![
             1, 2, 3, 4,
             5, 5, 1, 1,
-            0,
-            0,
+            1,
+            0, 0,
+            0, 0,
             0, 0, 0, 0
         ])
     ]);
@@ -564,7 +1334,8 @@ fn test_server_connection_close() {
             1, 2, 3, 4,
             9, 8, 7, 6,
             0,
-            0,
+            0, 0,
+            0, 0,
             0, 0, 0, 0,
             0, 0, 0, 3, 66, 97, 122
         ]),
@@ -572,15 +1343,16 @@ fn test_server_connection_close() {
             1, 2, 3, 4,
             5, 5, 1, 1,
             0,
-            0,
+            0, 0,
+            0, 0,
             0, 0, 0, 0,
             1, 0, 0, 3, 70, 111, 111
         ])
     ]);
 
     assert_eq!(server_events(&mut server), vec![
-        ServerEvent::Connection(ConnectionID(151521030)),
-        ServerEvent::Connection(ConnectionID(84214017)),
+        ServerEvent::Connection(ConnectionID(151521030), None),
+        ServerEvent::Connection(ConnectionID(84214017), None),
     ]);
 
     // Receive closure packet
@@ -588,8 +1360,9 @@ fn test_server_connection_close() {
         ("255.1.1.1:1000", vec![
             1, 2, 3, 4,
             9, 8, 7, 6,
-            0, 128, // Most distant sequence numbers
-            85, 85, 85, 85 // ack bitfield with every second bit set
+            2, // closure packet type
+            0, 0, 0, 0, // sequence numbers
+            0, 0, 0, 0 // ack bitfield
         ])
     ]);
 
@@ -630,23 +1403,28 @@ fn test_server_connection_loss() {
     server.listen("127.0.0.1:1234").ok();
 
     // Accept a incoming connection
+    server_handshake(&mut server, "255.1.1.1:1000", [9, 8, 7, 6]);
     server.socket().unwrap().mock_receive(vec![
         ("255.1.1.1:1000", vec![
             1, 2, 3, 4,
             9, 8, 7, 6,
-            0,
-            0,
+            1,
+            0, 0,
+            0, 0,
             0, 0, 0, 0
         ])
     ]);
 
     assert_eq!(server_events(&mut server), vec![
-        ServerEvent::Connection(ConnectionID(151521030))
+        ServerEvent::Connection(ConnectionID(151521030), None)
     ]);
 
     assert!(server.connection(&ConnectionID(151521030)).is_ok());
     assert_eq!(server.connections().keys().collect::<Vec<&ConnectionID>>(), vec![&ConnectionID(151521030)]);
 
+    // Initial packet of the connection
+    assert_eq!(server.socket().unwrap().sent_count(), 1);
+
     // Let the connection attempt time out
     thread::sleep(Duration::from_millis(200));
 
@@ -668,6 +1446,67 @@ fn test_server_connection_loss() {
 
 }
 
+#[test]
+fn test_server_requests() {
+
+    let mut server = Server::<MockSocket, BinaryRateLimiter, NoopPacketModifier>::new(Config {
+        connection_drop_threshold: Duration::from_millis(100),
+        .. Config::default()
+    });
+    server.listen("127.0.0.1:1234").ok();
+
+    // Requests require a connection
+    assert_eq!(server.request(&ConnectionID(151521030), Vec::new()).unwrap_err().kind(), ErrorKind::NotFound);
+
+    // Accept a incoming connection which sends a request
+    server_handshake(&mut server, "255.1.1.1:1000", [9, 8, 7, 6]);
+    server.socket().unwrap().mock_receive(vec![
+        ("255.1.1.1:1000", vec![
+            1, 2, 3, 4,
+            9, 8, 7, 6,
+            0,
+            0, 0,
+            0, 0,
+            0, 0, 0, 0,
+            5, 0, 0, 9, 0, 0, 0, 0, 7, 80, 105, 110, 103
+        ])
+    ]);
+
+    assert_eq!(server_events(&mut server), vec![
+        ServerEvent::Connection(ConnectionID(151521030), None),
+        ServerEvent::Request(ConnectionID(151521030), RequestID(7), b"Ping".to_vec())
+    ]);
+
+    server.socket().unwrap().sent();
+
+    // Respond to the client and send a request of our own
+    server.respond(&ConnectionID(151521030), RequestID(7), Ok(b"Pong".to_vec())).unwrap();
+    assert_eq!(server.request(&ConnectionID(151521030), b"Time".to_vec()).unwrap(), RequestID(0));
+
+    assert_eq!(server_events(&mut server), vec![]);
+    server.socket().unwrap().assert_sent(vec![
+        ("255.1.1.1:1000", [
+            1, 2, 3, 4,
+            9, 8, 7, 6,
+            0,
+            0, 1,
+            0, 0,
+            0, 0, 0, 0,
+            5, 0, 0, 9, 1, 0, 0, 0, 7, 80, 111, 110, 103,
+            5, 1, 0, 9, 0, 0, 0, 0, 0, 84, 105, 109, 101
+
+        ].to_vec())
+    ]);
+
+    // The pending request fails once the connection is lost
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(server_events(&mut server), vec![
+        ServerEvent::ConnectionLost(ConnectionID(151521030)),
+        ServerEvent::RequestTimedOut(ConnectionID(151521030), RequestID(0))
+    ]);
+
+}
+
 #[test]
 #[cfg(target_os = "linux")]
 fn test_server_flush_auto_delay() {
@@ -727,6 +1566,60 @@ fn test_server_auto_delay_with_load() {
 
 
 // Helpers --------------------------------------------------------------------
+fn server_handshake(
+    server: &mut Server<MockSocket, BinaryRateLimiter, NoopPacketModifier>,
+    addr: &str,
+    id: [u8; 4]
+) {
+    server_handshake_with_payload(server, addr, id, &[]);
+}
+
+fn server_handshake_with_payload(
+    server: &mut Server<MockSocket, BinaryRateLimiter, NoopPacketModifier>,
+    addr: &str,
+    id: [u8; 4],
+    payload: &[u8]
+) {
+
+    // Request a challenge from the server
+    server.socket().unwrap().mock_receive(vec![
+        (addr, vec![
+            1, 2, 3, 4,
+            id[0], id[1], id[2], id[3],
+            3, // connect request
+            0, 1, 0, 0, 0, 0, // protocol version
+            0, 0, 0, 0, 0, 0 // padding
+        ])
+    ]);
+
+    assert_eq!(server_events(server), vec![]);
+
+    // The challenge is send immediately
+    let challenge = server.socket().unwrap().sent().pop().unwrap();
+    assert_eq!(challenge.0, addr.parse().unwrap());
+    assert_eq!(challenge.1[0..9].to_vec(), vec![
+        1, 2, 3, 4,
+        id[0], id[1], id[2], id[3],
+        4, // challenge
+    ]);
+
+    // Return the cookie to the server
+    let mut response = vec![
+        1, 2, 3, 4,
+        id[0], id[1], id[2], id[3],
+        5, // challenge response
+    ];
+    response.extend_from_slice(&challenge.1[9..]);
+    response.extend_from_slice(payload);
+
+    server.socket().unwrap().mock_receive(vec![(addr, response)]);
+
+    // The connection is only established once the client sends its first
+    // packet
+    assert_eq!(server_events(server), vec![]);
+
+}
+
 fn server_events(server: &mut Server<MockSocket, BinaryRateLimiter, NoopPacketModifier>) -> Vec<ServerEvent> {
     server.send(false).ok();
     let mut events = Vec::new();
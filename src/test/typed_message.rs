@@ -0,0 +1,172 @@
+// Copyright (c) 2015-2017 Ivo Wetzel
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+extern crate serde;
+
+
+// STD Dependencies -----------------------------------------------------------
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::net::SocketAddr;
+use std::io::ErrorKind;
+
+
+// External Dependencies ------------------------------------------------------
+use self::serde::{Deserialize, Serialize};
+
+
+// Internal Dependencies ------------------------------------------------------
+use super::MockSocket;
+use ::{
+    encode_typed, BinaryRateLimiter, Config, Connection, ConnectionEvent,
+    ConnectionID, MessageKind, MessageRegistry, NoopPacketModifier,
+    RateLimiter, PacketModifier, Socket, TypedMessage
+};
+
+
+// Tests ----------------------------------------------------------------------
+#[test]
+fn test_encode_decode() {
+
+    let mut registry = MessageRegistry::<()>::new();
+    registry.register::<Chat>();
+    registry.register::<Move>();
+
+    let payload = encode_typed(&Move { x: -1, y: 2 }).unwrap();
+    assert_eq!(&payload[0..2], &[0, 2]);
+
+    let event = registry.decode(&payload).unwrap();
+    assert_eq!(event.id(), 2);
+    assert!(event.is::<Move>());
+    assert!(!event.is::<Chat>());
+    assert_eq!(event.downcast::<Move>().ok(), Some(Move { x: -1, y: 2 }));
+
+}
+
+#[test]
+fn test_downcast_other_type() {
+
+    let mut registry = MessageRegistry::<()>::new();
+    registry.register::<Chat>();
+
+    let payload = encode_typed(&Chat { text: "Hello".to_string() }).unwrap();
+    let event = registry.decode(&payload).unwrap();
+
+    // The event is given back so it can be downcast to another type
+    let event = event.downcast::<Move>().unwrap_err();
+    assert_eq!(event.downcast::<Chat>().ok(), Some(Chat { text: "Hello".to_string() }));
+
+}
+
+#[test]
+fn test_decode_errors() {
+
+    let mut registry = MessageRegistry::<()>::new();
+    registry.register::<Chat>();
+
+    // Missing type id
+    assert_eq!(registry.decode(&[0]).unwrap_err().kind(), ErrorKind::InvalidData);
+
+    // Unregistered type
+    let payload = encode_typed(&Move { x: 1, y: 1 }).unwrap();
+    assert_eq!(registry.decode(&payload).unwrap_err().kind(), ErrorKind::InvalidData);
+
+    // Truncated payload
+    let payload = encode_typed(&Chat { text: "Hello".to_string() }).unwrap();
+    let truncated = &payload[..payload.len() - 1];
+    assert_eq!(registry.decode(truncated).unwrap_err().kind(), ErrorKind::InvalidData);
+
+    // Length prefix exceeding the payload
+    assert_eq!(registry.decode(&[0, 1, 255, 255]).unwrap_err().kind(), ErrorKind::InvalidData);
+
+}
+
+#[test]
+fn test_dispatch() {
+
+    let received = Rc::new(RefCell::new(Vec::new()));
+    let handled = received.clone();
+
+    let mut registry = MessageRegistry::<ConnectionID>::new();
+    registry.register::<Chat>();
+    registry.handle::<Move, _>(move |id, m| {
+        handled.borrow_mut().push((id, m));
+    });
+
+    // Handled messages are consumed
+    let payload = encode_typed(&Move { x: 3, y: 4 }).unwrap();
+    assert!(registry.dispatch(ConnectionID(7), &payload).unwrap().is_none());
+    assert_eq!(*received.borrow(), vec![(ConnectionID(7), Move { x: 3, y: 4 })]);
+
+    // Messages without a handler are returned
+    let payload = encode_typed(&Chat { text: "Hi".to_string() }).unwrap();
+    let event = registry.dispatch(ConnectionID(7), &payload).unwrap().unwrap();
+    assert_eq!(event.downcast::<Chat>().ok(), Some(Chat { text: "Hi".to_string() }));
+
+    // Invalid messages are reported
+    assert!(registry.dispatch(ConnectionID(7), &[0, 9]).is_err());
+    assert_eq!(received.borrow().len(), 1);
+
+}
+
+#[test]
+fn test_connection_send_typed() {
+
+    let mut conn = create_connection();
+    let mut remote = create_connection();
+    let mut socket = MockSocket::new(conn.local_addr(), 0).unwrap();
+    let address = conn.peer_addr();
+
+    conn.send_typed(MessageKind::Reliable, &Chat { text: "Hello".to_string() }).unwrap();
+    conn.send_packet(&mut socket, &address);
+
+    for packet in socket.sent() {
+        remote.receive_packet(packet.1);
+    }
+
+    let mut registry = MessageRegistry::<()>::new();
+    registry.register::<Chat>();
+
+    let messages: Vec<Chat> = remote.events().filter_map(|event| match event {
+        ConnectionEvent::Message(payload) => registry.decode(&payload).ok(),
+        _ => None
+
+    }).filter_map(|event| event.downcast::<Chat>().ok()).collect();
+
+    assert_eq!(messages, vec![Chat { text: "Hello".to_string() }]);
+
+}
+
+
+// Helpers --------------------------------------------------------------------
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Chat {
+    text: String
+}
+
+impl TypedMessage for Chat {
+    const ID: u16 = 1;
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Move {
+    x: i32,
+    y: i32
+}
+
+impl TypedMessage for Move {
+    const ID: u16 = 2;
+}
+
+fn create_connection() -> Connection<BinaryRateLimiter, NoopPacketModifier> {
+    let config = Config::default();
+    let local_address: SocketAddr = "127.0.0.1:1234".parse().unwrap();
+    let peer_address: SocketAddr = "255.1.1.2:5678".parse().unwrap();
+    let limiter = BinaryRateLimiter::new(config);
+    let modifier = NoopPacketModifier::new(config);
+    Connection::new(config, local_address, peer_address, limiter, modifier)
+}
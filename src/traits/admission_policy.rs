@@ -0,0 +1,88 @@
+// Copyright (c) 2015-2017 Ivo Wetzel
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// STD Dependencies -----------------------------------------------------------
+use std::fmt;
+use std::net::SocketAddr;
+
+
+// Internal Dependencies ------------------------------------------------------
+use super::super::ConnectionID;
+
+
+/// Enum of possible outcomes of an `AdmissionPolicy` decision.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Admission {
+
+    /// The connection is created and the client gets connected.
+    Accept,
+
+    /// The connection attempt is rejected and the optional reason is send
+    /// back to the client.
+    ///
+    /// Reasons get truncated to the size of the client's challenge response
+    /// packet minus the size of an empty denial packet, so the denial a
+    /// server sends back is never larger than the packet it received from
+    /// the client.
+    Reject(Option<Vec<u8>>)
+
+}
+
+/// Trait describing a policy which decides whether a server accepts an
+/// incoming connection attempt.
+///
+/// The policy is only consulted for clients which completed the challenge
+/// handshake and is implemented for all closures with a matching signature.
+///
+/// # Examples
+///
+/// ```
+/// use std::net::SocketAddr;
+/// use cobalt::{
+///     Admission, BinaryRateLimiter, Config, ConnectionID, NoopPacketModifier,
+///     Server, UdpSocket
+/// };
+///
+/// let mut server = Server::<UdpSocket, BinaryRateLimiter, NoopPacketModifier>::new(Config::default());
+///
+/// // Only accept clients which present the correct password
+/// server.set_admission_policy(|_: SocketAddr, _: ConnectionID, payload: &[u8]| {
+///     if payload == b"secret" {
+///         Admission::Accept
+///
+///     } else {
+///         Admission::Reject(Some(b"Invalid password".to_vec()))
+///     }
+/// });
+/// ```
+pub trait AdmissionPolicy {
+
+    /// Method that decides whether the connection attempt of the client at
+    /// `addr` with the given `id` is accepted, based on the `payload` of its
+    /// challenge response.
+    fn admit(
+        &mut self, addr: SocketAddr, id: ConnectionID, payload: &[u8]
+
+    ) -> Admission;
+
+}
+
+impl<F> AdmissionPolicy for F where F: FnMut(SocketAddr, ConnectionID, &[u8]) -> Admission {
+    fn admit(
+        &mut self, addr: SocketAddr, id: ConnectionID, payload: &[u8]
+
+    ) -> Admission {
+        self(addr, id, payload)
+    }
+}
+
+impl fmt::Debug for dyn AdmissionPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "AdmissionPolicy")
+    }
+}
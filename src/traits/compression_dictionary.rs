@@ -0,0 +1,35 @@
+// Copyright (c) 2015-2017 Ivo Wetzel
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+/// Trait describing a preset dictionary used by the compressing packet
+/// modifiers.
+///
+/// Both ends of a connection must use the same dictionary. A good dictionary
+/// contains byte sequences which are common in the application's messages,
+/// allowing even small payloads to be compressed efficiently.
+///
+/// # Examples
+///
+/// ```
+/// use cobalt::CompressionDictionary;
+///
+/// #[derive(Debug)]
+/// struct GameDictionary;
+///
+/// impl CompressionDictionary for GameDictionary {
+///     fn dictionary() -> &'static [u8] {
+///         b"PlayerPositionUpdate PlayerHealthUpdate"
+///     }
+/// }
+/// ```
+pub trait CompressionDictionary {
+
+    /// Method that returns the dictionary's contents.
+    fn dictionary() -> &'static [u8];
+
+}
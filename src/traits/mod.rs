@@ -1,11 +1,17 @@
 // Modules --------------------------------------------------------------------
+mod admission_policy;
+mod compression_dictionary;
 mod packet_modifier;
+mod packet_producer;
 mod rate_limiter;
 mod socket;
 
 
 // Re-Exports -----------------------------------------------------------------
-pub use self::packet_modifier::PacketModifier;
+pub use self::admission_policy::{Admission, AdmissionPolicy};
+pub use self::compression_dictionary::CompressionDictionary;
+pub use self::packet_modifier::{PacketContext, PacketModifier};
+pub use self::packet_producer::PacketProducer;
 pub use self::rate_limiter::RateLimiter;
 pub use self::socket::Socket;
 
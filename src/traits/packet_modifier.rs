@@ -8,10 +8,37 @@
 
 // STD Dependencies -----------------------------------------------------------
 use std::fmt;
+use std::io::Error;
+use std::net::SocketAddr;
 
 
 // Internal Dependencies ------------------------------------------------------
-use super::super::Config;
+use super::super::{Config, ConnectionID};
+
+
+/// Structure describing the packet and connection a `PacketModifier` is
+/// invoked for.
+#[derive(Debug, Copy, Clone)]
+pub struct PacketContext<'a> {
+
+    /// The id of the connection the packet is send or received over.
+    pub id: ConnectionID,
+
+    /// The address of the remote end of the connection.
+    pub peer_address: SocketAddr,
+
+    /// The local sequence number of the connection, for outgoing packets
+    /// this is the sequence number of the packet itself.
+    pub local_sequence: u32,
+
+    /// The remote sequence number of the connection, for incoming packets
+    /// this is the sequence number of the packet itself.
+    pub remote_sequence: u32,
+
+    /// The raw header of the packet.
+    pub header: &'a [u8]
+
+}
 
 
 /// Trait describing optional per-packet payload modification logic.
@@ -20,6 +47,17 @@ pub trait PacketModifier {
     /// Method that constructs a new packet modifier using the provided configuration.
     fn new(Config) -> Self where Self: Sized;
 
+    /// Method that returns the maximum number of bytes by which the modifier
+    /// may grow a payload.
+    ///
+    /// Connections reserve this many bytes in each packet so modified packets
+    /// never exceed the configured `packet_max_size`.
+    ///
+    /// The default implementation returns `0`.
+    fn max_overhead(&self) -> usize {
+        0
+    }
+
     /// Method that is called for payload modification before a packet is send
     /// over a connection's underlying socket.
     ///
@@ -38,6 +76,32 @@ pub trait PacketModifier {
         None
     }
 
+    /// Method that is called for payload modification before a packet is send
+    /// over a connection's underlying socket, along with the context of the
+    /// packet.
+    ///
+    /// The default implementation simply calls `outgoing`.
+    fn outgoing_packet(&mut self, payload: &[u8], _: &PacketContext) -> Option<Vec<u8>> {
+        self.outgoing(payload)
+    }
+
+    /// Method that is called for payload verification and modification
+    /// purposes after a packet is received over a connection's underlying
+    /// socket, along with the context of the packet.
+    ///
+    /// Returning an error makes the connection drop the packet before it has
+    /// any effect on the connection's state.
+    ///
+    /// The default implementation simply calls `incoming`.
+    fn incoming_packet(
+        &mut self,
+        payload: &[u8],
+        _: &PacketContext
+
+    ) -> Result<Option<Vec<u8>>, Error> {
+        Ok(self.incoming(payload))
+    }
+
 }
 
 impl fmt::Debug for PacketModifier {
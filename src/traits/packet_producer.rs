@@ -0,0 +1,69 @@
+// Copyright (c) 2015-2017 Ivo Wetzel
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// STD Dependencies -----------------------------------------------------------
+use std::fmt;
+
+
+/// Trait describing a producer which fills the remaining space of outgoing
+/// packets with the freshest application data.
+///
+/// A connection invokes its producer every time it sends a packet, after all
+/// queued messages have been written. The produced data is send as a single
+/// message of the kind `MessageKind::Instant`, which is neither re-send nor
+/// reported via `ConnectionEvent::MessageDelivered` or
+/// `ConnectionEvent::MessageLost`.
+///
+/// The trait is implemented for all closures with a matching signature.
+///
+/// # Examples
+///
+/// ```
+/// use std::net::SocketAddr;
+/// use cobalt::{
+///     BinaryRateLimiter, Config, Connection, NoopPacketModifier,
+///     PacketModifier, RateLimiter
+/// };
+///
+/// let config = Config::default();
+/// let local_address: SocketAddr = "127.0.0.1:0".parse().unwrap();
+/// let peer_address: SocketAddr = "255.0.0.1:0".parse().unwrap();
+/// let limiter = BinaryRateLimiter::new(config);
+/// let modifier = NoopPacketModifier::new(config);
+/// let mut conn = Connection::new(config, local_address, peer_address, limiter, modifier);
+///
+/// // Send the most recent position with every packet
+/// let position: [u8; 8] = [0; 8];
+/// conn.set_packet_producer(move |available: usize, payload: &mut Vec<u8>| {
+///     if available >= position.len() {
+///         payload.extend_from_slice(&position);
+///     }
+/// });
+/// ```
+pub trait PacketProducer {
+
+    /// Method that writes up to `available` bytes of data into `payload`
+    /// right before a packet is send.
+    ///
+    /// Nothing is send in case the `payload` is left empty, payloads which
+    /// exceed the `available` bytes are dropped.
+    fn produce(&mut self, available: usize, payload: &mut Vec<u8>);
+
+}
+
+impl<F> PacketProducer for F where F: FnMut(usize, &mut Vec<u8>) {
+    fn produce(&mut self, available: usize, payload: &mut Vec<u8>) {
+        self(available, payload)
+    }
+}
+
+impl fmt::Debug for dyn PacketProducer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PacketProducer")
+    }
+}